@@ -0,0 +1,157 @@
+//! Restores with `--atomic`, into a directory next to the target that is only moved into place
+//! once the restore is complete
+
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process;
+
+use crate::{color, confirm, recursive_remove, RestoreOptions};
+
+/// Restores the backup at `path` into a new directory in `output_dir`, then replaces what it
+/// has of the same names with the restored entries. Killed before that, only the new directory
+/// is left behind, and the target is as it was.
+pub(crate) fn restore(path: &Path, output_dir: &Path, opts: &RestoreOptions) -> io::Result<()> {
+    let temp = output_dir.join(format!(".loppler-restore-{}", process::id()));
+    fs::create_dir(&temp)?;
+    // nothing is in the way in an empty directory
+    let inner = RestoreOptions {
+        atomic: false,
+        yes: true,
+        ..opts.clone()
+    };
+    let result = crate::restore(path, &temp, &inner)
+        .and_then(|()| move_into_place(&temp, output_dir, opts.yes));
+    // what is left is a failed restore, or what was replaced
+    recursive_remove(&temp)?;
+    result
+}
+
+/// Moves the entries of `temp` into `output_dir`, replacing those of the same names there after
+/// asking, unless `yes`. Each is replaced in one step, as long as the filesystem can exchange
+/// them, and what was there before is moved into `temp`.
+fn move_into_place(temp: &Path, output_dir: &Path, yes: bool) -> io::Result<()> {
+    let mut names = fs::read_dir(temp)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<OsString>>>()?;
+    names.sort();
+    let existing: Vec<_> = names
+        .iter()
+        .map(|name| output_dir.join(name))
+        .filter(|target| target.symlink_metadata().is_ok())
+        .collect();
+    if !existing.is_empty() {
+        for target in &existing {
+            println!("would be replaced: {}", target.display());
+        }
+        if !(yes || confirm(format!("replace {} existing entries?", existing.len()))?) {
+            let e = io::Error::new(io::ErrorKind::Interrupted, "restore cancelled");
+            eprintln!("{}", color::error(&e));
+            return Err(e);
+        }
+    }
+    for name in names {
+        let restored = temp.join(&name);
+        let target = output_dir.join(&name);
+        match exchange(&restored, &target) {
+            Ok(()) => (),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => fs::rename(&restored, &target)?,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+                // gone for a moment, but never half restored
+                let old = temp.join(format!(".loppler-replaced-{}", process::id()));
+                fs::rename(&target, &old)?;
+                fs::rename(&restored, &target)?;
+                recursive_remove(&old)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Swaps the entries at `a` and `b` in one step, fails with `ENOENT` if either does not exist
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let c = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other);
+    let (a, b) = (c(a)?, c(b)?);
+    // SAFETY: both paths are valid C strings for as long as the call takes
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::exchange;
+    use crate::tests::BACKUP_KINDS;
+    use crate::{backup_dir, restore, BackupOptions, BackupStats, RestoreOptions};
+
+    #[test]
+    #[serial]
+    fn test_atomic() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("foo"), b"foo")?;
+
+        let opts = RestoreOptions {
+            atomic: true,
+            yes: true,
+            ..Default::default()
+        };
+        for &compress in BACKUP_KINDS {
+            let backup_opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &backup_opts, &mut BackupStats::default())?;
+            fs::write(src.join("foo"), b"changed")?;
+            fs::write(src.join("extra"), b"extra")?;
+            restore(&backup, t.path(), &opts)?;
+            // the restored directory replaces the old one as a whole
+            assert_eq!(fs::read(src.join("foo"))?, b"foo");
+            assert!(!src.join("extra").exists());
+            let names: Vec<_> = fs::read_dir(t.path())?
+                .map(|entry| entry.unwrap().file_name())
+                .filter(|name| name.as_encoded_bytes().starts_with(b".loppler"))
+                .collect();
+            assert!(names.is_empty(), "{names:?}");
+
+            // a damaged backup leaves the target alone
+            if compress.is_some() {
+                fs::write(src.join("foo"), b"changed")?;
+                let archive = fs::read(&backup)?;
+                fs::write(&backup, &archive[..archive.len() / 2])?;
+                assert!(restore(&backup, t.path(), &opts).is_err());
+                assert_eq!(fs::read(src.join("foo"))?, b"changed");
+                fs::write(src.join("foo"), b"foo")?;
+            }
+            crate::recursive_remove(&backup)?;
+        }
+
+        fs::remove_dir_all(&src)?;
+        fs::write("a", b"a")?;
+        assert!(exchange("a".as_ref(), "b".as_ref()).is_err());
+        Ok(())
+    }
+}
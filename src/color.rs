@@ -0,0 +1,111 @@
+//! Colors for messages on terminals: errors red, created paths green and skipped ones yellow
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ValueEnum;
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+
+static STDOUT: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// When messages are colored, `--color`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum ColorChoice {
+    /// Only on terminals, and not if `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Decides for stdout and stderr separately whether their messages are colored
+pub(crate) fn init(choice: ColorChoice) {
+    let enabled = |fd| match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            // see <https://no-color.org>
+            std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                && std::env::var_os("TERM").is_none_or(|term| term != "dumb")
+                // SAFETY: isatty only inspects the descriptor
+                && unsafe { libc::isatty(fd) } == 1
+        }
+    };
+    STDOUT.store(enabled(libc::STDOUT_FILENO), Ordering::Relaxed);
+    STDERR.store(enabled(libc::STDERR_FILENO), Ordering::Relaxed);
+}
+
+/// An error, for stderr
+pub(crate) fn error<T: fmt::Display>(text: T) -> Painted<T> {
+    Painted {
+        text,
+        code: RED,
+        stream: &STDERR,
+    }
+}
+
+/// A path that was created, for stdout
+pub(crate) fn created<T: fmt::Display>(text: T) -> Painted<T> {
+    Painted {
+        text,
+        code: GREEN,
+        stream: &STDOUT,
+    }
+}
+
+/// Something that was skipped, for stdout
+pub(crate) fn skipped<T: fmt::Display>(text: T) -> Painted<T> {
+    Painted {
+        text,
+        code: YELLOW,
+        stream: &STDOUT,
+    }
+}
+
+/// Text that is colored when displayed, if its stream gets colors
+pub(crate) struct Painted<T> {
+    text: T,
+    code: &'static str,
+    stream: &'static AtomicBool,
+}
+
+impl<T> Painted<T> {
+    /// For stderr instead
+    pub(crate) fn stderr(self) -> Self {
+        Painted {
+            stream: &STDERR,
+            ..self
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.stream.load(Ordering::Relaxed) {
+            write!(f, "\x1b[{}m{}\x1b[0m", self.code, self.text)
+        } else {
+            self.text.fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{created, error, init, skipped, ColorChoice};
+
+    #[test]
+    fn test_color() {
+        init(ColorChoice::Always);
+        assert_eq!(error("no").to_string(), "\x1b[31mno\x1b[0m");
+        assert_eq!(created(5).to_string(), "\x1b[32m5\x1b[0m");
+        assert_eq!(skipped("x").stderr().to_string(), "\x1b[33mx\x1b[0m");
+
+        init(ColorChoice::Never);
+        assert_eq!(error("no").to_string(), "no");
+        assert_eq!(skipped(format_args!("{}-{}", 1, 2)).to_string(), "1-2");
+    }
+}
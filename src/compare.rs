@@ -0,0 +1,457 @@
+//! Comparing a backup against the live files it was made from
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::hash::{hash_reader, to_hex, HashAlgorithm};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::unpack::normalize_path;
+use crate::{
+    has_named_suffix, has_suffix, read_archive, remove_extension, walk_dir, BackupOptions,
+    BackupStats, Compression,
+};
+
+/// How an entry differs between a backup and the live tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Change {
+    /// Only on disk
+    Added,
+    /// Only in the backup
+    Removed,
+    /// In both, but with a different type, size or content
+    Modified,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Modified => "modified",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// The kind of a backup entry, as far as comparing is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Dir,
+    File,
+    Other,
+}
+
+impl Kind {
+    /// The kind of archive entries of type `ty`
+    pub(crate) fn of(ty: tar::EntryType) -> Self {
+        if ty.is_dir() {
+            Kind::Dir
+        } else if ty.is_file() {
+            Kind::File
+        } else {
+            Kind::Other
+        }
+    }
+}
+
+/// Collects the changes while going over the entries of a backup
+#[derive(Debug, Default)]
+struct Comparison {
+    changes: Vec<(Change, PathBuf)>,
+    /// Names and kinds of all entries in the backup
+    seen: HashMap<PathBuf, Kind>,
+    /// Top level names of the backup, these are scanned for added files
+    roots: HashSet<PathBuf>,
+}
+
+impl Comparison {
+    /// Compares one entry of the backup named `name` against its counterpart below `base`
+    fn entry(
+        &mut self,
+        base: &Path,
+        name: PathBuf,
+        kind: Kind,
+        size: u64,
+        content: &mut dyn Read,
+    ) -> io::Result<()> {
+        if let Some(Component::Normal(root)) = name.components().next() {
+            self.roots.insert(PathBuf::from(root));
+        }
+        let live = base.join(&name);
+        // files and directories might come from followed symlinks, anything else is compared
+        // as it is
+        let meta = match kind {
+            Kind::Other => fs::symlink_metadata(&live),
+            _ => fs::metadata(&live),
+        };
+        let change = match meta {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Some(Change::Removed),
+            Err(e) => return Err(e),
+            Ok(meta) => {
+                let unchanged = match kind {
+                    Kind::Dir => meta.is_dir(),
+                    Kind::File => {
+                        meta.is_file()
+                            && meta.len() == size
+                            && hash_reader(HashAlgorithm::Sha256, content)?
+                                == hash_reader(HashAlgorithm::Sha256, fs::File::open(&live)?)?
+                    }
+                    Kind::Other => !meta.is_dir() && !meta.is_file(),
+                };
+                (!unchanged).then_some(Change::Modified)
+            }
+        };
+        if let Some(change) = change {
+            self.changes.push((change, name.clone()));
+        }
+        self.seen.insert(name, kind);
+        Ok(())
+    }
+
+    /// Compares one file of a `--manifest-only` manifest against its counterpart below
+    /// `base`. Manifests only list files, the directories they are in count as seen. Only
+    /// `root`, the path that was given to the backup, is scanned for added files, as it
+    /// might be next to others that were not.
+    fn manifest_entry(
+        &mut self,
+        base: &Path,
+        algorithm: HashAlgorithm,
+        entry: &ManifestEntry,
+        root: &OsStr,
+    ) -> io::Result<()> {
+        let name = &entry.name;
+        match name.ancestors().find(|p| p.file_name() == Some(root)) {
+            Some(root) => self.roots.insert(root.to_path_buf()),
+            None => self.roots.insert(name.iter().take(1).collect()),
+        };
+        let live = base.join(name);
+        let change = match fs::metadata(&live) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Some(Change::Removed),
+            Err(e) => return Err(e),
+            Ok(meta) => {
+                let unchanged = meta.is_file()
+                    && meta.len() == entry.size
+                    && to_hex(&hash_reader(algorithm, fs::File::open(&live)?)?) == entry.hash;
+                (!unchanged).then_some(Change::Modified)
+            }
+        };
+        if let Some(change) = change {
+            self.changes.push((change, name.clone()));
+        }
+        for dir in name.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() {
+                self.seen.insert(dir.to_path_buf(), Kind::Dir);
+            }
+        }
+        self.seen.insert(name.clone(), Kind::File);
+        Ok(())
+    }
+
+    /// Scans the live trees of all roots for entries that are not in the backup
+    fn added(&mut self, base: &Path) -> io::Result<()> {
+        let roots: Vec<PathBuf> = self.roots.iter().cloned().collect();
+        for root in roots {
+            let live = base.join(&root);
+            if !live.is_dir() {
+                continue;
+            }
+            walk_dir(
+                &live,
+                &root,
+                &BackupOptions::default(),
+                &mut BackupStats::default(),
+                &mut |_, rel, _, _| {
+                    if let Some(kind) = self.seen.get(rel) {
+                        Ok(*kind == Kind::Dir)
+                    } else {
+                        // added directories are reported as a whole
+                        self.changes.push((Change::Added, rel.to_path_buf()));
+                        Ok(false)
+                    }
+                },
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Calls `visit` with the name, kind, size and content of every entry of the backup at
+/// `backup`. Names are what a restore creates below its output directory.
+pub(crate) fn backup_entries<F>(
+    backup: &Path,
+    window_log_max: Option<u32>,
+    mut visit: F,
+) -> io::Result<()>
+where
+    F: FnMut(PathBuf, Kind, u64, &mut dyn Read) -> io::Result<()>,
+{
+    if Compression::detect(backup).is_some() {
+        read_archive(backup, window_log_max, |a| archive_entries(a, visit))
+    } else if has_named_suffix(backup, ".bak") {
+        let name = remove_extension(backup, "bak");
+        let name = PathBuf::from(name.file_name().expect("checked to have a name"));
+        // copies of symlinks are symlinks, which might not point anywhere
+        if backup.is_symlink() {
+            return visit(name, Kind::Other, 0, &mut io::empty());
+        }
+        let size = fs::metadata(backup)?.len();
+        visit(name, Kind::File, size, &mut fs::File::open(backup)?)
+    } else if has_named_suffix(backup, ".bak.d") {
+        let root = remove_extension(backup, "bak.d");
+        let root = PathBuf::from(root.file_name().expect("checked to have a name"));
+        visit(root.clone(), Kind::Dir, 0, &mut io::empty())?;
+        walk_dir(
+            backup,
+            &root,
+            &BackupOptions::default(),
+            &mut BackupStats::default(),
+            &mut |path, rel, ty, _| {
+                if ty.is_dir() {
+                    visit(rel.to_path_buf(), Kind::Dir, 0, &mut io::empty())?;
+                } else if ty.is_file() {
+                    let size = fs::metadata(path)?.len();
+                    visit(
+                        rel.to_path_buf(),
+                        Kind::File,
+                        size,
+                        &mut fs::File::open(path)?,
+                    )?;
+                } else {
+                    visit(rel.to_path_buf(), Kind::Other, 0, &mut io::empty())?;
+                }
+                Ok(ty.is_dir())
+            },
+        )
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not a backup: {}", backup.display()),
+        ))
+    }
+}
+
+/// Calls `visit` like [`backup_entries`] for every entry of an opened archive
+pub(crate) fn archive_entries<R, F>(archive: &mut tar::Archive<R>, mut visit: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(PathBuf, Kind, u64, &mut dyn Read) -> io::Result<()>,
+{
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = Kind::of(entry.header().entry_type());
+        let name = normalize_path(&entry.path()?);
+        let size = entry.size();
+        visit(name, kind, size, &mut entry)?;
+    }
+    Ok(())
+}
+
+/// Compares the backup at `backup` against the live files below `base`, which is where a
+/// restore to `base` would put them. `backup` can be a manifest of `backup --manifest-only`
+/// as well. The changes are sorted by path.
+pub(crate) fn compare(
+    backup: &Path,
+    base: &Path,
+    window_log_max: Option<u32>,
+) -> io::Result<Vec<(Change, PathBuf)>> {
+    let mut cmp = Comparison::default();
+    if has_suffix(backup, "manifest") {
+        let manifest = Manifest::read(io::BufReader::new(fs::File::open(backup)?))?;
+        let root = remove_extension(backup, "manifest");
+        let root = root.file_name().unwrap_or_default();
+        for entry in &manifest.entries {
+            cmp.manifest_entry(base, manifest.algorithm, entry, root)?;
+        }
+    } else {
+        backup_entries(backup, window_log_max, |name, kind, size, content| {
+            cmp.entry(base, name, kind, size, content)
+        })?;
+    }
+    cmp.added(base)?;
+    cmp.changes.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(cmp.changes)
+}
+
+/// Kind, size and content hash of a backup entry, for [`diff`]
+type EntrySummary = (Kind, u64, Vec<u8>);
+
+/// Hashes the files of the backup at `backup`, for [`diff`]
+fn entry_map(
+    backup: &Path,
+    window_log_max: Option<u32>,
+) -> io::Result<HashMap<PathBuf, EntrySummary>> {
+    let mut entries = HashMap::new();
+    backup_entries(backup, window_log_max, |name, kind, size, content| {
+        let hash = match kind {
+            // only compared among backups, so the fastest cryptographic hash will do
+            Kind::File => hash_reader(HashAlgorithm::Blake3, content)?,
+            _ => Vec::new(),
+        };
+        entries.insert(name, (kind, size, hash));
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Compares the backup at `old` against the one at `new`, entries only in `new` are
+/// [`Change::Added`]. The changes are sorted by path.
+pub(crate) fn diff(
+    old: &Path,
+    new: &Path,
+    window_log_max: Option<u32>,
+) -> io::Result<Vec<(Change, PathBuf)>> {
+    let old = entry_map(old, window_log_max)?;
+    let new = entry_map(new, window_log_max)?;
+
+    let mut changes = Vec::new();
+    for (name, entry) in &old {
+        match new.get(name) {
+            None => changes.push((Change::Removed, name.clone())),
+            Some(other) if other != entry => changes.push((Change::Modified, name.clone())),
+            Some(_) => (),
+        }
+    }
+    for name in new.keys().filter(|name| !old.contains_key(*name)) {
+        changes.push((Change::Added, name.clone()));
+    }
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    #[cfg(feature = "compression")]
+    use super::diff;
+    use super::{compare, Change};
+    use crate::tests::BACKUP_KINDS;
+    #[cfg(feature = "compression")]
+    use crate::Compression;
+    use crate::{backup_dir, manifest_only, BackupOptions, BackupStats};
+
+    #[test]
+    #[serial]
+    fn test_compare_archive() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("same"), b"same")?;
+        fs::write(src.join("changed"), b"before")?;
+        fs::write(src.join("sub").join("gone"), b"gone")?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            assert!(compare(&backup, tdir, None)?.is_empty());
+
+            fs::write(src.join("changed"), b"after!")?;
+            fs::remove_file(src.join("sub").join("gone"))?;
+            fs::create_dir(src.join("new"))?;
+            fs::write(src.join("new").join("file"), b"new")?;
+
+            let changes = compare(&backup, tdir, None)?;
+            assert_eq!(
+                changes,
+                vec![
+                    (Change::Modified, src.join("changed")),
+                    (Change::Added, src.join("new")),
+                    (Change::Removed, src.join("sub").join("gone")),
+                ]
+            );
+
+            // back to the original state for the next round
+            fs::write(src.join("changed"), b"before")?;
+            fs::write(src.join("sub").join("gone"), b"gone")?;
+            fs::remove_dir_all(src.join("new"))?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_compare_manifest() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("top").join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("same"), b"same")?;
+        fs::write(src.join("changed"), b"before")?;
+        fs::write(src.join("sub").join("gone"), b"gone")?;
+
+        let manifest = manifest_only(&src, &BackupOptions::default(), &mut BackupStats::default())?;
+        assert_eq!(manifest, PathBuf::from("top/src.manifest"));
+        assert!(!PathBuf::from("top/src.bak.d").exists());
+        assert!(compare(&manifest, tdir, None)?.is_empty());
+
+        // the same size, only the hash tells
+        fs::write(src.join("changed"), b"after!")?;
+        fs::remove_file(src.join("sub").join("gone"))?;
+        fs::write(src.join("new"), b"new")?;
+        assert_eq!(
+            compare(&manifest, tdir, None)?,
+            vec![
+                (Change::Modified, src.join("changed")),
+                (Change::Added, src.join("new")),
+                (Change::Removed, src.join("sub").join("gone")),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_diff() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("same"), b"same")?;
+        fs::write(src.join("changed"), b"before")?;
+        fs::write(src.join("sub").join("gone"), b"gone")?;
+
+        let old = backup_dir(
+            &src,
+            &BackupOptions {
+                compress: Some(Compression::Zstd),
+                ..Default::default()
+            },
+            &mut BackupStats::default(),
+        )?;
+        fs::write(src.join("changed"), b"after!")?;
+        fs::remove_file(src.join("sub").join("gone"))?;
+        fs::write(src.join("new"), b"new")?;
+        // different kinds of backups can be compared as well
+        let new = backup_dir(&src, &BackupOptions::default(), &mut BackupStats::default())?;
+
+        assert!(diff(&old, &old, None)?.is_empty());
+        assert_eq!(
+            diff(&old, &new, None)?,
+            vec![
+                (Change::Modified, src.join("changed")),
+                (Change::Added, src.join("new")),
+                (Change::Removed, src.join("sub").join("gone")),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+//! Converting copy backups into archives and back, without reading the original files again
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compare::{archive_entries, Kind};
+use crate::manifest::Manifest;
+use crate::unpack::{unpack_archive, PrefixMap};
+use crate::{
+    add_extension, archive_dir_all, archive_extension, has_named_suffix, make_archive,
+    read_archive, recursive_remove, remove_extension, BackupOptions, BackupStats, Compression,
+    RestoreOptions,
+};
+
+/// Packs the copy of a directory at `backup` into an archive next to it, compressed like
+/// `opts` says. A manifest of the copy is kept for the archive, after checking the archive
+/// against it.
+pub(crate) fn pack(backup: &Path, opts: &BackupOptions) -> io::Result<PathBuf> {
+    if !has_named_suffix(backup, ".bak.d") || !backup.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not a directory backup: {}", backup.display()),
+        ));
+    }
+    let name = remove_extension(backup, "bak.d");
+    let opts = BackupOptions {
+        compress: Some(opts.compress.unwrap_or(Compression::Zstd)),
+        ..opts.clone()
+    };
+    let ext = archive_extension(&opts).expect("packing always creates an archive");
+    let archive_path = add_extension(&name, &ext)?;
+    refuse_existing(&archive_path)?;
+
+    // the entries get the names a restore of the copy gives them
+    let root = Path::new(name.file_name().expect("checked to have a name"));
+    let result = make_archive(&archive_path, None, &opts, |a| {
+        archive_dir_all(a, root, backup, &opts, &mut BackupStats::default())
+    })
+    .and_then(|()| {
+        let manifest = Manifest::path_for(backup)?;
+        if manifest.exists() {
+            fs::copy(&manifest, Manifest::path_for(&archive_path)?)?;
+            crate::manifest::verify_backup(&archive_path, opts.long)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&archive_path);
+        if let Ok(manifest) = Manifest::path_for(&archive_path) {
+            let _ = fs::remove_file(manifest);
+        }
+        return Err(e);
+    }
+    Ok(archive_path)
+}
+
+/// Unpacks the archive at `archive` into a copy backup next to it, `<name>.bak.d` for
+/// directories and `<name>.bak` for single files. The archive is checked against its manifest
+/// while unpacking, and the copy gets a new one.
+pub(crate) fn unpack(archive: &Path, window_log_max: Option<u32>) -> io::Result<PathBuf> {
+    let ext = ["tar.zstd", "tar.zst", "tar"]
+        .into_iter()
+        .find(|ext| has_named_suffix(archive, &format!(".{ext}")))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not an archive: {}", archive.display()),
+            )
+        })?;
+    let name = remove_extension(archive, ext);
+    let stem = name.file_name().expect("checked to have a name");
+
+    // archives are named after what was given to the backup, which might have had parents
+    let mut first = None;
+    read_archive(archive, window_log_max, |a| {
+        archive_entries(a, |name, kind, _, _| {
+            first.get_or_insert((name, kind));
+            Ok(())
+        })
+    })?;
+    let (root, single_file) = first
+        .and_then(|(name, kind)| {
+            let root = name.ancestors().find(|p| p.file_name() == Some(stem))?;
+            let single_file = root == name && kind == Kind::File;
+            Some((root.to_path_buf(), single_file))
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is not a backup of {}",
+                    archive.display(),
+                    Path::new(stem).display()
+                ),
+            )
+        })?;
+
+    let target = add_extension(&name, if single_file { ".bak" } else { ".bak.d" })?;
+    refuse_existing(&target)?;
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // entries outside of the root stay below the target as well
+    let (output_dir, to) = if single_file {
+        (
+            parent.to_path_buf(),
+            PathBuf::from(target.file_name().unwrap()),
+        )
+    } else {
+        fs::create_dir(&target)?;
+        (target.clone(), PathBuf::new())
+    };
+    let opts = RestoreOptions {
+        prefix_map: vec![PrefixMap::new(&root, &to)],
+        // like directory copies keep them
+        preserve_mtime_on_dirs: true,
+        ..Default::default()
+    };
+
+    let manifest = Manifest::for_backup(archive)?;
+    let result = read_archive(archive, window_log_max, |a| {
+        unpack_archive(a, &output_dir, &opts, manifest.as_ref()).map(drop)
+    })
+    .and_then(|()| match &manifest {
+        Some(manifest) => Manifest::create(&target, manifest.algorithm, None)?.write(
+            io::BufWriter::new(fs::File::create(Manifest::path_for(&target)?)?),
+        ),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        if target.symlink_metadata().is_ok() {
+            let _ = recursive_remove(&target);
+        }
+        return Err(e);
+    }
+    Ok(target)
+}
+
+/// Fails if something is at `path` already, converting never overwrites
+fn refuse_existing(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("already exists: {}", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    #[cfg(feature = "compression")]
+    use std::path::PathBuf;
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::{pack, unpack};
+    use crate::compare::backup_entries;
+    use crate::BackupOptions;
+    #[cfg(feature = "compression")]
+    use crate::{
+        backup_dir, backup_file,
+        compare::diff,
+        hash::HashAlgorithm,
+        manifest::{verify_backup, Manifest},
+        BackupStats, Compression,
+    };
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_pack_unpack() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::create_dir_all(src.join("empty"))?;
+        fs::write(src.join("foo"), b"foo")?;
+        fs::write(src.join("sub").join("bar"), b"bar")?;
+        std::os::unix::fs::symlink("foo", src.join("link"))?;
+
+        let opts = BackupOptions {
+            hash: Some(HashAlgorithm::Sha256),
+            ..Default::default()
+        };
+        let copy = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let archive = pack(&copy, &BackupOptions::default())?;
+        assert_eq!(archive, PathBuf::from("src.tar.zst"));
+        assert!(diff(&copy, &archive, None)?.is_empty());
+        assert!(Manifest::path_for(&archive)?.exists());
+        assert!(pack(&copy, &BackupOptions::default()).is_err());
+
+        fs::remove_dir_all(&copy)?;
+        fs::remove_file(Manifest::path_for(&copy)?)?;
+        let unpacked = unpack(&archive, None)?;
+        assert_eq!(unpacked, copy);
+        assert!(diff(&archive, &unpacked, None)?.is_empty());
+        assert_eq!(fs::read_link(unpacked.join("link"))?, PathBuf::from("foo"));
+        assert!(unpacked.join("empty").is_dir());
+        verify_backup(&unpacked, None)?;
+
+        // archives of nested paths and single files
+        let nested = PathBuf::from("src").join("sub");
+        let compressed = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        let archive = backup_dir(&nested, &compressed, &mut BackupStats::default())?;
+        let unpacked = unpack(&archive, None)?;
+        assert_eq!(fs::read(unpacked.join("bar"))?, b"bar");
+
+        let file = src.join("foo");
+        let archive = backup_file(&file, &compressed, &mut BackupStats::default())?;
+        let unpacked = unpack(&archive, None)?;
+        assert_eq!(unpacked, src.join("foo.bak"));
+        assert_eq!(fs::read(unpacked)?, b"foo");
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_unnamed_backups() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        fs::create_dir(".bak.d")?;
+        fs::write(".tar", b"")?;
+        fs::write(".bak", b"")?;
+        // names that are nothing but the extension are no backups of anything
+        let e = pack(Path::new(".bak.d"), &BackupOptions::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let e = unpack(Path::new(".tar"), None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        for name in [".bak", ".bak.d"] {
+            let e = backup_entries(name.as_ref(), None, |_, _, _, _| Ok(())).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{name}");
+        }
+        Ok(())
+    }
+}
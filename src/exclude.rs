@@ -0,0 +1,271 @@
+//! Exclude patterns in the syntax of gitignore, from the command line and `.lopplerignore`
+
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+/// Read from the root of every backed up directory
+pub(crate) const IGNORE_FILE: &str = ".lopplerignore";
+
+/// One line of an ignore file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    /// The line it was parsed from, to tell which pattern excluded something
+    line: String,
+    /// The parts between slashes, `**` matches any number of them
+    parts: Vec<Vec<u8>>,
+    /// Starts with `!`, includes what earlier patterns excluded
+    negated: bool,
+    /// Ends with `/`, only matches directories
+    dir_only: bool,
+    /// Contains a slash, so it is matched from the root instead of against every name
+    anchored: bool,
+}
+
+impl Pattern {
+    /// Parses one line, [`None`] for blank lines and comments
+    fn parse(line: &str) -> Option<Self> {
+        let mut line = line.strip_suffix('\r').unwrap_or(line);
+        // trailing spaces are ignored unless they are escaped
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let original = line.to_string();
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let line = match line.strip_prefix('\\') {
+            Some(rest) if rest.starts_with(['#', '!']) => rest,
+            _ => line,
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            line: original,
+            parts: line.split('/').map(|p| p.as_bytes().to_vec()).collect(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, names: &[&[u8]]) -> bool {
+        if self.anchored {
+            match_parts(&self.parts, names)
+        } else {
+            names
+                .last()
+                .is_some_and(|name| match_name(&self.parts[0], name))
+        }
+    }
+}
+
+/// Exclude patterns, the last one that matches a path decides
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Excludes {
+    patterns: Vec<Pattern>,
+}
+
+impl Excludes {
+    /// Adds one pattern, or ignores `line` if it is blank or a comment
+    pub(crate) fn add(&mut self, line: &str) {
+        self.patterns.extend(Pattern::parse(line));
+    }
+
+    /// Adds all patterns of the file at `path`, or none if `missing_ok` and it does not exist
+    pub(crate) fn add_file(&mut self, path: &Path, missing_ok: bool) -> io::Result<()> {
+        let content = match fs::read(path) {
+            Err(e) if missing_ok && e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+            Ok(content) => content,
+        };
+        for line in String::from_utf8_lossy(&content).lines() {
+            self.add(line);
+        }
+        Ok(())
+    }
+
+    /// Whether the entry at `rel`, relative to the backed up directory, is excluded
+    pub(crate) fn is_excluded(&self, rel: &Path, is_dir: bool) -> bool {
+        self.excluded_by(rel, is_dir).is_some()
+    }
+
+    /// The pattern that excludes the entry at `rel`, as it was given, [`None`] if it is not
+    /// excluded
+    pub(crate) fn excluded_by(&self, rel: &Path, is_dir: bool) -> Option<&str> {
+        if self.patterns.is_empty() {
+            return None;
+        }
+        let names: Vec<&[u8]> = rel
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.as_bytes()),
+                _ => None,
+            })
+            .collect();
+        let mut excluded = None;
+        for pattern in &self.patterns {
+            if (is_dir || !pattern.dir_only) && pattern.matches(&names) {
+                excluded = (!pattern.negated).then_some(pattern.line.as_str());
+            }
+        }
+        excluded
+    }
+}
+
+/// Matches the parts of a pattern against the names of a path, `**` stands for any number of
+/// names, but at least one at the end of the pattern
+fn match_parts(parts: &[Vec<u8>], names: &[&[u8]]) -> bool {
+    match parts.split_first() {
+        None => names.is_empty(),
+        Some((part, [])) if part == b"**" => !names.is_empty(),
+        Some((part, rest)) if part == b"**" => {
+            (0..=names.len()).any(|skip| match_parts(rest, &names[skip..]))
+        }
+        Some((part, rest)) => names
+            .split_first()
+            .is_some_and(|(name, names)| match_name(part, name) && match_parts(rest, names)),
+    }
+}
+
+/// Matches one part of a pattern against one name, with `*`, `?`, `[...]` and `\` escapes
+pub(crate) fn match_name(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => {
+            let rest = rest.strip_prefix(b"*").unwrap_or(rest);
+            (0..=name.len()).any(|skip| match_name(rest, &name[skip..]))
+        }
+        Some((b'?', rest)) => !name.is_empty() && match_name(rest, &name[1..]),
+        Some((b'[', _)) if name.is_empty() => false,
+        Some((b'[', rest)) => match match_class(rest, name[0]) {
+            Some((matched, rest)) => matched && match_name(rest, &name[1..]),
+            // without a closing bracket, it is an ordinary character
+            None => name[0] == b'[' && match_name(rest, &name[1..]),
+        },
+        Some((b'\\', [escaped, rest @ ..])) => {
+            name.first() == Some(escaped) && match_name(rest, &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && match_name(rest, &name[1..]),
+    }
+}
+
+/// Matches `c` against the class at the start of `pattern`, just after the `[`. Returns
+/// whether it matched and the pattern after the class, [`None`] if the class is not closed.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negated, mut rest) = match pattern.split_first() {
+        Some((b'!' | b'^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match rest {
+            [] => return None,
+            [b']', tail @ ..] if !first => return Some((matched != negated, tail)),
+            [lo, b'-', hi, tail @ ..] if *hi != b']' => {
+                matched |= (*lo..=*hi).contains(&c);
+                rest = tail;
+            }
+            [x, tail @ ..] => {
+                matched |= *x == c;
+                rest = tail;
+            }
+        }
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Excludes;
+
+    fn excludes(lines: &[&str]) -> Excludes {
+        let mut excludes = Excludes::default();
+        for line in lines {
+            excludes.add(line);
+        }
+        excludes
+    }
+
+    #[test]
+    fn test_patterns() {
+        let ex = excludes(&[
+            "# a comment",
+            "",
+            "*.log",
+            "!keep.log",
+            "build/",
+            "/top",
+            "doc/*.html",
+            "**/cache/**",
+            "a/**/z",
+            "file[0-9]",
+            "\\#literal",
+        ]);
+        let is = |path: &str, is_dir: bool| ex.is_excluded(Path::new(path), is_dir);
+
+        assert!(is("x.log", false));
+        assert!(is("deep/down/x.log", false));
+        assert!(!is("keep.log", false));
+        assert!(!is("x.logs", false));
+
+        assert!(is("build", true));
+        assert!(is("sub/build", true));
+        assert!(!is("build", false));
+
+        assert!(is("top", false));
+        assert!(!is("sub/top", false));
+
+        assert!(is("doc/index.html", false));
+        assert!(!is("doc/sub/index.html", false));
+
+        assert!(is("cache/x", false));
+        assert!(is("sub/cache/x/y", false));
+        assert!(!is("sub/cache", true));
+
+        assert!(is("a/z", false));
+        assert!(is("a/b/c/z", false));
+        assert!(!is("b/a/z", false));
+
+        assert!(is("file7", false));
+        assert!(!is("filex", false));
+        assert!(is("#literal", false));
+
+        assert!(!Excludes::default().is_excluded(Path::new("anything"), false));
+    }
+
+    #[test]
+    fn test_excluded_by() {
+        let ex = excludes(&["*.log  ", "build/", "!keep.log"]);
+        let by = |path: &str, is_dir: bool| ex.excluded_by(Path::new(path), is_dir);
+        assert_eq!(by("sub/x.log", false), Some("*.log"));
+        assert_eq!(by("build", true), Some("build/"));
+        assert_eq!(by("keep.log", false), None);
+        assert_eq!(by("x.txt", false), None);
+    }
+
+    #[test]
+    fn test_classes() {
+        let ex = excludes(&["[!a-c]x", "[]]y", "[unclosed"]);
+        let is = |path: &str| ex.is_excluded(Path::new(path), false);
+        assert!(is("dx"));
+        assert!(!is("bx"));
+        assert!(is("]y"));
+        assert!(is("[unclosed"));
+    }
+}
@@ -0,0 +1,435 @@
+//! The expressions of `--filter`, which pick the files to back up by their name, size and
+//! modification time, like `size > 1mb && ext == "log"`
+
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::{exclude, parse_duration, parse_size};
+
+/// A parsed `--filter` expression
+#[derive(Debug, Clone)]
+pub(crate) struct Filter {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Text(TextField, TextOp, Vec<u8>),
+    Number(NumberField, Cmp, i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextField {
+    Name,
+    Path,
+    /// Without the dot, compared regardless of case
+    Ext,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextOp {
+    Eq,
+    Ne,
+    /// Matches the wildcards of exclude patterns
+    Glob,
+    NotGlob,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumberField {
+    Size,
+    /// As a unix timestamp
+    Mtime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Filter {
+    /// Whether the file named `rel` below the backed up directory, with `meta`, is backed up
+    pub(crate) fn matches(&self, rel: &Path, meta: &fs::Metadata) -> bool {
+        self.expr.eval(rel, meta.len(), meta.mtime())
+    }
+}
+
+impl Expr {
+    fn eval(&self, rel: &Path, size: u64, mtime: i64) -> bool {
+        match self {
+            Expr::Not(expr) => !expr.eval(rel, size, mtime),
+            Expr::And(a, b) => a.eval(rel, size, mtime) && b.eval(rel, size, mtime),
+            Expr::Or(a, b) => a.eval(rel, size, mtime) || b.eval(rel, size, mtime),
+            Expr::Text(field, op, value) => {
+                let text = match field {
+                    TextField::Name => rel.file_name().unwrap_or_default().as_bytes().to_vec(),
+                    TextField::Path => rel.as_os_str().as_bytes().to_vec(),
+                    TextField::Ext => rel
+                        .extension()
+                        .unwrap_or_default()
+                        .as_bytes()
+                        .to_ascii_lowercase(),
+                };
+                match op {
+                    TextOp::Eq => text == *value,
+                    TextOp::Ne => text != *value,
+                    TextOp::Glob => exclude::match_name(value, &text),
+                    TextOp::NotGlob => !exclude::match_name(value, &text),
+                }
+            }
+            Expr::Number(field, cmp, value) => {
+                let number = match field {
+                    NumberField::Size => i64::try_from(size).unwrap_or(i64::MAX),
+                    NumberField::Mtime => mtime,
+                };
+                match cmp {
+                    Cmp::Eq => number == *value,
+                    Cmp::Ne => number != *value,
+                    Cmp::Lt => number < *value,
+                    Cmp::Le => number <= *value,
+                    Cmp::Gt => number > *value,
+                    Cmp::Ge => number >= *value,
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            now,
+        };
+        let expr = parser.or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {token} after the expression"));
+        }
+        Ok(Filter { expr })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// Field names, keywords and values like `1mb`, `7d` or `2024-05-01`
+    Word(String),
+    /// In quotes
+    Text(String),
+    Op(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Text(text) => write!(f, "{text:?}"),
+            Token::Op(op) => write!(f, "'{op}'"),
+        }
+    }
+}
+
+/// In the order they are tried, the longer ones first
+const OPS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=", "!~", "<", ">", "~", "!", "(", ")",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '"' || c == '\'' {
+            let mut text = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    None => return Err(format!("missing closing {c} in: {s}")),
+                    Some((i, q)) if q == c => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => text.push(escaped),
+                        None => return Err(format!("missing closing {c} in: {s}")),
+                    },
+                    Some((_, other)) => text.push(other),
+                }
+            };
+            tokens.push(Token::Text(text));
+            rest = &rest[end..];
+        } else if let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c.is_alphanumeric() || c == '.' || c == '*' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || "._-:*".contains(c)))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            return Err(format!("unexpected '{c}' in: {s}"));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// For ages like `7d`, which are that long before now
+    now: i64,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Takes the next token if it is `op`, or the keyword `word`
+    fn take(&mut self, op: &str, word: &str) -> bool {
+        let found = match self.tokens.get(self.pos) {
+            Some(Token::Op(o)) => *o == op,
+            Some(Token::Word(w)) => w == word,
+            _ => false,
+        };
+        self.pos += usize::from(found);
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.take("||", "or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.take("&&", "and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.take("!", "not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.take("(", "") {
+            let expr = self.or()?;
+            if !self.take(")", "") {
+                return Err("missing closing ')'".to_string());
+            }
+            return Ok(expr);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let field = match self.next() {
+            Some(Token::Word(word)) => word,
+            Some(token) => return Err(format!("expected a field like size or name, got {token}")),
+            None => return Err("expected a field like size or name at the end".to_string()),
+        };
+        let op = match self.next() {
+            Some(Token::Op(op)) if !matches!(op, "&&" | "||" | "!" | "(" | ")") => op,
+            Some(token) => return Err(format!("expected a comparison after {field}, got {token}")),
+            None => return Err(format!("expected a comparison after {field}")),
+        };
+        let value = match self.next() {
+            Some(Token::Word(value) | Token::Text(value)) => value,
+            Some(token) => return Err(format!("expected a value after {op}, got {token}")),
+            None => return Err(format!("expected a value after {op}")),
+        };
+        let text = match field.as_str() {
+            "name" => Some(TextField::Name),
+            "path" => Some(TextField::Path),
+            "ext" => Some(TextField::Ext),
+            _ => None,
+        };
+        if let Some(text) = text {
+            let op = match op {
+                "==" => TextOp::Eq,
+                "!=" => TextOp::Ne,
+                "~" => TextOp::Glob,
+                "!~" => TextOp::NotGlob,
+                _ => {
+                    return Err(format!(
+                        "{field} can only be compared with ==, !=, ~ and !~"
+                    ))
+                }
+            };
+            let value = match text {
+                TextField::Ext => value.trim_start_matches('.').to_ascii_lowercase(),
+                _ => value,
+            };
+            return Ok(Expr::Text(text, op, value.into_bytes()));
+        }
+        let cmp = match op {
+            "==" => Cmp::Eq,
+            "!=" => Cmp::Ne,
+            "<" => Cmp::Lt,
+            "<=" => Cmp::Le,
+            ">" => Cmp::Gt,
+            ">=" => Cmp::Ge,
+            _ => {
+                return Err(format!(
+                    "{field} can not be matched with {op}, only text can"
+                ))
+            }
+        };
+        match field.as_str() {
+            "size" => {
+                let size = parse_size(&value)
+                    .map_err(|_| format!("size needs a size like 1mb, got {value:?}"))?;
+                Ok(Expr::Number(NumberField::Size, cmp, size as i64))
+            }
+            "mtime" => {
+                let time = parse_date(&value)
+                    .or_else(|| {
+                        let age = i64::try_from(parse_duration(&value).ok()?.as_secs()).ok()?;
+                        self.now.checked_sub(age)
+                    })
+                    .ok_or_else(|| {
+                        format!(
+                            "mtime needs a date like 2024-05-01 or an age like 7d, got {value:?}"
+                        )
+                    })?;
+                Ok(Expr::Number(NumberField::Mtime, cmp, time))
+            }
+            _ => Err(format!(
+                "unknown field {field}, there are name, path, ext, size and mtime"
+            )),
+        }
+    }
+}
+
+/// The unix timestamp of the start of a `YYYY-MM-DD` in UTC
+fn parse_date(s: &str) -> Option<i64> {
+    let mut parts = s.splitn(3, '-');
+    let mut part = |len: usize| {
+        let part = parts.next()?;
+        (part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| part.parse::<i64>().ok())?
+    };
+    let (year, month, day) = (part(4)?, part(2)?, part(2)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // the civil date to days, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((era * 146_097 + doe - 719_468) * 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::{parse_date, Filter};
+    use crate::{backup_dir, BackupOptions, BackupStats};
+
+    fn eval(filter: &str, rel: &str, size: u64, mtime: i64) -> bool {
+        let filter: Filter = filter.parse().unwrap();
+        filter.expr.eval(Path::new(rel), size, mtime)
+    }
+
+    #[test]
+    fn test_filter() {
+        let big_log = ("logs/app.LOG", 2 << 20, 0);
+        let small_txt = ("notes.txt", 10, 0);
+        for (filter, big, small) in [
+            (r#"size > 1mb && ext == "log""#, true, false),
+            ("size > 1mb and ext == .log", true, false),
+            ("size <= 10 || name ~ 'app.*'", true, true),
+            ("!(ext == log)", false, true),
+            ("not ext == log && size == 10", false, true),
+            ("path ~ 'logs/*'", true, false),
+            ("name != notes.txt", true, false),
+            ("name !~ *.txt", true, false),
+        ] {
+            assert_eq!(
+                eval(filter, big_log.0, big_log.1, big_log.2),
+                big,
+                "{filter}"
+            );
+            assert_eq!(eval(filter, small_txt.0, small_txt.1, 0), small, "{filter}");
+        }
+
+        let may = parse_date("2024-05-01").unwrap();
+        assert_eq!(may, 1_714_521_600);
+        assert!(eval("mtime >= 2024-05-01", "f", 0, may));
+        assert!(!eval("mtime < '2024-05-01'", "f", 0, may));
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        assert!(eval("mtime > 7d", "f", 0, now - 3600));
+        assert!(!eval("mtime > 7d", "f", 0, now - 8 * 86400));
+
+        for bad in [
+            "",
+            "size >",
+            "size > big",
+            "color == red",
+            "name < a",
+            "size ~ 1",
+            "(size > 1",
+            "size > 1 size",
+            "name == \"open",
+            "mtime > 2024-13-01",
+            "size > 1 & ext == log",
+            "mtime > 99999999999999999d",
+            "mtime > 18446744073709551615s",
+        ] {
+            assert!(bad.parse::<Filter>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_filter_backup() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("logs"))?;
+        fs::write(src.join("logs").join("big.log"), vec![0; 2048])?;
+        fs::write(src.join("logs").join("small.log"), b"small")?;
+        fs::write(src.join("big.txt"), vec![0; 2048])?;
+
+        let opts = BackupOptions {
+            filter: Some("size > 1k && ext == log".parse().unwrap()),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert!(backup.join("logs").join("big.log").exists());
+        assert!(!backup.join("logs").join("small.log").exists());
+        assert!(!backup.join("big.txt").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+//! The pax extensions of archives: the versions of loppler and of its archive format, stored
+//! in every archive to notice archives that a newer version wrote, comments, the paths backups
+//! were made from and exact modification times. Also the kind of tar headers, `--archive-format`.
+
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+/// Bumped when archives change in a way that older versions would restore them wrongly
+pub(crate) const ARCHIVE_FORMAT: u32 = 1;
+
+const PAX_VERSION: &str = "LOPPLER.version";
+const PAX_FORMAT: &str = "LOPPLER.format";
+const PAX_MTIME: &str = "mtime";
+const PAX_COMMENT: &str = "LOPPLER.comment";
+const PAX_ORIGIN: &str = "LOPPLER.origin";
+const PAX_PATH: &str = "path";
+const PAX_LINKPATH: &str = "linkpath";
+const PAX_SIZE: &str = "size";
+
+/// Largest size that fits into the octal size field of a ustar header, 8 GiB - 1
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+/// Length of the name and link name fields of a header
+const NAME_LEN: usize = 100;
+
+/// The kind of tar headers, `--archive-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum ArchiveFormat {
+    /// ustar headers, with pax records for long paths and files of 8 GiB or more
+    #[default]
+    Pax,
+    /// GNU headers with the long names of GNU tar
+    Gnu,
+    /// ustar headers only, fails for long paths and files of 8 GiB or more
+    Ustar,
+}
+
+impl ArchiveFormat {
+    /// An empty header of the format
+    pub(crate) fn header(self) -> tar::Header {
+        match self {
+            ArchiveFormat::Gnu => tar::Header::new_gnu(),
+            ArchiveFormat::Pax | ArchiveFormat::Ustar => tar::Header::new_ustar(),
+        }
+    }
+
+    /// Sets the path and link target of a ustar `header`, whose size is set already. What does
+    /// not fit into it is added to the pax records in `data`, or fails for ustar. GNU headers
+    /// are left to the tar builder.
+    pub(crate) fn set_names(
+        self,
+        header: &mut tar::Header,
+        data: &mut Vec<u8>,
+        path: &Path,
+        target: Option<&Path>,
+    ) -> io::Result<()> {
+        let too_large = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}: the {what} does not fit into a ustar header, use --archive-format pax",
+                    path.display()
+                ),
+            )
+        };
+        if header.size()? > USTAR_MAX_SIZE {
+            if self == ArchiveFormat::Ustar {
+                return Err(too_large("size"));
+            }
+            pax_record(data, PAX_SIZE, header.size()?.to_string());
+        }
+        if let Err(e) = header.set_path(path) {
+            // like the tar builder, other errors are not about the length
+            if path.as_os_str().len() < NAME_LEN {
+                return Err(e);
+            }
+            if self == ArchiveFormat::Ustar {
+                return Err(too_large("path"));
+            }
+            long_name(data, PAX_PATH, path)?;
+            header.as_old_mut().name = truncated(path);
+        }
+        if let Some(target) = target {
+            if let Err(e) = header.set_link_name(target) {
+                if target.as_os_str().len() < NAME_LEN {
+                    return Err(e);
+                }
+                if self == ArchiveFormat::Ustar {
+                    return Err(too_large("link target"));
+                }
+                long_name(data, PAX_LINKPATH, target)?;
+                header.as_old_mut().linkname = truncated(target);
+            }
+        }
+        header.set_cksum();
+        Ok(())
+    }
+}
+
+/// Adds the record for a path that is too long for the header
+fn long_name(data: &mut Vec<u8>, key: &str, path: &Path) -> io::Result<()> {
+    let bytes = path.as_os_str().as_bytes();
+    // the tar crate splits records at line breaks
+    if bytes.contains(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}: long paths with line breaks can only be stored with --archive-format gnu",
+                path.display()
+            ),
+        ));
+    }
+    pax_record(data, key, bytes);
+    Ok(())
+}
+
+/// The start of `path` for the header, readers take the whole path from the pax records
+fn truncated(path: &Path) -> [u8; NAME_LEN] {
+    let bytes = path.as_os_str().as_bytes();
+    let mut field = [0; NAME_LEN];
+    let len = bytes.len().min(field.len());
+    field[..len].copy_from_slice(&bytes[..len]);
+    field
+}
+
+/// The pax records with the versions, for the first entry of an archive. Other tools and
+/// older versions of loppler ignore them.
+pub(crate) fn version_records() -> Vec<u8> {
+    let mut data = Vec::new();
+    pax_record(&mut data, PAX_VERSION, env!("CARGO_PKG_VERSION"));
+    pax_record(&mut data, PAX_FORMAT, ARCHIVE_FORMAT.to_string());
+    data
+}
+
+/// Adds the `--comment` of a backup to `data`, for the first entry like the versions
+pub(crate) fn comment_record(data: &mut Vec<u8>, comment: &str) {
+    pax_record(data, PAX_COMMENT, comment);
+}
+
+/// Adds the absolute path that a backup was made from to `data`, for the first entry
+pub(crate) fn origin_record(data: &mut Vec<u8>, origin: &Path) {
+    pax_record(data, PAX_ORIGIN, origin.as_os_str().as_bytes());
+}
+
+/// Adds the modification time with nanoseconds to `data`, if it has any. Times before 1970
+/// are left to the header.
+pub(crate) fn mtime_record(data: &mut Vec<u8>, mtime: SystemTime) {
+    if let Ok(since) = mtime.duration_since(UNIX_EPOCH) {
+        if since.subsec_nanos() != 0 {
+            let value = format!("{}.{:09}", since.as_secs(), since.subsec_nanos());
+            pax_record(data, PAX_MTIME, &value);
+        }
+    }
+}
+
+/// Appends pax records as extensions of the entry appended next, nothing if there are none
+pub(crate) fn append_pax<W: Write>(archiver: &mut tar::Builder<W>, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archiver.append(&header, data)
+}
+
+/// One `<length> <key>=<value>` line, the length counts the whole line including itself
+fn pax_record(data: &mut Vec<u8>, key: &str, value: impl AsRef<[u8]>) {
+    let value = value.as_ref();
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    data.extend_from_slice(format!("{len} {key}=").as_bytes());
+    data.extend_from_slice(value);
+    data.push(b'\n');
+}
+
+/// Describes why the archive that `entry` is the first entry of can not be restored reliably,
+/// [`None`] if it can. Archives without versions are from before they were stored.
+pub(crate) fn check_version<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<String>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    let (mut version, mut format): (Option<String>, Option<u32>) = (None, None);
+    for extension in extensions {
+        let extension = extension?;
+        match extension.key() {
+            Ok(PAX_VERSION) => version = extension.value().ok().map(str::to_string),
+            Ok(PAX_FORMAT) => format = extension.value().ok().and_then(|v| v.parse().ok()),
+            _ => (),
+        }
+    }
+    Ok(match format {
+        Some(format) if format > ARCHIVE_FORMAT => Some(format!(
+            "the archive was written by loppel {} in archive format {format}, this is loppel {} \
+             which only knows format {ARCHIVE_FORMAT}",
+            version.as_deref().unwrap_or("(unknown)"),
+            env!("CARGO_PKG_VERSION")
+        )),
+        _ => None,
+    })
+}
+
+/// The modification time of `entry` with the precision of its pax extensions, [`None`] if
+/// it only has the whole seconds of the header
+pub(crate) fn pax_mtime<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<SystemTime>> {
+    Ok(pax_value(entry, PAX_MTIME)?
+        .as_deref()
+        .and_then(parse_mtime))
+}
+
+/// The `--comment` of the archive that `entry` is the first entry of
+pub(crate) fn comment<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<String>> {
+    pax_value(entry, PAX_COMMENT)
+}
+
+/// The absolute path that the archive that `entry` is the first entry of was made from
+pub(crate) fn origin<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<String>> {
+    pax_value(entry, PAX_ORIGIN)
+}
+
+fn pax_value<R: Read>(entry: &mut tar::Entry<R>, key: &str) -> io::Result<Option<String>> {
+    let Some(extensions) = entry.pax_extensions()? else {
+        return Ok(None);
+    };
+    for extension in extensions {
+        let extension = extension?;
+        if extension.key() == Ok(key) {
+            return Ok(extension.value().ok().map(str::to_string));
+        }
+    }
+    Ok(None)
+}
+
+/// Parses `<seconds>[.<fraction>]`, other tools write fewer digits than nanoseconds
+fn parse_mtime(value: &str) -> Option<SystemTime> {
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let secs: u64 = secs.parse().ok()?;
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits = &fraction[..fraction.len().min(9)];
+    let nanos = format!("{digits:0<9}").parse().ok()?;
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        append_pax, check_version, comment, comment_record, mtime_record, origin, origin_record,
+        parse_mtime, pax_mtime, pax_record, version_records, ArchiveFormat, PAX_FORMAT,
+    };
+
+    #[test]
+    fn test_pax_record() {
+        let mut data = Vec::new();
+        pax_record(&mut data, "a", "b");
+        assert_eq!(data, b"6 a=b\n");
+        // counting the length makes it two digits long
+        let mut data = Vec::new();
+        pax_record(&mut data, "key", "abc");
+        assert_eq!(data, b"11 key=abc\n");
+    }
+
+    #[test]
+    fn test_check_version() -> io::Result<()> {
+        let archive = |future: bool| -> io::Result<Vec<u8>> {
+            let mut builder = tar::Builder::new(Vec::new());
+            if future {
+                let mut data = Vec::new();
+                pax_record(&mut data, PAX_FORMAT, "999");
+                let mut header = tar::Header::new_ustar();
+                header.set_entry_type(tar::EntryType::XHeader);
+                header.set_size(data.len() as u64);
+                header.set_cksum();
+                builder.append(&header, data.as_slice())?;
+            } else {
+                append_pax(&mut builder, &version_records())?;
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_size(3);
+            header.set_cksum();
+            builder.append_data(&mut header, "foo", b"foo".as_slice())?;
+            builder.into_inner()
+        };
+        let first = |data: Vec<u8>| -> io::Result<Option<String>> {
+            let mut archive = tar::Archive::new(data.as_slice());
+            let mut entry = archive.entries()?.next().unwrap()?;
+            assert_eq!(entry.path()?.to_str(), Some("foo"));
+            check_version(&mut entry)
+        };
+
+        assert_eq!(first(archive(false)?)?, None);
+        let warning = first(archive(true)?)?.unwrap();
+        assert!(warning.contains("archive format 999"), "{warning}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_names() -> io::Result<()> {
+        let long = "d/".repeat(150) + "file";
+        let huge = 1 << 33;
+        let entry = |format: ArchiveFormat, size: u64| -> io::Result<tar::Header> {
+            let mut header = format.header();
+            header.set_size(size);
+            let mut data = Vec::new();
+            format.set_names(
+                &mut header,
+                &mut data,
+                Path::new(&long),
+                Some(Path::new("t")),
+            )?;
+            let mut builder = tar::Builder::new(Vec::new());
+            append_pax(&mut builder, &data)?;
+            builder.append(&header, io::empty())?;
+            let data = builder.into_inner()?;
+            let mut archive = tar::Archive::new(data.as_slice());
+            let entry = archive.entries()?.next().unwrap()?;
+            assert_eq!(entry.path()?.to_str(), Some(long.as_str()));
+            assert_eq!(entry.link_name()?.as_deref(), Some(Path::new("t")));
+            assert_eq!(entry.size(), size);
+            Ok(entry.header().clone())
+        };
+
+        let header = entry(ArchiveFormat::Pax, 0)?;
+        assert!(header.as_ustar().is_some());
+        assert!(entry(ArchiveFormat::Ustar, 0).is_err());
+        // fits into the prefix and name fields of ustar
+        let mut header = ArchiveFormat::Ustar.header();
+        header.set_size(0);
+        let mut data = Vec::new();
+        let path = Path::new(&"a/".repeat(40)).join("c".repeat(90));
+        ArchiveFormat::Ustar.set_names(&mut header, &mut data, &path, None)?;
+        assert!(data.is_empty());
+
+        let mut header = ArchiveFormat::Pax.header();
+        header.set_size(huge);
+        let mut data = Vec::new();
+        ArchiveFormat::Pax.set_names(&mut header, &mut data, Path::new("big"), None)?;
+        assert_eq!(data, b"19 size=8589934592\n");
+        let mut header = ArchiveFormat::Ustar.header();
+        header.set_size(huge);
+        let e = ArchiveFormat::Ustar
+            .set_names(&mut header, &mut Vec::new(), Path::new("big"), None)
+            .unwrap_err();
+        assert!(e.to_string().contains("size"), "{e}");
+
+        let e = ArchiveFormat::Pax
+            .set_names(
+                &mut header,
+                &mut Vec::new(),
+                Path::new(&(long + "\n")),
+                None,
+            )
+            .unwrap_err();
+        assert!(e.to_string().contains("--archive-format gnu"), "{e}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_values() -> io::Result<()> {
+        let mtime = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let mut data = version_records();
+        comment_record(&mut data, "before the upgrade of everything");
+        origin_record(&mut data, Path::new("/etc/nginx"));
+        mtime_record(&mut data, mtime);
+        let mut builder = tar::Builder::new(Vec::new());
+        append_pax(&mut builder, &data)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mtime(1_700_000_000);
+        header.set_cksum();
+        builder.append_data(&mut header, "foo", io::empty())?;
+        let data = builder.into_inner()?;
+        let mut archive = tar::Archive::new(data.as_slice());
+        let mut entry = archive.entries()?.next().unwrap()?;
+        assert_eq!(check_version(&mut entry)?, None);
+        assert_eq!(pax_mtime(&mut entry)?, Some(mtime));
+        assert_eq!(
+            comment(&mut entry)?.as_deref(),
+            Some("before the upgrade of everything")
+        );
+        assert_eq!(origin(&mut entry)?.as_deref(), Some("/etc/nginx"));
+
+        // whole seconds need no record
+        let mut data = Vec::new();
+        mtime_record(&mut data, UNIX_EPOCH + Duration::from_secs(5));
+        assert!(data.is_empty());
+
+        let nanos = |value| parse_mtime(value).map(|t| t.duration_since(UNIX_EPOCH).unwrap());
+        assert_eq!(nanos("12.5"), Some(Duration::new(12, 500_000_000)));
+        assert_eq!(nanos("12"), Some(Duration::new(12, 0)));
+        assert_eq!(nanos("12.1234567891"), Some(Duration::new(12, 123_456_789)));
+        assert_eq!(nanos("-1.5"), None);
+        assert_eq!(nanos("1.x"), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,805 @@
+//! Hash functions for comparing and verifying backups
+
+use std::io::{self, Read};
+
+use clap::ValueEnum;
+
+/// Size of the chunks we read when hashing a stream
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Streaming SHA-256
+#[derive(Debug, Clone)]
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub(crate) fn new() -> Self {
+        Sha256 {
+            state: SHA256_IV,
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let take = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len == 64 {
+                compress(&mut self.state, &self.block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub(crate) fn finalize(mut self) -> [u8; 32] {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+        debug_assert_eq!(self.block_len, 0);
+
+        let mut out = [0; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8; 64]) {
+    let mut w = [0u32; 64];
+    for (i, chunk) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(SHA256_K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+/// Hash algorithms for checksums, SHA-256 is the only cryptographic one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Much faster than SHA-256, still cryptographic
+    Blake3,
+    /// Fastest, good for integrity checks but not against tampering
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    /// Name of the algorithm, as used on the command line and in manifests
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
+
+    /// Inverse of [`HashAlgorithm::name`]
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::value_variants()
+            .iter()
+            .copied()
+            .find(|alg| alg.name() == name)
+    }
+}
+
+/// Streaming hasher for any of the [`HashAlgorithm`]s
+#[derive(Debug, Clone)]
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Blake3(Blake3),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    pub(crate) fn new(alg: HashAlgorithm) -> Self {
+        match alg {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Blake3::new()),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => h.update(data),
+            Hasher::Xxh3(h) => h.update(data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Blake3(h) => h.finalize().to_vec(),
+            Hasher::Xxh3(h) => h.finalize().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+const BLAKE3_CHUNK_LEN: usize = 1024;
+const BLAKE3_CHUNK_START: u32 = 1;
+const BLAKE3_CHUNK_END: u32 = 2;
+const BLAKE3_PARENT: u32 = 4;
+const BLAKE3_ROOT: u32 = 8;
+const BLAKE3_MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+fn blake3_g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn blake3_compress(
+    cv: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        SHA256_IV[0],
+        SHA256_IV[1],
+        SHA256_IV[2],
+        SHA256_IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut m = *block;
+    for round in 0..7 {
+        blake3_g(&mut state, 0, 4, 8, 12, m[0], m[1]);
+        blake3_g(&mut state, 1, 5, 9, 13, m[2], m[3]);
+        blake3_g(&mut state, 2, 6, 10, 14, m[4], m[5]);
+        blake3_g(&mut state, 3, 7, 11, 15, m[6], m[7]);
+        blake3_g(&mut state, 0, 5, 10, 15, m[8], m[9]);
+        blake3_g(&mut state, 1, 6, 11, 12, m[10], m[11]);
+        blake3_g(&mut state, 2, 7, 8, 13, m[12], m[13]);
+        blake3_g(&mut state, 3, 4, 9, 14, m[14], m[15]);
+        if round < 6 {
+            m = BLAKE3_MSG_PERMUTATION.map(|i| m[i]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+fn first_8(words: [u32; 16]) -> [u32; 8] {
+    words[..8].try_into().unwrap()
+}
+
+fn le_words(bytes: &[u8; 64]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+/// Input of a final compression, either for the chaining value of a node or for the root hash
+#[derive(Debug, Clone, Copy)]
+struct Blake3Output {
+    cv: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Blake3Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8(blake3_compress(
+            &self.cv,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+
+    fn root_hash(&self) -> [u8; 32] {
+        let words = blake3_compress(
+            &self.cv,
+            &self.block,
+            0,
+            self.block_len,
+            self.flags | BLAKE3_ROOT,
+        );
+        let mut out = [0; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    fn parent(left: [u32; 8], right: [u32; 8]) -> Self {
+        let mut block = [0; 16];
+        block[..8].copy_from_slice(&left);
+        block[8..].copy_from_slice(&right);
+        Blake3Output {
+            cv: SHA256_IV,
+            block,
+            counter: 0,
+            block_len: 64,
+            flags: BLAKE3_PARENT,
+        }
+    }
+}
+
+/// State of the chunk of 1024 bytes currently being hashed
+#[derive(Debug, Clone)]
+struct Blake3Chunk {
+    cv: [u32; 8],
+    counter: u64,
+    block: [u8; 64],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl Blake3Chunk {
+    fn new(counter: u64) -> Self {
+        Blake3Chunk {
+            cv: SHA256_IV,
+            counter,
+            block: [0; 64],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        64 * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            BLAKE3_CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // the last block is compressed in output(), with the end flag
+            if self.block_len == 64 {
+                let words = le_words(&self.block);
+                self.cv = first_8(blake3_compress(
+                    &self.cv,
+                    &words,
+                    self.counter,
+                    64,
+                    self.start_flag(),
+                ));
+                self.blocks_compressed += 1;
+                self.block = [0; 64];
+                self.block_len = 0;
+            }
+            let take = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+        }
+    }
+
+    fn output(&self) -> Blake3Output {
+        Blake3Output {
+            cv: self.cv,
+            block: le_words(&self.block),
+            counter: self.counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | BLAKE3_CHUNK_END,
+        }
+    }
+}
+
+/// Streaming BLAKE3 with the default 32 byte output
+#[derive(Debug, Clone)]
+pub(crate) struct Blake3 {
+    chunk: Blake3Chunk,
+    /// Chaining values of completed subtrees, merged like a binary counter
+    cv_stack: Vec<[u32; 8]>,
+}
+
+impl Blake3 {
+    pub(crate) fn new() -> Self {
+        Blake3 {
+            chunk: Blake3Chunk::new(0),
+            cv_stack: Vec::new(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // only finish a chunk once more data follows, the last one has to become the root
+            if self.chunk.len() == BLAKE3_CHUNK_LEN {
+                let mut cv = self.chunk.output().chaining_value();
+                let mut total_chunks = self.chunk.counter + 1;
+                while total_chunks & 1 == 0 {
+                    let left = self.cv_stack.pop().expect("cv stack out of sync");
+                    cv = Blake3Output::parent(left, cv).chaining_value();
+                    total_chunks >>= 1;
+                }
+                self.cv_stack.push(cv);
+                self.chunk = Blake3Chunk::new(self.chunk.counter + 1);
+            }
+            let take = (BLAKE3_CHUNK_LEN - self.chunk.len()).min(data.len());
+            self.chunk.update(&data[..take]);
+            data = &data[take..];
+        }
+    }
+
+    pub(crate) fn finalize(self) -> [u8; 32] {
+        let mut output = self.chunk.output();
+        for left in self.cv_stack.iter().rev() {
+            output = Blake3Output::parent(*left, output.chaining_value());
+        }
+        output.root_hash()
+    }
+}
+
+const XXH3_SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+const XXH_PRIME32_1: u64 = 0x9E3779B1;
+const XXH_PRIME32_2: u64 = 0x85EBCA77;
+const XXH_PRIME32_3: u64 = 0xC2B2AE3D;
+const XXH_PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const XXH_PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const XXH_PRIME64_3: u64 = 0x165667B19E3779F9;
+const XXH_PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const XXH_PRIME64_5: u64 = 0x27D4EB2F165667C5;
+const XXH3_PRIME_MX1: u64 = 0x165667919E3779F9;
+const XXH3_PRIME_MX2: u64 = 0x9FB21C651E98DF25;
+
+const XXH3_STRIPE_LEN: usize = 64;
+const XXH3_SECRET_CONSUME_RATE: usize = 8;
+const XXH3_STRIPES_PER_BLOCK: usize =
+    (XXH3_SECRET.len() - XXH3_STRIPE_LEN) / XXH3_SECRET_CONSUME_RATE;
+/// Offset of the secret used for scrambling after every block
+const XXH3_SECRET_LIMIT: usize = XXH3_SECRET.len() - XXH3_STRIPE_LEN;
+const XXH3_MIDSIZE_MAX: usize = 240;
+const XXH3_BUFFER_SIZE: usize = 256;
+
+fn read_u32(bytes: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as u64
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn mul128_fold64(lhs: u64, rhs: u64) -> u64 {
+    let product = lhs as u128 * rhs as u128;
+    product as u64 ^ (product >> 64) as u64
+}
+
+fn xxh64_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(XXH_PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(XXH_PRIME64_3);
+    h ^ (h >> 32)
+}
+
+fn xxh3_avalanche(mut h: u64) -> u64 {
+    h ^= h >> 37;
+    h = h.wrapping_mul(XXH3_PRIME_MX1);
+    h ^ (h >> 32)
+}
+
+fn xxh3_rrmxmx(mut h: u64, len: u64) -> u64 {
+    h ^= h.rotate_left(49) ^ h.rotate_left(24);
+    h = h.wrapping_mul(XXH3_PRIME_MX2);
+    h ^= (h >> 35).wrapping_add(len);
+    h = h.wrapping_mul(XXH3_PRIME_MX2);
+    h ^ (h >> 28)
+}
+
+fn xxh3_mix16(input: &[u8], at: usize, secret_at: usize) -> u64 {
+    mul128_fold64(
+        read_u64(input, at) ^ read_u64(&XXH3_SECRET, secret_at),
+        read_u64(input, at + 8) ^ read_u64(&XXH3_SECRET, secret_at + 8),
+    )
+}
+
+/// XXH3 of inputs of up to [`XXH3_MIDSIZE_MAX`] bytes, which are hashed in one go
+fn xxh3_short(input: &[u8]) -> u64 {
+    let s = &XXH3_SECRET;
+    let len = input.len();
+    let len64 = len as u64;
+    match len {
+        0 => xxh64_avalanche(read_u64(s, 56) ^ read_u64(s, 64)),
+        1..=3 => {
+            let combined = ((input[0] as u64) << 16)
+                | ((input[len >> 1] as u64) << 24)
+                | input[len - 1] as u64
+                | (len64 << 8);
+            xxh64_avalanche(combined ^ (read_u32(s, 0) ^ read_u32(s, 4)))
+        }
+        4..=8 => {
+            let input64 = read_u32(input, len - 4).wrapping_add(read_u32(input, 0) << 32);
+            xxh3_rrmxmx(input64 ^ (read_u64(s, 8) ^ read_u64(s, 16)), len64)
+        }
+        9..=16 => {
+            let lo = read_u64(input, 0) ^ (read_u64(s, 24) ^ read_u64(s, 32));
+            let hi = read_u64(input, len - 8) ^ (read_u64(s, 40) ^ read_u64(s, 48));
+            let acc = len64
+                .wrapping_add(lo.swap_bytes())
+                .wrapping_add(hi)
+                .wrapping_add(mul128_fold64(lo, hi));
+            xxh3_avalanche(acc)
+        }
+        17..=128 => {
+            let mut acc = len64.wrapping_mul(XXH_PRIME64_1);
+            for i in 0..(len - 1) / 32 + 1 {
+                acc = acc
+                    .wrapping_add(xxh3_mix16(input, 16 * i, 32 * i))
+                    .wrapping_add(xxh3_mix16(input, len - 16 * (i + 1), 32 * i + 16));
+            }
+            xxh3_avalanche(acc)
+        }
+        _ => {
+            let mut acc = len64.wrapping_mul(XXH_PRIME64_1);
+            for i in 0..8 {
+                acc = acc.wrapping_add(xxh3_mix16(input, 16 * i, 16 * i));
+            }
+            acc = xxh3_avalanche(acc);
+            let mut acc_end = xxh3_mix16(input, len - 16, 136 - 17);
+            for i in 8..len / 16 {
+                acc_end = acc_end.wrapping_add(xxh3_mix16(input, 16 * i, 16 * (i - 8) + 3));
+            }
+            xxh3_avalanche(acc.wrapping_add(acc_end))
+        }
+    }
+}
+
+fn xxh3_accumulate_stripe(acc: &mut [u64; 8], stripe: &[u8], secret_at: usize) {
+    for lane in 0..8 {
+        let data = read_u64(stripe, lane * 8);
+        let key = data ^ read_u64(&XXH3_SECRET, secret_at + lane * 8);
+        acc[lane ^ 1] = acc[lane ^ 1].wrapping_add(data);
+        acc[lane] = acc[lane].wrapping_add((key & 0xFFFFFFFF).wrapping_mul(key >> 32));
+    }
+}
+
+fn xxh3_scramble(acc: &mut [u64; 8]) {
+    for (lane, a) in acc.iter_mut().enumerate() {
+        let key = read_u64(&XXH3_SECRET, XXH3_SECRET_LIMIT + lane * 8);
+        *a = ((*a ^ (*a >> 47)) ^ key).wrapping_mul(XXH_PRIME32_1);
+    }
+}
+
+/// Streaming 64 bit XXH3 without a seed
+#[derive(Debug, Clone)]
+pub(crate) struct Xxh3 {
+    acc: [u64; 8],
+    buffer: [u8; XXH3_BUFFER_SIZE],
+    buffer_len: usize,
+    /// Stripes consumed in the current block
+    stripes: usize,
+    total_len: u64,
+}
+
+impl Xxh3 {
+    pub(crate) fn new() -> Self {
+        Xxh3 {
+            acc: [
+                XXH_PRIME32_3,
+                XXH_PRIME64_1,
+                XXH_PRIME64_2,
+                XXH_PRIME64_3,
+                XXH_PRIME64_4,
+                XXH_PRIME32_2,
+                XXH_PRIME64_5,
+                XXH_PRIME32_1,
+            ],
+            buffer: [0; XXH3_BUFFER_SIZE],
+            buffer_len: 0,
+            stripes: 0,
+            total_len: 0,
+        }
+    }
+
+    /// Accumulates whole stripes of `input`, scrambling after every block
+    fn consume(acc: &mut [u64; 8], stripes: &mut usize, input: &[u8]) {
+        for stripe in input.chunks_exact(XXH3_STRIPE_LEN) {
+            xxh3_accumulate_stripe(acc, stripe, *stripes * XXH3_SECRET_CONSUME_RATE);
+            *stripes += 1;
+            if *stripes == XXH3_STRIPES_PER_BLOCK {
+                xxh3_scramble(acc);
+                *stripes = 0;
+            }
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        // the buffer is only consumed once more data follows, the last stripe is special
+        while self.buffer_len + data.len() > XXH3_BUFFER_SIZE {
+            let take = XXH3_BUFFER_SIZE - self.buffer_len;
+            self.buffer[self.buffer_len..].copy_from_slice(&data[..take]);
+            data = &data[take..];
+            Self::consume(&mut self.acc, &mut self.stripes, &self.buffer);
+            self.buffer_len = 0;
+        }
+        self.buffer[self.buffer_len..self.buffer_len + data.len()].copy_from_slice(data);
+        self.buffer_len += data.len();
+    }
+
+    pub(crate) fn finalize(self) -> u64 {
+        if self.total_len as usize <= XXH3_MIDSIZE_MAX {
+            return xxh3_short(&self.buffer[..self.buffer_len]);
+        }
+
+        let mut acc = self.acc;
+        let mut stripes = self.stripes;
+        // the last stripe always overlaps the previous data, which is still in the buffer
+        let mut last = [0; XXH3_STRIPE_LEN];
+        if self.buffer_len >= XXH3_STRIPE_LEN {
+            let whole = (self.buffer_len - 1) / XXH3_STRIPE_LEN * XXH3_STRIPE_LEN;
+            Self::consume(&mut acc, &mut stripes, &self.buffer[..whole]);
+            last.copy_from_slice(&self.buffer[self.buffer_len - XXH3_STRIPE_LEN..self.buffer_len]);
+        } else {
+            let catchup = XXH3_STRIPE_LEN - self.buffer_len;
+            last[..catchup].copy_from_slice(&self.buffer[XXH3_BUFFER_SIZE - catchup..]);
+            last[catchup..].copy_from_slice(&self.buffer[..self.buffer_len]);
+        }
+        xxh3_accumulate_stripe(&mut acc, &last, XXH3_SECRET_LIMIT - 7);
+
+        let mut result = self.total_len.wrapping_mul(XXH_PRIME64_1);
+        for i in 0..4 {
+            result = result.wrapping_add(mul128_fold64(
+                acc[2 * i] ^ read_u64(&XXH3_SECRET, 11 + 16 * i),
+                acc[2 * i + 1] ^ read_u64(&XXH3_SECRET, 11 + 16 * i + 8),
+            ));
+        }
+        xxh3_avalanche(result)
+    }
+}
+
+/// Hashes everything `reader` yields with `alg`
+pub(crate) fn hash_reader<R: Read>(alg: HashAlgorithm, mut reader: R) -> io::Result<Vec<u8>> {
+    let mut hasher = Hasher::new(alg);
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Lowercase hex representation of `bytes`
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_reader, to_hex, HashAlgorithm, Hasher, Sha256};
+
+    fn sha256(data: &[u8]) -> String {
+        to_hex(&hash_reader(HashAlgorithm::Sha256, data).unwrap())
+    }
+
+    /// The input used by the official BLAKE3 test vectors
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_sha256_vectors() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_sha256_chunked() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Sha256::new();
+        for chunk in data.chunks(7) {
+            hasher.update(chunk);
+        }
+        let expected = "4e4c294b331f7a2099a379bec34b9f9fc03dc46ab465d998f4d683da53487e6d";
+        assert_eq!(to_hex(&hasher.finalize()), expected);
+        assert_eq!(sha256(&data), expected);
+    }
+
+    #[test]
+    fn test_blake3_vectors() {
+        let vectors = [
+            (
+                0,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                1023,
+                "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                2048,
+                "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+            ),
+            (
+                3072,
+                "b98cb0ff3623be03326b373de6b9095218513e64f1ee2edd2525c7ad1e5cffd2",
+            ),
+        ];
+        for (len, expected) in vectors {
+            let data = pattern(len);
+            assert_eq!(
+                to_hex(&hash_reader(HashAlgorithm::Blake3, &data[..]).unwrap()),
+                expected
+            );
+            let mut hasher = Hasher::new(HashAlgorithm::Blake3);
+            for chunk in data.chunks(13) {
+                hasher.update(chunk);
+            }
+            assert_eq!(to_hex(&hasher.finalize()), expected, "len {len}");
+        }
+    }
+
+    #[test]
+    fn test_xxh3_vectors() {
+        let vectors = [
+            (0, "2d06800538d394c2"),
+            (1, "c44bdff4074eecdb"),
+            (3, "5f4299fc161c9cbb"),
+            (4, "60dab036a58211f2"),
+            (8, "3a1c2d7c85af88f8"),
+            (9, "e9612598145bb9dc"),
+            (16, "8355e3a6f61770db"),
+            (17, "9ef341a99de37328"),
+            (128, "85c6174c7ff4c46b"),
+            (129, "ec7642b431ba3e5a"),
+            (240, "375a384d957fe865"),
+            (241, "02e8cd95421c6d02"),
+            (1024, "e5d78bafa45b2aa5"),
+            (1025, "e95c42288f28186e"),
+            (5000, "b418500fc42320ee"),
+        ];
+        for (len, expected) in vectors {
+            let data = pattern(len);
+            assert_eq!(
+                to_hex(&hash_reader(HashAlgorithm::Xxh3, &data[..]).unwrap()),
+                expected
+            );
+            for chunk_size in [1, 63, 64, 300] {
+                let mut hasher = Hasher::new(HashAlgorithm::Xxh3);
+                for chunk in data.chunks(chunk_size) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(to_hex(&hasher.finalize()), expected, "len {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_algorithm_names() {
+        for alg in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Xxh3,
+        ] {
+            assert_eq!(HashAlgorithm::from_name(alg.name()), Some(alg));
+        }
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+    }
+}
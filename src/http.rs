@@ -0,0 +1,539 @@
+//! Restoring archives straight from `http://` URLs, streamed into the unpacking without a copy
+//! on disk. loppel has no TLS of its own, `https://` goes through the `curl` program.
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use crate::manifest::Manifest;
+use crate::{
+    color, finish_restore, ignore_damage, read_archive_from, unpack, zstd_decoder, ArchiveReader,
+    Compression, RestoreOptions,
+};
+
+/// Redirects that are followed before giving up
+const MAX_REDIRECTS: usize = 10;
+/// How long the server may keep us waiting for the next bytes
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The URL that `path` is, if it is one
+pub(crate) fn url(path: &Path) -> Option<&str> {
+    let url = path.to_str()?;
+    (url.starts_with("http://") || url.starts_with("https://")).then_some(url)
+}
+
+/// Restores the archive at `url` into `output_dir`, downloading it once. What would be
+/// overwritten is only known on the way, so every existing file is asked for on its own
+/// unless `--yes` is given.
+pub(crate) fn restore(url: &str, output_dir: &Path, opts: &RestoreOptions) -> io::Result<()> {
+    let unsupported = [
+        (opts.flatten, "--flatten"),
+        (opts.list_then_extract, "--list-then-extract"),
+        (opts.decompress_program.is_some(), "--decompress-program"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(given, _)| *given) {
+        let e = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{flag} needs the archive on disk, download it to restore with it"),
+        );
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    let manifest = manifest(url)?;
+    let opts = &RestoreOptions {
+        confirm_each: true,
+        ..opts.clone()
+    };
+    let mut written = Vec::new();
+    let mut failed = 0;
+    let unpack = |a: &mut tar::Archive<ArchiveReader>| {
+        (written, failed) = unpack::unpack_archive(a, output_dir, opts, manifest.as_ref())?;
+        Ok(())
+    };
+    ignore_damage(read_archive(url, opts.window.long, unpack), failed)?;
+    let kept = HashSet::new();
+    finish_restore(
+        output_dir,
+        opts,
+        manifest.as_ref(),
+        true,
+        written,
+        &kept,
+        failed,
+    )
+}
+
+/// Like [`crate::read_archive`], for the archive at `url`
+fn read_archive<F>(url: &str, window_log_max: Option<u32>, do_this: F) -> io::Result<()>
+where
+    F: FnOnce(&mut tar::Archive<ArchiveReader>) -> io::Result<()>,
+{
+    let body = get(url).inspect_err(|e| eprintln!("{}", color::error(e)))?;
+    let name = url.split(['?', '#']).next().unwrap_or(url);
+    let reader = match Compression::detect(Path::new(name)).unwrap_or(Compression::Zstd) {
+        Compression::None => ArchiveReader::Download(body),
+        Compression::Zstd => ArchiveReader::ZstdDownload(zstd_decoder(body, window_log_max)?),
+    };
+    read_archive_from(reader, Path::new(url), do_this)
+}
+
+/// The manifest next to the archive at `url`, [`None`] if the server has none
+fn manifest(url: &str) -> io::Result<Option<Manifest>> {
+    let (path, query) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    match get(&format!("{path}.manifest{query}")) {
+        Ok(body) => Manifest::read(io::BufReader::new(body)).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => {
+            eprintln!("{}", color::error(&e));
+            Err(e)
+        }
+    }
+}
+
+/// The body of the answer to a request for `url`
+pub(crate) struct Body {
+    url: String,
+    reader: Box<dyn Read>,
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // errors of the archive come from what reads this, those of the download from here
+        self.reader
+            .read(buf)
+            .map_err(|e| download_error(&self.url, e.kind(), e))
+    }
+}
+
+fn download_error(url: &str, kind: io::ErrorKind, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(kind, format!("download of {url} failed: {e}"))
+}
+
+/// Requests `url`, following redirects. Fails with [`io::ErrorKind::NotFound`] for a 404.
+fn get(url: &str) -> io::Result<Body> {
+    let mut url = url.to_string();
+    for _ in 0..MAX_REDIRECTS {
+        if url.starts_with("https://") {
+            return curl(&url);
+        }
+        let error = |kind, e: &dyn std::fmt::Display| download_error(&url, kind, e);
+        let (host, path) = split_url(&url).map_err(|e| error(io::ErrorKind::InvalidInput, &e))?;
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+        let stream = TcpStream::connect(&addr).map_err(|e| error(e.kind(), &e))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        write!(
+            &stream,
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: loppel/{}\r\n\
+             Accept-Encoding: identity\r\nConnection: close\r\n\r\n",
+            env!("CARGO_PKG_VERSION")
+        )
+        .map_err(|e| error(e.kind(), &e))?;
+        let mut stream = io::BufReader::new(stream);
+        let response = read_head(&mut stream).map_err(|e| error(e.kind(), &e))?;
+        match response.status {
+            200 => (),
+            301 | 302 | 303 | 307 | 308 => {
+                let location = response.header("location").ok_or_else(|| {
+                    error(io::ErrorKind::InvalidData, &"redirect without a location")
+                })?;
+                url = resolve(&url, location);
+                continue;
+            }
+            404 => return Err(error(io::ErrorKind::NotFound, &response.reason)),
+            _ => return Err(error(io::ErrorKind::Other, &response.reason)),
+        }
+        let reader: Box<dyn Read> = if response
+            .header("transfer-encoding")
+            .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+        {
+            Box::new(Chunked {
+                inner: stream,
+                left: 0,
+                done: false,
+            })
+        } else if let Some(len) = response.header("content-length") {
+            let len = len
+                .parse()
+                .map_err(|_| error(io::ErrorKind::InvalidData, &"bad content-length"))?;
+            Box::new(Sized {
+                inner: stream.take(len),
+            })
+        } else {
+            Box::new(stream)
+        };
+        return Ok(Body { url, reader });
+    }
+    Err(download_error(
+        &url,
+        io::ErrorKind::Other,
+        format_args!("more than {MAX_REDIRECTS} redirects"),
+    ))
+}
+
+/// The host with its port, if any, and the path of an `http://` URL
+fn split_url(url: &str) -> Result<(&str, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("not an http URL: {url}"))?;
+    let rest = rest.split('#').next().unwrap_or(rest);
+    let (host, path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => (&rest[..i], &rest[i..]),
+        // a query right after the host is below the root
+        Some(_) | None => (rest.split('?').next().unwrap_or(rest), "/"),
+    };
+    if host.is_empty() || host.contains('@') {
+        return Err(format!("unusable host in {url}"));
+    }
+    Ok((host, path))
+}
+
+/// Where the `location` of a redirect from `url` points to
+fn resolve(url: &str, location: &str) -> String {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return location.to_string();
+    }
+    if let Some(rest) = location.strip_prefix("//") {
+        return format!("http://{rest}");
+    }
+    let (host, path) = split_url(url).unwrap_or((url, "/"));
+    if location.starts_with('/') {
+        return format!("http://{host}{location}");
+    }
+    let path = path.split('?').next().unwrap_or(path);
+    let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+    format!("http://{host}{dir}{location}")
+}
+
+/// The status line and headers of a response
+#[derive(Debug)]
+struct Head {
+    status: u16,
+    /// The status with its text, like `404 Not Found`
+    reason: String,
+    /// With lower case names
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Head> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let line = read_line(reader)?;
+    let mut parts = line.splitn(2, ' ');
+    if !parts
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/1."))
+    {
+        return Err(invalid("not an HTTP/1 response"));
+    }
+    let reason = parts.next().unwrap_or_default().to_string();
+    let status = reason
+        .get(..3)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("no status in the response"))?;
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("bad header in the response"))?;
+        headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+    }
+    Ok(Head {
+        status,
+        reason,
+        headers,
+    })
+}
+
+/// Reads a line without its `\r\n`, failing at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.truncate(line.trim_end_matches(['\r', '\n']).len());
+    Ok(line)
+}
+
+/// A body of a known length, which fails if the connection ends before it
+struct Sized<R> {
+    inner: io::Take<R>,
+}
+
+impl<R: Read> Read for Sized<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() && self.inner.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the connection closed {} bytes early", self.inner.limit()),
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// A body with `Transfer-Encoding: chunked`
+struct Chunked<R> {
+    inner: R,
+    /// What is left of the current chunk
+    left: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            let line = read_line(&mut self.inner)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.left = u64::from_str_radix(size, 16).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "bad chunk size in the response")
+            })?;
+            if self.left == 0 {
+                // the trailers, up to the empty line that ends the body
+                while !read_line(&mut self.inner)?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.left -= n as u64;
+        if self.left == 0 && !read_line(&mut self.inner)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk longer than its size in the response",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+/// Requests `url` through `curl`, which follows redirects itself
+fn curl(url: &str) -> io::Result<Body> {
+    let mut child = process::Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--max-redirs", &MAX_REDIRECTS.to_string()])
+        .args(["--proto-redir", "=http,https", "--", url])
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            download_error(
+                url,
+                e.kind(),
+                format_args!("https needs the curl program, which could not be run: {e}"),
+            )
+        })?;
+    let stdout = child.stdout.take().expect("stdout of curl is piped");
+    let mut body = Curl {
+        child,
+        stdout,
+        first: None,
+        done: false,
+    };
+    // curl fails before any of the body if the server answers with an error
+    let mut byte = [0];
+    let n = body
+        .read(&mut byte)
+        .map_err(|e| download_error(url, e.kind(), e))?;
+    if n == 1 {
+        body.first = Some(byte[0]);
+    }
+    Ok(Body {
+        url: url.to_string(),
+        reader: Box::new(body),
+    })
+}
+
+/// The output of `curl`, which fails at its end if `curl` did
+struct Curl {
+    child: process::Child,
+    stdout: process::ChildStdout,
+    /// The byte that was read to see whether the download started
+    first: Option<u8>,
+    done: bool,
+}
+
+impl Read for Curl {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(first) = self.first.take() {
+            buf[0] = first;
+            return Ok(1);
+        }
+        let n = self.stdout.read(buf)?;
+        if n > 0 || self.done {
+            return Ok(n);
+        }
+        self.done = true;
+        let mut stderr = String::new();
+        if let Some(mut pipe) = self.child.stderr.take() {
+            pipe.read_to_string(&mut stderr)?;
+        }
+        let status = self.child.wait()?;
+        if status.success() {
+            return Ok(0);
+        }
+        let msg = stderr.trim().trim_start_matches("curl: ");
+        // `--fail` exits with 22 for errors of the server like 404
+        let kind = match status.code() == Some(22) && msg.contains("404") {
+            true => io::ErrorKind::NotFound,
+            false => io::ErrorKind::Other,
+        };
+        Err(io::Error::new(
+            kind,
+            format!("curl failed, {status}: {msg}"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, BufRead, Read, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::{resolve, Chunked, Sized};
+    use crate::hash::HashAlgorithm;
+    use crate::manifest::Manifest;
+    use crate::{backup_dir, restore, BackupOptions, BackupStats, Compression, RestoreOptions};
+
+    /// Answers requests on a port of localhost with what `respond` makes of their paths
+    fn serve(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base = format!("http://{}", listener.local_addr()?);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = io::BufReader::new(&stream);
+                let mut line = String::new();
+                request.read_line(&mut line).unwrap();
+                while request.read_line(&mut String::new()).unwrap() > 2 {}
+                let path = line.split(' ').nth(1).unwrap().to_string();
+                stream.write_all(&respond(&path)).unwrap();
+            }
+        });
+        Ok(base)
+    }
+
+    fn response(head: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {head}\r\n\r\n").into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    #[test]
+    fn test_bodies() -> io::Result<()> {
+        let mut body = String::new();
+        Chunked {
+            inner: &b"3\r\nabc\r\n2;name=value\r\nde\r\n0\r\nTrailer: x\r\n\r\n"[..],
+            left: 0,
+            done: false,
+        }
+        .read_to_string(&mut body)?;
+        assert_eq!(body, "abcde");
+        let mut chunked = Chunked {
+            inner: &b"5\r\nabc"[..],
+            left: 0,
+            done: false,
+        };
+        assert!(chunked.read_to_end(&mut Vec::new()).is_err());
+
+        let mut sized = Sized {
+            inner: (&b"abc"[..]).take(5),
+        };
+        let e = sized.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(resolve("http://h/a/b.tar", "c.tar"), "http://h/a/c.tar");
+        assert_eq!(resolve("http://h:8/a/b", "/c"), "http://h:8/c");
+        assert_eq!(resolve("http://h/a", "//g/c"), "http://g/c");
+        assert_eq!(resolve("http://h/a", "https://g/c"), "https://g/c");
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_url() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), b"foo")?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            hash: Some(HashAlgorithm::Sha256),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let archive = fs::read(&backup)?;
+        let mut manifest = Vec::new();
+        Manifest::for_backup(&backup)?
+            .unwrap()
+            .write(&mut manifest)?;
+        crate::recursive_remove(&backup)?;
+        fs::remove_dir_all(&src)?;
+
+        let base = serve(move |path| match path {
+            "/latest.tar.zst" => response("302 Found\r\nLocation: files/src.tar.zst", b""),
+            "/files/src.tar.zst" => {
+                let head = format!("200 OK\r\nContent-Length: {}", archive.len());
+                response(&head, &archive)
+            }
+            "/latest.tar.zst.manifest" => {
+                let mut body = format!("{:x}\r\n", manifest.len()).into_bytes();
+                body.extend_from_slice(&manifest);
+                body.extend_from_slice(b"\r\n0\r\n\r\n");
+                response("200 OK\r\nTransfer-Encoding: chunked", &body)
+            }
+            _ => response("404 Not Found\r\nContent-Length: 0", b""),
+        })?;
+
+        let opts = RestoreOptions {
+            yes: true,
+            ..Default::default()
+        };
+        let url = format!("{base}/latest.tar.zst");
+        restore(Path::new(&url), t.path(), &opts)?;
+        assert_eq!(fs::read(src.join("sub").join("foo"))?, b"foo");
+
+        // failures of the download say so
+        let url = format!("{base}/missing.tar.zst");
+        let e = restore(Path::new(&url), t.path(), &opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().starts_with("download of"), "{e}");
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! Listing the entries of an archive, optionally with their metadata like `tar -tv`, or as
+//! a tree like `tree`
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::format::{comment, origin};
+use crate::format_size;
+use crate::unpack::normalize_path;
+
+/// Prints the names of all entries of `archive`, with `long` also their type, permissions,
+/// owner, size and modification time in the columns of `ls -l`, after the `--comment` of the
+/// backup and the path it was made from. Sizes are for humans unless `raw`.
+pub(crate) fn list<R: Read>(
+    archive: &mut tar::Archive<R>,
+    long: bool,
+    raw: bool,
+) -> io::Result<()> {
+    for (i, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        if i == 0 && long {
+            if let Some(comment) = comment(&mut entry)? {
+                println!("comment: {comment}");
+            }
+            if let Some(origin) = origin(&mut entry)? {
+                println!("origin: {origin}");
+            }
+        }
+        let name = normalize_path(&entry.path()?);
+        if !long {
+            println!("{}", name.display());
+            continue;
+        }
+
+        let header = entry.header();
+        let owner = |name: Option<&str>, id: u64| match name {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => id.to_string(),
+        };
+        let user = owner(header.username().ok().flatten(), header.uid()?);
+        let group = owner(header.groupname().ok().flatten(), header.gid()?);
+        print!(
+            "{} {user}/{group} {:>10} {} {}",
+            mode_string(header.entry_type(), header.mode()?),
+            format_size(header.size()?, raw),
+            format_time(header.mtime()?),
+            name.display()
+        );
+        match entry.link_name()? {
+            Some(target) if header.entry_type().is_symlink() => {
+                println!(" -> {}", target.display())
+            }
+            Some(target) => println!(" link to {}", normalize_path(&target).display()),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
+
+/// A directory of the tree of [`tree`], or a file without `children`
+#[derive(Debug, Default)]
+struct Node {
+    children: BTreeMap<OsString, Node>,
+    dir: bool,
+    link: Option<PathBuf>,
+}
+
+impl Node {
+    /// Adds the entry at `name`, with the directories on its way that have no entry of their own
+    fn insert(&mut self, name: &Path, dir: bool, link: Option<PathBuf>) {
+        let mut node = self;
+        for component in name.components() {
+            if let Component::Normal(part) = component {
+                node.dir = true;
+                node = node.children.entry(part.to_os_string()).or_default();
+            }
+        }
+        node.dir |= dir;
+        node.link = link.or(node.link.take());
+    }
+}
+
+/// Prints the entries of `archive` as an indented tree like `tree` does, sorted by name, with
+/// the targets of symlinks and a count of directories and files at the end
+pub(crate) fn tree<R: Read>(archive: &mut tar::Archive<R>) -> io::Result<()> {
+    let mut root = Node::default();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let ty = entry.header().entry_type();
+        let link = match entry.link_name()? {
+            Some(target) if ty.is_symlink() => Some(target.into_owned()),
+            _ => None,
+        };
+        root.insert(&entry.path()?, ty.is_dir(), link);
+    }
+    print!("{}", render_tree(&root));
+    Ok(())
+}
+
+/// The lines of [`tree`] for the entries below `root`. Like `tree`, the directories given to it
+/// are not counted, only what is in them.
+fn render_tree(root: &Node) -> String {
+    fn line(out: &mut String, name: &OsString, node: &Node) {
+        let _ = write!(out, "{}", Path::new(name).display());
+        match &node.link {
+            Some(target) => {
+                let _ = writeln!(out, " -> {}", target.display());
+            }
+            None => out.push('\n'),
+        }
+    }
+    fn render(node: &Node, indent: &str, out: &mut String, counts: &mut (usize, usize)) {
+        for (i, (name, child)) in node.children.iter().enumerate() {
+            let last = i + 1 == node.children.len();
+            out.push_str(indent);
+            out.push_str(if last { "└── " } else { "├── " });
+            line(out, name, child);
+            match child.dir {
+                true => counts.0 += 1,
+                false => counts.1 += 1,
+            }
+            let indent = format!("{indent}{}", if last { "    " } else { "│   " });
+            render(child, &indent, out, counts);
+        }
+    }
+
+    let mut out = String::new();
+    let mut counts = (0, 0);
+    // the backed up paths are the roots, like the arguments of `tree`
+    for (name, node) in &root.children {
+        line(&mut out, name, node);
+        if !node.dir {
+            counts.1 += 1;
+        }
+        render(node, "", &mut out, &mut counts);
+    }
+    let plural = |n: usize, one: &str, many: &str| match n {
+        1 => format!("1 {one}"),
+        n => format!("{n} {many}"),
+    };
+    let _ = writeln!(
+        out,
+        "\n{}, {}",
+        plural(counts.0, "directory", "directories"),
+        plural(counts.1, "file", "files")
+    );
+    out
+}
+
+/// Permissions like `ls -l` shows them, with the type of the entry in front
+fn mode_string(ty: tar::EntryType, mode: u32) -> String {
+    let kind = match ty {
+        tar::EntryType::Directory => 'd',
+        tar::EntryType::Symlink => 'l',
+        tar::EntryType::Char => 'c',
+        tar::EntryType::Block => 'b',
+        tar::EntryType::Fifo => 'p',
+        tar::EntryType::Link => 'h',
+        _ => '-',
+    };
+    let mut s = String::with_capacity(10);
+    s.push(kind);
+    // (read, write, execute, special bit, letter for special with and without execute)
+    let triples = [
+        (0o400, 0o200, 0o100, 0o4000, ('s', 'S')),
+        (0o040, 0o020, 0o010, 0o2000, ('s', 'S')),
+        (0o004, 0o002, 0o001, 0o1000, ('t', 'T')),
+    ];
+    for (r, w, x, special, (with_x, without_x)) in triples {
+        s.push(if mode & r != 0 { 'r' } else { '-' });
+        s.push(if mode & w != 0 { 'w' } else { '-' });
+        s.push(match (mode & x != 0, mode & special != 0) {
+            (true, true) => with_x,
+            (false, true) => without_x,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+/// `YYYY-MM-DD HH:MM` in UTC of a unix timestamp
+fn format_time(secs: u64) -> String {
+    let (date, rem) = civil_date(secs);
+    format!("{date} {:02}:{:02}", rem / 3600, rem % 3600 / 60)
+}
+
+/// `YYYY-MM-DD-HHMMSS` in UTC of a unix timestamp, for file names
+pub(crate) fn file_time(secs: u64) -> String {
+    let (date, rem) = civil_date(secs);
+    format!(
+        "{date}-{:02}{:02}{:02}",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// `YYYY-MM-DD` in UTC of a unix timestamp, and the seconds of that day
+fn civil_date(secs: u64) -> (String, u64) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // days to the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (format!("{year:04}-{month:02}-{day:02}"), rem)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{file_time, format_time, mode_string, render_tree, Node};
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!(mode_string(tar::EntryType::Regular, 0o644), "-rw-r--r--");
+        assert_eq!(mode_string(tar::EntryType::Directory, 0o755), "drwxr-xr-x");
+        assert_eq!(mode_string(tar::EntryType::Symlink, 0o777), "lrwxrwxrwx");
+        assert_eq!(mode_string(tar::EntryType::Regular, 0o4755), "-rwsr-xr-x");
+        assert_eq!(mode_string(tar::EntryType::Directory, 0o1777), "drwxrwxrwt");
+        assert_eq!(mode_string(tar::EntryType::Regular, 0o2640), "-rw-r-S---");
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13");
+        assert_eq!(file_time(1_700_000_005), "2023-11-14-221325");
+    }
+
+    #[test]
+    fn test_tree() {
+        let mut root = Node::default();
+        root.insert(Path::new("src"), true, None);
+        root.insert(Path::new("src/b/deep/file"), false, None);
+        root.insert(Path::new("./src/a"), false, None);
+        root.insert(Path::new("src/link"), false, Some(PathBuf::from("a")));
+        root.insert(Path::new("src/b/c"), false, None);
+        root.insert(Path::new("other"), false, None);
+        assert_eq!(
+            render_tree(&root),
+            "other\n\
+             src\n\
+             ├── a\n\
+             ├── b\n\
+             │   ├── c\n\
+             │   └── deep\n\
+             │       └── file\n\
+             └── link -> a\n\
+             \n\
+             2 directories, 5 files\n"
+        );
+        assert_eq!(render_tree(&Node::default()), "\n0 directories, 0 files\n");
+    }
+}
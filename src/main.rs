@@ -1,10 +1,117 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::io::{Read, Seek, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::{fs, io, process};
+#[cfg(feature = "compression")]
 use zstd::DEFAULT_COMPRESSION_LEVEL;
 
+mod atomic;
+mod color;
+mod compare;
+mod convert;
+mod exclude;
+mod filter;
+mod format;
+mod hash;
+#[cfg(feature = "http")]
+mod http;
+mod list;
+mod manifest;
+#[cfg(feature = "fuse")]
+mod mount;
+mod progress;
+mod reflink;
+mod since;
+mod tee;
+mod unpack;
+mod watchdog;
+#[cfg(not(feature = "compression"))]
+mod zstd;
+#[cfg(not(feature = "compression"))]
+use crate::zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// Start of a `CACHEDIR.TAG` file, see <https://bford.info/cachedir/>
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Names of version control directories and files skipped with `--exclude-vcs`, the list of
+/// tar
+const VCS_NAMES: &[&str] = &[
+    ".git",
+    ".gitignore",
+    ".gitattributes",
+    ".gitmodules",
+    ".hg",
+    ".hgignore",
+    ".hgtags",
+    ".svn",
+    ".bzr",
+    ".bzrignore",
+    ".bzrtags",
+    "_darcs",
+    "CVS",
+    ".cvsignore",
+    "RCS",
+    "SCCS",
+    ".arch-ids",
+    "{arch}",
+];
+
+/// Extensions of compressed formats, which `--detect-compressed` stores with the fastest level
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz",
+    "heic", "jar", "jpeg", "jpg", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odp",
+    "ods", "odt", "ogg", "opus", "png", "pptx", "rar", "rpm", "tgz", "webm", "webp", "whl", "xlsx",
+    "xz", "zip", "zst",
+];
+
+/// Bits of entropy per byte in the start of a file above which it counts as compressed
+const COMPRESSED_ENTROPY: f64 = 7.5;
+
+/// zstd level of the files found by `--detect-compressed`
+const COMPRESSED_LEVEL: i32 = 1;
+
+/// Files smaller than this stay in the zstd frame of the files around them with
+/// `--store-incompressible`, a frame of their own would cost more than storing them saves
+const INCOMPRESSIBLE_MIN_SIZE: u64 = 128 * 1024;
+
+/// Magic number at the start of every zstd frame
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+
+/// Largest raw block of a stored zstd frame
+const RAW_BLOCK_MAX: usize = 128 * 1024;
+
+/// Window descriptor of stored frames, a window of [`RAW_BLOCK_MAX`]
+const RAW_WINDOW_DESCRIPTOR: u8 = 7 << 3;
+
+/// Default name of the files `--store-empty-as-placeholder` puts into empty directories
+const DEFAULT_PLACEHOLDER: &str = ".loppler-keep";
+
+/// Delay before the first retry of a failed operation, doubled for every further retry
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How often the retry delay is doubled at most, so it stops growing at 3.2s
+const MAX_RETRY_DOUBLINGS: u32 = 5;
+
+/// Largest window log zstd decompresses without `--long`
+const DEFAULT_WINDOW_LOG_MAX: u32 = 27;
+
+/// Smallest window log zstd knows
+const MIN_WINDOW_LOG: u32 = 10;
+
+/// `-v`, prints what happens to every path that is given
+const VERBOSE_PATHS: u8 = 1;
+/// `-vv`, prints every file that is backed up or skipped
+const VERBOSE_FILES: u8 = 2;
+/// `-vvv`, prints how long paths and files take and the levels they are compressed with
+const VERBOSE_DEBUG: u8 = 3;
+
+/// Exit code when a backup was aborted by `--time-limit`, the same as timeout(1) uses
+const EXIT_TIME_LIMIT: i32 = 124;
+
 const HELP_TEMPLATE: &str = r"{about-section}
 {usage-heading} {usage}
 
@@ -25,13 +132,35 @@ struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
 
-    /// Do not confirm
-    #[clap(short = 'y', long = "yes", global = true)]
+    /// Do not confirm. `LOPPLER_YES` takes `1`, `yes` or `on` as well as `true`.
+    #[clap(
+        short = 'y',
+        long = "yes",
+        global = true,
+        env = "LOPPLER_YES",
+        action = clap::ArgAction::SetTrue,
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
     confirm: bool,
 
-    /// Print out every action
-    #[clap(short = 'v', long = "verbose", global = true)]
-    verbose: bool,
+    /// Print what happens to every path, `-vv` to every file, `-vvv` also how long it takes
+    #[clap(
+        short = 'v',
+        long = "verbose",
+        global = true,
+        env = "LOPPLER_VERBOSE",
+        action = clap::ArgAction::Count,
+        value_parser = parse_verbosity
+    )]
+    verbose: u8,
+
+    /// Print sizes as exact numbers of bytes instead of KiB, MiB and so on, for scripts
+    #[clap(long, global = true)]
+    bytes: bool,
+
+    /// Color errors, created and skipped paths, `auto` only on terminals without `NO_COLOR`
+    #[clap(long, global = true, value_enum, default_value_t)]
+    color: color::ColorChoice,
 }
 
 #[derive(Debug, Subcommand)]
@@ -43,9 +172,8 @@ enum Commands {
         /// Files or directories to backup
         paths: Vec<PathBuf>,
 
-        /// Use zstd compression
-        #[arg(short = 'z', long)]
-        compress: bool,
+        #[command(flatten)]
+        opts: Box<BackupOptions>,
     },
 
     /// Restore from backup
@@ -60,372 +188,4556 @@ enum Commands {
         delete: bool,
 
         /// Directory to restore to
-        #[arg(short = 'o', long = "output")]
+        #[arg(short = 'o', long = "output", env = "LOPPLER_OUTPUT_DIR")]
         output_dir: Option<PathBuf>,
+
+        /// Restore into a new `restore-<time>` directory below the output directory, to look
+        /// at the files before moving them into place
+        #[arg(long)]
+        into_new_dir: bool,
+
+        #[command(flatten)]
+        opts: Box<RestoreOptions>,
     },
-}
 
-fn help_and_exit() -> ! {
-    use clap::CommandFactory;
-    let mut cmd = Cli::command();
-    cmd.print_help().expect("could not print");
-    std::process::exit(1)
-}
+    /// Compare a backup against the current files
+    #[clap(visible_alias = "cmp")]
+    Compare {
+        /// Backup to compare
+        path: PathBuf,
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli;
-    let command = {
-        let mut a: Vec<String> = std::env::args().collect();
-        if a.len() < 2 {
-            help_and_exit()
-        }
-        if !(a[1].starts_with("-")
-            || a[1] == "r"
-            || a[1] == "res"
-            || a[1] == "restore"
-            || a[1] == "b"
-            || a[1] == "bak"
-            || a[1] == "backup")
-        {
-            let slice = if a[1].contains("bak") {
-                &["restore".to_string()]
-            } else {
-                &["backup".to_string()]
-            };
+        /// Directory the backup would be restored to
+        #[arg(short = 'o', long = "output", env = "LOPPLER_OUTPUT_DIR")]
+        output_dir: Option<PathBuf>,
 
-            a.splice(1..1, slice.iter().cloned());
-        }
-        cli = Cli::parse_from(a.iter());
-        cli.command.unwrap()
-    };
+        #[command(flatten)]
+        window: WindowLog,
+    },
 
-    match command {
-        Commands::Backup { paths, compress } => {
-            if paths.is_empty() {
-                help_and_exit()
-            }
-            for path in paths {
-                if !path.exists() {
-                    eprintln!("Error: {:?} does not exist", path);
-                    continue;
-                }
+    /// Mount an archive read-only to browse it, until it is unmounted or Ctrl-C
+    #[cfg(feature = "fuse")]
+    Mount {
+        /// Archive to mount
+        path: PathBuf,
 
-                let result = if path.is_dir() {
-                    backup_dir(&path, compress)
-                } else if path.is_file() {
-                    backup_file(&path, compress)
-                } else {
-                    panic!("this is neither a file nor a directory, don't know what to do")
-                };
+        /// Empty directory to mount it at
+        mountpoint: PathBuf,
 
-                if let Err(e) = result {
-                    eprintln!("Error backing up {:?}: {}", path, e);
-                }
-            }
-        }
-        Commands::Restore {
-            path,
-            delete,
-            output_dir,
-        } => {
-            println!("Restoring from {:?}", path);
-            let out = output_dir.unwrap_or(std::env::current_dir()?);
-            restore(&path, &out)?;
-            if delete && (cli.confirm || confirm(format!("delete {}?", path.display()))?) {
-                recursive_remove(&path)?;
-            }
-        }
-    }
+        #[command(flatten)]
+        window: WindowLog,
+    },
 
-    Ok(())
-}
+    /// List the entries of an archive
+    #[clap(visible_alias = "ls")]
+    List {
+        /// Archive to list
+        path: PathBuf,
 
-fn confirm(prompt: String) -> io::Result<bool> {
-    print!("{prompt} - y/N ");
-    io::stdout().flush()?;
-    let mut buf = String::new();
-    loop {
-        io::stdin().read_line(&mut buf)?;
-        buf = buf.trim().to_lowercase();
-        match buf.as_str() {
-            "y" | "yes" => return Ok(true),
-            "" | "n" | "no" => return Ok(false),
-            _ => println!("That is neither yes or no"),
-        }
-    }
-}
+        /// Show the type, permissions, owner, size and modification time of every entry
+        #[arg(short = 'l', long)]
+        long: bool,
 
-fn recursive_remove(path: &Path) -> io::Result<()> {
-    if path.is_dir() {
-        fs::remove_dir_all(path)?;
-    } else if path.is_file() || path.is_symlink() {
-        fs::remove_file(path)?;
-    } else {
-        eprintln!("skipping unknown file: {}", path.display());
-    }
-    Ok(())
-}
+        /// Show the entries as an indented directory tree, like `tree`
+        #[arg(long, conflicts_with = "long")]
+        tree: bool,
 
-fn add_extension(path: &Path, postfix: &str) -> PathBuf {
-    let parts = [
-        path.file_name()
-            .expect("this string is weird, no file name"),
-        OsStr::new(postfix),
-    ];
-    let newname: OsString = parts.iter().copied().collect();
-    path.with_file_name(newname)
-}
+        /// Allow the window log used with `backup --long`, needed above the default of 27
+        #[arg(
+            long,
+            value_name = "WINDOW_LOG",
+            value_parser = clap::value_parser!(u32).range(10..=31)
+        )]
+        window_log: Option<u32>,
+    },
 
-fn remove_extension(path: &Path, suffix: &str) -> PathBuf {
-    let r = path.display().to_string();
-    match r.strip_suffix(&format!(".{suffix}")) {
-        None => panic!("that path did not have that suffix"),
-        Some(short) => PathBuf::from(short),
-    }
-}
+    /// Compare two backups with each other
+    Diff {
+        /// The older backup
+        old: PathBuf,
 
-fn restore(path: &Path, output_dir: &Path) -> io::Result<()> {
-    if !path.exists() {
-        let e = io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("File or directory not found: {}", path.display()),
-        );
-        eprintln!("{e}");
-        return Err(e);
-    }
-    if !output_dir.exists() {
-        let e = io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("File or directory not found: {}", output_dir.display()),
-        );
-        eprintln!("{e}");
-        return Err(e);
-    }
-    if !output_dir.is_dir() {
-        let e = io::Error::new(
-            io::ErrorKind::NotFound,
-            format!(
-                "Output directory is not a directory: {}",
-                output_dir.display()
-            ),
-        );
-        eprintln!("{e}");
-        return Err(e);
-    }
+        /// The newer backup
+        new: PathBuf,
 
-    let path_s: String = path.display().to_string();
-    if path_s.ends_with("tar.zstd") || path_s.ends_with("tar.zst") {
-        if !path.is_file() {
-            panic!("archive name but not an archive")
-        }
+        #[command(flatten)]
+        window: WindowLog,
+    },
 
-        read_archive(path, |a| a.unpack(output_dir))?;
-        Ok(())
-    } else if path_s.ends_with("bak") {
-        if !path.is_file() {
-            panic!("bak name but not a file")
-        }
+    /// Check that backups can be read completely and match their manifests, without restoring
+    Verify {
+        /// Backups to check
+        paths: Vec<PathBuf>,
 
-        let target = remove_extension(path, "bak");
-        let target = output_dir.join(target.file_name().unwrap());
-        fs::copy(path, target)?;
-        Ok(())
-    } else if path_s.ends_with("bak.d") {
-        if path.is_file() {
-            panic!("bak.d name but not a directory")
-        }
-        let target = remove_extension(path, "bak.d");
-        let target = output_dir.join(target.file_name().unwrap());
-        copy_dir_all(path, &target)?;
-        Ok(())
-    } else {
-        panic!("unknown file {}", path_s)
-    }
-}
+        #[command(flatten)]
+        window: WindowLog,
+    },
 
-fn backup_file(path: &Path, compress: bool) -> io::Result<PathBuf> {
-    if compress {
-        let archive_path = add_extension(path, ".tar.zstd");
-        make_archive(&archive_path, |a| a.append_path(path))?;
-        Ok(archive_path)
-    } else {
-        let backup_path = add_extension(path, ".bak");
-        fs::copy(path, &backup_path)?;
-        Ok(backup_path)
-    }
-}
+    /// List the backups in a directory that have all of the given tags
+    Find {
+        /// Directory to look in, the current one by default
+        dir: Option<PathBuf>,
 
-fn backup_dir(path: &Path, compress: bool) -> io::Result<PathBuf> {
-    if compress {
-        let archive_path = add_extension(path, ".tar.zstd");
-        make_archive(&archive_path, |a| a.append_dir_all(path, path))?;
-        Ok(archive_path)
-    } else {
-        let backup_path = add_extension(path, ".bak.d");
-        copy_dir_all(path, &backup_path)?;
-        Ok(backup_path)
-    }
-}
+        /// Only backups with this tag, can be given multiple times
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
 
-fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
-    fs::create_dir_all(dst)?;
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        let dst_path = dst.join(entry.file_name());
+    /// Set the modification time of backups to now, without rewriting them
+    Touch {
+        /// Backups to touch, their manifests are touched as well
+        paths: Vec<PathBuf>,
 
-        if ty.is_dir() {
-            copy_dir_all(&entry.path(), &dst_path)?;
-        } else if ty.is_file() {
-            fs::copy(entry.path(), dst_path)?;
-        } else {
-            eprintln!(
-                "neither a file nor a directory, skipping: {}",
-                entry.path().display()
-            );
-        }
-    }
-    Ok(())
-}
+        /// Only print what would be touched
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
 
-fn make_archive<F>(archive_path: &Path, do_this: F) -> std::io::Result<()>
-where
-    F: FnOnce(
-        &mut tar::Builder<zstd::stream::AutoFinishEncoder<std::fs::File>>,
-    ) -> std::io::Result<()>,
-{
-    let compressed_file = fs::File::create(archive_path)?;
+    /// Turn a directory backup made without compression into a zstd archive
+    #[command(mut_arg("long", |arg| {
+        arg.help("Use zstd long distance matching, optionally with a window log")
+            .visible_alias(None)
+    }))]
+    Pack {
+        /// `.bak.d` directory to pack
+        path: PathBuf,
 
-    let compressor = zstd::Encoder::new(compressed_file, DEFAULT_COMPRESSION_LEVEL)?.auto_finish();
-    let mut archiver = tar::Builder::new(compressor);
+        /// Delete the directory once the archive is written
+        #[arg(short = 'd', long)]
+        delete: bool,
 
-    do_this(&mut archiver)?;
+        /// zstd compression level
+        #[arg(short = 'l', long, value_parser = clap::value_parser!(i32).range(1..=22))]
+        level: Option<i32>,
 
-    archiver.finish()?;
+        #[command(flatten)]
+        window: WindowLog,
 
-    Ok(())
-}
+        /// Extension of the archive
+        #[arg(long, value_name = "EXT", default_value = "zst")]
+        ext: ZstdExtension,
+    },
 
-fn read_archive<F>(archive_path: &Path, do_this: F) -> std::io::Result<()>
-where
-    F: FnOnce(
-        &mut tar::Archive<zstd::Decoder<'_, std::io::BufReader<std::fs::File>>>,
-    ) -> std::io::Result<()>,
-{
-    let compressed_file = match fs::File::open(archive_path) {
-        Err(e) => {
-            eprintln!("could not open archive: {e}");
-            return Err(e);
-        }
-        Ok(f) => f,
-    };
+    /// Turn an archive back into a backup without compression, the reverse of `pack`
+    Unpack {
+        /// Archive to unpack
+        path: PathBuf,
 
-    let decompressor = match zstd::Decoder::new(compressed_file) {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("could not open zstd decoder: {e}");
-            return Err(e);
-        }
-    };
-    let mut unarchiver = tar::Archive::new(decompressor);
+        /// Delete the archive once it is unpacked
+        #[arg(short = 'd', long)]
+        delete: bool,
 
-    match do_this(&mut unarchiver) {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("could perform read_archive actions: {e}");
-            return Err(e);
-        }
-    };
+        #[command(flatten)]
+        window: WindowLog,
+    },
+}
 
-    Ok(())
+/// `--long` of the commands that read archives
+#[derive(Debug, Clone, Copy, Default, Args)]
+struct WindowLog {
+    /// Allow the window log used with `backup --long`, needed above the default of 27
+    #[arg(
+        long,
+        visible_alias = "window-log",
+        value_name = "WINDOW_LOG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "27",
+        value_parser = clap::value_parser!(u32).range(10..=31)
+    )]
+    long: Option<u32>,
 }
 
-#[cfg(test)]
-mod tests {
-    use std::os::unix::fs::MetadataExt;
-    use std::path::{Path, PathBuf};
-    use std::{fs, io};
+#[derive(Debug, Clone, Default, Args)]
+struct BackupOptions {
+    /// Create an archive, compressed with zstd unless `none` is given. Applies to the paths
+    /// after it, or to all if it is given after them.
+    #[arg(
+        short = 'z',
+        long,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "zstd",
+        env = "LOPPLER_COMPRESS",
+        action = clap::ArgAction::Append
+    )]
+    compress: Option<Compression>,
 
-    use serial_test::serial;
-    use tempfile::tempdir;
+    /// Copy instead of creating an archive, even if `LOPPLER_COMPRESS` or `--compress` is
+    /// given. Applies to the paths after it like `--compress`.
+    #[arg(
+        long,
+        num_args = 0,
+        default_value_t = false,
+        default_missing_value = "true",
+        action = clap::ArgAction::Append
+    )]
+    no_compress: bool,
 
-    use crate::{backup_dir, backup_file, make_archive, read_archive, restore};
+    /// zstd compression level
+    #[arg(
+        short = 'l',
+        long,
+        env = "LOPPLER_LEVEL",
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    level: Option<i32>,
 
-    const CONTENT: &[u8] = b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+    /// zstd compression level by name, instead of `--level`. Wins over `LOPPLER_LEVEL`.
+    #[arg(long, value_name = "PRESET")]
+    preset: Option<Preset>,
 
-    fn filesize(p: &Path) -> io::Result<u64> {
-        Ok(fs::metadata(p)?.size())
-    }
+    /// Compress files matching PATTERN with another zstd level, like `*.txt=19`, or `jpg=-5`
+    /// for files that are compressed already. A plain extension matches regardless of case,
+    /// the first matching rule counts. Every change of the level starts a new zstd frame,
+    /// which can not refer to the data before it.
+    #[arg(
+        long,
+        value_name = "PATTERN=LEVEL",
+        env = "LOPPLER_LEVEL_PER_EXTENSION",
+        value_delimiter = ','
+    )]
+    compress_level_per_extension: Vec<LevelRule>,
 
-    #[test]
-    #[serial]
-    fn test_make_archive() -> io::Result<()> {
-        let t = tempdir()?;
-        let tdir = t.path();
-        std::env::set_current_dir(tdir).unwrap(); // NOTE: if multiple tests use this, this
-                                                  // creates a race condition
-        let tfile = PathBuf::from("foo");
-        let tfile_a = PathBuf::from("foo.tar.zstd");
+    /// Compress files that are compressed already, by their extension or by how random their
+    /// start looks, with the fastest zstd level instead of `--level`. Text is compressed as
+    /// usual, and `--compress-level-per-extension` still wins.
+    #[arg(long)]
+    detect_compressed: bool,
 
-        fs::write(&tfile, CONTENT).unwrap();
-        assert!(tfile.exists());
-        assert!(tfile.is_file());
-        assert_eq!(fs::read(&tfile).unwrap(), CONTENT);
-        let raw_size = fs::metadata(&tfile).unwrap().size();
-        assert!(raw_size > 1, "raw size was {raw_size}");
+    /// Store files of 128 KiB and more uncompressed in the zstd archive if compressing them
+    /// does not make them smaller. Each of them gets a zstd frame of its own, which can not
+    /// refer to the data before it, and that is written again without compression if needed.
+    #[arg(long)]
+    store_incompressible: bool,
 
-        // NOTE: append_path needs a relative path
-        make_archive(&tfile_a, |a| a.append_path(&tfile)).unwrap();
-        assert!(tfile_a.exists());
-        assert!(tfile_a.is_file());
-        let arch_size = fs::metadata(&tfile_a).unwrap().size();
-        assert!(arch_size > 1, "archive size was {arch_size}");
+    /// Adapt the zstd level to the speed of the output like `zstd --adapt`. The zstd library
+    /// has no adaptive mode, so this falls back to the level that is given, unlike
+    /// `--compress-program 'zstd --adapt'`.
+    #[arg(long)]
+    adapt: bool,
 
-        fs::remove_file(&tfile).unwrap();
-        assert!(!tfile.exists());
+    /// Do not put a checksum of the content into the zstd frame. With it, which is the default,
+    /// corruption of the archive is detected while reading it.
+    #[arg(long, alias = "no-compression-checksum")]
+    no_frame_checksum: bool,
 
-        read_archive(&tfile_a, |a| a.unpack(tdir)).unwrap();
-        assert!(tfile.exists());
-        assert!(!tfile.is_dir());
-        assert!(tfile.is_file());
-        let copy_size = fs::metadata(&tfile).unwrap().size();
-        assert!(copy_size > 1, "archive size was {arch_size}");
+    /// Skip entries that cannot be read instead of aborting
+    #[arg(long)]
+    skip_unreadable: bool,
 
-        let copy_content = fs::read(&tfile).unwrap();
-        assert_eq!(CONTENT, copy_content);
+    /// Use zstd long distance matching, optionally with a window log
+    #[arg(
+        long,
+        alias = "compression-long",
+        value_name = "WINDOW_LOG",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "27",
+        env = "LOPPLER_LONG",
+        value_parser = clap::value_parser!(u32).range(10..=31)
+    )]
+    long: Option<u32>,
 
-        Ok(())
-    }
+    /// Compress zstd archives with this many threads, 0 for one per core. More threads need
+    /// more memory, `-v` tells how much.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(0..=200)
+    )]
+    threads: u32,
 
-    #[test]
-    fn test_simple_bak_restore() -> io::Result<()> {
-        let t = tempdir()?;
-        let tdir = t.path();
-        let tfile = tdir.join("foo");
-        let tfile_b = tdir.join("foo.bak");
+    /// Only back up regular files, directories are created as needed
+    #[arg(long, conflicts_with = "only_dirs")]
+    only_files: bool,
 
-        fs::write(&tfile, CONTENT).unwrap();
-        assert!(tfile.exists());
-        assert!(tfile.is_file());
-        assert_eq!(fs::read(&tfile).unwrap(), CONTENT);
-        let raw_size = filesize(&tfile)?;
-        assert!(raw_size > 1, "raw size was {raw_size}");
+    /// Only back up the directory structure, without any files
+    #[arg(long)]
+    only_dirs: bool,
 
-        backup_file(&tfile, false).unwrap();
+    /// Do not descend into subdirectories of a directory
+    #[arg(long)]
+    no_recursion: bool,
 
-        assert!(tfile_b.exists());
-        assert!(tfile_b.is_file());
-        assert_eq!(fs::read(&tfile_b).unwrap(), CONTENT);
-        let raw_size = filesize(&tfile)?;
-        assert!(raw_size > 1, "raw size was {raw_size}");
+    /// Back up every entry of the given directories on its own, like `bigdir/a.tar.zst` and
+    /// `bigdir/b.tar.zst`, instead of the directories as a whole
+    #[arg(long)]
+    split_entries: bool,
 
-        fs::remove_file(&tfile).unwrap();
-        assert!(!tfile.exists());
+    /// Do not descend into directories on other file systems
+    #[arg(long)]
+    one_file_system: bool,
 
-        restore(&tfile_b, tdir).unwrap();
+    /// Refuse to back up directories that are mount points themselves, like `/`, to not back
+    /// up a whole file system by accident
+    #[arg(long)]
+    no_mount_root: bool,
 
-        assert!(tfile.exists());
-        assert!(tfile.is_file());
+    /// Flush every backup and its manifest to disk before reporting it as done, so that a
+    /// crash right afterwards can not lose it
+    #[arg(long)]
+    fsync: bool,
+
+    /// Only write a manifest of the files themselves to `<path>.manifest` without copying
+    /// them, which `compare` checks them against later. Hashed with `--hash`, or SHA-256.
+    #[arg(long)]
+    manifest_only: bool,
+
+    /// Skip directories marked as cache with a `CACHEDIR.TAG`
+    #[arg(long)]
+    exclude_caches: bool,
+
+    /// Skip directories that contain a file or directory of this name, like `.nobackup`. Can
+    /// be given multiple times.
+    #[arg(long, value_name = "NAME")]
+    exclude_if_present: Vec<OsString>,
+
+    /// Only back up the files that match EXPR, like `size > 1mb && ext == log`. It compares
+    /// `name`, `path`, `ext` and `size` and `mtime`, with `==`, `!=`, `<`, `<=`, `>` and `>=`,
+    /// or the wildcards of patterns with `~` and `!~`, and combines those with `&&`, `||`, `!`
+    /// and parentheses. `mtime` takes dates like `2024-05-01` or ages like `7d`. Directories
+    /// are always walked.
+    #[arg(long, value_name = "EXPR")]
+    filter: Option<filter::Filter>,
+
+    /// Skip the metadata of version control systems, like `.git` or `.svn`
+    #[arg(long)]
+    exclude_vcs: bool,
+
+    /// Skip entries matching this gitignore style pattern, relative to the backed up
+    /// directory. Can be given multiple times, and is combined with the patterns of a
+    /// `.lopplerignore` at the root of the directory.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Read exclude patterns from this file, in the syntax of `.gitignore`
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    #[arg(skip)]
+    excludes: exclude::Excludes,
+
+    /// Back up hidden files and directories, whose names start with `.`, even if exclude
+    /// patterns match them
+    #[arg(long, conflicts_with = "no_hidden")]
+    hidden: bool,
+
+    /// Skip hidden files and directories, whose names start with `.`
+    #[arg(long)]
+    no_hidden: bool,
+
+    /// Put an empty placeholder file into every directory that is empty in the source, for
+    /// storage that can not keep empty directories. Restoring removes them again.
+    #[arg(long)]
+    store_empty_as_placeholder: bool,
+
+    /// Name of the placeholder files, `.loppler-keep` by default
+    #[arg(long, value_name = "NAME")]
+    placeholder_name: Option<String>,
+
+    /// Stop at the first path that can not be backed up, instead of going on with the others
+    #[arg(long, alias = "strict")]
+    fail_fast: bool,
+
+    /// Retry failed file operations this many times before giving up, waiting 100ms before the
+    /// first retry and twice as long before every further one, up to 3.2s
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Create byte identical archives for identical contents, ignoring times, owners and most
+    /// permissions
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Back up the targets of symlinks instead of the links themselves. Symlinks back to a
+    /// directory that contains them are skipped, as they would never end.
+    #[arg(short = 'L', long, aliases = ["dereference", "recurse-symlinked-dirs"])]
+    follow_symlinks: bool,
+
+    /// Back up the targets of the given paths if they are symlinks, but keep the symlinks inside
+    /// of them
+    #[arg(long)]
+    dereference_root: bool,
+
+    /// Store copies of what symlinks point to instead of link entries, for tools that can not
+    /// handle those. Like `--follow-symlinks`, but broken symlinks are left out rather than kept
+    /// as links. Hard links are always stored as copies.
+    #[arg(long)]
+    preserve_links_as_copies: bool,
+
+    /// Do not copy files again whose copy from an earlier backup is newer than them, archives
+    /// are always written completely
+    #[arg(long, alias = "update")]
+    keep_newer: bool,
+
+    /// Only back up the files of directories that are not in BACKUP as they are now, for a
+    /// differential backup on top of it. BACKUP is read from its manifest if it has one, and
+    /// the new backup records it in its own, which is always written.
+    #[arg(long, value_name = "BACKUP")]
+    since: Option<PathBuf>,
+
+    /// The files of `--since`
+    #[arg(skip)]
+    reference: Option<std::rc::Rc<since::Reference>>,
+
+    /// Clone files copy on write where the filesystem can, like btrfs and XFS. Copies take no
+    /// time and space, and archives are made from a clone of each file that stays as it was
+    /// while the file changes. Elsewhere files are copied as usual.
+    #[arg(long)]
+    reflink: bool,
+
+    /// Size of the buffers for reading and writing backups, like `64K` or `4M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    buffer_size: Option<usize>,
+
+    /// Do not back up further paths once the backups of this run add up to this size, like
+    /// `4G`. The backup that reaches it is still completed.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    total_size_limit: Option<usize>,
+
+    /// Abort a backup if less than this is left free where it is written, like `10G`, and
+    /// remove what was written of it. Checked before every file.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_free_space: Option<usize>,
+
+    /// The directory the backup of the current path is written to, for `--min-free-space`
+    #[arg(skip)]
+    free_space_dir: Option<PathBuf>,
+
+    /// Warn when the files of an uncompressed archive are smaller than this on average, as
+    /// their tar headers and padding then make up much of it. 0 never warns.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "4K")]
+    small_file_threshold: usize,
+
+    /// A note about the backup, like why it was made, stored in the archive and shown by
+    /// `list --long`. Only archives can have one.
+    #[arg(long, value_name = "TEXT", alias = "archive-comment", value_parser = parse_comment)]
+    comment: Option<String>,
+
+    /// Store all entries of archives below this directory, like `snapshot`, which restoring
+    /// creates around them
+    #[arg(long, value_name = "DIR", value_parser = parse_prefix)]
+    prefix: Option<PathBuf>,
+
+    /// Also write the backups into this directory, like a second disk or a network mount, can
+    /// be given multiple times. The sources are read once for all of them.
+    #[arg(long, value_name = "DIR")]
+    also_to: Vec<PathBuf>,
+
+    /// The kind of tar headers of archives. GNU tar reads all of them, but only pax and gnu
+    /// have room for long paths and files of 8 GiB or more.
+    #[arg(long, value_name = "FORMAT", default_value_t, value_enum)]
+    archive_format: format::ArchiveFormat,
+
+    /// Write progress as newline delimited JSON to this file descriptor
+    #[arg(long, value_name = "FD")]
+    progress_fd: Option<i32>,
+
+    /// Write at most one progress event per file every MS milliseconds, 0 for all of them.
+    /// By default 100 for terminals and 1000 for files and pipes.
+    #[arg(long, value_name = "MS", requires = "progress_fd")]
+    progress_interval: Option<u64>,
+
+    /// Abort if the backups take longer than this, like `30m` or `1h30m`, removing the partial
+    /// backup
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    time_limit: Option<std::time::Duration>,
+
+    #[arg(skip)]
+    deadline: Option<std::time::Instant>,
+
+    /// Give up on a file when opening or reading it gets no response for this long, like
+    /// `30s`, for storage that can hang like a dead NFS mount. What then happens is up to
+    /// `--on-timeout`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_timeout)]
+    keep_going_timeout: Option<std::time::Duration>,
+
+    /// What to do with a file that gets no response within `--keep-going-timeout`
+    #[arg(long, value_name = "ACTION", default_value_t, value_enum)]
+    on_timeout: watchdog::OnTimeout,
+
+    /// Owner of all archive entries, as `NAME`, `ID` or `NAME:ID`, names without an id get 0
+    #[arg(long, value_name = "OWNER")]
+    owner: Option<Owner>,
+
+    /// Group of all archive entries, as `NAME`, `ID` or `NAME:ID`, names without an id get 0
+    #[arg(long, value_name = "GROUP")]
+    group: Option<Owner>,
+
+    /// Create an archive and pipe it through this shell command instead of compressing it with
+    /// zstd, like `lz4 -9`. The archive is named after the command, like `.tar.lz4`. Overrides
+    /// the zstd options, which might come from the environment.
+    #[arg(long, value_name = "CMD", conflicts_with = "hash")]
+    compress_program: Option<String>,
+
+    /// Extension of zstd compressed archives
+    #[arg(long, value_name = "EXT", default_value = "zst")]
+    ext: ZstdExtension,
+
+    /// Write a checksum manifest next to the backup, which restore verifies against
+    #[arg(long, value_name = "ALG")]
+    hash: Option<hash::HashAlgorithm>,
+
+    /// Tag the backup, like `weekly`, to find it with `find --tag`. Tags are stored in the
+    /// manifest, which is written with the default hash if `--hash` is not given.
+    #[arg(long = "tag", value_name = "TAG", value_parser = parse_tag)]
+    tags: Vec<String>,
+
+    /// Print how many files and bytes each file extension contributed, to find out what makes
+    /// backups large
+    #[arg(long)]
+    stats: bool,
+
+    #[arg(skip)]
+    verbose: u8,
+
+    /// Only print what the exclude patterns and other rules leave out, with the pattern or
+    /// rule that does, without backing up anything
+    #[arg(long)]
+    list_excluded: bool,
+
+    /// `--bytes`, for the statistics
+    #[arg(skip)]
+    bytes: bool,
+
+    /// `--yes`, to replace what is in the way of a backup without asking
+    #[arg(skip)]
+    yes: bool,
+
+    /// Existing files that copying leaves as they are, those that `restore --confirm-each`
+    /// was told to keep
+    #[arg(skip)]
+    keep: HashSet<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Args)]
+struct RestoreOptions {
+    #[command(flatten)]
+    window: WindowLog,
+
+    /// Refuse archives whose zstd window needs more memory than this to decompress, like
+    /// `64M`, for archives from others. Lowers the limit of `--long`, never raises it.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with = "decompress_program"
+    )]
+    max_memory: Option<usize>,
+
+    /// Restore archive entries starting with OLD below NEW instead, can be given multiple times
+    #[arg(long, value_name = "OLD=NEW")]
+    prefix_map: Vec<unpack::PrefixMap>,
+
+    /// Set the permissions of all restored files and directories to this octal mode
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    chmod: Option<u32>,
+
+    /// Add and overwrite files, but keep those that are not in the backup, the default
+    #[arg(long, conflicts_with = "mirror")]
+    merge: bool,
+
+    /// Make the restored directories match the backup exactly, deleting files that are not in it
+    #[arg(long)]
+    mirror: bool,
+
+    /// Name of the placeholder files of `backup --store-empty-as-placeholder` to remove,
+    /// `.loppler-keep` by default
+    #[arg(long, value_name = "NAME")]
+    placeholder_name: Option<String>,
+
+    /// Restore an archive made with `backup --compress-program`, read through this shell command
+    #[arg(long, value_name = "CMD", conflicts_with = "long")]
+    decompress_program: Option<String>,
+
+    /// Read archives with this extension through CMD, like `lz4=lz4 -d` for the `.tar.lz4` of
+    /// `backup --compress-program lz4`. The first matching rule counts, and
+    /// `--decompress-program` wins over all of them.
+    #[arg(
+        long,
+        value_name = "EXT=CMD",
+        env = "LOPPLER_COMPRESS_PROGRAM_EXT",
+        value_delimiter = ','
+    )]
+    compress_program_ext: Vec<ProgramExt>,
+
+    /// Give the restored directories their modification times from the backup, in a last pass
+    /// after everything inside of them is written
+    #[arg(long)]
+    preserve_mtime_on_dirs: bool,
+
+    /// Refuse to restore archives that a newer version of loppel wrote in a format this one
+    /// does not know, instead of only warning
+    #[arg(long)]
+    version_check: bool,
+
+    /// Restore the contents of the directory that was backed up directly into the output
+    /// directory, instead of into a directory of its name
+    #[arg(long, conflicts_with = "prefix_map")]
+    flatten: bool,
+
+    /// Read the whole archive first and only start writing once it decoded without errors and
+    /// matched the manifest, so a damaged archive restores nothing instead of a part
+    #[arg(long)]
+    list_then_extract: bool,
+
+    /// Only overwrite files that were modified before their version in the backup, and keep
+    /// those that were changed since, without asking
+    #[arg(long, conflicts_with = "mirror")]
+    overwrite_older: bool,
+
+    /// Ask before overwriting each existing file, instead of once for all of them. `--yes`
+    /// overwrites them all without asking.
+    #[arg(long, conflicts_with = "mirror")]
+    confirm_each: bool,
+
+    /// Restore to where the backup was made from, as its manifest records it, instead of into
+    /// the output directory. This writes anywhere, so it asks first unless `--yes` is given.
+    #[arg(
+        long,
+        conflicts_with_all = ["output_dir", "into_new_dir", "prefix_map", "flatten", "mirror"]
+    )]
+    use_manifest_paths: bool,
+
+    /// Go on past entries of archives that can not be restored, and past damage in them, to
+    /// restore as much as possible. Fails at the end if any entry did.
+    #[arg(long, conflicts_with_all = ["list_then_extract", "mirror"])]
+    ignore_errors: bool,
+
+    /// Translate the uids and gids of archive entries with the lines of FILE, `OLD:NEW` for
+    /// both or `user OLD:NEW` and `group OLD:NEW` for one, and give the restored entries the
+    /// result as their owners. Ids that are not in FILE are kept as they are in the archive.
+    #[arg(long, value_name = "FILE", value_parser = unpack::OwnerMap::from_file)]
+    owner_map: Option<unpack::OwnerMap>,
+
+    /// Restore into a new directory next to the target first, and only replace the existing
+    /// entries with the restored ones once that is complete, each in one step. Nothing is
+    /// merged into them, files that are not in the backup go away with them.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "merge", "mirror", "overwrite_older", "confirm_each", "flatten", "ignore_errors"
+        ]
+    )]
+    atomic: bool,
+
+    #[arg(skip)]
+    verbose: u8,
+
+    #[arg(skip)]
+    yes: bool,
+}
+
+/// A user or group for archive entries, overriding the one of the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Owner {
+    name: String,
+    id: u64,
+}
+
+impl std::str::FromStr for Owner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty owner".to_string());
+        }
+        let (name, id) = match s.rsplit_once(':') {
+            Some((name, id)) => (name, Some(id)),
+            None if s.bytes().all(|b| b.is_ascii_digit()) => ("", Some(s)),
+            None => (s, None),
+        };
+        let id = match id {
+            Some(id) => id.parse().map_err(|_| format!("not a numeric id: {id}"))?,
+            None => 0,
+        };
+        Ok(Owner {
+            name: name.to_string(),
+            id,
+        })
+    }
+}
+
+/// A `PATTERN=LEVEL` rule of `--compress-level-per-extension`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LevelRule {
+    /// Matched against file names, with the wildcards of exclude patterns
+    pattern: Vec<u8>,
+    /// For rules given as a plain extension, which match regardless of case
+    ignore_case: bool,
+    level: i32,
+}
+
+impl LevelRule {
+    fn matches(&self, name: &OsStr) -> bool {
+        if self.ignore_case {
+            exclude::match_name(&self.pattern, &name.as_bytes().to_ascii_lowercase())
+        } else {
+            exclude::match_name(&self.pattern, name.as_bytes())
+        }
+    }
+}
+
+impl std::str::FromStr for LevelRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, level) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATTERN=LEVEL, got {s:?}"))?;
+        let level: i32 = level
+            .parse()
+            .map_err(|_| format!("not a compression level: {level}"))?;
+        if !(-7..=22).contains(&level) || level == 0 {
+            return Err(format!("level {level} is not in -7..=-1 or 1..=22"));
+        }
+        if pattern.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        // `jpg` and `.jpg` are short for `*.jpg`
+        let ignore_case = !pattern.contains(['*', '?', '[']);
+        let pattern = if ignore_case {
+            format!("*.{}", pattern.trim_start_matches('.').to_ascii_lowercase())
+        } else {
+            pattern.to_string()
+        };
+        Ok(LevelRule {
+            pattern: pattern.into_bytes(),
+            ignore_case,
+            level,
+        })
+    }
+}
+
+/// An `EXT=CMD` rule of `--compress-program-ext`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProgramExt {
+    /// The extension after `.tar.`, like `lz4`
+    ext: String,
+    program: String,
+}
+
+impl std::str::FromStr for ProgramExt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ext, program) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected EXT=CMD, got {s:?}"))?;
+        // `lz4`, `.lz4` and `.tar.lz4` are the same
+        let ext = ext.trim_start_matches('.');
+        let ext = ext.strip_prefix("tar.").unwrap_or(ext);
+        if ext.is_empty() || ext.contains('/') {
+            return Err(format!("not an extension: {ext:?}"));
+        }
+        if program.trim().is_empty() {
+            return Err(format!("no command for .tar.{ext}"));
+        }
+        Ok(ProgramExt {
+            ext: ext.to_string(),
+            program: program.to_string(),
+        })
+    }
+}
+
+/// The command of the first rule of `--compress-program-ext` for the extension of the archive
+/// at `path`, [`None`] if none has it
+fn program_for<'a>(path: &Path, rules: &'a [ProgramExt]) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|rule| has_suffix(path, &format!(".tar.{}", rule.ext)))
+        .map(|rule| rule.program.as_str())
+}
+
+/// How archives are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Compression {
+    /// zstd compressed tar
+    Zstd,
+    /// Plain tar
+    None,
+}
+
+/// Named zstd compression levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Preset {
+    /// Level 1, for when time matters more than space
+    Fast,
+    /// Level 3, what is used without `--level` as well
+    Default,
+    /// Level 19, small archives that take a while
+    Best,
+}
+
+impl Preset {
+    fn level(self) -> i32 {
+        match self {
+            Preset::Fast => 1,
+            Preset::Default => DEFAULT_COMPRESSION_LEVEL,
+            Preset::Best => 19,
+        }
+    }
+}
+
+/// Which extension zstd compressed archives get, both are recognized when restoring
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum ZstdExtension {
+    /// `.tar.zst`
+    #[default]
+    Zst,
+    /// `.tar.zstd`
+    Zstd,
+}
+
+/// The extension of archives made with `--compress-program`, from the name of the program
+fn program_extension(program: &str) -> String {
+    let name = program.split_whitespace().next().unwrap_or_default();
+    let name = Path::new(name).file_name().unwrap_or_default();
+    format!(".tar.{}", name.to_string_lossy())
+}
+
+/// The extension of the backups, [`None`] if they are copies instead of archives
+fn archive_extension(opts: &BackupOptions) -> Option<String> {
+    match &opts.compress_program {
+        Some(program) => Some(program_extension(program)),
+        None => opts.compress.map(|c| c.extension(opts.ext).to_string()),
+    }
+}
+
+impl Compression {
+    fn extension(self, zstd_ext: ZstdExtension) -> &'static str {
+        match (self, zstd_ext) {
+            (Compression::Zstd, ZstdExtension::Zst) => ".tar.zst",
+            (Compression::Zstd, ZstdExtension::Zstd) => ".tar.zstd",
+            (Compression::None, _) => ".tar",
+        }
+    }
+
+    /// Detects the compression of an archive by its name, [`None`] if it is not an archive
+    fn detect(path: &Path) -> Option<Self> {
+        if has_suffix(path, ".tar.zstd") || has_suffix(path, ".tar.zst") {
+            Some(Compression::Zstd)
+        } else if has_suffix(path, ".tar") {
+            Some(Compression::None)
+        } else {
+            None
+        }
+    }
+
+    /// Detects the compression of the archive in `file` by its start, [`None`] if it looks
+    /// like neither or can not be read at an offset like pipes, for archives that were renamed
+    fn sniff(file: &fs::File) -> Option<Self> {
+        let mut block = [0; 512];
+        let mut len = 0;
+        while len < block.len() {
+            match file.read_at(&mut block[len..], len as u64) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return None,
+            }
+        }
+        let magic = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        // skippable frames too, which other tools put in front of the data
+        let zstd = magic == ZSTD_MAGIC || magic & 0xffff_fff0 == 0x184d_2a50;
+        if len >= 4 && zstd {
+            Some(Compression::Zstd)
+        } else if &block[257..262] == b"ustar" {
+            Some(Compression::None)
+        } else {
+            None
+        }
+    }
+}
+
+/// Counters collected over all backups of one run
+#[derive(Debug, Default)]
+struct BackupStats {
+    /// Paths that were backed up
+    backed_up: usize,
+    /// Paths whose backup failed
+    failed: usize,
+    /// Paths that were not backed up at all
+    skipped: usize,
+    /// Entries skipped because of `--skip-unreadable` and paths whose backup failed, reported
+    /// together at the end
+    errors: Vec<(PathBuf, io::Error)>,
+    /// Regular files read from the sources
+    files: u64,
+    /// Files stored uncompressed in archives by `--store-incompressible`
+    stored: u64,
+    /// Bytes read from the sources
+    bytes_in: u64,
+    /// Bytes written to the backups
+    bytes_out: u64,
+    /// Number of files and bytes read per file extension, for `--stats`
+    extensions: HashMap<OsString, (u64, u64)>,
+    /// Where to report progress to, with `--progress-fd`
+    progress: Option<progress::Progress>,
+}
+
+impl BackupStats {
+    /// Counts the file at `path` with the `bytes` read from it, and reports it if progress is
+    /// reported
+    fn file_done(&mut self, path: &Path, bytes: u64) -> io::Result<()> {
+        self.files += 1;
+        self.bytes_in += bytes;
+        let ext = path.extension().unwrap_or_default().to_os_string();
+        let counts = self.extensions.entry(ext).or_default();
+        counts.0 += 1;
+        counts.1 += bytes;
+        match &mut self.progress {
+            Some(progress) => progress.file(path, self.bytes_in),
+            None => Ok(()),
+        }
+    }
+
+    /// Prints all collected errors, so that they do not get lost in the rest of the output
+    fn report_errors(&self) {
+        if !self.errors.is_empty() {
+            report_errors(&self.errors);
+        }
+    }
+
+    /// Table of the files and bytes per extension, the largest first. Compressed sizes are not
+    /// known per file, as an archive is compressed as a whole.
+    fn extension_table(&self, raw: bool) -> String {
+        let mut rows: Vec<_> = self.extensions.iter().collect();
+        rows.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(b.0)));
+        let mut s = format!(
+            "{:<16} {:>8} {:>10} {:>6}\n",
+            "extension", "files", "size", "share"
+        );
+        for (ext, (files, bytes)) in rows {
+            let name = if ext.is_empty() {
+                "(none)".to_string()
+            } else {
+                format!(".{}", ext.to_string_lossy())
+            };
+            let share = *bytes as f64 * 100.0 / self.bytes_in.max(1) as f64;
+            s += &format!(
+                "{name:<16} {files:>8} {:>10} {share:>5.1}%\n",
+                format_size(*bytes, raw)
+            );
+        }
+        s
+    }
+
+    /// One line summary of the whole run
+    fn summary(&self, elapsed: std::time::Duration, raw: bool) -> String {
+        let mut s = format!("{} backed up, {} failed", self.backed_up, self.failed);
+        if self.skipped > 0 {
+            s += &format!(", {} skipped", self.skipped);
+        }
+        if self.stored > 0 {
+            s += &format!(", {} files stored uncompressed", self.stored);
+        }
+        s += &format!(
+            ", {} in, {} out in {:.1}s",
+            format_size(self.bytes_in, raw),
+            format_size(self.bytes_out, raw),
+            elapsed.as_secs_f64()
+        );
+        s
+    }
+}
+
+/// Formats a byte count for humans, like `1.2 GiB`, or as the plain number with `raw` for
+/// `--bytes`
+fn format_size(bytes: u64, raw: bool) -> String {
+    if raw {
+        return bytes.to_string();
+    }
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Parses a byte count with an optional binary unit, like `512`, `64K`, `1mb` or `1.5GiB`
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let unit = unit.trim().to_ascii_lowercase();
+    let unit = unit
+        .strip_suffix("ib")
+        .or(unit.strip_suffix('b'))
+        .unwrap_or(&unit);
+    let factor: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        _ => return Err(format!("unknown size unit: {unit}")),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("not a size: {s}"))?;
+    Ok((number * factor as f64) as usize)
+}
+
+/// Reads `LOPPLER_VERBOSE`, a level like `2` or a boolean for `-v` as it was before the levels
+fn parse_verbosity(s: &str) -> Result<u8, String> {
+    match s {
+        "true" | "yes" | "on" => Ok(VERBOSE_PATHS),
+        "false" | "no" | "off" | "" => Ok(0),
+        _ => s.parse().map_err(|_| format!("not a verbosity level: {s}")),
+    }
+}
+
+/// Checks a `--comment`, the tar crate can not read pax values with line breaks
+fn parse_comment(s: &str) -> Result<String, String> {
+    if s.contains('\n') {
+        return Err("comments can only have one line".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Checks a `--tag`, which has to be one word
+fn parse_tag(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!("tags have to be one word: {s:?}"));
+    }
+    Ok(s.to_string())
+}
+
+/// Checks a `--prefix`, which has to stay inside of the directory the archive is restored to
+fn parse_prefix(s: &str) -> Result<PathBuf, String> {
+    let path = Path::new(s);
+    if !path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "the prefix has to be a relative path without ..: {s}"
+        ));
+    }
+    let prefix = unpack::normalize_path(path);
+    if prefix.as_os_str().is_empty() {
+        return Err("the prefix is empty".to_string());
+    }
+    Ok(prefix)
+}
+
+/// Parses a duration like `90`, `45s`, `30m` or `1h30m`, plain numbers are seconds
+fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let invalid = || format!("not a duration: {s}");
+    let mut secs = 0u64;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let factor = match unit {
+            "" | "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        secs = number
+            .checked_mul(factor)
+            .and_then(|n| secs.checked_add(n))
+            .ok_or_else(invalid)?;
+        rest = tail;
+    }
+    Ok(std::time::Duration::from_secs(secs))
+}
+
+/// Parses a `--keep-going-timeout`, which has to be more than none at all
+fn parse_timeout(s: &str) -> Result<std::time::Duration, String> {
+    match parse_duration(s)? {
+        std::time::Duration::ZERO => Err("the timeout has to be at least 1s".to_string()),
+        timeout => Ok(timeout),
+    }
+}
+
+/// Prints the help to stderr and exits with the usage error code, explicit `--help` is handled
+/// by clap and goes to stdout
+fn help_and_exit() -> ! {
+    let mut cmd = Cli::command();
+    eprint!("{}", cmd.render_help());
+    std::process::exit(2)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli;
+    let matches;
+    let command = {
+        // not args(), which panics on names that are not UTF-8
+        let mut a: Vec<OsString> = std::env::args_os().collect();
+        if a.len() < 2 {
+            help_and_exit()
+        }
+        let first = a[1].as_bytes();
+        let is_subcommand = a[1]
+            .to_str()
+            .is_some_and(|s| Cli::command().find_subcommand(s).is_some());
+        if !(first.starts_with(b"-") || is_subcommand) {
+            let slice = if first.windows(3).any(|w| w == b"bak") {
+                &[OsString::from("restore")]
+            } else {
+                &[OsString::from("backup")]
+            };
+
+            a.splice(1..1, slice.iter().cloned());
+        }
+        matches = Cli::command().get_matches_from(a.iter());
+        cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        color::init(cli.color);
+        cli.command.unwrap()
+    };
+
+    match command {
+        Commands::Backup { paths, mut opts } => {
+            opts.verbose = cli.verbose;
+            opts.bytes = cli.bytes;
+            opts.yes = cli.confirm;
+            opts.follow_symlinks |= opts.preserve_links_as_copies;
+            if let Some(since) = &opts.since {
+                let reference = since::Reference::read(since, opts.long)?;
+                opts.reference = Some(std::rc::Rc::new(reference));
+            }
+            preset_level(&matches, &mut opts).unwrap_or_else(|e| e.exit());
+            let compressions = path_compressions(&matches, &mut opts);
+            let zstd = opts.compress == Some(Compression::Zstd) && opts.compress_program.is_none();
+            if opts.adapt && zstd {
+                eprintln!(
+                    "adaptive compression is only available in the zstd program, \
+                     compressing with level {} instead. \
+                     Use --compress-program 'zstd --adapt' to get it.",
+                    compression_level(&opts)
+                );
+            } else if opts.verbose >= VERBOSE_PATHS && zstd {
+                println!("Compressing with zstd level {}", compression_level(&opts));
+            }
+            if opts.threads == 0 {
+                opts.threads = std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+            }
+            if zstd {
+                let level = opts
+                    .compress_level_per_extension
+                    .iter()
+                    .map(|rule| rule.level)
+                    .fold(compression_level(&opts), i32::max);
+                let needed = zstd_memory(level, opts.long, opts.threads);
+                if opts.verbose >= VERBOSE_PATHS {
+                    let threads = match opts.threads {
+                        1 => "1 thread".to_string(),
+                        n => format!("{n} threads"),
+                    };
+                    println!(
+                        "zstd level {level} with {threads} needs about {} of memory",
+                        format_size(needed, opts.bytes)
+                    );
+                }
+                if let Some(warning) = memory_warning(needed, available_memory(), &opts) {
+                    eprintln!("warning: {warning}");
+                }
+            }
+            if paths.is_empty() {
+                help_and_exit()
+            }
+            let mut paths: Vec<_> = paths.into_iter().zip(compressions).collect();
+            let archives = opts.compress_program.is_some()
+                || paths.iter().any(|(_, compress)| compress.is_some());
+            if opts.comment.is_some() && !archives {
+                return Err("only archives can have a --comment, add -z to create one".into());
+            }
+            if opts.prefix.is_some() && !archives {
+                return Err("only archives can have a --prefix, add -z to create one".into());
+            }
+            if !opts.also_to.is_empty() && opts.manifest_only {
+                return Err("--manifest-only writes no backups for --also-to".into());
+            }
+            if let Some(dir) = opts.also_to.iter().find(|dir| !dir.is_dir()) {
+                return Err(format!("--also-to {}: is not a directory", dir.display()).into());
+            }
+            #[cfg(not(feature = "compression"))]
+            if opts.compress_program.is_none()
+                && paths
+                    .iter()
+                    .any(|(_, compress)| *compress == Some(Compression::Zstd))
+            {
+                return Err(zstd::unavailable().to_string().into());
+            }
+            if opts.split_entries {
+                let mut split = Vec::new();
+                for (path, compress) in paths {
+                    let entries = split_entries(vec![path], &opts)?;
+                    split.extend(entries.into_iter().map(|entry| (entry, compress)));
+                }
+                paths = split;
+            }
+            if opts.list_excluded {
+                for (path, _) in &paths {
+                    list_excluded(path, &opts)?;
+                }
+                return Ok(());
+            }
+            let start = std::time::Instant::now();
+            // limits too far away to be a time are none
+            opts.deadline = opts.time_limit.and_then(|limit| start.checked_add(limit));
+            let mut stats = BackupStats::default();
+            let mut timed_out = false;
+            let mut over_limit = Vec::new();
+            if let Some(fd) = opts.progress_fd {
+                let total = paths
+                    .iter()
+                    .map(|(path, _)| estimate_size(path, &opts))
+                    .sum();
+                let mut progress = progress::Progress::from_fd(fd, total)?;
+                if let Some(ms) = opts.progress_interval {
+                    progress.set_interval(std::time::Duration::from_millis(ms));
+                }
+                progress.event("start", None, 0)?;
+                stats.progress = Some(progress);
+            }
+            let mut collisions = find_collisions(&paths, &opts);
+            let mut paths = paths.into_iter();
+            for (i, (path, compress)) in paths.by_ref().enumerate() {
+                if timed_out || check_deadline(&opts).is_err() {
+                    eprintln!(
+                        "{}",
+                        color::skipped(format_args!(
+                            "Skipping {:?}, the time limit is exceeded",
+                            path
+                        ))
+                        .stderr()
+                    );
+                    timed_out = true;
+                    stats.skipped += 1;
+                    continue;
+                }
+                if size_limit_reached(&opts, &stats) {
+                    over_limit.push(path);
+                    stats.skipped += 1;
+                    continue;
+                }
+                let opts = path_options(&opts, compress);
+                let meta = match collisions.remove(&i) {
+                    Some(e) => Err(e),
+                    None => root_metadata(&path, &opts).and_then(|meta| {
+                        let output = output_path(&path, meta.is_dir(), &opts)?;
+                        for output in destinations(&output, &opts) {
+                            check_output(&output, &opts)?;
+                        }
+                        Ok(meta)
+                    }),
+                };
+                let meta = match meta {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        stats.failed += 1;
+                        stats.errors.push((path, e));
+                        if opts.fail_fast {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                let started = std::time::Instant::now();
+                let result = if opts.manifest_only {
+                    manifest_only(&path, &opts, &mut stats)
+                } else if meta.is_dir() {
+                    backup_dir(&path, &opts, &mut stats)
+                } else if opts.preserve_links_as_copies && is_broken_link(&path) {
+                    Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "a broken symlink, which has nothing to copy with \
+                         --preserve-links-as-copies",
+                    ))
+                } else if meta.is_file() || meta.is_symlink() {
+                    if meta.is_symlink() && path.is_dir() {
+                        eprintln!(
+                            "{} is a symlink, backing up the link itself, see --dereference-root",
+                            path.display()
+                        );
+                    }
+                    backup_file(&path, &opts, &mut stats)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "neither a file nor a directory, not backed up",
+                    ))
+                };
+
+                match result {
+                    Ok(backup) => {
+                        if opts.verbose >= VERBOSE_DEBUG {
+                            println!(
+                                "{} in {:.3?}",
+                                color::created(format_args!("created {}", backup.display())),
+                                started.elapsed()
+                            );
+                        } else if opts.verbose >= VERBOSE_PATHS {
+                            println!(
+                                "{}",
+                                color::created(format_args!("created {}", backup.display()))
+                            );
+                        }
+                        if opts.verbose >= VERBOSE_PATHS {
+                            for copy in destinations(&backup, &opts).iter().skip(1) {
+                                println!(
+                                    "{}",
+                                    color::created(format_args!("and {}", copy.display()))
+                                );
+                            }
+                        }
+                        stats.backed_up += 1
+                    }
+                    Err(e) => {
+                        // a skipped file is no reason to stop the others
+                        timed_out = e.kind() == io::ErrorKind::TimedOut
+                            && !(watchdog::is_stalled(&e)
+                                && opts.on_timeout == watchdog::OnTimeout::Skip);
+                        stats.failed += 1;
+                        stats.errors.push((path, e));
+                        if opts.fail_fast {
+                            break;
+                        }
+                    }
+                }
+            }
+            if paths.len() > 0 {
+                eprintln!(
+                    "{}",
+                    color::error(format_args!(
+                        "Stopped after the first error, {} paths are left",
+                        paths.len()
+                    ))
+                );
+                stats.skipped += paths.len();
+            }
+            stats.report_errors();
+            if !over_limit.is_empty() {
+                eprintln!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "Skipped {} paths, the total size limit is reached:",
+                        over_limit.len()
+                    ))
+                    .stderr()
+                );
+                for path in &over_limit {
+                    eprintln!("  {}", color::skipped(path.display()).stderr());
+                }
+            }
+            if let Some(progress) = &mut stats.progress {
+                progress.event("done", None, stats.bytes_in)?;
+            }
+            if opts.stats {
+                print!("{}", stats.extension_table(opts.bytes));
+            }
+            println!("{}", stats.summary(start.elapsed(), opts.bytes));
+            if timed_out {
+                std::process::exit(EXIT_TIME_LIMIT);
+            }
+            if !stats.errors.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Restore {
+            path,
+            delete,
+            output_dir,
+            into_new_dir,
+            mut opts,
+        } => {
+            opts.yes = cli.confirm;
+            opts.verbose = cli.verbose;
+            if let Some(max_memory) = opts.max_memory {
+                opts.window.long = Some(window_log_limit(opts.window.long, max_memory)?);
+            }
+            if opts.decompress_program.is_none() {
+                opts.decompress_program =
+                    program_for(&path, &opts.compress_program_ext).map(str::to_string);
+            }
+            if delete && is_url(&path) {
+                return Err("--delete can not delete archives from URLs".into());
+            }
+            println!("Restoring from {:?}", path);
+            let mut out = match opts.use_manifest_paths {
+                true => original_location(&path, &mut opts)?,
+                false => output_dir.unwrap_or(std::env::current_dir()?),
+            };
+            if into_new_dir {
+                out = new_restore_dir(&out)?;
+                println!("Restoring into {}", color::created(out.display()));
+            }
+            restore(&path, &out, &opts)?;
+            if delete && (cli.confirm || confirm(format!("delete {}?", path.display()))?) {
+                remove_backup(&path)?;
+            }
+        }
+        Commands::Compare {
+            path,
+            output_dir,
+            window,
+        } => {
+            let out = output_dir.unwrap_or(std::env::current_dir()?);
+            print_changes(&compare::compare(&path, &out, window.long)?);
+        }
+        #[cfg(feature = "fuse")]
+        Commands::Mount {
+            path,
+            mountpoint,
+            window,
+        } => mount::mount(&path, &mountpoint, window.long)?,
+        Commands::List {
+            path,
+            long,
+            tree,
+            window_log,
+        } => {
+            if !is_archive(&path) {
+                return Err(format!("not an archive: {}", path.display()).into());
+            }
+            read_archive(&path, window_log, |a| match tree {
+                true => list::tree(a),
+                false => list::list(a, long, cli.bytes),
+            })?;
+        }
+        Commands::Diff { old, new, window } => {
+            print_changes(&compare::diff(&old, &new, window.long)?);
+        }
+        Commands::Verify { paths, window } => {
+            let mut failed = 0;
+            for path in &paths {
+                match manifest::verify_backup(path, window.long) {
+                    Ok((entries, None)) => println!("{}: OK, {entries} entries", path.display()),
+                    Ok((entries, Some(algorithm))) => println!(
+                        "{}: OK, {entries} entries match the {} manifest",
+                        path.display(),
+                        algorithm.name()
+                    ),
+                    Err(e) => {
+                        eprintln!("{}", color::error(format_args!("{}: {e}", path.display())));
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(format!("{failed} of {} backups are damaged", paths.len()).into());
+            }
+        }
+        Commands::Find { dir, tags } => {
+            let dir = dir.unwrap_or(std::env::current_dir()?);
+            for (backup, tags) in manifest::find(&dir, &tags)? {
+                println!("{}  {}", backup.display(), tags.join(" "));
+            }
+        }
+        Commands::Touch { paths, dry_run } => {
+            let mut failed = 0;
+            for path in &paths {
+                let result = if !is_backup(path) || path.symlink_metadata().is_err() {
+                    Err(io::Error::new(io::ErrorKind::InvalidInput, "not a backup"))
+                } else if dry_run {
+                    println!("would touch {}", path.display());
+                    Ok(())
+                } else {
+                    touch(path).and_then(|()| {
+                        let manifest = manifest::Manifest::path_for(path)?;
+                        if manifest.exists() {
+                            touch(&manifest)
+                        } else {
+                            Ok(())
+                        }
+                    })
+                };
+                match result {
+                    Ok(()) if cli.verbose >= VERBOSE_PATHS && !dry_run => {
+                        println!("touched {}", path.display())
+                    }
+                    Ok(()) => (),
+                    Err(e) => {
+                        eprintln!("{}", color::error(format_args!("{}: {e}", path.display())));
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(format!("{failed} of {} backups were not touched", paths.len()).into());
+            }
+        }
+        Commands::Pack {
+            path,
+            delete,
+            level,
+            window,
+            ext,
+        } => {
+            let opts = BackupOptions {
+                level,
+                long: window.long,
+                ext,
+                ..Default::default()
+            };
+            let archive = convert::pack(&path, &opts)?;
+            println!(
+                "{} packed into {}",
+                path.display(),
+                color::created(archive.display())
+            );
+            if delete && (cli.confirm || confirm(format!("delete {}?", path.display()))?) {
+                remove_backup(&path)?;
+            }
+        }
+        Commands::Unpack {
+            path,
+            delete,
+            window,
+        } => {
+            let backup = convert::unpack(&path, window.long)?;
+            println!(
+                "{} unpacked into {}",
+                path.display(),
+                color::created(backup.display())
+            );
+            if delete && (cli.confirm || confirm(format!("delete {}?", path.display()))?) {
+                remove_backup(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lets a `--preset` on the command line win over the `--level` from `LOPPLER_LEVEL`, and
+/// fails if both are given on the command line
+fn preset_level(matches: &clap::ArgMatches, opts: &mut BackupOptions) -> Result<(), clap::Error> {
+    let Some(matches) = matches.subcommand_matches("backup") else {
+        return Ok(());
+    };
+    if opts.preset.is_none() {
+        return Ok(());
+    }
+    match matches.value_source("level") {
+        Some(clap::parser::ValueSource::CommandLine) => Err(Cli::command().error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "the argument '--preset <PRESET>' cannot be used with '--level <LEVEL>'",
+        )),
+        _ => {
+            opts.level = None;
+            Ok(())
+        }
+    }
+}
+
+/// The compression of each of the paths given to `backup`, by the last `--compress` or
+/// `--no-compress` before it on the command line. Paths before all of them get the one of the
+/// whole command, the last of them, so that flags at the end apply to all paths like any
+/// other flag. `opts.compress` is set to that as well.
+fn path_compressions(
+    matches: &clap::ArgMatches,
+    opts: &mut BackupOptions,
+) -> Vec<Option<Compression>> {
+    let Some(matches) = matches.subcommand_matches("backup") else {
+        return Vec::new();
+    };
+    let from_cli = |id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine);
+    // (index on the command line, compression from there on)
+    let mut flags: Vec<(usize, Option<Compression>)> = Vec::new();
+    if from_cli("compress") {
+        let values = matches.get_many::<Compression>("compress").into_iter();
+        let indices = matches.indices_of("compress").into_iter().flatten();
+        flags.extend(indices.zip(values.flatten().map(|c| Some(*c))));
+    }
+    if from_cli("no_compress") {
+        let indices = matches.indices_of("no_compress").into_iter().flatten();
+        flags.extend(indices.map(|i| (i, None)));
+    }
+    flags.sort_by_key(|(i, _)| *i);
+    if let Some((_, compress)) = flags.last() {
+        opts.compress = *compress;
+    } else if opts.no_compress {
+        opts.compress = None;
+    }
+
+    let paths = matches.indices_of("paths").into_iter().flatten();
+    paths
+        .map(|path| match flags.iter().rev().find(|(i, _)| *i < path) {
+            Some((_, compress)) => *compress,
+            None => opts.compress,
+        })
+        .collect()
+}
+
+/// Replaces the directories among `paths` with their entries for `--split-entries`. Entries
+/// that are backups themselves, like those of an earlier split, and entries excluded by the
+/// patterns of the directory or `--no-hidden` are left out. Unreadable directories are kept, to fail on their own.
+fn split_entries(paths: Vec<PathBuf>, opts: &BackupOptions) -> io::Result<Vec<PathBuf>> {
+    let mut split = Vec::new();
+    for path in paths {
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) if path.is_dir() => entries,
+            _ => {
+                split.push(path);
+                continue;
+            }
+        };
+        let excludes = with_excludes(&path, opts)?.excludes;
+        let mut children = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = PathBuf::from(entry.file_name());
+            let hidden = name.as_os_str().as_bytes().starts_with(b".");
+            if is_backup(&name)
+                || (opts.no_hidden && hidden)
+                || (!(opts.hidden && hidden)
+                    && excludes.is_excluded(&name, entry.file_type()?.is_dir()))
+            {
+                continue;
+            }
+            children.push(entry.path());
+        }
+        children.sort();
+        split.extend(children);
+    }
+    Ok(split)
+}
+
+/// Sets the modification time of `path` to now, of the link itself for symlinks
+fn touch(path: &Path) -> io::Result<()> {
+    set_mtime(path, None)
+}
+
+/// Sets the modification time of `path` to `mtime` with nanoseconds, or to now for [`None`].
+/// Symlinks themselves are changed, and the access time is kept.
+fn set_mtime(path: &Path, mtime: Option<std::time::SystemTime>) -> io::Result<()> {
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mtime = match mtime {
+        Some(mtime) => {
+            let since = mtime
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            libc::timespec {
+                tv_sec: since.as_secs() as libc::time_t,
+                tv_nsec: since.subsec_nanos() as libc::c_long,
+            }
+        }
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+    };
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        mtime,
+    ];
+    // SAFETY: c_path is a valid, nul terminated string and times has the two entries
+    // utimensat reads, both outlive the call
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `path` is named like an archive, or looks like one like [`read_archive`] tells
+fn is_archive(path: &Path) -> bool {
+    Compression::detect(path).is_some()
+        || fs::File::open(path).is_ok_and(|file| Compression::sniff(&file).is_some())
+}
+
+/// Whether `path` is named like a backup or a manifest that loppler writes
+fn is_backup(path: &Path) -> bool {
+    Compression::detect(path).is_some()
+        || [".bak", ".bak.d", ".manifest"]
+            .iter()
+            .any(|ext| has_suffix(path, ext))
+        || path
+            .file_name()
+            .is_some_and(|name| name.as_bytes().windows(5).any(|w| w == b".tar."))
+}
+
+/// Removes the backup at `path` together with its manifest
+fn remove_backup(path: &Path) -> io::Result<()> {
+    recursive_remove(path)?;
+    let manifest = manifest::Manifest::path_for(path)?;
+    if manifest.exists() {
+        recursive_remove(&manifest)?;
+    }
+    Ok(())
+}
+
+/// Prints the result of [`compare::compare`] or [`compare::diff`] with a summary
+fn print_changes(changes: &[(compare::Change, PathBuf)]) {
+    for (change, name) in changes {
+        println!("{change}: {}", name.display());
+    }
+    let count = |c| changes.iter().filter(|(change, _)| *change == c).count();
+    println!(
+        "{} added, {} removed, {} modified",
+        count(compare::Change::Added),
+        count(compare::Change::Removed),
+        count(compare::Change::Modified)
+    );
+}
+
+fn confirm(prompt: String) -> io::Result<bool> {
+    print!("{prompt} - y/N ");
+    io::stdout().flush()?;
+    let mut buf = String::new();
+    loop {
+        io::stdin().read_line(&mut buf)?;
+        buf = buf.trim().to_lowercase();
+        match buf.as_str() {
+            "y" | "yes" => return Ok(true),
+            "" | "n" | "no" => return Ok(false),
+            _ => println!("That is neither yes or no"),
+        }
+    }
+}
+
+fn recursive_remove(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else if path.is_file() || path.is_symlink() {
+        fs::remove_file(path)?;
+    } else {
+        eprintln!(
+            "{}",
+            color::skipped(format_args!("skipping unknown file: {}", path.display())).stderr()
+        );
+    }
+    Ok(())
+}
+
+/// The options for a path with its own `--compress` or `--no-compress`
+fn path_options(
+    opts: &BackupOptions,
+    compress: Option<Compression>,
+) -> std::borrow::Cow<'_, BackupOptions> {
+    if compress == opts.compress {
+        std::borrow::Cow::Borrowed(opts)
+    } else {
+        std::borrow::Cow::Owned(BackupOptions {
+            compress,
+            ..opts.clone()
+        })
+    }
+}
+
+/// The metadata of a path given to back up, of the target of a symlink if it is followed
+fn root_metadata(path: &Path, opts: &BackupOptions) -> io::Result<fs::Metadata> {
+    let meta = path.symlink_metadata()?;
+    if meta.is_symlink() && (opts.follow_symlinks || opts.dereference_root) && !is_broken_link(path)
+    {
+        return path.metadata();
+    }
+    Ok(meta)
+}
+
+/// Where the backup of `path` is written, `is_dir` if it is backed up as a directory
+fn output_path(path: &Path, is_dir: bool, opts: &BackupOptions) -> io::Result<PathBuf> {
+    if opts.manifest_only {
+        manifest::Manifest::path_for(path)
+    } else if let Some(ext) = archive_extension(opts) {
+        add_extension(path, &ext)
+    } else if is_dir {
+        add_extension(path, ".bak.d")
+    } else {
+        add_extension(path, ".bak")
+    }
+}
+
+/// Finds the paths whose backups would overwrite another path of the same run, or the backup
+/// of an earlier one, by their index, and those that have no name to name a backup after.
+/// Those are not backed up and fail with the error.
+fn find_collisions(
+    paths: &[(PathBuf, Option<Compression>)],
+    opts: &BackupOptions,
+) -> HashMap<usize, io::Error> {
+    let inputs: HashMap<PathBuf, &Path> = paths
+        .iter()
+        .map(|(path, _)| (path_identity(path), path.as_path()))
+        .collect();
+    let mut outputs: HashMap<PathBuf, &Path> = HashMap::new();
+    let mut collisions = HashMap::new();
+    for (i, (path, compress)) in paths.iter().enumerate() {
+        let opts = path_options(opts, *compress);
+        let Ok(meta) = root_metadata(path, &opts) else {
+            continue;
+        };
+        let output = match output_path(path, meta.is_dir(), &opts) {
+            Ok(output) => output,
+            Err(e) => {
+                collisions.insert(i, e);
+                continue;
+            }
+        };
+        let identity = path_identity(&output);
+        let message = if let Some(input) = inputs.get(&identity) {
+            format!(
+                "its backup would overwrite {}, which is backed up as well",
+                input.display()
+            )
+        } else if let Some(earlier) = outputs.get(&identity) {
+            format!(
+                "its backup {} would overwrite the one of {}",
+                output.display(),
+                earlier.display()
+            )
+        } else {
+            outputs.insert(identity, path);
+            continue;
+        };
+        collisions.insert(i, io::Error::new(io::ErrorKind::AlreadyExists, message));
+    }
+    collisions
+}
+
+/// `path` with its directory resolved, to tell whether two paths are the same even if they
+/// do not exist yet
+fn path_identity(path: &Path) -> PathBuf {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Asks before replacing what is at `output` if it does not look like an earlier backup, a
+/// directory where a file is written or the other way around. Fails if it is kept.
+fn check_output(output: &Path, opts: &BackupOptions) -> io::Result<()> {
+    if let Some(reference) = &opts.reference {
+        if path_identity(output) == reference.backup {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "the backup would replace {}, which it is --since, move that away first",
+                    output.display()
+                ),
+            ));
+        }
+    }
+    let Ok(meta) = output.symlink_metadata() else {
+        return Ok(());
+    };
+    if meta.is_dir() == has_suffix(output, ".bak.d") {
+        return Ok(());
+    }
+    let what = if meta.is_dir() {
+        "a directory"
+    } else {
+        "a file"
+    };
+    let prompt = format!("{} is {what}, not a backup, replace it?", output.display());
+    if opts.yes || confirm(prompt)? {
+        return recursive_remove(output);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} is in the way of the backup", output.display()),
+    ))
+}
+
+/// `path` with `postfix` added to its file name. Paths like `.`, `..` and `/` have no name of
+/// their own, which fails.
+fn add_extension(path: &Path, postfix: &str) -> io::Result<PathBuf> {
+    let name = match path.components().next_back() {
+        Some(Component::Normal(name)) => name,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("has no name of its own to add {postfix} to, give it by its name"),
+            ))
+        }
+    };
+    let newname: OsString = [name, OsStr::new(postfix)].iter().copied().collect();
+    Ok(path.with_file_name(newname))
+}
+
+/// Whether the name of `path` ends with `suffix`, compared as bytes so that names which are
+/// not UTF-8 work as well
+fn has_suffix(path: &Path, suffix: &str) -> bool {
+    path.as_os_str().as_bytes().ends_with(suffix.as_bytes())
+}
+
+/// Whether the file name of `path` ends with `suffix` and has a name of its own before it, so
+/// that [`remove_extension`] leaves a file name
+fn has_named_suffix(path: &Path, suffix: &str) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.len() > suffix.len() && has_suffix(path, suffix))
+}
+
+fn remove_extension(path: &Path, suffix: &str) -> PathBuf {
+    let r = path.as_os_str().as_bytes();
+    match r.strip_suffix(format!(".{suffix}").as_bytes()) {
+        None => panic!("that path did not have that suffix"),
+        Some(short) => PathBuf::from(OsStr::from_bytes(short)),
+    }
+}
+
+fn restore(path: &Path, output_dir: &Path, opts: &RestoreOptions) -> io::Result<()> {
+    if !is_url(path) && path.symlink_metadata().is_err() {
+        let e = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("File or directory not found: {}", path.display()),
+        );
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    if !output_dir.exists() {
+        let e = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("File or directory not found: {}", output_dir.display()),
+        );
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    if !output_dir.is_dir() {
+        let e = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Output directory is not a directory: {}",
+                output_dir.display()
+            ),
+        );
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    // restored paths are compared against the tree below it with --mirror
+    let output_dir = &output_dir.canonicalize()?;
+    if opts.atomic {
+        return atomic::restore(path, output_dir, opts);
+    }
+    #[cfg(feature = "http")]
+    if let Some(url) = http::url(path) {
+        return http::restore(url, output_dir, opts);
+    }
+
+    let flattened;
+    let opts = if opts.flatten {
+        flattened = RestoreOptions {
+            prefix_map: vec![unpack::PrefixMap::new(
+                &top_level_dir(path, opts)?,
+                Path::new(""),
+            )],
+            ..opts.clone()
+        };
+        &flattened
+    } else {
+        opts
+    };
+
+    let manifest = manifest::Manifest::for_backup(path)?;
+    if let Some(base) = manifest.as_ref().and_then(|m| m.since.as_ref()) {
+        println!(
+            "{} only has the files that changed since {}, restore that first",
+            path.display(),
+            base.display()
+        );
+    }
+    let is_archive = opts.decompress_program.is_some() || Compression::detect(path).is_some();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    // copies end in `.bak`, like `notes.tar.gz.bak`
+    let unknown = name
+        .rsplit_once(".tar.")
+        .is_some_and(|(_, ext)| !ext.contains('.'));
+    if !is_archive && unknown && path.is_file() {
+        let e = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is compressed with a program that loppel does not know, read it with \
+                 --decompress-program or --compress-program-ext",
+                path.display()
+            ),
+        );
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    let conflicts = if opts.list_then_extract && is_archive {
+        let mut entries = Vec::new();
+        let check = |a: &mut tar::Archive<ArchiveReader>| {
+            entries = unpack::check_archive(a, opts, manifest.as_ref())?;
+            Ok(())
+        };
+        match &opts.decompress_program {
+            Some(program) => read_program_archive(path, program, check)?,
+            None => read_archive(path, opts.window.long, check)?,
+        }
+        println!("Checked {} entries of {}", entries.len(), path.display());
+        let mut conflicts = Vec::new();
+        for (name, kind) in entries {
+            let target = output_dir.join(name);
+            if is_conflict(&target, kind)? {
+                conflicts.push(target);
+            }
+        }
+        conflicts
+    } else {
+        restore_conflicts(path, output_dir, opts)?
+    };
+    if !conflicts.is_empty() && !opts.overwrite_older && !opts.confirm_each {
+        for conflict in &conflicts {
+            println!("would be overwritten: {}", conflict.display());
+        }
+        if !(opts.yes || confirm(format!("overwrite {} existing entries?", conflicts.len()))?) {
+            let e = io::Error::new(io::ErrorKind::Interrupted, "restore cancelled");
+            eprintln!("{}", color::error(&e));
+            return Err(e);
+        }
+    }
+
+    // everything that was restored, for the post-processing below
+    let mut written = Vec::new();
+    // entries of archives that failed with --ignore-errors
+    let mut failed = 0;
+    // copied files that --overwrite-older kept, which do not match the manifest
+    let mut kept = HashSet::new();
+    // archives are checked against the manifest while unpacking, copies afterwards
+    let unpack = |a: &mut tar::Archive<ArchiveReader>| {
+        (written, failed) = unpack::unpack_archive(a, output_dir, opts, manifest.as_ref())?;
+        Ok(())
+    };
+    let verified = if let Some(program) = &opts.decompress_program {
+        ignore_damage(read_program_archive(path, program, unpack), failed)?;
+        true
+    } else if Compression::detect(path).is_some() {
+        if !path.is_file() {
+            panic!("archive name but not an archive")
+        }
+
+        ignore_damage(read_archive(path, opts.window.long, unpack), failed)?;
+        true
+    } else if has_suffix(path, "bak") {
+        if !(path.is_file() || path.is_symlink()) {
+            panic!("bak name but not a file")
+        }
+
+        let target = remove_extension(path, "bak");
+        let target = output_dir.join(target.file_name().unwrap());
+        if opts.overwrite_older && is_newer(&target, path) {
+            println!(
+                "{}",
+                color::skipped(format_args!("keeping newer: {}", target.display()))
+            );
+            kept.insert(target.clone());
+        } else if keep_existing(&target, opts)? {
+            kept.insert(target.clone());
+        } else if path.is_symlink() {
+            copy_link(path, &target)?;
+        } else {
+            let started = trace(opts.verbose, "restoring", &target);
+            fs::copy(path, &target)?;
+            trace_done(started);
+        }
+        written.push(target);
+        false
+    } else if has_suffix(path, "bak.d") {
+        if path.is_file() {
+            panic!("bak.d name but not a directory")
+        }
+        let target = remove_extension(path, "bak.d");
+        let target = if opts.flatten {
+            output_dir.to_path_buf()
+        } else {
+            output_dir.join(target.file_name().unwrap())
+        };
+        // asks for every file before any is copied
+        let mut keep = HashSet::new();
+        if opts.overwrite_older || opts.confirm_each {
+            walk_dir(
+                path,
+                Path::new(""),
+                &BackupOptions::default(),
+                &mut BackupStats::default(),
+                &mut |backed_up, rel, ty, _| {
+                    let dst = target.join(rel);
+                    if ty.is_dir() {
+                        return Ok(true);
+                    }
+                    if opts.overwrite_older && is_newer(&dst, backed_up) {
+                        println!(
+                            "{}",
+                            color::skipped(format_args!("keeping newer: {}", dst.display()))
+                        );
+                        kept.insert(dst);
+                    } else if keep_existing(&dst, opts)? {
+                        keep.insert(dst.clone());
+                        kept.insert(dst);
+                    }
+                    Ok(false)
+                },
+            )?;
+        }
+        copy_dir_all(
+            path,
+            &[&target],
+            &BackupOptions {
+                keep_newer: opts.overwrite_older,
+                keep,
+                verbose: opts.verbose,
+                ..Default::default()
+            },
+            &mut BackupStats::default(),
+        )?;
+        // the backup tells what was restored, the target might have more in it
+        walk_dir(
+            path,
+            Path::new(""),
+            &BackupOptions::default(),
+            &mut BackupStats::default(),
+            &mut |_, rel, ty, _| {
+                written.push(target.join(rel));
+                Ok(ty.is_dir())
+            },
+        )?;
+        if !opts.flatten {
+            written.push(target);
+        }
+        false
+    } else {
+        panic!("unknown file {}", path.display())
+    };
+    finish_restore(
+        output_dir,
+        opts,
+        manifest.as_ref(),
+        verified,
+        written,
+        &kept,
+        failed,
+    )
+}
+
+/// Checks the `written` paths against the manifest unless unpacking `verified` them already,
+/// then cleans up and adjusts them for `opts`. Fails if any of the `failed` entries or
+/// mismatches, other than the `kept` files, were there.
+fn finish_restore(
+    output_dir: &Path,
+    opts: &RestoreOptions,
+    manifest: Option<&manifest::Manifest>,
+    verified: bool,
+    mut written: Vec<PathBuf>,
+    kept: &HashSet<PathBuf>,
+    failed: usize,
+) -> io::Result<()> {
+    if let Some(manifest) = manifest {
+        let mismatches = if verified {
+            Vec::new()
+        } else {
+            let mut mismatches = manifest.verify(output_dir, copy_maps(opts))?;
+            mismatches.retain(|name| !kept.contains(&output_dir.join(name)));
+            mismatches
+        };
+        for name in &mismatches {
+            eprintln!(
+                "{}",
+                color::error(format_args!(
+                    "does not match the manifest: {}",
+                    name.display()
+                ))
+            );
+        }
+        if !mismatches.is_empty() {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} restored files do not match the manifest",
+                    mismatches.len()
+                ),
+            );
+            eprintln!("{}", color::error(&e));
+            return Err(e);
+        }
+        if failed == 0 {
+            println!(
+                "Verified {} files with {}",
+                manifest.entries.len(),
+                manifest.algorithm.name()
+            );
+        }
+    }
+    // removing placeholders and extras changes the times of their directories again
+    let times = if opts.preserve_mtime_on_dirs {
+        dir_times(&written)?
+    } else {
+        Vec::new()
+    };
+    // the placeholders are part of the manifest, so they are only removed after verifying
+    remove_placeholders(&mut written, opts.placeholder_name.as_deref())?;
+    // after verifying, a restrictive mode could keep us from reading the files
+    if let Some(mode) = opts.chmod {
+        chmod_all(&written, mode)?;
+    }
+    if opts.mirror {
+        remove_extras(output_dir, &written, opts.yes)?;
+    }
+    set_dir_times(times)?;
+    if failed > 0 {
+        let e = io::Error::other(format!("{failed} entries could not be restored"));
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Whether `path` is a URL to download the archive from, which only builds with the `http`
+/// feature know
+fn is_url(path: &Path) -> bool {
+    let path = path.as_os_str().as_bytes();
+    cfg!(feature = "http") && (path.starts_with(b"http://") || path.starts_with(b"https://"))
+}
+
+/// Whether to keep what is at `target` instead of restoring over it, which `--confirm-each`
+/// asks for every existing one
+fn keep_existing(target: &Path, opts: &RestoreOptions) -> io::Result<bool> {
+    if !opts.confirm_each || opts.yes || target.symlink_metadata().is_err() {
+        return Ok(false);
+    }
+    let keep = !confirm(format!("overwrite {}?", target.display()))?;
+    if keep {
+        println!(
+            "{}",
+            color::skipped(format_args!("keeping: {}", target.display()))
+        );
+    }
+    Ok(keep)
+}
+
+/// Removes the empty placeholder files named `name` (or the default) from the restored
+/// paths, leaving the directories they kept
+fn remove_placeholders(written: &mut Vec<PathBuf>, name: Option<&str>) -> io::Result<()> {
+    let name = OsStr::new(name.unwrap_or(DEFAULT_PLACEHOLDER));
+    let mut result = Ok(());
+    written.retain(|path| {
+        let is_placeholder = path.file_name() == Some(name)
+            && path
+                .symlink_metadata()
+                .is_ok_and(|m| m.is_file() && m.len() == 0);
+        if is_placeholder && result.is_ok() {
+            result = fs::remove_file(path);
+        }
+        !is_placeholder
+    });
+    result
+}
+
+/// The name of the placeholder to put into the directory at `path`, if it gets one
+fn placeholder_for<'a>(path: &Path, opts: &'a BackupOptions) -> Option<&'a str> {
+    let empty = || fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none());
+    (opts.store_empty_as_placeholder && empty()).then(|| {
+        opts.placeholder_name
+            .as_deref()
+            .unwrap_or(DEFAULT_PLACEHOLDER)
+    })
+}
+
+/// Drops the error of reading an archive to its end if `--ignore-errors` went past `failed`
+/// entries already, which were damaged the same way
+fn ignore_damage(result: io::Result<()>, failed: usize) -> io::Result<()> {
+    match result {
+        Err(_) if failed > 0 => Ok(()),
+        result => result,
+    }
+}
+
+/// Lists the existing paths below `output_dir` that restoring the backup at `path` would
+/// replace. Directories that stay directories are merged into, not replaced.
+fn restore_conflicts(
+    path: &Path,
+    output_dir: &Path,
+    opts: &RestoreOptions,
+) -> io::Result<Vec<PathBuf>> {
+    let maps: &[unpack::PrefixMap] =
+        if opts.decompress_program.is_some() || Compression::detect(path).is_some() {
+            &opts.prefix_map
+        } else {
+            copy_maps(opts)
+        };
+    let mut conflicts = Vec::new();
+    let visit = |name: PathBuf, kind, _, _: &mut dyn Read| {
+        let target = output_dir.join(unpack::map_name(&name, maps));
+        if target != output_dir && is_conflict(&target, kind)? {
+            conflicts.push(target);
+        }
+        Ok(())
+    };
+    let result = match &opts.decompress_program {
+        Some(program) => {
+            read_program_archive(path, program, |a| compare::archive_entries(a, visit))
+        }
+        None => compare::backup_entries(path, opts.window.long, visit),
+    };
+    match result {
+        // unpacking reports the damage, the conflicts before it are known
+        Err(_) if opts.ignore_errors => (),
+        result => result?,
+    }
+    Ok(conflicts)
+}
+
+/// Whether restoring an entry of `kind` to `target` overwrites something, existing directories
+/// are merged with instead
+fn is_conflict(target: &Path, kind: compare::Kind) -> io::Result<bool> {
+    match target.symlink_metadata() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+        Ok(meta) => Ok(!(kind == compare::Kind::Dir && meta.is_dir())),
+    }
+}
+
+/// The prefix maps that apply to copies, only the one of `--flatten`, as `--prefix-map` is
+/// for archives
+fn copy_maps(opts: &RestoreOptions) -> &[unpack::PrefixMap] {
+    if opts.flatten {
+        &opts.prefix_map
+    } else {
+        &[]
+    }
+}
+
+/// The directory that all entries of the backup at `path` are in, for `--flatten`. Fails if
+/// there is none, like for backups of single files.
+fn top_level_dir(path: &Path, opts: &RestoreOptions) -> io::Result<PathBuf> {
+    let mut common: Option<PathBuf> = None;
+    let mut tops = BTreeSet::new();
+    let visit = |name: PathBuf, kind, _, _: &mut dyn Read| {
+        if let Some(top) = name.components().next() {
+            tops.insert(PathBuf::from(top.as_os_str()));
+        }
+        let dir = match kind {
+            compare::Kind::Dir => name.as_path(),
+            _ => name.parent().unwrap_or(Path::new("")),
+        };
+        common = Some(match common.take() {
+            None => dir.to_path_buf(),
+            Some(common) => common
+                .components()
+                .zip(dir.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+        Ok(())
+    };
+    match &opts.decompress_program {
+        Some(program) => {
+            read_program_archive(path, program, |a| compare::archive_entries(a, visit))?
+        }
+        None => compare::backup_entries(path, opts.window.long, visit)?,
+    }
+    match common {
+        Some(common) if !common.as_os_str().is_empty() => Ok(common),
+        _ => {
+            let tops: Vec<String> = tops.iter().map(|t| t.display().to_string()).collect();
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "can not flatten, the backup has no single top level directory: {}",
+                    tops.join(", ")
+                ),
+            ))
+        }
+    }
+}
+
+/// Removes everything in the restored trees below `output_dir` that was not restored, after
+/// asking unless `yes` is set
+fn remove_extras(output_dir: &Path, written: &[PathBuf], yes: bool) -> io::Result<()> {
+    let written: HashSet<&Path> = written.iter().map(PathBuf::as_path).collect();
+    let roots: BTreeSet<PathBuf> = written
+        .iter()
+        .filter_map(|path| path.strip_prefix(output_dir).ok()?.components().next())
+        .map(|root| output_dir.join(root))
+        .collect();
+
+    let mut extras = Vec::new();
+    for root in roots
+        .iter()
+        .filter(|root| root.is_dir() && !root.is_symlink())
+    {
+        walk_dir(
+            root,
+            Path::new(""),
+            &BackupOptions::default(),
+            &mut BackupStats::default(),
+            &mut |path, _, ty, _| {
+                if written.contains(path) {
+                    Ok(ty.is_dir())
+                } else {
+                    extras.push(path.to_path_buf());
+                    Ok(false)
+                }
+            },
+        )?;
+    }
+    if extras.is_empty() {
+        return Ok(());
+    }
+
+    for extra in &extras {
+        println!("not in the backup: {}", extra.display());
+    }
+    if yes
+        || confirm(format!(
+            "delete {} entries not in the backup?",
+            extras.len()
+        ))?
+    {
+        for extra in &extras {
+            recursive_remove(extra)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the permissions of all `paths` to `mode`, contents of directories before the
+/// directories themselves so that a restrictive mode does not lock us out. Symlinks are left
+/// alone, their permissions are meaningless.
+fn chmod_all(paths: &[PathBuf], mode: u32) -> io::Result<()> {
+    let mut paths = paths.to_vec();
+    paths.sort();
+    for path in paths.iter().rev() {
+        if !path.symlink_metadata()?.is_symlink() {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses an octal permission mode like `644` or `0o2775`
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("not an octal permission mode: {s}")),
+    }
+}
+
+fn backup_file(path: &Path, opts: &BackupOptions, stats: &mut BackupStats) -> io::Result<PathBuf> {
+    let src = source_path(path, opts)?;
+    check_free_space(destination(path), opts)?;
+    if let Some(ext) = archive_extension(opts) {
+        let archive_path = add_extension(path, &ext)?;
+        let origin = absolute_path(path)?;
+        let result = make_archive(&archive_path, Some(&origin), opts, |a| {
+            append_entry(a, &src, &archive_name(path, opts)?, opts, stats)
+        });
+        remove_partial(&archive_path, result, opts)?;
+        stats.bytes_out += fs::metadata(&archive_path)?.len();
+        write_manifest(&archive_path, path, opts)?;
+        sync_backup(&archive_path, opts)?;
+        Ok(archive_path)
+    } else {
+        let backup_path = add_extension(path, ".bak")?;
+        let mut targets = destinations(&backup_path, opts);
+        if src.is_symlink() && keep_link(&src, opts) {
+            for target in &targets {
+                copy_link(&src, target)?;
+            }
+        } else {
+            if opts.keep_newer {
+                targets.retain(|target| {
+                    let newer = is_newer(target, &src);
+                    if newer && opts.verbose >= VERBOSE_PATHS {
+                        println!(
+                            "{}",
+                            color::skipped(format_args!("{} is up to date", target.display()))
+                        );
+                    }
+                    !newer
+                });
+            }
+            if targets.is_empty() {
+                write_manifest(&backup_path, path, opts)?;
+                sync_backup(&backup_path, opts)?;
+                return Ok(backup_path);
+            }
+            let started = trace(opts.verbose, "copying", path);
+            let copied = with_retries(path, opts, || copy_file(&src, &targets, opts))?;
+            trace_done(started);
+            stats.bytes_out += copied;
+            stats.file_done(path, copied)?;
+        }
+        write_manifest(&backup_path, path, opts)?;
+        sync_backup(&backup_path, opts)?;
+        Ok(backup_path)
+    }
+}
+
+fn backup_dir(path: &Path, opts: &BackupOptions, stats: &mut BackupStats) -> io::Result<PathBuf> {
+    let src = source_path(path, opts)?;
+    if opts.no_mount_root && is_mount_point(&src)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "is a mount point, which --no-mount-root refuses to back up",
+        ));
+    }
+    check_free_space(destination(path), opts)?;
+    let opts = &BackupOptions {
+        free_space_dir: Some(destination(path).to_path_buf()),
+        ..with_excludes(&src, opts)?
+    };
+    if let Some(ext) = archive_extension(opts) {
+        let archive_path = add_extension(path, &ext)?;
+        let (files, bytes_in) = (stats.files, stats.bytes_in);
+        let origin = absolute_path(path)?;
+        let result = make_archive(&archive_path, Some(&origin), opts, |a| {
+            archive_dir_all(a, &archive_name(path, opts)?, &src, opts, stats)
+        });
+        remove_partial(&archive_path, result, opts)?;
+        let size = fs::metadata(&archive_path)?.len();
+        stats.bytes_out += size;
+        let content = (stats.files - files, stats.bytes_in - bytes_in);
+        if let Some(warning) = overhead_warning(size, content, opts) {
+            eprintln!("{}: {warning}", archive_path.display());
+        }
+        write_manifest(&archive_path, path, opts)?;
+        sync_backup(&archive_path, opts)?;
+        Ok(archive_path)
+    } else {
+        let backup_path = add_extension(path, ".bak.d")?;
+        let result = copy_dir_all(&src, &destinations(&backup_path, opts), opts, stats);
+        remove_partial(&backup_path, result, opts)?;
+        write_manifest(&backup_path, path, opts)?;
+        sync_backup(&backup_path, opts)?;
+        Ok(backup_path)
+    }
+}
+
+/// Whether the directory `dir` is the root of a mounted file system, because its parent is on
+/// another device or, like for `/`, is the directory itself. Bind mounts of directories on the
+/// same file system are not noticed.
+fn is_mount_point(dir: &Path) -> io::Result<bool> {
+    let meta = fs::metadata(dir)?;
+    let parent = fs::metadata(dir.join(".."))?;
+    Ok(meta.dev() != parent.dev() || meta.ino() == parent.ino())
+}
+
+/// The options for backing up the directory `src`, with the exclude patterns of its
+/// `.lopplerignore`, `--exclude-from` and `--exclude`, in this order so the later ones win
+fn with_excludes(src: &Path, opts: &BackupOptions) -> io::Result<BackupOptions> {
+    let mut excludes = exclude::Excludes::default();
+    excludes.add_file(&src.join(exclude::IGNORE_FILE), true)?;
+    for file in &opts.exclude_from {
+        excludes
+            .add_file(file, false)
+            .map_err(|e| with_path(file, e))?;
+    }
+    for pattern in &opts.exclude {
+        excludes.add(pattern);
+    }
+    Ok(BackupOptions {
+        excludes,
+        ..opts.clone()
+    })
+}
+
+/// Where the backup at `backup` is written to: there, and into each of the `--also-to`
+/// directories under the same name
+fn destinations(backup: &Path, opts: &BackupOptions) -> Vec<PathBuf> {
+    let name = backup.file_name().unwrap_or_default();
+    let mut destinations = vec![backup.to_path_buf()];
+    for dir in &opts.also_to {
+        let copy = dir.join(name);
+        // writing the same file twice at once would garble it
+        if !destinations
+            .iter()
+            .any(|known| path_identity(known) == path_identity(&copy))
+        {
+            destinations.push(copy);
+        }
+    }
+    destinations
+}
+
+/// Removes the partial backup at `backup`, and its copies for `--also-to`, if it was aborted
+/// by `--time-limit`, or for lack of space
+fn remove_partial(backup: &Path, result: io::Result<()>, opts: &BackupOptions) -> io::Result<()> {
+    let aborted = |e: &io::Error| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::StorageFull
+        )
+    };
+    if result.as_ref().is_err_and(aborted) {
+        for backup in destinations(backup, opts) {
+            if backup.symlink_metadata().is_ok() {
+                eprintln!("removing partial backup: {}", backup.display());
+                recursive_remove(&backup)?;
+            }
+        }
+    }
+    result
+}
+
+/// Tells how much of an uncompressed archive of `size` bytes its tar headers and padding take
+/// up, if its `(files, bytes)` are below the `--small-file-threshold` on average
+fn overhead_warning(size: u64, (files, bytes): (u64, u64), opts: &BackupOptions) -> Option<String> {
+    let compressed = opts.compress_program.is_some() || opts.compress != Some(Compression::None);
+    if compressed || files == 0 || bytes / files >= opts.small_file_threshold as u64 {
+        return None;
+    }
+    let overhead = size.saturating_sub(bytes);
+    Some(format!(
+        "its {files} files are {} on average, tar headers and padding make up {} or {}% of \
+         it. Compress it with -z to shrink them.",
+        format_size(bytes / files, opts.bytes),
+        format_size(overhead, opts.bytes),
+        overhead * 100 / size.max(1)
+    ))
+}
+
+/// Window, chain and hash logs of the zstd levels 1 to 22 for inputs above 256 KiB, from the
+/// tables of the zstd library
+const ZSTD_LEVEL_LOGS: [(u32, u32, u32); 22] = [
+    (19, 13, 14),
+    (20, 15, 16),
+    (21, 16, 17),
+    (21, 18, 18),
+    (21, 18, 19),
+    (21, 18, 19),
+    (21, 19, 20),
+    (21, 19, 20),
+    (22, 20, 21),
+    (22, 21, 22),
+    (22, 21, 22),
+    (22, 22, 23),
+    (22, 22, 22),
+    (22, 22, 23),
+    (22, 23, 23),
+    (22, 22, 22),
+    (23, 23, 22),
+    (23, 23, 22),
+    (23, 24, 22),
+    (25, 25, 23),
+    (26, 26, 24),
+    (27, 27, 25),
+];
+
+/// Roughly how much memory zstd takes to compress at `level` with `threads`, or the window
+/// log of `--long`. Every thread has tables of its own and buffers for jobs of four windows.
+fn zstd_memory(level: i32, window_log: Option<u32>, threads: u32) -> u64 {
+    let (log, chain, hash) = ZSTD_LEVEL_LOGS[level.clamp(1, 22) as usize - 1];
+    let window = 1u64 << window_log.unwrap_or(log);
+    let tables = 4 * ((1u64 << chain) + (1u64 << hash));
+    if threads <= 1 {
+        return window + tables;
+    }
+    let job = (4 * window).max(1 << 20);
+    threads as u64 * (tables + 2 * job + window)
+}
+
+/// Warns that compressing takes the memory that is `needed`, if less is `available`
+fn memory_warning(needed: u64, available: Option<u64>, opts: &BackupOptions) -> Option<String> {
+    let available = available.filter(|&available| needed > available)?;
+    Some(format!(
+        "compressing needs about {} of memory, but only {} are available. Lower --threads or \
+         --level if the backup runs out of memory.",
+        format_size(needed, opts.bytes),
+        format_size(available, opts.bytes)
+    ))
+}
+
+/// The memory that can be used without swapping, from `/proc/meminfo`
+fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Whether the backups so far have written as much as `--total-size-limit` allows
+fn size_limit_reached(opts: &BackupOptions, stats: &BackupStats) -> bool {
+    opts.total_size_limit
+        .is_some_and(|limit| stats.bytes_out >= limit as u64)
+}
+
+/// Fails with [`io::ErrorKind::TimedOut`] once `--time-limit` is exceeded
+fn check_deadline(opts: &BackupOptions) -> io::Result<()> {
+    match opts.deadline {
+        Some(deadline) if std::time::Instant::now() >= deadline => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the time limit is exceeded",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The directory that the backup of `path` is written to, next to it
+fn destination(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Fails with [`io::ErrorKind::StorageFull`] if less than `--min-free-space` is free in `dir`
+fn check_free_space(dir: &Path, opts: &BackupOptions) -> io::Result<()> {
+    let Some(min) = opts.min_free_space else {
+        return Ok(());
+    };
+    let free = free_space(dir).map_err(|e| with_path(dir, e))?;
+    if free < min as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "only {} are free in {}, less than --min-free-space",
+                format_size(free, opts.bytes),
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The bytes that are free for unprivileged users on the file system of `dir`
+fn free_space(dir: &Path) -> io::Result<u64> {
+    let c_path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and `stat` has room for the result
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs succeeded, so it filled `stat`
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+/// Walks `path` like a backup of it for `--list-excluded`, which prints what is skipped
+fn list_excluded(path: &Path, opts: &BackupOptions) -> io::Result<()> {
+    let src = source_path(path, opts)?;
+    if !src.is_dir() {
+        return Ok(());
+    }
+    let opts = with_excludes(&src, opts)?;
+    walk_dir(
+        &src,
+        Path::new(""),
+        &opts,
+        &mut BackupStats::default(),
+        &mut |path, _, ty, _| {
+            Ok(ty.is_dir() || (opts.follow_symlinks && ty.is_symlink() && path.is_dir()))
+        },
+    )
+}
+
+/// Estimates how many bytes a backup of `path` reads, for reporting progress. Unreadable
+/// parts are not counted. Symlinks count with the size of their target when they are followed
+/// and as nothing otherwise, the same as the backup reads them.
+fn estimate_size(path: &Path, opts: &BackupOptions) -> u64 {
+    let Ok(src) = source_path(path, opts) else {
+        return 0;
+    };
+    let size_of = |path: &Path| {
+        let meta = if opts.follow_symlinks {
+            fs::metadata(path)
+        } else {
+            fs::symlink_metadata(path)
+        };
+        meta.map_or(0, |m| if m.is_file() { m.len() } else { 0 })
+    };
+    if !src.is_dir() || (src.is_symlink() && !opts.follow_symlinks) {
+        return size_of(&src);
+    }
+
+    // the walk logs and counts skipped entries, which the backup itself does again
+    let Ok(opts) = with_excludes(&src, opts) else {
+        return 0;
+    };
+    let quiet = BackupOptions {
+        verbose: 0,
+        list_excluded: false,
+        skip_unreadable: false,
+        ..opts.clone()
+    };
+    let mut total = 0;
+    let _ = walk_dir(
+        &src,
+        Path::new(""),
+        &quiet,
+        &mut BackupStats::default(),
+        &mut |path, _, ty, _| {
+            total += size_of(path);
+            Ok(ty.is_dir() || (opts.follow_symlinks && ty.is_symlink() && path.is_dir()))
+        },
+    );
+    total
+}
+
+/// Where the contents of the backup of `path` are read from: the target of `path` with
+/// `--dereference-root`, `path` itself otherwise. The backup is named after `path` either way.
+fn source_path(path: &Path, opts: &BackupOptions) -> io::Result<PathBuf> {
+    if opts.dereference_root && !is_broken_link(path) {
+        fs::canonicalize(path)
+    } else {
+        Ok(path.to_path_buf())
+    }
+}
+
+/// Whether `path` is a symlink to something that does not exist
+fn is_broken_link(path: &Path) -> bool {
+    path.is_symlink() && !path.exists()
+}
+
+/// Whether the symlink at `path` is backed up as a link: always without `--follow-symlinks`,
+/// and if it is broken, which is reported
+fn keep_link(path: &Path, opts: &BackupOptions) -> bool {
+    if !opts.follow_symlinks {
+        return true;
+    }
+    let broken = is_broken_link(path);
+    if broken {
+        eprintln!(
+            "{}",
+            color::skipped(format_args!(
+                "broken symlink, backing up the link itself: {}",
+                path.display()
+            ))
+            .stderr()
+        );
+    }
+    broken
+}
+
+/// Copies the symlink at `src` to `dst` as a symlink with the same target
+fn copy_link(src: &Path, dst: &Path) -> io::Result<()> {
+    let target = fs::read_link(src)?;
+    if dst.symlink_metadata().is_ok() {
+        fs::remove_file(dst)?;
+    }
+    std::os::unix::fs::symlink(target, dst)
+}
+
+/// Writes the checksum manifest of the finished backup at `backup` if `--hash` is given. The
+/// backup is read back for this, so the manifest describes what was actually written.
+fn write_manifest(backup: &Path, path: &Path, opts: &BackupOptions) -> io::Result<()> {
+    // tags are stored in the manifest
+    let algorithm = match opts.hash {
+        Some(algorithm) => algorithm,
+        None if !opts.tags.is_empty() || opts.since.is_some() => hash::HashAlgorithm::default(),
+        None => return Ok(()),
+    };
+    let mut manifest = manifest::Manifest::create(backup, algorithm, opts.long)?;
+    manifest.tags = opts.tags.clone();
+    manifest.origin = Some(origin(path, opts)?);
+    manifest.since = opts.since.as_deref().map(absolute_path).transpose()?;
+    let manifest_path = manifest::Manifest::path_for(backup)?;
+    manifest.write(io::BufWriter::new(fs::File::create(&manifest_path)?))?;
+    // the copies are the same, and so is their manifest
+    for copy in destinations(backup, opts).iter().skip(1) {
+        fs::copy(&manifest_path, manifest::Manifest::path_for(copy)?)?;
+    }
+    Ok(())
+}
+
+/// Where the backed up `path` is, for `restore --use-manifest-paths`. Archives name it by
+/// [`archive_name`], copies by its file name.
+fn origin(path: &Path, opts: &BackupOptions) -> io::Result<manifest::Origin> {
+    let name = match archive_extension(opts) {
+        Some(_) => unpack::normalize_path(&archive_name(path, opts)?),
+        None => PathBuf::from(path.file_name().unwrap_or_default()),
+    };
+    Ok(manifest::Origin {
+        name,
+        path: absolute_path(path)?,
+    })
+}
+
+/// `path` from the root, without resolving a symlink at its end
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    let path = path_identity(path);
+    match path.is_absolute() {
+        true => Ok(path),
+        // like `.` and `..`, which have no name of their own
+        false => path.canonicalize(),
+    }
+}
+
+/// Writes a manifest of the files at `path` to `<path>.manifest` instead of backing them up,
+/// for `--manifest-only`. They are named like in archives, by [`entry_name`].
+fn manifest_only(
+    path: &Path,
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+) -> io::Result<PathBuf> {
+    let algorithm = opts.hash.unwrap_or_default();
+    let src = source_path(path, opts)?;
+    // before hashing everything below a path that has nowhere to put it
+    let manifest_path = manifest::Manifest::path_for(path)?;
+    let mut entries = Vec::new();
+    let mut add = |file: &Path, name: &Path, stats: &mut BackupStats| -> io::Result<()> {
+        let started = trace(opts.verbose, "hashing", file);
+        let size = fs::metadata(file)?.len();
+        let hash = hash::to_hex(&hash::hash_reader(algorithm, fs::File::open(file)?)?);
+        trace_done(started);
+        stats.file_done(file, size)?;
+        entries.push(manifest::ManifestEntry {
+            name: name.to_path_buf(),
+            size,
+            hash,
+        });
+        Ok(())
+    };
+
+    let name = entry_name(path)?;
+    if src.is_dir() {
+        let opts = &with_excludes(&src, opts)?;
+        walk_dir(&src, &name, opts, stats, &mut |file, rel, ty, stats| {
+            // manifests only list files, broken links are left out
+            let is_dir = if opts.follow_symlinks && ty.is_symlink() && !is_broken_link(file) {
+                let meta = fs::metadata(file)?;
+                if meta.is_file() {
+                    add(file, rel, stats)?;
+                }
+                meta.is_dir()
+            } else {
+                if ty.is_file() {
+                    add(file, rel, stats)?;
+                }
+                ty.is_dir()
+            };
+            Ok(is_dir)
+        })?;
+    } else {
+        add(&src, &name, stats)?;
+    }
+
+    let manifest = manifest::Manifest {
+        algorithm,
+        tags: opts.tags.clone(),
+        origin: Some(manifest::Origin {
+            name,
+            path: absolute_path(path)?,
+        }),
+        since: None,
+        entries,
+    };
+    manifest.write(io::BufWriter::new(fs::File::create(&manifest_path)?))?;
+    if opts.fsync {
+        sync_tree(&manifest_path)?;
+    }
+    Ok(manifest_path)
+}
+
+/// Flushes the backup at `backup` and its copies for `--also-to`, everything in them if they
+/// are copies of a directory, their manifests and the directories they are in to disk, if
+/// `--fsync` is given
+fn sync_backup(backup: &Path, opts: &BackupOptions) -> io::Result<()> {
+    if !opts.fsync {
+        return Ok(());
+    }
+    for backup in destinations(backup, opts) {
+        sync_tree(&backup)?;
+        let manifest = manifest::Manifest::path_for(&backup)?;
+        if manifest.exists() {
+            sync_tree(&manifest)?;
+        }
+        // the directory entry of the backup has to reach the disk as well
+        fs::File::open(destination(&backup))?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Flushes the file or directory tree at `path` to disk, symlinks are part of their directory
+fn sync_tree(path: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sync_tree(&entry?.path())?;
+        }
+    } else if !meta.is_file() {
+        return Ok(());
+    }
+    fs::File::open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(|e| with_path(path, e))
+}
+
+/// Walks the tree below `src` and calls `visit` with the path of every entry, its path relative
+/// to `src`, its file type and the stats. Directories (and whatever else `visit` wants to) are descended
+/// into when `visit` returns `true`.
+fn walk_dir<F>(
+    src: &Path,
+    rel: &Path,
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+    visit: &mut F,
+) -> io::Result<()>
+where
+    F: FnMut(&Path, &Path, &fs::FileType, &mut BackupStats) -> io::Result<bool>,
+{
+    let mut ancestors = Vec::new();
+    // links to the directories above `src` lead back into it as well
+    if opts.follow_symlinks {
+        if let Ok(real) = src.canonicalize() {
+            for dir in real.ancestors().skip(1) {
+                if let Ok(meta) = fs::metadata(dir) {
+                    ancestors.push((meta.dev(), meta.ino()));
+                }
+            }
+        }
+    }
+    walk_dir_below(src, rel, opts, stats, visit, &mut ancestors)
+}
+
+/// [`walk_dir`] below the directories `ancestors`, as device and inode numbers. With
+/// `--follow-symlinks`, symlinks to them are skipped instead of walking in circles.
+fn walk_dir_below<F>(
+    src: &Path,
+    rel: &Path,
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+    visit: &mut F,
+    ancestors: &mut Vec<(u64, u64)>,
+) -> io::Result<()>
+where
+    F: FnMut(&Path, &Path, &fs::FileType, &mut BackupStats) -> io::Result<bool>,
+{
+    let read_dir = |src: &Path| fs::read_dir(src).and_then(|d| d.collect::<io::Result<Vec<_>>>());
+    let mut entries = match watchdog::run(src, opts.keep_going_timeout, read_dir) {
+        Ok(entries) => entries,
+        Err(e) => return skip_unreadable(src, e, opts, stats),
+    };
+    if opts.follow_symlinks {
+        let meta = fs::metadata(src)?;
+        ancestors.push((meta.dev(), meta.ino()));
+    }
+    // the order of read_dir depends on the file system, keep the backups deterministic
+    entries.sort_by_key(|entry| entry.file_name());
+    let dev = if opts.one_file_system {
+        Some(fs::metadata(src)?.dev())
+    } else {
+        None
+    };
+    for entry in entries {
+        check_deadline(opts)?;
+        if let Some(dir) = &opts.free_space_dir {
+            check_free_space(dir, opts)?;
+        }
+        let ty = entry.file_type()?;
+        let path = entry.path();
+        let rel = rel.join(entry.file_name());
+
+        if dev.is_some_and(|dev| entry.metadata().is_ok_and(|m| m.dev() != dev)) {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, on another file system: {}",
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+        if opts.follow_symlinks
+            && ty.is_symlink()
+            && fs::metadata(&path).is_ok_and(|m| ancestors.contains(&(m.dev(), m.ino())))
+        {
+            eprintln!(
+                "{}",
+                color::skipped(format_args!(
+                    "skipping, symlink loop back to a parent directory: {}",
+                    path.display()
+                ))
+                .stderr()
+            );
+            continue;
+        }
+        if opts.preserve_links_as_copies && ty.is_symlink() && is_broken_link(&path) {
+            eprintln!(
+                "{}",
+                color::skipped(format_args!(
+                    "skipping, broken symlink with nothing to copy: {}",
+                    path.display()
+                ))
+                .stderr()
+            );
+            continue;
+        }
+
+        if opts.exclude_caches && ty.is_dir() && is_cache_dir(&path) {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, cache directory: {}",
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+        let marker = match ty.is_dir() {
+            true => marker_in(&path, opts),
+            false => None,
+        };
+        if let Some(marker) = marker {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, contains {}: {}",
+                        marker.to_string_lossy(),
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+        if opts.exclude_vcs && VCS_NAMES.iter().any(|name| entry.file_name() == *name) {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, version control: {}",
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+        let hidden = entry.file_name().as_bytes().starts_with(b".");
+        if opts.no_hidden && hidden {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!("skipping, hidden: {}", path.display()))
+                );
+            }
+            continue;
+        }
+        let pattern = match opts.hidden && hidden {
+            true => None,
+            false => opts.excludes.excluded_by(&rel, ty.is_dir()),
+        };
+        if let Some(pattern) = pattern {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, excluded by '{pattern}': {}",
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+        if opts.only_dirs && !ty.is_dir() {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, not a directory: {}",
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+        if opts.filter.as_ref().is_some_and(|filter| {
+            let is_dir = ty.is_dir() || (opts.follow_symlinks && ty.is_symlink() && path.is_dir());
+            let meta = match opts.follow_symlinks {
+                true => fs::metadata(&path).or_else(|_| entry.metadata()),
+                false => entry.metadata(),
+            };
+            // what can not be read fails when it is backed up
+            !is_dir && meta.is_ok_and(|meta| !filter.matches(&rel, &meta))
+        }) {
+            if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, not matched by --filter: {}",
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+        if opts.only_files && !ty.is_file() {
+            if ty.is_dir() && !opts.no_recursion {
+                walk_dir_below(&path, &rel, opts, stats, visit, ancestors)?;
+            } else if opts.verbose >= VERBOSE_FILES || opts.list_excluded {
+                println!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "skipping, not a regular file: {}",
+                        path.display()
+                    ))
+                );
+            }
+            continue;
+        }
+
+        match visit(&path, &rel, &ty, stats) {
+            Ok(true) if !opts.no_recursion => {
+                walk_dir_below(&path, &rel, opts, stats, visit, ancestors)?
+            }
+            Ok(_) => (),
+            Err(e) => skip_unreadable(&path, e, opts, stats)?,
+        }
+    }
+    if opts.follow_symlinks {
+        ancestors.pop();
+    }
+    Ok(())
+}
+
+/// The first name of `--exclude-if-present` that is in `dir`
+fn marker_in<'a>(dir: &Path, opts: &'a BackupOptions) -> Option<&'a OsStr> {
+    opts.exclude_if_present
+        .iter()
+        .find(|name| dir.join(name).symlink_metadata().is_ok())
+        .map(OsString::as_os_str)
+}
+
+/// Checks if `dir` contains a valid `CACHEDIR.TAG`
+fn is_cache_dir(dir: &Path) -> bool {
+    let mut buf = [0; CACHEDIR_SIGNATURE.len()];
+    fs::File::open(dir.join("CACHEDIR.TAG"))
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .is_ok_and(|()| buf == CACHEDIR_SIGNATURE)
+}
+
+/// Collects permission errors if `--skip-unreadable` is set, and files that did not respond
+/// within `--keep-going-timeout` unless it aborts. Other errors are returned with the path they
+/// happened at.
+fn skip_unreadable(
+    path: &Path,
+    e: io::Error,
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+) -> io::Result<()> {
+    if watchdog::is_stalled(&e) && opts.on_timeout == watchdog::OnTimeout::Skip {
+        eprintln!("{}", color::skipped(format_args!("skipping, {e}")).stderr());
+        stats.errors.push((path.to_path_buf(), e));
+        Ok(())
+    } else if watchdog::is_stalled(&e) {
+        Err(e)
+    } else if opts.skip_unreadable && e.kind() == io::ErrorKind::PermissionDenied {
+        if opts.verbose >= VERBOSE_FILES {
+            println!(
+                "{}",
+                color::skipped(format_args!("skipping unreadable: {}", path.display()))
+            );
+        }
+        stats.errors.push((path.to_path_buf(), e));
+        Ok(())
+    } else {
+        Err(with_path(path, e))
+    }
+}
+
+/// Adds `path` to the message of `e`, keeping its kind
+fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {e}", path.display()))
+}
+
+/// Prints a list of paths and what went wrong with them
+fn report_errors(errors: &[(PathBuf, io::Error)]) {
+    eprintln!("{}", color::error(format_args!("{} errors:", errors.len())));
+    for (path, e) in errors {
+        eprintln!(
+            "  {}",
+            color::error(format_args!("{}: {e}", path.display()))
+        );
+    }
+}
+
+/// Copies the file at `src` to `dsts` like [`fs::copy`], through buffers of `--buffer-size` if
+/// it is given. With more than one of them, `src` is read once for all.
+fn copy_file<P: AsRef<Path>>(src: &Path, dsts: &[P], opts: &BackupOptions) -> io::Result<u64> {
+    for dst in dsts {
+        // a symlink left over from an earlier backup would be written through
+        if dst.as_ref().is_symlink() {
+            fs::remove_file(dst)?;
+        }
+    }
+    let timeout = opts.keep_going_timeout;
+    if let [dst] = dsts {
+        let dst = dst.as_ref();
+        let target = dst.to_path_buf();
+        if opts.reflink && watchdog::run(src, timeout, move |src| reflink::copy(src, &target))? {
+            return Ok(fs::metadata(dst)?.len());
+        }
+        if opts.buffer_size.is_none() && timeout.is_none() {
+            return fs::copy(src, dst);
+        }
+    }
+    let (input, permissions) = watchdog::run(src, timeout, |src| {
+        let input = fs::File::open(src)?;
+        let permissions = input.metadata()?.permissions();
+        Ok((input, permissions))
+    })?;
+    let input: Box<dyn Read> = match timeout {
+        Some(timeout) => Box::new(watchdog::Reader::new(src, input, timeout)?),
+        None => Box::new(input),
+    };
+    let (mut reader, mut writer) = match opts.buffer_size {
+        Some(size) => (
+            io::BufReader::with_capacity(size, input),
+            io::BufWriter::with_capacity(size, tee::Tee::create(dsts)?),
+        ),
+        None => (
+            io::BufReader::new(input),
+            io::BufWriter::new(tee::Tee::create(dsts)?),
+        ),
+    };
+    let copied = io::copy(&mut reader, &mut writer).inspect_err(|e| {
+        // not a copy of anything
+        if watchdog::is_stalled(e) {
+            for dst in dsts {
+                let _ = fs::remove_file(dst);
+            }
+        }
+    })?;
+    writer.into_inner().map_err(|e| e.into_error())?;
+    for dst in dsts {
+        fs::set_permissions(dst, permissions.clone())?;
+    }
+    Ok(copied)
+}
+
+/// Whether `copy` exists and was modified after `src`, for `--keep-newer`
+fn is_newer(copy: &Path, src: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified());
+    match (modified(copy), modified(src)) {
+        (Ok(copy), Ok(src)) => copy >= src,
+        _ => false,
+    }
+}
+
+/// Runs `op` until it succeeds, retrying errors that might go away up to `--retries` times.
+///
+/// Only use this for operations that can be repeated, a failed write into an archive for
+/// example has already corrupted it.
+fn with_retries<T>(
+    path: &Path,
+    opts: &BackupOptions,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e)
+                if attempt < opts.retries
+                    && !matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+                    ) =>
+            {
+                let delay = retry_delay(attempt);
+                eprintln!("retrying {} in {delay:?}: {e}", path.display());
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How long to wait before retrying after `attempt` retries
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    RETRY_BACKOFF * (1 << attempt.min(MAX_RETRY_DOUBLINGS))
+}
+
+/// Copies the directory tree at `src` to each of `dsts`, reading every file once for all
+fn copy_dir_all<P: AsRef<Path>>(
+    src: &Path,
+    dsts: &[P],
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+) -> io::Result<()> {
+    // the times are set once everything inside of the directories is written
+    let mut dir_times = Vec::new();
+    let mut create_dir = |path: &Path, dst_path: &Path| -> io::Result<()> {
+        fs::create_dir_all(dst_path)?;
+        if let Some(placeholder) = placeholder_for(path, opts) {
+            fs::File::create(dst_path.join(placeholder))?;
+        }
+        dir_times.push((dst_path.to_path_buf(), fs::metadata(path)?.modified()?));
+        Ok(())
+    };
+    for dst in dsts {
+        create_dir(src, dst.as_ref())?;
+    }
+    walk_dir(
+        src,
+        Path::new(""),
+        opts,
+        stats,
+        &mut |path, rel, ty, stats| {
+            let mut dst_paths: Vec<_> = dsts.iter().map(|dst| dst.as_ref().join(rel)).collect();
+            if ty.is_symlink() && keep_link(path, opts) {
+                for dst_path in &dst_paths {
+                    copy_link(path, dst_path)?;
+                }
+                Ok(false)
+            } else if ty.is_dir() || (ty.is_symlink() && path.is_dir()) {
+                for dst_path in &dst_paths {
+                    create_dir(path, dst_path)?;
+                }
+                Ok(true)
+            } else if ty.is_file() || (ty.is_symlink() && path.is_file()) {
+                if ty.is_file() && unchanged(path, rel, opts)? {
+                    return Ok(false);
+                }
+                if opts.only_files {
+                    for dst_path in &dst_paths {
+                        fs::create_dir_all(dst_path.parent().expect("entry has no parent"))?;
+                    }
+                }
+                dst_paths.retain(|dst_path| {
+                    !(opts.keep_newer && is_newer(dst_path, path) || opts.keep.contains(dst_path))
+                });
+                if dst_paths.is_empty() {
+                    return Ok(false);
+                }
+                let started = trace(opts.verbose, "copying", path);
+                let copied = with_retries(path, opts, || copy_file(path, &dst_paths, opts))?;
+                trace_done(started);
+                stats.bytes_out += copied;
+                stats.file_done(path, copied)?;
+                Ok(false)
+            } else if ty.is_fifo() || ty.is_char_device() || ty.is_block_device() {
+                let meta = fs::symlink_metadata(path)?;
+                for dst_path in &dst_paths {
+                    unpack::mknod(dst_path, meta.mode(), meta.rdev())?;
+                }
+                Ok(false)
+            } else {
+                eprintln!(
+                    "{}",
+                    color::skipped(format_args!(
+                        "neither a file nor a directory, skipping: {}",
+                        path.display()
+                    ))
+                    .stderr()
+                );
+                Ok(false)
+            }
+        },
+    )?;
+    set_dir_times(dir_times)
+}
+
+/// Sets the modification times of directories, the deepest ones first
+fn set_dir_times(mut dirs: Vec<(PathBuf, std::time::SystemTime)>) -> io::Result<()> {
+    dirs.sort_by(|a, b| b.0.cmp(&a.0));
+    for (dir, time) in dirs {
+        fs::File::open(&dir)
+            .and_then(|d| d.set_modified(time))
+            .map_err(|e| with_path(&dir, e))?;
+    }
+    Ok(())
+}
+
+/// The modification times of the directories among `paths`, to set them again with
+/// [`set_dir_times`]
+fn dir_times(paths: &[PathBuf]) -> io::Result<Vec<(PathBuf, std::time::SystemTime)>> {
+    paths
+        .iter()
+        .filter(|path| path.symlink_metadata().is_ok_and(|m| m.is_dir()))
+        .map(|path| Ok((path.clone(), fs::metadata(path)?.modified()?)))
+        .collect()
+}
+
+/// Whether the file at `path`, named `rel` below the backed up directory, is in the `--since`
+/// backup as it is now, and is left out
+fn unchanged(path: &Path, rel: &Path, opts: &BackupOptions) -> io::Result<bool> {
+    let Some(reference) = &opts.reference else {
+        return Ok(false);
+    };
+    let unchanged = reference.has(path, rel)?;
+    if unchanged && opts.verbose >= VERBOSE_FILES {
+        println!(
+            "{}",
+            color::skipped(format_args!("skipping, unchanged: {}", path.display()))
+        );
+    }
+    Ok(unchanged)
+}
+
+/// Like [`tar::Builder::append_dir_all`], but walks the tree itself so that the backup options
+/// are respected
+fn archive_dir_all(
+    archiver: &mut tar::Builder<ArchiveWriter>,
+    name: &Path,
+    src: &Path,
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+) -> io::Result<()> {
+    if !opts.only_files {
+        append_entry(archiver, src, name, opts, stats)?;
+    }
+    walk_dir(
+        src,
+        Path::new(""),
+        opts,
+        stats,
+        &mut |path, rel, ty, stats| {
+            if ty.is_file() && unchanged(path, rel, opts)? {
+                return Ok(false);
+            }
+            append_entry(archiver, path, &name.join(rel), opts, stats)?;
+            Ok(ty.is_dir() || (opts.follow_symlinks && ty.is_symlink() && path.is_dir()))
+        },
+    )
+}
+
+/// Name of the archive entry of the backed up `path`, below `--prefix` if it is given
+fn archive_name(path: &Path, opts: &BackupOptions) -> io::Result<PathBuf> {
+    let name = entry_name(path)?;
+    match &opts.prefix {
+        Some(prefix) => Ok(prefix.join(name)),
+        None if name.as_os_str().is_empty() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the root has no name to store it by in an archive, give it a --prefix",
+        )),
+        None => Ok(name),
+    }
+}
+
+/// `path` for the names of archive entries: a relative path below the working directory as it
+/// was given, others by their path from the root without the leading `/`, like tar does. So
+/// `/etc/nginx`, and `../nginx` in `/etc/ssl`, are both `etc/nginx`.
+fn entry_name(path: &Path) -> io::Result<PathBuf> {
+    let below = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    let name = match below && path.components().any(|c| matches!(c, Component::Normal(_))) {
+        true => unpack::normalize_path(path),
+        false => unpack::normalize_path(&absolute_path(path)?),
+    };
+    Ok(name)
+}
+
+/// Appends the file or directory at `path` to the archive as `name`, with a header built from
+/// its metadata according to the backup options
+fn append_entry(
+    archiver: &mut tar::Builder<ArchiveWriter>,
+    path: &Path,
+    name: &Path,
+    opts: &BackupOptions,
+    stats: &mut BackupStats,
+) -> io::Result<()> {
+    let timeout = opts.keep_going_timeout;
+    let meta = with_retries(path, opts, || {
+        watchdog::run(path, timeout, |path| fs::symlink_metadata(path))
+    })?;
+    let meta = if meta.is_symlink() && !keep_link(path, opts) {
+        with_retries(path, opts, || {
+            watchdog::run(path, timeout, |path| fs::metadata(path))
+        })?
+    } else {
+        meta
+    };
+
+    let mut header = opts.archive_format.header();
+    header.set_metadata_in_mode(&meta, header_mode(opts));
+    if let Some(owner) = &opts.owner {
+        header.set_uid(owner.id);
+        header.set_username(&owner.name)?;
+    }
+    if let Some(group) = &opts.group {
+        header.set_gid(group.id);
+        header.set_groupname(&group.name)?;
+    }
+
+    let ty = meta.file_type();
+    if ty.is_symlink() {
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        let target = fs::read_link(path)?;
+        add_mtime(archiver, &meta, opts)?;
+        return append_named(
+            archiver,
+            &mut header,
+            name,
+            Some(&target),
+            io::empty(),
+            opts,
+        );
+    }
+    if !ty.is_file() && !ty.is_dir() {
+        let entry_type = if ty.is_fifo() {
+            tar::EntryType::Fifo
+        } else if ty.is_char_device() {
+            tar::EntryType::Char
+        } else if ty.is_block_device() {
+            tar::EntryType::Block
+        } else {
+            return Err(io::Error::other(format!(
+                "{}: sockets can not be archived",
+                path.display()
+            )));
+        };
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        // the glibc encoding of device numbers, like the tar builder does it
+        let dev = meta.rdev();
+        let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+        let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+        header.set_device_major(major as u32)?;
+        header.set_device_minor(minor as u32)?;
+        add_mtime(archiver, &meta, opts)?;
+        return append_named(archiver, &mut header, name, None, io::empty(), opts);
+    }
+
+    if meta.is_dir() {
+        add_mtime(archiver, &meta, opts)?;
+        append_named(archiver, &mut header, name, None, io::empty(), opts)?;
+        if let Some(placeholder) = placeholder_for(path, opts) {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            let name = name.join(placeholder);
+            append_named(archiver, &mut header, &name, None, io::empty(), opts)?;
+        }
+        Ok(())
+    } else {
+        // the file might grow while we read it, stick to the size in the header
+        let started = trace(opts.verbose, "archiving", path);
+        let snapshot = if opts.reflink {
+            with_retries(path, opts, || {
+                watchdog::run(path, timeout, reflink::snapshot)
+            })?
+        } else {
+            None
+        };
+        let (file, len) = match snapshot {
+            Some(file) => {
+                let len = file.metadata()?.len();
+                header.set_size(len);
+                (file, len)
+            }
+            None => (
+                with_retries(path, opts, || {
+                    watchdog::run(path, timeout, |path| fs::File::open(path))
+                })?,
+                meta.len(),
+            ),
+        };
+        // stored frames read the file again
+        let source = match opts.store_incompressible && len >= INCOMPRESSIBLE_MIN_SIZE {
+            true => Some(file.try_clone()?),
+            false => None,
+        };
+        let file: Box<dyn Read> = match timeout {
+            Some(timeout) => Box::new(watchdog::Reader::new(path, file, timeout)?),
+            None => Box::new(file),
+        };
+        let mut file = match opts.buffer_size {
+            Some(size) => io::BufReader::with_capacity(size, file),
+            None => io::BufReader::new(file),
+        };
+        // the start of the file is read anyway, looking at it costs nothing
+        let level = if opts.detect_compressed {
+            level_for(path, io::BufRead::fill_buf(&mut file)?, opts)
+        } else {
+            level_for(path, &[], opts)
+        };
+        archiver.get_mut().set_level(level)?;
+        if started.is_some()
+            && opts.compress == Some(Compression::Zstd)
+            && opts.compress_program.is_none()
+        {
+            println!("  zstd level {level}");
+        }
+        add_mtime(archiver, &meta, opts)?;
+        let result = if let Some(source) = source {
+            // the headers stay with the entries before, only the content gets a frame
+            append_named(archiver, &mut header, name, None, io::empty(), opts)?;
+            archiver
+                .get_mut()
+                .append_content(&mut file, &source, len)
+                .map(|stored| {
+                    if stored {
+                        stats.stored += 1;
+                        if started.is_some() {
+                            println!("  stored uncompressed");
+                        }
+                    }
+                })
+        } else {
+            append_named(archiver, &mut header, name, None, file.take(len), opts)
+        };
+        result.map_err(|e| match watchdog::is_stalled(&e) {
+            // its header is written already, the archive can not skip it anymore
+            true => io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{e}, while it was archived"),
+            ),
+            false => e,
+        })?;
+        trace_done(started);
+        stats.file_done(path, len)?;
+        Ok(())
+    }
+}
+
+/// Prints that `action` is done to the file at `path` with `-vv`, and returns when it started
+/// with `-vvv` for [`trace_done`]
+fn trace(verbose: u8, action: &str, path: &Path) -> Option<std::time::Instant> {
+    if verbose >= VERBOSE_FILES {
+        println!("{action} {}", path.display());
+    }
+    (verbose >= VERBOSE_DEBUG).then(std::time::Instant::now)
+}
+
+/// Prints how long the file of the last [`trace`] took, with `-vvv`
+fn trace_done(started: Option<std::time::Instant>) {
+    if let Some(started) = started {
+        println!("  took {:.3?}", started.elapsed());
+    }
+}
+
+/// Adds the modification time of `meta` with nanoseconds, which the header only has in whole
+/// seconds, to the pax records of the entry appended next
+fn add_mtime(
+    archiver: &mut tar::Builder<ArchiveWriter>,
+    meta: &fs::Metadata,
+    opts: &BackupOptions,
+) -> io::Result<()> {
+    // reproducible archives have no times
+    if !opts.reproducible {
+        format::mtime_record(&mut archiver.get_mut().pax, meta.modified()?);
+    }
+    Ok(())
+}
+
+/// Appends `header` with `data` as `name`, linking to `target` for symlinks, after the pending
+/// pax records and those that the `--archive-format` needs for the names and size
+fn append_named<R: Read>(
+    archiver: &mut tar::Builder<ArchiveWriter>,
+    header: &mut tar::Header,
+    name: &Path,
+    target: Option<&Path>,
+    data: R,
+    opts: &BackupOptions,
+) -> io::Result<()> {
+    let mut pax = std::mem::take(&mut archiver.get_mut().pax);
+    if opts.archive_format == format::ArchiveFormat::Gnu {
+        format::append_pax(archiver, &pax)?;
+        return match target {
+            Some(target) => archiver.append_link(header, name, target),
+            None => archiver.append_data(header, name, data),
+        };
+    }
+    opts.archive_format
+        .set_names(header, &mut pax, name, target)?;
+    format::append_pax(archiver, &pax)?;
+    archiver.append(header, data)
+}
+
+/// The zstd level from `--level` or `--preset`
+fn compression_level(opts: &BackupOptions) -> i32 {
+    opts.level
+        .or(opts.preset.map(Preset::level))
+        .unwrap_or(DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// The zstd level for the file at `path` that starts with `sample`, by the first of
+/// `--compress-level-per-extension` that matches its name, or `--detect-compressed`
+fn level_for(path: &Path, sample: &[u8], opts: &BackupOptions) -> i32 {
+    let name = path.file_name().unwrap_or_default();
+    match opts
+        .compress_level_per_extension
+        .iter()
+        .find(|rule| rule.matches(name))
+    {
+        Some(rule) => rule.level,
+        None if opts.detect_compressed && is_compressed(path, sample) => COMPRESSED_LEVEL,
+        None => compression_level(opts),
+    }
+}
+
+/// Whether the file at `path` is compressed already, by its extension or the entropy of
+/// `sample`, its first bytes
+fn is_compressed(path: &Path, sample: &[u8]) -> bool {
+    let by_name = path.extension().is_some_and(|ext| {
+        COMPRESSED_EXTENSIONS
+            .iter()
+            .any(|known| ext.eq_ignore_ascii_case(known))
+    });
+    by_name || entropy(sample) > COMPRESSED_ENTROPY
+}
+
+/// Shannon entropy of `data` in bits per byte, 8 for random data
+fn entropy(data: &[u8]) -> f64 {
+    // too little to tell
+    if data.len() < 512 {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn header_mode(opts: &BackupOptions) -> tar::HeaderMode {
+    if opts.reproducible {
+        tar::HeaderMode::Deterministic
+    } else {
+        tar::HeaderMode::Complete
+    }
+}
+
+/// Where [`make_archive`] writes the tar stream to
+struct ArchiveWriter {
+    output: ArchiveOutput,
+    /// pax records for the next entry, starting with the versions for the first one
+    pax: Vec<u8>,
+}
+
+enum ArchiveOutput {
+    Plain(io::BufWriter<tee::Tee>),
+    Zstd(ZstdWriter),
+    /// The input of `--compress-program`, which writes to the archive file itself, or with
+    /// `--also-to` to a thread that writes its output to all of them
+    Program(
+        io::BufWriter<process::ChildStdin>,
+        process::Child,
+        Option<std::thread::JoinHandle<io::Result<u64>>>,
+    ),
+}
+
+impl ArchiveWriter {
+    /// Compresses what is written from now on with `level`, if the archive is compressed
+    /// with zstd
+    fn set_level(&mut self, level: i32) -> io::Result<()> {
+        match &mut self.output {
+            ArchiveOutput::Zstd(zstd) => zstd.set_level(level),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the first `len` bytes of `file` padded to a whole tar block, as the content of
+    /// the entry whose header was appended last. With zstd they get a frame of their own,
+    /// which is stored uncompressed from `source`, the same file, if that is smaller. Returns
+    /// whether it was.
+    fn append_content<R: Read>(
+        &mut self,
+        file: &mut io::BufReader<R>,
+        source: &fs::File,
+        len: u64,
+    ) -> io::Result<bool> {
+        if let ArchiveOutput::Zstd(zstd) = &mut self.output {
+            zstd.begin_file()?;
+        }
+        // like the tar builder, which pads what it could read
+        let copied = io::copy(&mut file.take(len), self)?;
+        let padding = copied.next_multiple_of(512) - copied;
+        self.write_all(&[0; 512][..padding as usize])?;
+        match &mut self.output {
+            ArchiveOutput::Zstd(zstd) => zstd.end_file(source, copied),
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes the end of the compressed stream, if any, and flushes the buffer
+    fn finish(self) -> io::Result<()> {
+        let buffered = match self.output {
+            ArchiveOutput::Plain(f) => f,
+            ArchiveOutput::Zstd(zstd) => zstd.finish()?,
+            ArchiveOutput::Program(stdin, child, copier) => {
+                // closing its input lets the program finish
+                drop(stdin.into_inner().map_err(|e| e.into_error())?);
+                wait_program(child, "compress")?;
+                if let Some(copier) = copier {
+                    copier
+                        .join()
+                        .expect("copying the compressed archive panicked")?;
+                }
+                return Ok(());
+            }
+        };
+        buffered.into_inner().map_err(|e| e.into_error())?;
+        Ok(())
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.output {
+            ArchiveOutput::Plain(f) => f.write(buf),
+            ArchiveOutput::Zstd(encoder) => encoder.write(buf),
+            ArchiveOutput::Program(stdin, ..) => stdin.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            ArchiveOutput::Plain(f) => f.flush(),
+            ArchiveOutput::Zstd(encoder) => encoder.flush(),
+            ArchiveOutput::Program(stdin, ..) => stdin.flush(),
+        }
+    }
+}
+
+/// A zstd stream whose level can change in between, by ending the current frame and starting
+/// a new one. The frames are decompressed one after the other like a single one.
+struct ZstdWriter {
+    /// Only [`None`] while switching to the next frame
+    encoder: Option<zstd::Encoder<'static, io::BufWriter<tee::Tee>>>,
+    level: i32,
+    checksum: bool,
+    window_log: Option<u32>,
+    threads: u32,
+    /// The file in a frame of its own, between [`ZstdWriter::begin_file`] and
+    /// [`ZstdWriter::end_file`]
+    file: Option<FileFrame>,
+}
+
+/// Where the frame of a file starts and how much went into it, to write it again without
+/// compression
+struct FileFrame {
+    start: u64,
+    written: u64,
+}
+
+impl ZstdWriter {
+    fn new(file: io::BufWriter<tee::Tee>, opts: &BackupOptions) -> io::Result<Self> {
+        let mut writer = ZstdWriter {
+            encoder: None,
+            level: compression_level(opts),
+            checksum: !opts.no_frame_checksum,
+            window_log: opts.long,
+            threads: opts.threads,
+            file: None,
+        };
+        writer.encoder = Some(writer.encoder(file)?);
+        Ok(writer)
+    }
+
+    /// An encoder for the next frame, with the current settings
+    fn encoder(
+        &self,
+        file: io::BufWriter<tee::Tee>,
+    ) -> io::Result<zstd::Encoder<'static, io::BufWriter<tee::Tee>>> {
+        let mut encoder = zstd::Encoder::new(file, self.level)?;
+        encoder.include_checksum(self.checksum)?;
+        if self.threads > 1 {
+            encoder.multithread(self.threads)?;
+        }
+        if let Some(window_log) = self.window_log {
+            encoder.long_distance_matching(true)?;
+            encoder.window_log(window_log)?;
+        }
+        Ok(encoder)
+    }
+
+    fn current(&mut self) -> &mut zstd::Encoder<'static, io::BufWriter<tee::Tee>> {
+        self.encoder.as_mut().expect("no zstd encoder")
+    }
+
+    fn set_level(&mut self, level: i32) -> io::Result<()> {
+        if level == self.level {
+            return Ok(());
+        }
+        let file = self.encoder.take().expect("no zstd encoder").finish()?;
+        self.level = level;
+        self.encoder = Some(self.encoder(file)?);
+        Ok(())
+    }
+
+    /// Ends the current frame and starts one for the next file alone, to replace it with a
+    /// stored frame in [`ZstdWriter::end_file`] if compressing did not pay off
+    fn begin_file(&mut self) -> io::Result<()> {
+        let mut file = self.encoder.take().expect("no zstd encoder").finish()?;
+        let start = file.stream_position()?;
+        self.encoder = Some(self.encoder(file)?);
+        self.file = Some(FileFrame { start, written: 0 });
+        Ok(())
+    }
+
+    /// Ends the frame of the file whose first `len` bytes from `source` and their padding were
+    /// written since [`ZstdWriter::begin_file`], and writes it again as a stored frame if that
+    /// is smaller than the compressed one. Returns whether it did, a file that shrank meanwhile
+    /// stays compressed.
+    fn end_file(&mut self, source: &fs::File, len: u64) -> io::Result<bool> {
+        let frame = self.file.take().expect("no file frame");
+        let mut file = self.encoder.take().expect("no zstd encoder").finish()?;
+        let compressed = file.stream_position()? - frame.start;
+        let padding = frame.written - len;
+        let store = compressed > stored_size(frame.written) && source.metadata()?.len() >= len;
+        if store {
+            file.seek(io::SeekFrom::Start(frame.start))?;
+            let mut source = source;
+            source.seek(io::SeekFrom::Start(0))?;
+            let content = source.take(len).chain(io::repeat(0).take(padding));
+            write_stored(&mut file, content, frame.written)?;
+            let end = file.stream_position()?;
+            file.get_ref().set_len(end)?;
+        }
+        self.encoder = Some(self.encoder(file)?);
+        Ok(store)
+    }
+
+    fn finish(mut self) -> io::Result<io::BufWriter<tee::Tee>> {
+        self.encoder.take().expect("no zstd encoder").finish()
+    }
+}
+
+/// Size of a stored frame of `len` bytes from [`write_stored`]
+fn stored_size(len: u64) -> u64 {
+    let blocks = len.div_ceil(RAW_BLOCK_MAX as u64).max(1);
+    6 + len + 3 * blocks
+}
+
+/// Writes `len` bytes from `content` as a zstd frame of raw blocks, which the decoder copies
+/// as they are. It has no checksum, that would need xxHash.
+fn write_stored<W: Write, R: Read>(out: &mut W, mut content: R, len: u64) -> io::Result<()> {
+    out.write_all(&ZSTD_MAGIC.to_le_bytes())?;
+    // no content size, checksum or dictionary
+    out.write_all(&[0, RAW_WINDOW_DESCRIPTOR])?;
+    let mut block = vec![0; RAW_BLOCK_MAX];
+    let mut left = len;
+    loop {
+        let size = left.min(RAW_BLOCK_MAX as u64) as usize;
+        content.read_exact(&mut block[..size])?;
+        left -= size as u64;
+        // the size, the type 0 for raw blocks and whether it is the last block
+        let header = (size as u32) << 3 | u32::from(left == 0);
+        out.write_all(&header.to_le_bytes()[..3])?;
+        out.write_all(&block[..size])?;
+        if left == 0 {
+            return Ok(());
+        }
+    }
+}
+
+impl Write for ZstdWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.current().write(buf)?;
+        if let Some(frame) = &mut self.file {
+            frame.written += n as u64;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current().flush()
+    }
+}
+
+/// Where [`read_archive`] reads the tar stream from
+enum ArchiveReader {
+    Plain(fs::File),
+    Zstd(zstd::Decoder<'static, io::BufReader<fs::File>>),
+    /// The output of `--decompress-program`
+    Program(process::ChildStdout),
+    /// Archives from a URL
+    #[cfg(feature = "http")]
+    Download(http::Body),
+    #[cfg(feature = "http")]
+    ZstdDownload(zstd::Decoder<'static, io::BufReader<http::Body>>),
+}
+
+impl io::Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ArchiveReader::Plain(f) => f.read(buf),
+            ArchiveReader::Zstd(decoder) => decoder.read(buf),
+            ArchiveReader::Program(stdout) => stdout.read(buf),
+            #[cfg(feature = "http")]
+            ArchiveReader::Download(body) => body.read(buf),
+            #[cfg(feature = "http")]
+            ArchiveReader::ZstdDownload(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// Creates the archive at `archive_path` with the entries that `do_this` appends, recording
+/// the `origin` of what they were backed up from
+fn make_archive<F>(
+    archive_path: &Path,
+    origin: Option<&Path>,
+    opts: &BackupOptions,
+    do_this: F,
+) -> std::io::Result<()>
+where
+    F: FnOnce(&mut tar::Builder<ArchiveWriter>) -> std::io::Result<()>,
+{
+    let file = tee::Tee::create(&destinations(archive_path, opts))?;
+    fn buffered<W: Write>(w: W, opts: &BackupOptions) -> io::BufWriter<W> {
+        match opts.buffer_size {
+            Some(size) => io::BufWriter::with_capacity(size, w),
+            None => io::BufWriter::new(w),
+        }
+    }
+
+    let output = if let Some(program) = &opts.compress_program {
+        let (stdout, tee) = match file.into_single() {
+            Ok(file) => (process::Stdio::from(file), None),
+            Err(tee) => (process::Stdio::piped(), Some(tee)),
+        };
+        let mut child = shell(program)
+            .stdin(process::Stdio::piped())
+            .stdout(stdout)
+            .spawn()
+            .inspect_err(|e| {
+                eprintln!(
+                    "{}",
+                    color::error(format_args!(
+                        "could not run compress program {program:?}: {e}"
+                    ))
+                )
+            })?;
+        let stdin = child
+            .stdin
+            .take()
+            .expect("stdin of the compress program is piped");
+        let copier = match (tee, child.stdout.take()) {
+            (Some(mut tee), Some(mut stdout)) => {
+                Some(std::thread::spawn(move || io::copy(&mut stdout, &mut tee)))
+            }
+            _ => None,
+        };
+        ArchiveOutput::Program(buffered(stdin, opts), child, copier)
+    } else {
+        let file = buffered(file, opts);
+        match opts.compress.unwrap_or(Compression::Zstd) {
+            Compression::None => ArchiveOutput::Plain(file),
+            Compression::Zstd => ArchiveOutput::Zstd(ZstdWriter::new(file, opts)?),
+        }
+    };
+    let mut pax = format::version_records();
+    if let Some(comment) = &opts.comment {
+        format::comment_record(&mut pax, comment);
+    }
+    if let Some(origin) = origin {
+        format::origin_record(&mut pax, origin);
+    }
+    let mut archiver = tar::Builder::new(ArchiveWriter { output, pax });
+    archiver.mode(header_mode(opts));
+
+    do_this(&mut archiver)?;
+
+    archiver.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+/// A command running `program` with the shell, like tar does for `-I`
+fn shell(program: &str) -> process::Command {
+    let mut command = process::Command::new("sh");
+    command.arg("-c").arg(program);
+    command
+}
+
+/// Waits for a `--compress-program` or `--decompress-program` and fails if it did
+fn wait_program(mut child: process::Child, what: &str) -> io::Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        let e = io::Error::other(format!("{what} program failed: {status}"));
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Opens the archive at `archive_path` for reading through the shell command `program`, for
+/// archives made with `--compress-program`
+fn read_program_archive<F>(archive_path: &Path, program: &str, do_this: F) -> io::Result<()>
+where
+    F: FnOnce(&mut tar::Archive<ArchiveReader>) -> io::Result<()>,
+{
+    let file = fs::File::open(archive_path).inspect_err(|e| {
+        eprintln!(
+            "{}",
+            color::error(format_args!("could not open archive: {e}"))
+        )
+    })?;
+    let mut child = shell(program)
+        .stdin(file)
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .inspect_err(|e| {
+            eprintln!(
+                "{}",
+                color::error(format_args!(
+                    "could not run decompress program {program:?}: {e}"
+                ))
+            )
+        })?;
+    let stdout = child
+        .stdout
+        .take()
+        .expect("stdout of the decompress program is piped");
+    let mut unarchiver = tar::Archive::new(ArchiveReader::Program(stdout));
+
+    if let Err(e) = do_this(&mut unarchiver) {
+        eprintln!(
+            "{}",
+            color::error(format_args!("could perform read_archive actions: {e}"))
+        );
+        return Err(e);
+    }
+    // tar stops reading at the end marker, the program should not die of a broken pipe
+    io::copy(&mut unarchiver.into_inner(), &mut io::sink())?;
+    wait_program(child, "decompress")
+}
+
+/// Opens the archive at `archive_path` for reading, the compression is detected by its name.
+/// Frames using a window log above `window_log_max` (27 if [`None`]) are rejected by the
+/// decoder.
+fn read_archive<F>(
+    archive_path: &Path,
+    window_log_max: Option<u32>,
+    do_this: F,
+) -> std::io::Result<()>
+where
+    F: FnOnce(&mut tar::Archive<ArchiveReader>) -> std::io::Result<()>,
+{
+    let file = match fs::File::open(archive_path) {
+        Err(e) => {
+            eprintln!(
+                "{}",
+                color::error(format_args!("could not open archive: {e}"))
+            );
+            return Err(e);
+        }
+        Ok(f) => f,
+    };
+
+    // by the content first, names can be changed
+    let compression = Compression::sniff(&file)
+        .or(Compression::detect(archive_path))
+        .unwrap_or(Compression::Zstd);
+    let reader = match compression {
+        Compression::None => ArchiveReader::Plain(file),
+        Compression::Zstd => ArchiveReader::Zstd(zstd_decoder(file, window_log_max)?),
+    };
+    read_archive_from(reader, archive_path, do_this)
+}
+
+/// A decoder for the zstd stream in `reader`, with the limit of [`read_archive`]
+fn zstd_decoder<R: Read>(
+    reader: R,
+    window_log_max: Option<u32>,
+) -> io::Result<zstd::Decoder<'static, io::BufReader<R>>> {
+    let mut decompressor = zstd::Decoder::new(reader).inspect_err(|e| {
+        eprintln!(
+            "{}",
+            color::error(format_args!("could not open zstd decoder: {e}"))
+        )
+    })?;
+    if let Some(window_log_max) = window_log_max {
+        decompressor.window_log_max(window_log_max)?;
+    }
+    Ok(decompressor)
+}
+
+/// Does `do_this` with the archive that `reader` reads from `archive_path`, to its end
+fn read_archive_from<F>(reader: ArchiveReader, archive_path: &Path, do_this: F) -> io::Result<()>
+where
+    F: FnOnce(&mut tar::Archive<ArchiveReader>) -> io::Result<()>,
+{
+    let mut unarchiver = tar::Archive::new(reader);
+
+    // tar stops at the end of the entries, the rest of the frame has to be read as well for the
+    // checksum at its end to be checked
+    let result = do_this(&mut unarchiver)
+        .and_then(|()| io::copy(&mut unarchiver.into_inner(), &mut io::sink()).map(drop))
+        .map_err(|e| zstd_error(archive_path, e));
+    if let Err(e) = &result {
+        eprintln!(
+            "{}",
+            color::error(format_args!("could perform read_archive actions: {e}"))
+        );
+    }
+    result
+}
+
+/// The output directory to restore the backup at `path` to where it was backed up from, for
+/// `--use-manifest-paths`, and the prefix map for its entries. Asks before, unless `--yes`.
+fn original_location(path: &Path, opts: &mut RestoreOptions) -> io::Result<PathBuf> {
+    let origin = manifest::Manifest::for_backup(path)?
+        .and_then(|manifest| manifest.origin)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} has no manifest that records where it was backed up from, back it up \
+                     with --hash to write one",
+                    path.display()
+                ),
+            )
+        })?;
+    // a manifest from elsewhere, or edited by hand, could point anywhere
+    let plain = origin.path.is_absolute()
+        && origin
+            .path
+            .components()
+            .all(|c| matches!(c, Component::RootDir | Component::Normal(_)));
+    if !plain {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the manifest of {} records an unusable path: {}",
+                path.display(),
+                origin.path.display()
+            ),
+        ));
+    }
+    let prompt = format!(
+        "restore {} to {}, overwriting what is there?",
+        path.display(),
+        origin.path.display()
+    );
+    if !(opts.yes || confirm(prompt)?) {
+        let e = io::Error::new(io::ErrorKind::Interrupted, "restore cancelled");
+        eprintln!("{}", color::error(&e));
+        return Err(e);
+    }
+    let out = origin.path.parent().unwrap_or(Path::new("/"));
+    fs::create_dir_all(out)?;
+    let name = origin.path.file_name().unwrap_or_default();
+    opts.prefix_map = vec![unpack::PrefixMap::new(&origin.name, Path::new(name))];
+    Ok(out.to_path_buf())
+}
+
+/// Creates a new `restore-<time>` directory in `out` and returns it, with a number appended
+/// if one of that time exists already
+fn new_restore_dir(out: &Path) -> io::Result<PathBuf> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let name = format!("restore-{}", list::file_time(now.as_secs()));
+    let mut dir = out.join(&name);
+    let mut n = 1;
+    loop {
+        match fs::create_dir(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                n += 1;
+                dir = out.join(format!("{name}-{n}"));
+            }
+            result => return result.map(|()| dir),
+        }
+    }
+}
+
+/// The largest zstd window log to decompress with `--max-memory`, below that of `--long` or
+/// the default of zstd. Fails if not even the smallest window fits.
+fn window_log_limit(long: Option<u32>, max_memory: usize) -> io::Result<u32> {
+    let log = max_memory.checked_ilog2().unwrap_or(0);
+    if log < MIN_WINDOW_LOG {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--max-memory is too small to decompress anything, the least is {}",
+                format_size(1 << MIN_WINDOW_LOG, false)
+            ),
+        ));
+    }
+    Ok(log.min(long.unwrap_or(DEFAULT_WINDOW_LOG_MAX)))
+}
+
+/// Makes a failed zstd frame checksum reported by `e` say that the archive at `archive_path` is
+/// corrupt, and a window above the limit say how to allow it. Other errors are returned as
+/// they are.
+fn zstd_error(archive_path: &Path, e: io::Error) -> io::Error {
+    // zstd only tells with the message, which tar wraps into its own errors
+    let mut source: Option<&dyn std::error::Error> = Some(&e);
+    while let Some(err) = source {
+        let message = err.to_string();
+        if message.contains("match checksum") {
+            return io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is corrupt, the zstd checksum does not match",
+                    archive_path.display()
+                ),
+            );
+        }
+        if message.contains("too much memory") {
+            return io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!(
+                    "{} needs a larger zstd window than allowed, see --long and --max-memory",
+                    archive_path.display()
+                ),
+            );
+        }
+        source = err.source();
+    }
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io::{Read, Seek};
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use std::{fs, io};
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    use crate::hash::HashAlgorithm;
+    use crate::manifest::Manifest;
+    use crate::{
+        add_extension, archive_name, backup_dir, backup_file, check_output, compare::compare,
+        compression_level, copy_dir_all, destinations, estimate_size, find_collisions, format,
+        format_size, is_mount_point, keep_existing, level_for, manifest_only, new_restore_dir,
+        original_location, output_path, overhead_warning, parse_duration, parse_mode, parse_size,
+        parse_verbosity, path_compressions, preset_level, program_extension, program_for, progress,
+        read_archive, recursive_remove, remove_partial, restore, restore_conflicts, retry_delay,
+        set_mtime, since, size_limit_reached, split_entries, touch, unpack, walk_dir, with_retries,
+        BackupOptions, BackupStats, Cli, Commands, Compression, LevelRule, Owner, ProgramExt,
+        RestoreOptions, VERBOSE_DEBUG, VERBOSE_FILES, VERBOSE_PATHS,
+    };
+    #[cfg(feature = "compression")]
+    use crate::{
+        available_memory, is_archive, make_archive, memory_warning, parse_prefix, window_log_limit,
+        zstd_memory, WindowLog, ZstdExtension, DEFAULT_WINDOW_LOG_MAX,
+    };
+
+    const CONTENT: &[u8] = b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+    /// The compressions tests make every kind of backup with, copies and zstd archives if
+    /// this build has zstd
+    pub(crate) const BACKUP_KINDS: &[Option<Compression>] = &[
+        None,
+        #[cfg(feature = "compression")]
+        Some(Compression::Zstd),
+    ];
+
+    /// The compressions of the archives this build makes
+    pub(crate) const ARCHIVE_KINDS: &[Compression] = &[
+        Compression::None,
+        #[cfg(feature = "compression")]
+        Compression::Zstd,
+    ];
+
+    fn filesize(p: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(p)?.size())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_make_archive() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir).unwrap(); // NOTE: if multiple tests use this, this
+                                                  // creates a race condition
+        let tfile = PathBuf::from("foo");
+        let tfile_a = PathBuf::from("foo.tar.zstd");
+
+        fs::write(&tfile, CONTENT).unwrap();
+        assert!(tfile.exists());
+        assert!(tfile.is_file());
+        assert_eq!(fs::read(&tfile).unwrap(), CONTENT);
+        let raw_size = fs::metadata(&tfile).unwrap().size();
+        assert!(raw_size > 1, "raw size was {raw_size}");
+
+        // NOTE: append_path needs a relative path
+        make_archive(&tfile_a, None, &BackupOptions::default(), |a| {
+            a.append_path(&tfile)
+        })
+        .unwrap();
+        assert!(tfile_a.exists());
+        assert!(tfile_a.is_file());
+        let arch_size = fs::metadata(&tfile_a).unwrap().size();
+        assert!(arch_size > 1, "archive size was {arch_size}");
+
+        fs::remove_file(&tfile).unwrap();
+        assert!(!tfile.exists());
+
+        read_archive(&tfile_a, None, |a| a.unpack(tdir)).unwrap();
+        assert!(tfile.exists());
+        assert!(!tfile.is_dir());
+        assert!(tfile.is_file());
+        let copy_size = fs::metadata(&tfile).unwrap().size();
+        assert!(copy_size > 1, "archive size was {arch_size}");
+
+        let copy_content = fs::read(&tfile).unwrap();
+        assert_eq!(CONTENT, copy_content);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_simple_bak_restore() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        let tfile = tdir.join("foo");
+        let tfile_b = tdir.join("foo.bak");
+
+        fs::write(&tfile, CONTENT).unwrap();
+        assert!(tfile.exists());
+        assert!(tfile.is_file());
+        assert_eq!(fs::read(&tfile).unwrap(), CONTENT);
+        let raw_size = filesize(&tfile)?;
+        assert!(raw_size > 1, "raw size was {raw_size}");
+
+        backup_file(
+            &tfile,
+            &BackupOptions::default(),
+            &mut BackupStats::default(),
+        )
+        .unwrap();
+
+        assert!(tfile_b.exists());
+        assert!(tfile_b.is_file());
+        assert_eq!(fs::read(&tfile_b).unwrap(), CONTENT);
+        let raw_size = filesize(&tfile)?;
+        assert!(raw_size > 1, "raw size was {raw_size}");
+
+        fs::remove_file(&tfile).unwrap();
+        assert!(!tfile.exists());
+
+        restore(&tfile_b, tdir, &RestoreOptions::default()).unwrap();
+
+        assert!(tfile.exists());
+        assert!(tfile.is_file());
         assert_eq!(fs::read(&tfile).unwrap(), CONTENT);
         let raw_size = filesize(&tfile)?;
         assert!(raw_size > 1, "raw size was {raw_size}");
@@ -434,60 +4746,2964 @@ mod tests {
     }
 
     #[test]
-    fn test_dir_bak_restore() -> io::Result<()> {
+    fn test_split_entries() -> io::Result<()> {
+        let t = tempdir()?;
+        let big = t.path().join("big");
+        fs::create_dir_all(big.join("a"))?;
+        fs::write(big.join("a").join("foo"), CONTENT)?;
+        fs::write(big.join("b"), CONTENT)?;
+        fs::write(big.join("skipped"), CONTENT)?;
+        fs::write(big.join(".lopplerignore"), b"skipped\n")?;
+        let file = t.path().join("file");
+        fs::write(&file, CONTENT)?;
+
+        let opts = BackupOptions::default();
+        let split = split_entries(vec![big.clone(), file.clone()], &opts)?;
+        assert_eq!(
+            split,
+            vec![
+                big.join(".lopplerignore"),
+                big.join("a"),
+                big.join("b"),
+                file.clone()
+            ]
+        );
+        backup_dir(&big.join("a"), &opts, &mut BackupStats::default())?;
+        backup_file(&big.join("b"), &opts, &mut BackupStats::default())?;
+        // a second run leaves the backups of the first one alone
+        assert_eq!(
+            split_entries(vec![big.clone(), file.clone()], &opts)?,
+            split
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_fsync() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink("sub", src.join("link"))?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                fsync: true,
+                hash: Some(HashAlgorithm::Sha256),
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            assert!(compare(&backup, Path::new("."), None)?.is_empty());
+            recursive_remove(&backup)?;
+            let backup = backup_file(
+                &src.join("sub").join("foo"),
+                &opts,
+                &mut BackupStats::default(),
+            )?;
+            assert!(Manifest::path_for(&backup)?.exists());
+            recursive_remove(&backup)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_comment() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("foo"), CONTENT)?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            comment: Some("pre-upgrade snapshot".to_string()),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let mut comment = None;
+        read_archive(&backup, None, |a| {
+            let mut first = a.entries()?.next().unwrap()?;
+            comment = format::comment(&mut first)?;
+            Ok(())
+        })?;
+        assert_eq!(comment.as_deref(), Some("pre-upgrade snapshot"));
+
+        assert!(Cli::try_parse_from(["loppel", "backup", "--comment", "a\nb", "src"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verbosity() {
+        let verbose = |args: &[&str]| Cli::try_parse_from(args).unwrap().verbose;
+        assert_eq!(verbose(&["loppel", "backup", "src"]), 0);
+        assert_eq!(verbose(&["loppel", "-v", "backup", "src"]), VERBOSE_PATHS);
+        assert_eq!(verbose(&["loppel", "backup", "-vv", "src"]), VERBOSE_FILES);
+        assert_eq!(verbose(&["loppel", "backup", "-vvv", "src"]), VERBOSE_DEBUG);
+        assert_eq!(parse_verbosity("true"), Ok(VERBOSE_PATHS));
+        assert_eq!(parse_verbosity("false"), Ok(0));
+        assert_eq!(parse_verbosity("3"), Ok(VERBOSE_DEBUG));
+        assert!(parse_verbosity("loud").is_err());
+    }
+
+    #[test]
+    fn test_tag_args() {
+        let cli = Cli::try_parse_from([
+            "loppel", "backup", "--tag", "weekly", "--tag", "prod", "src",
+        ])
+        .unwrap();
+        let Some(Commands::Backup { opts, .. }) = cli.command else {
+            panic!("not a backup");
+        };
+        assert_eq!(opts.tags, ["weekly", "prod"]);
+        for bad in ["", "two words", "line\nbreak"] {
+            assert!(Cli::try_parse_from(["loppel", "backup", "--tag", bad, "src"]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_window_log_args() {
+        let window_log = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Some(Commands::Restore { opts, .. }) => opts.window.long,
+            Some(Commands::Compare { window, .. } | Commands::Unpack { window, .. }) => window.long,
+            Some(Commands::List { window_log, .. }) => window_log,
+            _ => panic!("no window log"),
+        };
+        assert_eq!(window_log(&["loppel", "restore", "a.tar.zst"]), None);
+        assert_eq!(
+            window_log(&["loppel", "restore", "--long", "a.tar.zst"]),
+            Some(27)
+        );
+        assert_eq!(
+            window_log(&["loppel", "compare", "--long=30", "a.tar.zst"]),
+            Some(30)
+        );
+        assert_eq!(
+            window_log(&["loppel", "unpack", "--window-log=30", "a.tar.zst"]),
+            Some(30)
+        );
+        assert_eq!(
+            window_log(&["loppel", "list", "--window-log", "30", "a.tar.zst"]),
+            Some(30)
+        );
+        assert!(Cli::try_parse_from(["loppel", "verify", "--long=32", "a.tar.zst"]).is_err());
+        assert!(Cli::try_parse_from(["loppel", "pack", "--window-log=30", "a.bak.d"]).is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_mtime_nanoseconds() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink("foo", src.join("sub").join("link"))?;
+        let then = std::time::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        for name in ["sub/foo", "sub/link", "sub"] {
+            set_mtime(&src.join(name), Some(then))?;
+        }
+
+        let backup = backup_dir(
+            &src,
+            &BackupOptions {
+                compress: Some(Compression::Zstd),
+                ..Default::default()
+            },
+            &mut BackupStats::default(),
+        )?;
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        let opts = RestoreOptions {
+            preserve_mtime_on_dirs: true,
+            ..Default::default()
+        };
+        restore(&backup, &out, &opts)?;
+        for name in ["src/sub/foo", "src/sub/link", "src/sub"] {
+            let meta = fs::symlink_metadata(out.join(name))?;
+            assert_eq!(meta.modified()?, then, "{name}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_overwrite_older() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("edited"), CONTENT)?;
+        fs::write(src.join("sub").join("stale"), CONTENT)?;
+        let opts = RestoreOptions {
+            overwrite_older: true,
+            ..Default::default()
+        };
+        let now = std::time::SystemTime::now();
+
+        for &compress in BACKUP_KINDS {
+            let backup = backup_dir(
+                &src,
+                &BackupOptions {
+                    compress,
+                    hash: Some(HashAlgorithm::Sha256),
+                    ..Default::default()
+                },
+                &mut BackupStats::default(),
+            )?;
+            let out = t.path().join("out");
+            fs::create_dir(&out)?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            let (edited, stale) = (out.join("src/edited"), out.join("src/sub/stale"));
+            fs::write(&edited, b"recent work")?;
+            set_mtime(&edited, Some(now + Duration::from_secs(60)))?;
+            fs::write(&stale, b"old")?;
+            set_mtime(&stale, Some(now - Duration::from_secs(3600 * 24 * 365)))?;
+
+            restore(&backup, &out, &opts)?;
+            assert_eq!(fs::read(&edited)?, b"recent work", "{compress:?}");
+            assert_eq!(fs::read(&stale)?, CONTENT);
+
+            fs::remove_dir_all(&out)?;
+            recursive_remove(&backup)?;
+            fs::remove_file(Manifest::path_for(&backup)?)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_confirm_each() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("foo"), CONTENT)?;
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        let restored = out.join("src/foo");
+
+        // nothing to ask about, or --yes answered already
+        let mut opts = RestoreOptions {
+            confirm_each: true,
+            ..Default::default()
+        };
+        assert!(!keep_existing(&restored, &opts)?);
+        opts.yes = true;
+        for &compress in BACKUP_KINDS {
+            let backup = backup_dir(
+                &src,
+                &BackupOptions {
+                    compress,
+                    ..Default::default()
+                },
+                &mut BackupStats::default(),
+            )?;
+            restore(&backup, &out, &opts)?;
+            fs::write(&restored, b"changed")?;
+            assert!(!keep_existing(&restored, &opts)?);
+            restore(&backup, &out, &opts)?;
+            assert_eq!(fs::read(&restored)?, CONTENT, "{compress:?}");
+            recursive_remove(&backup)?;
+        }
+
+        // what the answers kept is left alone by copies
+        fs::write(&restored, b"changed")?;
+        let keep = BackupOptions {
+            keep: [restored.clone()].into(),
+            ..Default::default()
+        };
+        copy_dir_all(&src, &[out.join("src")], &keep, &mut BackupStats::default())?;
+        assert_eq!(fs::read(&restored)?, b"changed");
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_use_manifest_paths() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path().canonicalize()?;
+        std::env::set_current_dir(&tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("a"), CONTENT)?;
+        fs::create_dir("elsewhere")?;
+
+        let opts = RestoreOptions {
+            use_manifest_paths: true,
+            yes: true,
+            ..Default::default()
+        };
+        for &compress in BACKUP_KINDS {
+            let backup_opts = BackupOptions {
+                compress,
+                prefix: compress.map(|_| PathBuf::from("stored/below")),
+                hash: Some(HashAlgorithm::Sha256),
+                ..Default::default()
+            };
+            let backup = tdir.join(backup_dir(&src, &backup_opts, &mut BackupStats::default())?);
+            let origin = Manifest::for_backup(&backup)?.unwrap().origin.unwrap();
+            assert_eq!(origin.path, tdir.join("src"));
+            fs::remove_dir_all(&src)?;
+
+            // from anywhere, and whatever the entries are named
+            std::env::set_current_dir("elsewhere")?;
+            let mut restore_opts = opts.clone();
+            let out = original_location(&backup, &mut restore_opts)?;
+            assert_eq!(out, tdir);
+            restore(&backup, &out, &restore_opts)?;
+            std::env::set_current_dir(&tdir)?;
+            assert_eq!(
+                fs::read(src.join("sub").join("a"))?,
+                CONTENT,
+                "{compress:?}"
+            );
+
+            let manifest = Manifest::path_for(&backup)?;
+            let content = fs::read_to_string(&manifest)?;
+            let moved = content.replace("origin /", "origin /../");
+            fs::write(&manifest, moved)?;
+            assert!(original_location(&backup, &mut opts.clone()).is_err());
+            fs::remove_file(&manifest)?;
+            assert!(original_location(&backup, &mut opts.clone()).is_err());
+            recursive_remove(&backup)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_since() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("same"), CONTENT)?;
+        fs::write(src.join("sub").join("edited"), b"before")?;
+        fs::create_dir("full")?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let full = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let base = Path::new("full").join(full.file_name().unwrap());
+            fs::rename(&full, &base)?;
+            let mut since = BackupOptions {
+                since: Some(base.clone()),
+                reference: Some(std::rc::Rc::new(since::Reference::read(&base, None)?)),
+                ..opts.clone()
+            };
+            assert!(check_output(&base, &since).is_err());
+
+            fs::write(src.join("sub").join("edited"), b"after")?;
+            fs::write(src.join("new"), CONTENT)?;
+            since.yes = true;
+            let diff = backup_dir(&src, &since, &mut BackupStats::default())?;
+            let mut files = Vec::new();
+            crate::compare::backup_entries(&diff, None, |name, kind, _, _| {
+                if kind == crate::compare::Kind::File {
+                    files.push(name);
+                }
+                Ok(())
+            })?;
+            files.sort();
+            assert_eq!(files, [src.join("new"), src.join("sub").join("edited")]);
+            let manifest = Manifest::for_backup(&diff)?.unwrap();
+            assert_eq!(manifest.since, Some(t.path().canonicalize()?.join(&base)));
+
+            // both together are the files as they are now
+            let out = t.path().join("out");
+            fs::create_dir(&out)?;
+            restore(&base, &out, &RestoreOptions::default())?;
+            restore(
+                &diff,
+                &out,
+                &RestoreOptions {
+                    yes: true,
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(
+                fs::read(out.join("src/sub/edited"))?,
+                b"after",
+                "{compress:?}"
+            );
+            assert_eq!(fs::read(out.join("src/new"))?, CONTENT);
+            assert_eq!(fs::read(out.join("src/same"))?, CONTENT);
+
+            fs::remove_dir_all(&out)?;
+            fs::write(src.join("sub").join("edited"), b"before")?;
+            fs::remove_file(src.join("new"))?;
+            recursive_remove(&diff)?;
+            fs::remove_file(Manifest::path_for(&diff)?)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_list_then_extract() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        for name in ["a", "b", "sub/c"] {
+            fs::write(src.join(name), CONTENT)?;
+        }
+        let backup = backup_dir(
+            &src,
+            &BackupOptions {
+                compress: Some(Compression::Zstd),
+                hash: Some(HashAlgorithm::Sha256),
+                ..Default::default()
+            },
+            &mut BackupStats::default(),
+        )?;
+        let opts = RestoreOptions {
+            list_then_extract: true,
+            ..Default::default()
+        };
+        let out = tdir.join("out");
+        fs::create_dir(&out)?;
+
+        // a file that does not match the manifest keeps all others from being written
+        let good = fs::read(Manifest::path_for(&backup)?)?;
+        let mut manifest = Manifest::read(good.as_slice())?;
+        let last = manifest
+            .entries
+            .iter_mut()
+            .max_by(|a, b| a.name.cmp(&b.name));
+        last.unwrap().hash = "0".repeat(64);
+        manifest.write(fs::File::create(Manifest::path_for(&backup)?)?)?;
+        let e = restore(&backup, &out, &opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read_dir(&out)?.count(), 0);
+
+        fs::write(Manifest::path_for(&backup)?, good)?;
+        restore(&backup, &out, &opts)?;
+        assert_eq!(fs::read(out.join("src").join("sub").join("c"))?, CONTENT);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_ignore_errors() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        for name in ["a", "b"] {
+            fs::write(src.join(name), CONTENT)?;
+        }
+        let noise: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+            .take(1 << 18)
+            .collect();
+        fs::write(src.join("sub").join("c"), noise)?;
+        let backup = backup_dir(
+            &src,
+            &BackupOptions {
+                compress: Some(Compression::Zstd),
+                hash: Some(HashAlgorithm::Sha256),
+                ..Default::default()
+            },
+            &mut BackupStats::default(),
+        )?;
+        let opts = RestoreOptions {
+            ignore_errors: true,
+            yes: true,
+            ..Default::default()
+        };
+        let out = tdir.join("out");
+        fs::create_dir(&out)?;
+
+        // a file that does not match the manifest
+        let mut manifest = Manifest::for_backup(&backup)?.unwrap();
+        manifest.entries[0].hash = "0".repeat(64);
+        let bad = manifest.entries[0].name.clone();
+        manifest.write(fs::File::create(Manifest::path_for(&backup)?)?)?;
+        let e = restore(&backup, &out, &RestoreOptions::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = restore(&backup, &out, &opts).unwrap_err();
+        assert_eq!(e.to_string(), "1 entries could not be restored");
+        assert!(!out.join(&bad).exists());
+        assert_eq!(fs::read_dir(out.join("src"))?.count(), 2);
+        fs::remove_dir_all(&out)?;
+        fs::create_dir(&out)?;
+
+        // an archive that ends in the middle, everything before the damage is restored
+        fs::remove_file(Manifest::path_for(&backup)?)?;
+        let len = fs::metadata(&backup)?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&backup)?
+            .set_len(len / 2)?;
+        assert!(restore(&backup, &out, &RestoreOptions::default()).is_err());
+        assert!(restore(&backup, &out, &opts).is_err());
+        assert_eq!(fs::read(out.join("src").join("a"))?, CONTENT);
+        assert_eq!(fs::read(out.join("src").join("b"))?, CONTENT);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_prefix() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("top").join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("foo"), CONTENT)?;
+        fs::write("single", CONTENT)?;
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            prefix: parse_prefix("./snapshot/").ok(),
+            hash: Some(HashAlgorithm::Sha256),
+            ..Default::default()
+        };
+
+        let dir = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let file = backup_file(Path::new("single"), &opts, &mut BackupStats::default())?;
+        let out = tdir.join("out");
+        fs::create_dir(&out)?;
+        restore(&dir, &out, &RestoreOptions::default())?;
+        restore(&file, &out, &RestoreOptions::default())?;
+        assert_eq!(fs::read(out.join("snapshot/top/src/foo"))?, CONTENT);
+        assert_eq!(fs::read(out.join("snapshot/single"))?, CONTENT);
+
+        assert!(parse_prefix("/abs").is_err());
+        assert!(parse_prefix("a/../b").is_err());
+        assert!(parse_prefix(".").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_absolute_paths() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path().canonicalize()?;
+        std::env::set_current_dir(&tdir)?;
+        let src = tdir.join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        fs::create_dir("elsewhere")?;
+        // stored by their path from the root, however they are given
+        let stored = src.strip_prefix("/").unwrap();
+
+        for &compress in ARCHIVE_KINDS {
+            let opts = BackupOptions {
+                compress: Some(compress),
+                hash: Some(HashAlgorithm::Sha256),
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let out = tdir.join("out");
+            fs::create_dir(&out)?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join(stored).join("sub/foo"))?, CONTENT);
+            let manifest = Manifest::read(io::BufReader::new(fs::File::open(
+                Manifest::path_for(&backup)?,
+            )?))?;
+            let origin = manifest.origin.unwrap();
+            assert_eq!((origin.name.as_path(), origin.path), (stored, src.clone()));
+            read_archive(&backup, None, |a| {
+                let mut first = a.entries()?.next().unwrap()?;
+                assert_eq!(format::origin(&mut first)?, src.to_str().map(String::from));
+                Ok(())
+            })?;
+            recursive_remove(&backup)?;
+            recursive_remove(&out)?;
+
+            let file = backup_file(&src.join("sub/foo"), &opts, &mut BackupStats::default())?;
+            fs::create_dir(&out)?;
+            restore(&file, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join(stored).join("sub/foo"))?, CONTENT);
+            recursive_remove(&file)?;
+            recursive_remove(&out)?;
+        }
+
+        // `..` leaves the working directory, and gets the same names
+        std::env::set_current_dir(tdir.join("elsewhere"))?;
+        let opts = BackupOptions {
+            compress: Some(Compression::None),
+            ..Default::default()
+        };
+        let backup = backup_dir(Path::new("../src"), &opts, &mut BackupStats::default())?;
+        restore(&backup, Path::new("."), &RestoreOptions::default())?;
+        assert_eq!(fs::read(Path::new(stored).join("sub/foo"))?, CONTENT);
+
+        let e = archive_name(Path::new("/"), &opts).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let opts = BackupOptions {
+            prefix: Some(PathBuf::from("root")),
+            ..opts
+        };
+        assert_eq!(archive_name(Path::new("/"), &opts)?, Path::new("root"));
+        assert_eq!(
+            archive_name(Path::new("./a/b"), &opts)?,
+            Path::new("root/a/b")
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_flatten() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("top").join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("foo"), CONTENT)?;
+        fs::write(src.join("sub").join("bar"), CONTENT)?;
+        let opts = RestoreOptions {
+            flatten: true,
+            ..Default::default()
+        };
+
+        for &compress in BACKUP_KINDS {
+            let backup = backup_dir(
+                &src,
+                &BackupOptions {
+                    compress,
+                    hash: Some(HashAlgorithm::Sha256),
+                    ..Default::default()
+                },
+                &mut BackupStats::default(),
+            )?;
+            let out = tdir.join("out");
+            fs::create_dir(&out)?;
+            restore(&backup, &out, &opts)?;
+            assert_eq!(fs::read(out.join("foo"))?, CONTENT, "{compress:?}");
+            assert_eq!(fs::read(out.join("sub").join("bar"))?, CONTENT);
+            assert!(!out.join("src").exists());
+
+            fs::remove_dir_all(&out)?;
+            recursive_remove(&backup)?;
+            fs::remove_file(Manifest::path_for(&backup)?)?;
+        }
+
+        // a single file has no directory to flatten
+        let backup = backup_file(
+            &src.join("foo"),
+            &BackupOptions::default(),
+            &mut BackupStats::default(),
+        )?;
+        let e = restore(&backup, tdir, &opts).unwrap_err();
+        assert!(
+            e.to_string().contains("no single top level directory: foo"),
+            "{e}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_mount_point() -> io::Result<()> {
+        assert!(is_mount_point(Path::new("/"))?);
+        let t = tempdir()?;
+        fs::create_dir(t.path().join("sub"))?;
+        assert!(!is_mount_point(&t.path().join("sub"))?);
+
+        let opts = BackupOptions {
+            no_mount_root: true,
+            ..Default::default()
+        };
+        let e = backup_dir(Path::new("/"), &opts, &mut BackupStats::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_touch() -> io::Result<()> {
+        let t = tempdir()?;
+        let then = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mtime = |path: &Path| fs::symlink_metadata(path).and_then(|m| m.modified());
+        let dir = t.path().join("dir.bak.d");
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("foo"), CONTENT)?;
+        let link = t.path().join("link.bak");
+        std::os::unix::fs::symlink("dir.bak.d", &link)?;
+        for path in [&dir, &dir.join("foo")] {
+            fs::File::open(path)?.set_modified(then)?;
+        }
+
+        touch(&dir)?;
+        assert!(mtime(&dir)? > then);
+        assert_eq!(mtime(&dir.join("foo"))?, then);
+        // the link itself, not what it points to
+        fs::File::open(&dir)?.set_modified(then)?;
+        touch(&link)?;
+        assert_eq!(mtime(&dir)?, then);
+        assert!(touch(&t.path().join("missing")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_preserve_mtime_on_dirs() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        let (sub, empty) = (src.join("sub"), src.join("empty"));
+        fs::create_dir_all(&sub)?;
+        fs::create_dir_all(&empty)?;
+        fs::write(sub.join("foo"), CONTENT)?;
+        let then = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for dir in [&sub, &empty, &src] {
+            fs::File::open(dir)?.set_modified(then)?;
+        }
+        let mtime = |path: &Path| fs::metadata(path).and_then(|m| m.modified());
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                store_empty_as_placeholder: true,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let out = tdir.join("out");
+            fs::create_dir(&out)?;
+            let opts = RestoreOptions {
+                preserve_mtime_on_dirs: true,
+                ..Default::default()
+            };
+            restore(&backup, &out, &opts)?;
+            // the placeholder in empty is removed last, which would change its time
+            assert!(fs::read_dir(out.join("src").join("empty"))?
+                .next()
+                .is_none());
+            for dir in [&sub, &empty, &src] {
+                assert_eq!(mtime(&out.join(dir))?, then, "{dir:?} {compress:?}");
+            }
+
+            fs::remove_dir_all(&out)?;
+            recursive_remove(&backup)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_frame_checksum() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("random");
+        fastrand::seed(148);
+        // incompressible, so zstd stores it as it is and a flipped bit still decodes
+        let content: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+            .take(1 << 16)
+            .collect();
+
+        for no_frame_checksum in [false, true] {
+            fs::write(&src, &content)?;
+            let opts = BackupOptions {
+                compress: Some(Compression::Zstd),
+                no_frame_checksum,
+                ..Default::default()
+            };
+            let backup = backup_file(&src, &opts, &mut BackupStats::default())?;
+            let mut archive = fs::read(&backup)?;
+            let middle = archive.len() / 2;
+            archive[middle] ^= 1;
+            fs::write(&backup, archive)?;
+
+            let result = read_archive(&backup, None, |a| a.unpack(tdir.join("out")));
+            if no_frame_checksum {
+                result?;
+            } else {
+                let e = result.unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                assert!(e.to_string().contains("is corrupt"), "{e}");
+            }
+            fs::remove_file(&backup)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_level_rule() {
+        let rule: LevelRule = "JPG=-5".parse().unwrap();
+        assert_eq!(rule.level, -5);
+        assert!(rule.matches(OsStr::new("photo.jpg")));
+        assert!(rule.matches(OsStr::new("photo.JPG")));
+        assert!(!rule.matches(OsStr::new("photo.jpgx")));
+        let rule: LevelRule = ".txt=19".parse().unwrap();
+        assert!(rule.matches(OsStr::new("a.b.txt")));
+        let rule: LevelRule = "notes-*.md=22".parse().unwrap();
+        assert!(rule.matches(OsStr::new("notes-1.md")));
+        assert!(!rule.matches(OsStr::new("NOTES-1.md")));
+        for bad in ["txt", "txt=0", "txt=23", "txt=-8", "=3", "txt=high"] {
+            assert!(bad.parse::<LevelRule>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_detect_compressed() {
+        fastrand::seed(164);
+        let random: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+            .take(8192)
+            .collect();
+        let text = b"all work and no play makes jack a dull boy\n".repeat(200);
+        let opts = BackupOptions {
+            level: Some(19),
+            detect_compressed: true,
+            compress_level_per_extension: vec!["*.keep=9".parse().unwrap()],
+            ..Default::default()
+        };
+        let level = |name: &str, sample: &[u8]| level_for(Path::new(name), sample, &opts);
+
+        assert_eq!(level("notes.txt", &text), 19);
+        assert_eq!(level("photo.JPG", &text), 1);
+        assert_eq!(level("blob", &random), 1);
+        // too short to tell
+        assert_eq!(level("blob", &random[..100]), 19);
+        assert_eq!(level("blob.keep", &random), 9);
+        let off = BackupOptions {
+            detect_compressed: false,
+            ..opts.clone()
+        };
+        assert_eq!(level_for(Path::new("photo.jpg"), &random, &off), 19);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_compress_level_per_extension() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.txt"), b"text ".repeat(1000))?;
+        fs::write(src.join("b.JPG"), b"jpeg ".repeat(1000))?;
+        fs::write(src.join("c.txt"), b"more text ".repeat(1000))?;
+        fs::write(src.join("d"), b"other")?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            compress_level_per_extension: vec![
+                "*.txt=19".parse().unwrap(),
+                "jpg=1".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        // every change of the level starts a new frame
+        let archive = fs::read(&backup)?;
+        let frames = archive
+            .windows(4)
+            .filter(|w| *w == [0x28, 0xb5, 0x2f, 0xfd])
+            .count();
+        assert!(frames > 1, "{frames} frames");
+        assert!(compare(&backup, Path::new("."), None)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_write_stored() -> io::Result<()> {
+        use crate::{stored_size, write_stored};
+
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut archive = zstd::encode_all(&b"before"[..], 3)?;
+        for data in [&content[..], b""] {
+            let start = archive.len();
+            write_stored(&mut archive, data, data.len() as u64)?;
+            assert_eq!(
+                archive.len() - start,
+                stored_size(data.len() as u64) as usize
+            );
+        }
+        let mut expected = b"before".to_vec();
+        expected.extend_from_slice(&content);
+        assert_eq!(zstd::decode_all(&archive[..])?, expected);
+
+        // the blocks are counted, not taken from the content
+        assert!(write_stored(&mut Vec::new(), &b"short"[..], 6).is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_store_incompressible() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(&src)?;
+        fastrand::seed(184);
+        let noise: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+            .take(300_000)
+            .collect();
+        fs::write(src.join("noise.bin"), &noise)?;
+        fs::write(
+            src.join("small.bin"),
+            fastrand::u64(..).to_le_bytes().repeat(100),
+        )?;
+        fs::write(src.join("text.txt"), b"some text ".repeat(20_000))?;
+
+        for store_incompressible in [false, true] {
+            let opts = BackupOptions {
+                compress: Some(Compression::Zstd),
+                store_incompressible,
+                ..Default::default()
+            };
+            let mut stats = BackupStats::default();
+            let backup = backup_dir(&src, &opts, &mut stats)?;
+            // only the noise is large enough and does not compress
+            assert_eq!(stats.stored, u64::from(store_incompressible));
+            assert!(compare(&backup, Path::new("."), None)?.is_empty());
+            let size = fs::metadata(&backup)?.len();
+            assert!(size < noise.len() as u64 + 4096, "{size}");
+            fs::remove_file(&backup)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_empty_files() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let marker = PathBuf::from("marker");
+        let dir = PathBuf::from("dir");
+
+        for &compress in BACKUP_KINDS {
+            for hash in [None, Some(HashAlgorithm::Sha256)] {
+                fs::create_dir_all(&dir)?;
+                fs::write(&marker, b"")?;
+                fs::write(dir.join("marker"), b"")?;
+                let opts = BackupOptions {
+                    compress,
+                    hash,
+                    ..Default::default()
+                };
+                let mut stats = BackupStats::default();
+                let file_backup = backup_file(&marker, &opts, &mut stats)?;
+                let dir_backup = backup_dir(&dir, &opts, &mut stats)?;
+                assert!(stats.errors.is_empty(), "{opts:?}");
+                assert_eq!(stats.extensions.values().map(|c| c.0).sum::<u64>(), 2);
+
+                fs::remove_file(&marker)?;
+                fs::remove_dir_all(&dir)?;
+                let opts = RestoreOptions {
+                    yes: true,
+                    ..Default::default()
+                };
+                restore(&file_backup, tdir, &opts)?;
+                restore(&dir_backup, tdir, &opts)?;
+                assert_eq!(fs::read(&marker)?, b"", "{compress:?} {hash:?}");
+                assert_eq!(fs::read(dir.join("marker"))?, b"", "{compress:?} {hash:?}");
+
+                recursive_remove(&file_backup)?;
+                recursive_remove(&dir_backup)?;
+                for backup in [&file_backup, &dir_backup] {
+                    let _ = fs::remove_file(Manifest::path_for(backup)?);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_bak_restore() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        let tdir_a = tdir.join("ichi");
+        let tdir_b = tdir_a.join("ni");
+        let dirs = [&tdir_a, &tdir_b];
+        let names = ["foo", "bar", "qux"];
+        fastrand::seed(133719);
+
+        let mut contents: Vec<[u8; 16]> = vec![];
+        for _ in 0..(dirs.len() * names.len()) {
+            contents.push(fastrand::u128(0..u128::MAX).to_le_bytes());
+        }
+
+        let mut i = 0;
+        for sdir in dirs {
+            fs::create_dir_all(sdir)?;
+            assert!(sdir.exists());
+            assert!(sdir.is_dir());
+            for fname in names {
+                let p = sdir.join(fname);
+                fs::write(&p, contents[i])?;
+                assert!(p.exists());
+                assert!(p.is_file());
+                assert!(p.is_file());
+                let raw_size = filesize(&p)?;
+                assert!(raw_size > 1, "raw size of {} was {raw_size}", p.display());
+                i += 1;
+            }
+        }
+
+        let backup = backup_dir(
+            &tdir_a,
+            &BackupOptions::default(),
+            &mut BackupStats::default(),
+        )?;
+        dbg!(&tdir_a);
+        dbg!(fs::metadata(&tdir_a)?);
+        fs::remove_dir_all(&tdir_a)?;
+        dbg!(&backup);
+        dbg!(fs::metadata(&backup)?);
+        restore(&backup, tdir, &RestoreOptions::default())?;
+        dbg!(&tdir_a);
+        dbg!(fs::metadata(&tdir_a)?);
+
+        let mut i = 0;
+        for sdir in dirs {
+            assert!(sdir.exists());
+            assert!(sdir.is_dir());
+            for fname in names {
+                let p = sdir.join(fname);
+                assert!(p.exists());
+                assert!(p.is_file());
+                let actual = fs::read(&p)?;
+                assert_eq!(actual, contents[i]);
+                i += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_dir_archive_skip_unreadable() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        let secret = src.join("secret");
+        fs::create_dir_all(&secret)?;
+        fs::write(src.join("foo"), CONTENT)?;
+        fs::write(secret.join("bar"), CONTENT)?;
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o000))?;
+        // with privileges, nothing is unreadable
+        let privileged = fs::read_dir(&secret).is_ok();
+
+        let mut opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        if !privileged {
+            assert!(backup_dir(&src, &opts, &mut BackupStats::default()).is_err());
+        }
+
+        opts.skip_unreadable = true;
+        let mut stats = BackupStats::default();
+        let backup = backup_dir(&src, &opts, &mut stats)?;
+        if privileged {
+            assert!(stats.errors.is_empty());
+        } else {
+            assert_eq!(stats.errors.len(), 1);
+            assert_eq!(stats.errors[0].0, secret);
+        }
+        fs::set_permissions(&secret, fs::Permissions::from_mode(0o755))?;
+        fs::remove_dir_all(&src)?;
+
+        restore(&backup, tdir, &RestoreOptions::default())?;
+        assert_eq!(fs::read(src.join("foo"))?, CONTENT);
+        assert_eq!(src.join("secret").join("bar").exists(), privileged);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_long_archive_restore() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let tfile = PathBuf::from("foo");
+        fs::write(&tfile, CONTENT)?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            long: Some(28),
+            ..Default::default()
+        };
+        let backup = backup_file(&tfile, &opts, &mut BackupStats::default())?;
+        fs::remove_file(&tfile)?;
+
+        // the default decoder window is too small
+        assert!(restore(&backup, tdir, &RestoreOptions::default()).is_err());
+        assert!(!tfile.exists());
+
+        let opts = RestoreOptions {
+            window: WindowLog { long: Some(28) },
+            ..Default::default()
+        };
+        restore(&backup, tdir, &opts)?;
+        assert_eq!(fs::read(&tfile)?, CONTENT);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_max_memory() -> io::Result<()> {
+        assert_eq!(window_log_limit(None, 64 << 20)?, 26);
+        assert_eq!(window_log_limit(None, usize::MAX)?, DEFAULT_WINDOW_LOG_MAX);
+        assert_eq!(window_log_limit(Some(30), 100 << 20)?, 26);
+        assert_eq!(window_log_limit(Some(20), 1 << 30)?, 20);
+        assert!(window_log_limit(None, 1000).is_err());
+
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let tfile = PathBuf::from("foo");
+        let data: Vec<u8> = (0..1 << 20).map(|_| fastrand::u8(..)).collect();
+        fs::write(&tfile, &data)?;
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        let backup = backup_file(&tfile, &opts, &mut BackupStats::default())?;
+        fs::remove_file(&tfile)?;
+
+        let opts = RestoreOptions {
+            window: WindowLog {
+                long: Some(window_log_limit(None, 64 << 10)?),
+            },
+            ..Default::default()
+        };
+        let e = restore(&backup, tdir, &opts).unwrap_err();
+        assert!(e.to_string().contains("--max-memory"), "{e}");
+        assert!(!tfile.exists());
+
+        restore(&backup, tdir, &RestoreOptions::default())?;
+        assert_eq!(fs::read(&tfile)?, data);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_archive_format() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        let deep = src.join("d".repeat(120)).join("e".repeat(120));
+        fs::create_dir_all(&deep)?;
+        fs::write(deep.join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink(deep.join("f".repeat(100)), src.join("link"))?;
+
+        for archive_format in [format::ArchiveFormat::Pax, format::ArchiveFormat::Gnu] {
+            let opts = BackupOptions {
+                compress: Some(Compression::Zstd),
+                archive_format,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let out = tdir.join("out");
+            fs::create_dir(&out)?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join(deep.join("foo")))?, CONTENT);
+            assert_eq!(
+                fs::read_link(out.join("src/link"))?,
+                deep.join("f".repeat(100))
+            );
+            fs::remove_dir_all(&out)?;
+            fs::remove_file(&backup)?;
+        }
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            archive_format: format::ArchiveFormat::Ustar,
+            ..Default::default()
+        };
+        assert!(backup_dir(&src, &opts, &mut BackupStats::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_restore_dir() -> io::Result<()> {
+        let t = tempdir()?;
+        let first = new_restore_dir(t.path())?;
+        let second = new_restore_dir(t.path())?;
+        assert!(first.is_dir() && second.is_dir());
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("restore-20"), "{name}");
+        // both in the same second, or the next one
+        let second = second.file_name().unwrap().to_str().unwrap();
+        assert!(second == format!("{name}-2") || second > name, "{second}");
+        assert!(new_restore_dir(&t.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_reflink_backup() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        fs::set_permissions(src.join("sub/foo"), fs::Permissions::from_mode(0o640))?;
+
+        // falls back to copying where files can not be cloned
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                reflink: true,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let out = tdir.join("out");
+            fs::create_dir(&out)?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            let foo = out.join("src/sub/foo");
+            assert_eq!(fs::read(&foo)?, CONTENT, "{compress:?}");
+            assert_eq!(fs::metadata(&foo)?.mode() & 0o777, 0o640);
+            assert_eq!(fs::read_dir(src.join("sub"))?.count(), 1);
+            fs::remove_dir_all(&out)?;
+            recursive_remove(&backup)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_collisions() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        fs::write("foo", CONTENT)?;
+        fs::write("foo.bak", CONTENT)?;
+        fs::create_dir("dir")?;
+        let paths: Vec<(PathBuf, Option<Compression>)> = ["foo", "foo.bak", "bar", "./dir", "dir/"]
+            .iter()
+            .map(|path| (PathBuf::from(path), None))
+            .collect();
+        fs::write("bar", CONTENT)?;
+        let collisions = find_collisions(&paths, &BackupOptions::default());
+        let mut found: Vec<_> = collisions.keys().copied().collect();
+        found.sort();
+        assert_eq!(found, [0, 4]);
+        assert!(collisions[&0].to_string().contains("foo.bak"));
+        assert!(collisions[&4].to_string().contains("./dir"));
+
+        // archives do not collide with the copy
+        let mut paths = paths;
+        paths[0].1 = Some(Compression::Zstd);
+        paths.truncate(3);
+        assert!(find_collisions(&paths, &BackupOptions::default()).is_empty());
+
+        let opts = BackupOptions::default();
+        fs::create_dir("bar.bak")?;
+        assert!(check_output(Path::new("dir.bak.d"), &opts).is_ok());
+        fs::write("dir.bak.d", CONTENT)?;
+        let opts = BackupOptions {
+            yes: true,
+            ..Default::default()
+        };
+        check_output(Path::new("bar.bak"), &opts)?;
+        assert!(!Path::new("bar.bak").exists());
+        check_output(Path::new("dir.bak.d"), &opts)?;
+        assert!(!Path::new("dir.bak.d").exists());
+        fs::write("bar.bak", CONTENT)?;
+        check_output(Path::new("bar.bak"), &opts)?;
+        assert!(Path::new("bar.bak").exists());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_nameless_paths() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        fs::write("foo", CONTENT)?;
+        for path in [".", "..", "/", "foo/.."] {
+            let e = add_extension(Path::new(path), ".bak").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{path}");
+            assert!(output_path(Path::new(path), true, &BackupOptions::default()).is_err());
+        }
+        assert_eq!(
+            add_extension(Path::new("./foo"), ".bak")?,
+            Path::new("./foo.bak")
+        );
+        assert_eq!(
+            add_extension(Path::new("dir/"), ".bak.d")?,
+            Path::new("dir.bak.d")
+        );
+
+        let paths: Vec<(PathBuf, Option<Compression>)> = [".", "foo", "/"]
+            .iter()
+            .map(|path| (PathBuf::from(path), None))
+            .collect();
+        let collisions = find_collisions(&paths, &BackupOptions::default());
+        let mut found: Vec<_> = collisions.keys().copied().collect();
+        found.sort();
+        assert_eq!(found, [0, 2]);
+        assert_eq!(collisions[&0].kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_nameless_backups() -> io::Result<()> {
+        let t = tempdir()?;
+        let dir = t.path().join("dir");
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("foo"), CONTENT)?;
+        std::env::set_current_dir(&dir)?;
+        for compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress: *compress,
+                ..Default::default()
+            };
+            for path in [".", "..", "/"] {
+                let path = Path::new(path);
+                let mut stats = BackupStats::default();
+                let e = backup_dir(path, &opts, &mut stats).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{path:?}");
+                let e = backup_file(path, &opts, &mut stats).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{path:?}");
+                let e = manifest_only(path, &opts, &mut stats).unwrap_err();
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{path:?}");
+                assert_eq!(stats.files, 0);
+            }
+        }
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+        assert_eq!(fs::read_dir(t.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overhead_warning() {
+        let opts = BackupOptions {
+            compress: Some(Compression::None),
+            small_file_threshold: 4096,
+            bytes: true,
+            ..Default::default()
+        };
+        let warning = overhead_warning(20480, (10, 1000), &opts).unwrap();
+        assert!(warning.contains("10 files are 100 on average"), "{warning}");
+        assert!(warning.contains("19480 or 95%"), "{warning}");
+        assert_eq!(overhead_warning(1 << 20, (10, 1 << 19), &opts), None);
+        assert_eq!(overhead_warning(1024, (0, 0), &opts), None);
+
+        let zstd = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..opts.clone()
+        };
+        assert_eq!(overhead_warning(20480, (10, 1000), &zstd), None);
+        let off = BackupOptions {
+            small_file_threshold: 0,
+            ..opts
+        };
+        assert_eq!(overhead_warning(20480, (10, 1000), &off), None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_threads() -> io::Result<()> {
+        // the tables of level 19 are 80 MiB, its jobs 32 MiB and its window 8 MiB
+        assert_eq!(zstd_memory(19, None, 8), (8 * (80 + 2 * 32 + 8)) << 20);
+        assert_eq!(zstd_memory(3, None, 1), (2 << 20) + (768 << 10));
+        assert!(zstd_memory(3, Some(27), 1) > 1 << 27);
+        assert!(zstd_memory(1, None, 2) > zstd_memory(1, None, 1));
+        assert!(available_memory().is_some());
+
+        let opts = BackupOptions {
+            bytes: true,
+            ..Default::default()
+        };
+        let warning = memory_warning(2 << 30, Some(1 << 30), &opts).unwrap();
+        assert!(warning.contains("2147483648"), "{warning}");
+        assert!(warning.contains("--threads"), "{warning}");
+        assert_eq!(memory_warning(1 << 20, Some(1 << 30), &opts), None);
+        assert_eq!(memory_warning(1 << 20, None, &opts), None);
+
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        let content: Vec<u8> = (0..3 << 20).map(|i: u32| (i % 251) as u8).collect();
+        fs::write(src.join("foo"), &content)?;
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            threads: 4,
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        fs::remove_dir_all(&src)?;
+        restore(&backup, t.path(), &RestoreOptions::default())?;
+        assert_eq!(fs::read(src.join("foo"))?, content);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_plain_tar_restore() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::None),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert_eq!(backup, PathBuf::from("src.tar"));
+        fs::remove_dir_all(&src)?;
+
+        restore(&backup, tdir, &RestoreOptions::default())?;
+        assert_eq!(fs::read(src.join("sub").join("foo"))?, CONTENT);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_path_compressions() {
+        std::env::remove_var("LOPPLER_COMPRESS");
+        let compressions = |args: &[&str]| {
+            let matches = Cli::command().get_matches_from(args);
+            let mut opts = match Cli::from_arg_matches(&matches).unwrap().command {
+                Some(Commands::Backup { opts, .. }) => *opts,
+                _ => unreachable!(),
+            };
+            (path_compressions(&matches, &mut opts), opts.compress)
+        };
+        let (z, none) = (Some(Compression::Zstd), Some(Compression::None));
+
+        assert_eq!(
+            compressions(&["loppel", "backup", "a", "b"]),
+            (vec![None, None], None)
+        );
+        assert_eq!(
+            compressions(&["loppel", "backup", "-z", "big", "--no-compress", "small"]),
+            (vec![z, None], None)
+        );
+        // flags after all paths apply to all of them
+        assert_eq!(
+            compressions(&["loppel", "backup", "a", "b", "-z"]),
+            (vec![z, z], z)
+        );
+        assert_eq!(
+            compressions(&[
+                "loppel",
+                "backup",
+                "a",
+                "-z=none",
+                "b",
+                "-z",
+                "c",
+                "--no-compress"
+            ]),
+            (vec![None, none, z], None)
+        );
+
+        std::env::set_var("LOPPLER_COMPRESS", "zstd");
+        assert_eq!(
+            compressions(&["loppel", "backup", "a", "--no-compress", "b"]),
+            (vec![None, None], None)
+        );
+        assert_eq!(
+            compressions(&["loppel", "backup", "--compress=none", "a", "b"]),
+            (vec![none, none], none)
+        );
+        std::env::remove_var("LOPPLER_COMPRESS");
+        assert_eq!(compressions(&["loppel", "backup", "a"]), (vec![None], None));
+    }
+
+    #[test]
+    #[serial]
+    fn test_env_defaults() {
+        let backup_opts = |args: &[&str]| match Cli::parse_from(args).command {
+            Some(Commands::Backup { opts, .. }) => opts,
+            _ => unreachable!(),
+        };
+
+        std::env::set_var("LOPPLER_COMPRESS", "none");
+        std::env::set_var("LOPPLER_LEVEL", "19");
+        let opts = backup_opts(&["loppel", "backup", "foo"]);
+        assert_eq!(opts.compress, Some(Compression::None));
+        assert_eq!(opts.level, Some(19));
+
+        // flags take precedence
+        let opts = backup_opts(&["loppel", "backup", "-z", "--level", "5", "foo"]);
+        assert_eq!(opts.compress, Some(Compression::Zstd));
+        assert_eq!(opts.level, Some(5));
+        let opts = backup_opts(&["loppel", "backup", "--no-compress", "foo"]);
+        assert!(opts.no_compress);
+
+        std::env::remove_var("LOPPLER_COMPRESS");
+        std::env::remove_var("LOPPLER_LEVEL");
+        // switches take the values of container setups
+        for (value, confirm) in [("1", true), ("yes", true), ("on", true), ("0", false)] {
+            std::env::set_var("LOPPLER_YES", value);
+            let cli = Cli::try_parse_from(["loppel", "backup", "foo"]).unwrap();
+            assert_eq!(cli.confirm, confirm, "{value}");
+        }
+        std::env::set_var("LOPPLER_YES", "off");
+        assert!(
+            Cli::try_parse_from(["loppel", "-y", "backup", "foo"])
+                .unwrap()
+                .confirm
+        );
+        std::env::remove_var("LOPPLER_YES");
+        let opts = backup_opts(&["loppel", "backup", "foo"]);
+        assert_eq!(opts.compress, None);
+        assert_eq!(opts.level, None);
+        assert_eq!(compression_level(&opts), 3);
+    }
+
+    #[test]
+    #[serial]
+    fn test_preset() {
+        let backup_opts = |args: &[&str]| -> Result<BackupOptions, clap::Error> {
+            let matches = Cli::command().try_get_matches_from(args)?;
+            let mut opts = match Cli::from_arg_matches(&matches)?.command {
+                Some(Commands::Backup { opts, .. }) => *opts,
+                _ => unreachable!(),
+            };
+            preset_level(&matches, &mut opts)?;
+            Ok(opts)
+        };
+        std::env::remove_var("LOPPLER_LEVEL");
+
+        let opts = backup_opts(&["loppel", "backup", "--preset", "best", "foo"]).unwrap();
+        assert_eq!(compression_level(&opts), 19);
+        let opts = backup_opts(&["loppel", "backup", "--preset", "fast", "foo"]).unwrap();
+        assert_eq!(compression_level(&opts), 1);
+        let opts = backup_opts(&["loppel", "backup", "--level", "7", "foo"]).unwrap();
+        assert_eq!(compression_level(&opts), 7);
+        assert!(backup_opts(&["loppel", "backup", "--preset", "fast", "-l", "3", "foo"]).is_err());
+
+        // a preset on the command line wins over the level of the environment
+        std::env::set_var("LOPPLER_LEVEL", "5");
+        let opts = backup_opts(&["loppel", "backup", "-z", "--preset", "best", "foo"]).unwrap();
+        assert_eq!(compression_level(&opts), 19);
+        let opts = backup_opts(&["loppel", "backup", "-z", "foo"]).unwrap();
+        assert_eq!(compression_level(&opts), 5);
+        std::env::remove_var("LOPPLER_LEVEL");
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_reproducible_archive() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        for name in ["c", "a", "b"] {
+            fs::write(src.join(name), name)?;
+        }
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            reproducible: true,
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let first = fs::read(&backup)?;
+
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(src.join("a"))?
+            .set_modified(later)?;
+        backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert_eq!(fs::read(&backup)?, first);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retries() {
+        let path = Path::new("foo");
+        let mut opts = BackupOptions::default();
+        let mut failures = 2;
+        let mut flaky = || {
+            if failures > 0 {
+                failures -= 1;
+                Err(io::Error::new(io::ErrorKind::TimedOut, "hiccup"))
+            } else {
+                Ok(())
+            }
+        };
+
+        assert!(with_retries(path, &opts, &mut flaky).is_err());
+        opts.retries = 1;
+        assert!(with_retries(path, &opts, &mut flaky).is_ok());
+
+        let mut missing = || Err::<(), _>(io::Error::from(io::ErrorKind::NotFound));
+        assert!(with_retries(path, &opts, &mut missing).is_err());
+
+        let ms = std::time::Duration::from_millis;
+        assert_eq!(retry_delay(0), ms(100));
+        assert_eq!(retry_delay(1), ms(200));
+        assert_eq!(retry_delay(5), ms(3200));
+        assert_eq!(retry_delay(6), ms(3200));
+        assert_eq!(retry_delay(u32::MAX), ms(3200));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0, false), "0 B");
+        assert_eq!(format_size(1023, false), "1023 B");
+        assert_eq!(format_size(1536, false), "1.5 KiB");
+        assert_eq!(format_size(1288490189, false), "1.2 GiB");
+        assert_eq!(format_size(1288490189, true), "1288490189");
+    }
+
+    #[test]
+    #[serial]
+    fn test_extension_table() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.log"), [0; 300])?;
+        fs::write(src.join("b.log"), [0; 300])?;
+        fs::write(src.join("c.txt"), [0; 200])?;
+        fs::write(src.join("README"), [0; 200])?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let mut stats = BackupStats::default();
+            backup_dir(&src, &opts, &mut stats)?;
+            assert_eq!(
+                stats.extension_table(false),
+                concat!(
+                    "extension           files       size  share\n",
+                    ".log                    2      600 B  60.0%\n",
+                    "(none)                  1      200 B  20.0%\n",
+                    ".txt                    1      200 B  20.0%\n",
+                )
+            );
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_restore_prefix_map() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("alice");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+
+        let opts = RestoreOptions {
+            prefix_map: vec!["alice=users/bob".parse().unwrap()],
+            ..Default::default()
+        };
+        restore(&backup, tdir, &opts)?;
+        assert_eq!(fs::read(tdir.join("users/bob/sub/foo"))?, CONTENT);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_restore_verifies_manifest() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("alice");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            hash: Some(HashAlgorithm::Blake3),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert!(Manifest::path_for(&backup)?.exists());
+        let opts = RestoreOptions {
+            prefix_map: vec!["alice=bob".parse().unwrap()],
+            ..Default::default()
+        };
+        restore(&backup, tdir, &opts)?;
+        let restored = PathBuf::from("bob/sub/foo");
+        assert_eq!(fs::read(&restored)?, CONTENT);
+        // the times and permissions are restored like tar does
+        let (src_meta, meta) = (
+            fs::metadata(src.join("sub").join("foo"))?,
+            fs::metadata(&restored)?,
+        );
+        assert_eq!(meta.mtime(), src_meta.mtime());
+        assert_eq!(meta.mode(), src_meta.mode());
+
+        // archives are checked while unpacking, a mismatching file is removed again
+        let mut manifest = Manifest::for_backup(&backup)?.unwrap();
+        manifest.entries[0].hash = "00".to_string();
+        manifest.write(fs::File::create(Manifest::path_for(&backup)?)?)?;
+        let out = tdir.join("streamed");
+        fs::create_dir(&out)?;
+        let err = restore(&backup, &out, &RestoreOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!out.join("alice/sub/foo").exists());
+
+        // what the file was before is kept when the archived one does not match
+        fs::write(out.join("alice/sub/foo"), b"edited")?;
+        let opts = RestoreOptions {
+            yes: true,
+            ..Default::default()
+        };
+        let err = restore(&backup, &out, &opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(out.join("alice/sub/foo"))?, b"edited");
+        assert_eq!(fs::read_dir(out.join("alice/sub"))?.count(), 1);
+
+        let file = PathBuf::from("file");
+        fs::write(&file, CONTENT)?;
+        let opts = BackupOptions {
+            hash: Some(HashAlgorithm::Xxh3),
+            ..Default::default()
+        };
+        let backup = backup_file(&file, &opts, &mut BackupStats::default())?;
+        fs::write(&backup, b"corrupted")?;
+        let out = tdir.join("out");
+        fs::create_dir(&out)?;
+        let err = restore(&backup, &out, &RestoreOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_chmod() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        fs::set_permissions(
+            src.join("sub").join("foo"),
+            fs::Permissions::from_mode(0o600),
+        )?;
+
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        let opts = RestoreOptions {
+            chmod: Some(0o750),
+            ..Default::default()
+        };
+        for &compress in BACKUP_KINDS {
+            let backup_opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &backup_opts, &mut BackupStats::default())?;
+            restore(&backup, &out, &opts)?;
+            let restored = out.join("src");
+            for path in [
+                restored.clone(),
+                restored.join("sub"),
+                restored.join("sub/foo"),
+            ] {
+                assert_eq!(fs::metadata(&path)?.mode() & 0o7777, 0o750, "{path:?}");
+            }
+            fs::remove_dir_all(restored)?;
+        }
+        assert_eq!(parse_mode("0o2775"), Ok(0o2775));
+        assert!(parse_mode("888").is_err());
+        assert!(parse_mode("17777").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_buffer_size() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(src.join("foo"), &content)?;
+        fs::set_permissions(src.join("foo"), fs::Permissions::from_mode(0o640))?;
+
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                buffer_size: Some(100),
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join("src/foo"))?, content);
+            let mode = fs::metadata(out.join("src/foo"))?.mode() & 0o777;
+            assert_eq!(mode, 0o640);
+            fs::remove_dir_all(out.join("src"))?;
+        }
+
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1.5 MiB"), Ok(1536 * 1024));
+        assert_eq!(parse_size("1mb"), Ok(1 << 20));
+        assert_eq!(parse_size("2 KB"), Ok(2048));
+        assert!(parse_size("12 parsecs").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_special_files() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        unpack::mknod(&src.join("fifo"), libc::S_IFIFO | 0o640, 0)?;
+        // like /dev/null, only works with privileges
+        let null = libc::makedev(1, 3);
+        let privileged = unpack::mknod(&src.join("null"), libc::S_IFCHR | 0o666, null).is_ok();
+
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+
+            let fifo = fs::symlink_metadata(out.join("src/fifo"))?;
+            assert!(fifo.file_type().is_fifo());
+            assert_eq!(fifo.mode() & 0o777, 0o640);
+            if privileged {
+                let dev = fs::symlink_metadata(out.join("src/null"))?;
+                assert!(dev.file_type().is_char_device());
+                assert_eq!(dev.rdev(), null);
+            }
+            fs::remove_dir_all(out.join("src"))?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_symlinks() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let real = PathBuf::from("real");
+        fs::create_dir_all(real.join("sub"))?;
+        fs::write(real.join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink("foo", real.join("link"))?;
+        std::os::unix::fs::symlink("sub", real.join("dirlink"))?;
+        let root = PathBuf::from("root");
+        std::os::unix::fs::symlink(&real, &root)?;
+
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        for &compress in BACKUP_KINDS {
+            // symlinks are kept by default
+            let opts = BackupOptions {
+                compress,
+                dereference_root: true,
+                ..Default::default()
+            };
+            let backup = backup_dir(&root, &opts, &mut BackupStats::default())?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join("root/foo"))?, CONTENT);
+            assert_eq!(fs::read_link(out.join("root/link"))?, PathBuf::from("foo"));
+            assert_eq!(
+                fs::read_link(out.join("root/dirlink"))?,
+                PathBuf::from("sub")
+            );
+            assert!(compare(&backup, &out, None)?.is_empty());
+            fs::remove_dir_all(out.join("root"))?;
+            recursive_remove(&backup)?;
+
+            let opts = BackupOptions {
+                compress,
+                follow_symlinks: true,
+                ..Default::default()
+            };
+            let backup = backup_dir(&root, &opts, &mut BackupStats::default())?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            assert!(!out.join("root").is_symlink());
+            assert!(!out.join("root/link").is_symlink());
+            assert_eq!(fs::read(out.join("root/link"))?, CONTENT);
+            assert!(out.join("root/dirlink").symlink_metadata()?.is_dir());
+            fs::remove_dir_all(out.join("root"))?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_preserve_links_as_copies() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("foo"), CONTENT)?;
+        fs::hard_link(src.join("foo"), src.join("hard"))?;
+        std::os::unix::fs::symlink("foo", src.join("link"))?;
+        std::os::unix::fs::symlink("sub", src.join("dirlink"))?;
+        std::os::unix::fs::symlink("missing", src.join("broken"))?;
+        std::os::unix::fs::symlink("..", src.join("sub").join("up"))?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            follow_symlinks: true,
+            preserve_links_as_copies: true,
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let mut entries = Vec::new();
+        read_archive(&backup, None, |a| {
+            for entry in a.entries()? {
+                let entry = entry?;
+                let name = entry.path()?.into_owned();
+                entries.push((name, entry.header().entry_type()));
+            }
+            Ok(())
+        })?;
+        for (name, ty) in &entries {
+            assert!(
+                matches!(ty, tar::EntryType::Regular | tar::EntryType::Directory),
+                "{name:?}"
+            );
+        }
+        let names: Vec<_> = entries
+            .iter()
+            .map(|(name, _)| name.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "src",
+                "src/dirlink",
+                "src/foo",
+                "src/hard",
+                "src/link",
+                "src/sub"
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_symlink_loops() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::create_dir(t.path().join("other"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink("..", src.join("sub").join("up"))?;
+        std::os::unix::fs::symlink(".", src.join("sub").join("here"))?;
+        std::os::unix::fs::symlink("..", src.join("outside"))?;
+        // the same directory twice is no loop
+        std::os::unix::fs::symlink("../other", src.join("a"))?;
+        std::os::unix::fs::symlink("../other", src.join("b"))?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                follow_symlinks: true,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let out = t.path().join("out");
+            fs::create_dir(&out)?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            let restored = out.join("src");
+            assert_eq!(fs::read(restored.join("sub").join("foo"))?, CONTENT);
+            assert!(!restored.join("sub").join("up").exists());
+            assert!(!restored.join("sub").join("here").exists());
+            assert!(!restored.join("outside").exists());
+            assert!(restored.join("a").is_dir() && restored.join("b").is_dir());
+            fs::remove_dir_all(&out)?;
+            recursive_remove(&backup)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_broken_symlinks() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink("nowhere", src.join("dangling"))?;
+        std::os::unix::fs::symlink("nowhere", "single")?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                follow_symlinks: true,
+                ..Default::default()
+            };
+            let dir = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let file = backup_file(Path::new("single"), &opts, &mut BackupStats::default())?;
+            let out = t.path().join("out");
+            fs::create_dir(&out)?;
+            restore(&dir, &out, &RestoreOptions::default())?;
+            restore(&file, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join("src").join("foo"))?, CONTENT);
+            for link in [out.join("src").join("dangling"), out.join("single")] {
+                assert_eq!(fs::read_link(&link)?, PathBuf::from("nowhere"), "{link:?}");
+            }
+            fs::remove_dir_all(&out)?;
+            recursive_remove(&dir)?;
+            recursive_remove(&file)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_archive_extension() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let file = PathBuf::from("foo");
+        fs::write(&file, CONTENT)?;
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+
+        for (ext, name) in [
+            (ZstdExtension::Zst, "foo.tar.zst"),
+            (ZstdExtension::Zstd, "foo.tar.zstd"),
+        ] {
+            let opts = BackupOptions {
+                compress: Some(Compression::Zstd),
+                ext,
+                ..Default::default()
+            };
+            let backup = backup_file(&file, &opts, &mut BackupStats::default())?;
+            assert_eq!(backup, PathBuf::from(name));
+            restore(&backup, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join("foo"))?, CONTENT);
+            fs::remove_file(out.join("foo"))?;
+        }
+
+        // renamed for tools that only know one of the names, or to the wrong one
+        for (compress, name) in [
+            (Compression::Zstd, "renamed.tar.zst"),
+            (Compression::None, "plain.tar.zst"),
+            (Compression::Zstd, "zstd.tar"),
+        ] {
+            let opts = BackupOptions {
+                compress: Some(compress),
+                ext: ZstdExtension::Zstd,
+                ..Default::default()
+            };
+            let backup = backup_file(&file, &opts, &mut BackupStats::default())?;
+            fs::rename(&backup, name)?;
+            let archive = fs::File::open(name)?;
+            assert_eq!(Compression::sniff(&archive), Some(compress));
+            restore(Path::new(name), &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join("foo"))?, CONTENT);
+            fs::remove_file(out.join("foo"))?;
+        }
+        fs::write("empty.tar.zst", b"")?;
+        assert_eq!(Compression::sniff(&fs::File::open("empty.tar.zst")?), None);
+
+        // `list` takes them by their content as well, whatever their name
+        fs::rename("renamed.tar.zst", "renamed.backup")?;
+        assert!(is_archive(Path::new("renamed.backup")));
+        read_archive(Path::new("renamed.backup"), None, |a| {
+            assert_eq!(a.entries()?.count(), 1);
+            Ok(())
+        })?;
+        fs::write("notes.txt", CONTENT)?;
+        assert!(!is_archive(Path::new("notes.txt")));
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_mirror() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        fs::write(out.join("unrelated"), CONTENT)?;
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            fs::create_dir_all(out.join("src/extra/deep"))?;
+            fs::create_dir_all(out.join("src/sub"))?;
+            fs::write(out.join("src/sub/extra"), CONTENT)?;
+
+            // the second round overwrites the files of the first
+            let opts = RestoreOptions {
+                yes: true,
+                ..Default::default()
+            };
+            restore(&backup, &out, &opts)?;
+            assert!(out.join("src/sub/extra").exists());
+
+            let opts = RestoreOptions {
+                mirror: true,
+                yes: true,
+                ..Default::default()
+            };
+            restore(&backup, &out, &opts)?;
+            assert_eq!(fs::read(out.join("src/sub/foo"))?, CONTENT);
+            assert!(!out.join("src/sub/extra").exists());
+            assert!(!out.join("src/extra").exists());
+            // only the restored trees are mirrored
+            assert!(out.join("unrelated").exists());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_conflicts() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a"), CONTENT)?;
+        fs::write(src.join("sub").join("b"), CONTENT)?;
+        let out = t.path().join("out");
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            fs::create_dir_all(&out)?;
+            let opts = RestoreOptions::default();
+            assert!(restore_conflicts(&backup, &out, &opts)?.is_empty());
+
+            // existing directories are merged into, files and type changes are conflicts
+            fs::create_dir_all(out.join("src/sub/b"))?;
+            fs::write(out.join("src/a"), b"old")?;
+            let mut conflicts = restore_conflicts(&backup, &out, &opts)?;
+            conflicts.sort();
+            assert_eq!(conflicts, vec![out.join("src/a"), out.join("src/sub/b")]);
+            fs::remove_dir_all(&out)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_compress_program() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        assert_eq!(program_extension("/usr/bin/gzip -9"), ".tar.gzip");
+
+        let opts = BackupOptions {
+            compress_program: Some("gzip -1".to_string()),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert_eq!(backup, PathBuf::from("src.tar.gzip"));
+        // gzip magic
+        assert_eq!(fs::read(&backup)?[..2], [0x1f, 0x8b]);
+
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        let opts = RestoreOptions {
+            decompress_program: Some("gzip -d".to_string()),
+            ..Default::default()
+        };
+        restore(&backup, &out, &opts)?;
+        assert_eq!(fs::read(out.join("src/sub/foo"))?, CONTENT);
+        fs::remove_dir_all(&out)?;
+        fs::create_dir(&out)?;
+        let e = restore(&backup, &out, &RestoreOptions::default()).unwrap_err();
+        assert!(e.to_string().contains("--compress-program-ext"), "{e}");
+        fs::remove_dir_all(&out)?;
+
+        // found by its extension, as `restore` does without --decompress-program
+        let cli = Cli::try_parse_from([
+            "loppel",
+            "restore",
+            "--compress-program-ext",
+            "zst=true,.tar.gzip=gzip -d",
+            "src.tar.gzip",
+        ])
+        .unwrap();
+        let Some(Commands::Restore { opts, .. }) = cli.command else {
+            panic!("not a restore");
+        };
+        let rules = &opts.compress_program_ext;
+        assert_eq!(program_for(Path::new("x.tar.lz4"), rules), None);
+        let opts = RestoreOptions {
+            decompress_program: program_for(&backup, rules).map(str::to_string),
+            ..Default::default()
+        };
+        assert_eq!(opts.decompress_program.as_deref(), Some("gzip -d"));
+        fs::create_dir(&out)?;
+        restore(&backup, &out, &opts)?;
+        assert_eq!(fs::read(out.join("src/sub/foo"))?, CONTENT);
+        for bad in ["gzip", "=gzip -d", "gz=", "a/b=cat"] {
+            assert!(bad.parse::<ProgramExt>().is_err(), "{bad}");
+        }
+
+        let opts = RestoreOptions {
+            decompress_program: Some("false".to_string()),
+            yes: true,
+            ..Default::default()
+        };
+        assert!(restore(&backup, &out, &opts).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_newer() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        let file = src.join("sub").join("foo");
+        fs::write(&file, CONTENT)?;
+        let opts = BackupOptions {
+            keep_newer: true,
+            ..Default::default()
+        };
+
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let single = backup_file(&file, &opts, &mut BackupStats::default())?;
+        let copy = backup.join("sub").join("foo");
+        for path in [&copy, &single] {
+            // looks like a copy with changes, but it is newer than the source
+            fs::write(path, b"stale")?;
+        }
+        backup_dir(&src, &opts, &mut BackupStats::default())?;
+        backup_file(&file, &opts, &mut BackupStats::default())?;
+        assert_eq!(fs::read(&copy)?, b"stale");
+        assert_eq!(fs::read(&single)?, b"stale");
+
+        let later = std::time::SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&file)?
+            .set_modified(later)?;
+        backup_dir(&src, &opts, &mut BackupStats::default())?;
+        backup_file(&file, &opts, &mut BackupStats::default())?;
+        assert_eq!(fs::read(&copy)?, CONTENT);
+        assert_eq!(fs::read(&single)?, CONTENT);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_non_utf8_names() -> io::Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
         let t = tempdir()?;
         let tdir = t.path();
-        let tdir_a = tdir.join("ichi");
-        let tdir_b = tdir_a.join("ni");
-        let dirs = [&tdir_a, &tdir_b];
-        let names = ["foo", "bar", "qux"];
-        fastrand::seed(133719);
+        std::env::set_current_dir(tdir)?;
+        // latin-1 names
+        let dir = PathBuf::from(OsStr::from_bytes(b"d\xe9j\xe0"));
+        let file = PathBuf::from(OsStr::from_bytes(b"caf\xe9"));
 
-        let mut contents: Vec<[u8; 16]> = vec![];
-        for _ in 0..(dirs.len() * names.len()) {
-            contents.push(fastrand::u128(0..u128::MAX).to_le_bytes());
+        for &compress in BACKUP_KINDS {
+            fs::create_dir(&dir)?;
+            fs::write(dir.join(&file), CONTENT)?;
+            fs::write(&file, CONTENT)?;
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let dir_backup = backup_dir(&dir, &opts, &mut BackupStats::default())?;
+            let file_backup = backup_file(&file, &opts, &mut BackupStats::default())?;
+            fs::remove_dir_all(&dir)?;
+            fs::remove_file(&file)?;
+
+            let opts = RestoreOptions::default();
+            restore(&dir_backup, tdir, &opts)?;
+            restore(&file_backup, tdir, &opts)?;
+            assert_eq!(fs::read(dir.join(&file))?, CONTENT);
+            assert_eq!(fs::read(&file)?, CONTENT);
+            fs::remove_dir_all(&dir)?;
+            fs::remove_file(&file)?;
         }
 
-        let mut i = 0;
-        for sdir in dirs {
-            fs::create_dir_all(sdir)?;
-            assert!(sdir.exists());
-            assert!(sdir.is_dir());
-            for fname in names {
-                let p = sdir.join(fname);
-                fs::write(&p, contents[i])?;
-                assert!(p.exists());
-                assert!(p.is_file());
-                assert!(p.is_file());
-                let raw_size = filesize(&p)?;
-                assert!(raw_size > 1, "raw size of {} was {raw_size}", p.display());
-                i += 1;
+        Ok(())
+    }
+
+    #[test]
+    fn test_total_size_limit() -> io::Result<()> {
+        let t = tempdir()?;
+        let file = t.path().join("foo");
+        fs::write(&file, CONTENT)?;
+        let opts = BackupOptions {
+            total_size_limit: Some(CONTENT.len() + 1),
+            ..Default::default()
+        };
+        let mut stats = BackupStats::default();
+        assert!(!size_limit_reached(&opts, &stats));
+        backup_file(&file, &opts, &mut stats)?;
+        assert!(!size_limit_reached(&opts, &stats));
+        backup_file(&file, &opts, &mut stats)?;
+        assert!(size_limit_reached(&opts, &stats));
+        assert!(!size_limit_reached(&BackupOptions::default(), &stats));
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_also_to() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink("sub/foo", src.join("link"))?;
+        fs::write("file", CONTENT)?;
+        let (a, b) = (PathBuf::from("a"), PathBuf::from("b"));
+        fs::create_dir(&a)?;
+        fs::create_dir(&b)?;
+
+        let archives = [
+            (Some(Compression::None), None, "src.tar"),
+            #[cfg(feature = "compression")]
+            (Some(Compression::Zstd), None, "src.tar.zst"),
+            (None, Some("gzip -1"), "src.tar.gzip"),
+        ];
+        for (compress, program, name) in archives {
+            let opts = BackupOptions {
+                compress,
+                compress_program: program.map(str::to_string),
+                // the primary destination is left out
+                also_to: vec![a.clone(), b.clone(), PathBuf::from(".")],
+                // manifests can not read archives of other compressors
+                hash: program.is_none().then_some(HashAlgorithm::Sha256),
+                ..Default::default()
+            };
+            assert_eq!(destinations(Path::new(name), &opts).len(), 3);
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let content = fs::read(&backup)?;
+            let manifest = fs::read(Manifest::path_for(&backup)?).ok();
+            for dir in [&a, &b] {
+                assert_eq!(fs::read(dir.join(name))?, content, "{name}");
+                assert_eq!(
+                    fs::read(Manifest::path_for(&dir.join(name))?).ok(),
+                    manifest
+                );
             }
+            let out = t.path().join("out");
+            fs::create_dir(&out)?;
+            let opts = RestoreOptions {
+                decompress_program: program.map(|_| "gzip -d".to_string()),
+                ..Default::default()
+            };
+            restore(&b.join(name), &out, &opts)?;
+            assert_eq!(fs::read(out.join("src/sub/foo"))?, CONTENT);
+            fs::remove_dir_all(&out)?;
         }
 
-        let backup = backup_dir(&tdir_a, false)?;
-        dbg!(&tdir_a);
-        dbg!(fs::metadata(&tdir_a)?);
-        fs::remove_dir_all(&tdir_a)?;
-        dbg!(&backup);
-        dbg!(fs::metadata(&backup)?);
-        restore(&backup, tdir)?;
-        dbg!(&tdir_a);
-        dbg!(fs::metadata(&tdir_a)?);
+        let opts = BackupOptions {
+            also_to: vec![a.clone(), b.clone()],
+            ..Default::default()
+        };
+        backup_dir(&src, &opts, &mut BackupStats::default())?;
+        backup_file(Path::new("file"), &opts, &mut BackupStats::default())?;
+        for dir in [Path::new("."), &a, &b] {
+            assert_eq!(fs::read(dir.join("src.bak.d/sub/foo"))?, CONTENT);
+            assert_eq!(
+                fs::read_link(dir.join("src.bak.d/link"))?,
+                Path::new("sub/foo")
+            );
+            assert_eq!(fs::read(dir.join("file.bak"))?, CONTENT);
+        }
 
-        let mut i = 0;
-        for sdir in dirs {
-            assert!(sdir.exists());
-            assert!(sdir.is_dir());
-            for fname in names {
-                let p = sdir.join(fname);
-                assert!(p.exists());
-                assert!(p.is_file());
-                let actual = fs::read(&p)?;
-                assert_eq!(actual, contents[i]);
-                i += 1;
+        // a partial backup is removed everywhere
+        let full = io::Error::from(io::ErrorKind::StorageFull);
+        assert!(remove_partial(Path::new("src.bak.d"), Err(full), &opts).is_err());
+        for dir in [Path::new("."), &a, &b] {
+            assert!(!dir.join("src.bak.d").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_min_free_space() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let dir = PathBuf::from("dir");
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("foo"), CONTENT)?;
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                min_free_space: Some(1),
+                ..Default::default()
+            };
+            let backup = backup_dir(&dir, &opts, &mut BackupStats::default())?;
+            recursive_remove(&backup)?;
+
+            let opts = BackupOptions {
+                min_free_space: Some(usize::MAX),
+                ..opts
+            };
+            let e = backup_dir(&dir, &opts, &mut BackupStats::default()).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::StorageFull);
+            assert!(!backup.exists());
+            let e = backup_file(&dir.join("foo"), &opts, &mut BackupStats::default());
+            assert_eq!(e.unwrap_err().kind(), io::ErrorKind::StorageFull);
+        }
+
+        // what was written before running out of space is removed
+        let partial = PathBuf::from("partial.bak.d");
+        fs::create_dir(&partial)?;
+        fs::write(partial.join("foo"), CONTENT)?;
+        let full = io::Error::from(io::ErrorKind::StorageFull);
+        assert!(remove_partial(&partial, Err(full), &BackupOptions::default()).is_err());
+        assert!(!partial.exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_restore_collects_errors() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        for name in ["a", "b", "c"] {
+            fs::write(src.join(name), CONTENT)?;
+        }
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+
+        // a directory in the way of a file can not be overwritten
+        let out = t.path().join("out");
+        fs::create_dir_all(out.join("src/b/sub"))?;
+        let opts = RestoreOptions {
+            yes: true,
+            ..Default::default()
+        };
+        assert!(restore(&backup, &out, &opts).is_err());
+        // everything else is restored anyway
+        assert_eq!(fs::read(out.join("src/a"))?, CONTENT);
+        assert_eq!(fs::read(out.join("src/c"))?, CONTENT);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_empty_dir_placeholders() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("empty"))?;
+        fs::create_dir_all(src.join("full"))?;
+        fs::write(src.join("full").join("foo"), CONTENT)?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                store_empty_as_placeholder: true,
+                placeholder_name: Some(".keep".to_string()),
+                hash: Some(HashAlgorithm::Sha256),
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let manifest = Manifest::for_backup(&backup)?.unwrap();
+            let names: Vec<PathBuf> = manifest.entries.into_iter().map(|e| e.name).collect();
+            assert!(names.contains(&src.join("empty").join(".keep")));
+            assert!(!names.contains(&src.join("full").join(".keep")));
+
+            let out = t.path().join("out");
+            fs::create_dir(&out)?;
+            let opts = RestoreOptions {
+                placeholder_name: Some(".keep".to_string()),
+                ..Default::default()
+            };
+            restore(&backup, &out, &opts)?;
+            assert!(out.join("src/empty").is_dir());
+            assert!(!out.join("src/empty/.keep").exists());
+            assert_eq!(fs::read(out.join("src/full/foo"))?, CONTENT);
+            fs::remove_dir_all(&out)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a"), CONTENT)?;
+        fs::write(src.join("sub").join("b"), CONTENT)?;
+        std::os::unix::fs::symlink("a", src.join("link"))?;
+
+        let opts = BackupOptions::default();
+        let total = estimate_size(&src, &opts);
+        assert_eq!(total, 2 * CONTENT.len() as u64);
+        let follow = BackupOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        assert_eq!(estimate_size(&src, &follow), 3 * CONTENT.len() as u64);
+
+        let mut events = tempfile::tempfile()?;
+        let fd = events.try_clone()?.into_raw_fd();
+        let mut progress = progress::Progress::from_fd(fd, total)?;
+        progress.set_interval(Duration::ZERO);
+        let mut stats = BackupStats {
+            progress: Some(progress),
+            ..Default::default()
+        };
+        backup_dir(&src, &opts, &mut stats)?;
+        drop(stats);
+
+        let mut written = String::new();
+        events.rewind()?;
+        events.read_to_string(&mut written)?;
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains(&format!("\"bytes_done\":{total},\"bytes_total\":{total}")));
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_estimate_matches_backup() -> io::Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let data = PathBuf::from("data");
+        fs::create_dir_all(data.join("dir"))?;
+        fs::write(data.join("a"), CONTENT)?;
+        fs::write(data.join("dir").join("b"), b"bb")?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("own"), b"own")?;
+        symlink("../data/a", src.join("file_link"))?;
+        symlink("../data/dir", src.join("dir_link"))?;
+        symlink("src", "root_link")?;
+
+        for (root, follow_symlinks, dereference_root) in [
+            (&src, false, false),
+            (&src, true, false),
+            (&PathBuf::from("root_link"), false, true),
+            (&PathBuf::from("root_link"), true, true),
+        ] {
+            for &compress in BACKUP_KINDS {
+                let opts = BackupOptions {
+                    compress,
+                    follow_symlinks,
+                    dereference_root,
+                    ..Default::default()
+                };
+                let mut stats = BackupStats::default();
+                let backup = backup_dir(root, &opts, &mut stats)?;
+                assert_eq!(
+                    estimate_size(root, &opts),
+                    stats.bytes_in,
+                    "{root:?} {opts:?}"
+                );
+                recursive_remove(&backup)?;
+            }
+        }
+
+        // a symlink given as path itself
+        let link = PathBuf::from("file_link");
+        symlink("data/a", &link)?;
+        for follow_symlinks in [false, true] {
+            for &compress in BACKUP_KINDS {
+                let opts = BackupOptions {
+                    compress,
+                    follow_symlinks,
+                    ..Default::default()
+                };
+                let mut stats = BackupStats::default();
+                let backup = backup_file(&link, &opts, &mut stats)?;
+                assert_eq!(estimate_size(&link, &opts), stats.bytes_in, "{opts:?}");
+                recursive_remove(&backup)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_time_limit() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("foo"), CONTENT)?;
+
+        for &compress in BACKUP_KINDS {
+            let opts = BackupOptions {
+                compress,
+                deadline: Some(std::time::Instant::now()),
+                ..Default::default()
+            };
+            let err = backup_dir(&src, &opts, &mut BackupStats::default()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            // only the source is left
+            assert_eq!(fs::read_dir(t.path())?.count(), 1);
+        }
+
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(2 * 86400)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_owner_map() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("foo"), CONTENT)?;
+        std::os::unix::fs::symlink("foo", src.join("link"))?;
+
+        let backup = backup_dir(
+            &src,
+            &BackupOptions {
+                compress: Some(Compression::Zstd),
+                owner: Some("1000".parse().unwrap()),
+                group: Some("2000".parse().unwrap()),
+                ..Default::default()
+            },
+            &mut BackupStats::default(),
+        )?;
+        // giving files away only works with privileges
+        // SAFETY: geteuid has no preconditions and can not fail
+        if unsafe { libc::geteuid() } != 0 {
+            return Ok(());
+        }
+        let opts = RestoreOptions {
+            owner_map: Some("1000:1234\ngroup 2000:2345".parse().unwrap()),
+            ..Default::default()
+        };
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+        restore(&backup, &out, &opts)?;
+        for name in ["src", "src/foo", "src/link"] {
+            let meta = fs::symlink_metadata(out.join(name))?;
+            assert_eq!((meta.uid(), meta.gid()), (1234, 2345), "{name}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_archive_owner() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("foo"), CONTENT)?;
+        unpack::mknod(&src.join("fifo"), libc::S_IFIFO | 0o644, 0)?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::None),
+            owner: Some("nobody:65534".parse().unwrap()),
+            group: Some("1234".parse().unwrap()),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        let mut count = 0;
+        read_archive(&backup, None, |a| {
+            for entry in a.entries()? {
+                let header = entry?.header().clone();
+                assert_eq!(header.uid()?, 65534);
+                assert_eq!(header.username().unwrap(), Some("nobody"));
+                assert_eq!(header.gid()?, 1234);
+                assert_eq!(header.groupname().unwrap(), Some(""));
+                count += 1;
             }
+            Ok(())
+        })?;
+        assert_eq!(count, 3);
+
+        assert_eq!(
+            "staff".parse(),
+            Ok(Owner {
+                name: "staff".to_string(),
+                id: 0
+            })
+        );
+        assert!("name:x".parse::<Owner>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_caches() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        let cache = src.join("cache");
+        let fake = src.join("fake");
+        for dir in [&cache, &fake] {
+            fs::create_dir_all(dir)?;
+            fs::write(dir.join("foo"), CONTENT)?;
+        }
+        fs::write(
+            cache.join("CACHEDIR.TAG"),
+            b"Signature: 8a477f597d28d172789f06886806bc55\n# generated\n",
+        )?;
+        fs::write(fake.join("CACHEDIR.TAG"), b"not a signature")?;
+
+        let opts = BackupOptions {
+            exclude_caches: true,
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert!(!backup.join("cache").exists());
+        assert!(backup.join("fake").join("foo").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_if_present() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        for dir in ["marked/sub", "other", "kept"] {
+            fs::create_dir_all(src.join(dir))?;
+            fs::write(src.join(dir).join("foo"), CONTENT)?;
         }
+        fs::write(src.join("marked").join(".nobackup"), b"")?;
+        fs::create_dir(src.join("other").join(".skip-me"))?;
+        // the directory that is backed up is not
+        fs::write(src.join(".nobackup"), b"")?;
+
+        let opts = BackupOptions {
+            exclude_if_present: vec![".nobackup".into(), ".skip-me".into()],
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert!(!backup.join("marked").exists());
+        assert!(!backup.join("other").exists());
+        assert!(backup.join("kept").join("foo").exists());
+        assert!(backup.join(".nobackup").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_vcs() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        fs::create_dir_all(src.join(".git").join("objects"))?;
+        fs::create_dir_all(src.join("sub").join(".svn"))?;
+        fs::write(src.join(".gitignore"), b"target\n")?;
+        fs::write(src.join("sub").join("foo"), CONTENT)?;
+        // only exact names are version control metadata
+        fs::write(src.join(".github"), CONTENT)?;
+
+        let opts = BackupOptions {
+            exclude_vcs: true,
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert!(!backup.join(".git").exists());
+        assert!(!backup.join(".gitignore").exists());
+        assert!(!backup.join("sub").join(".svn").exists());
+        assert!(backup.join("sub").join("foo").exists());
+        assert!(backup.join(".github").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hidden() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        fs::create_dir_all(src.join(".config"))?;
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join(".config").join("foo"), CONTENT)?;
+        fs::write(src.join("sub").join(".env"), CONTENT)?;
+        fs::write(src.join("sub").join("bar"), CONTENT)?;
+        fs::write(src.join("visible.tmp"), CONTENT)?;
+
+        let backup_with = |opts: BackupOptions| -> io::Result<Vec<PathBuf>> {
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let mut names = Vec::new();
+            walk_dir(
+                &backup,
+                Path::new(""),
+                &BackupOptions::default(),
+                &mut BackupStats::default(),
+                &mut |_, rel, ty, _| {
+                    names.push(rel.to_path_buf());
+                    Ok(ty.is_dir())
+                },
+            )?;
+            recursive_remove(&backup)?;
+            names.sort();
+            Ok(names)
+        };
+        let names = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert_eq!(
+            backup_with(BackupOptions::default())?,
+            names(&[
+                ".config",
+                ".config/foo",
+                "sub",
+                "sub/.env",
+                "sub/bar",
+                "visible.tmp"
+            ])
+        );
+        assert_eq!(
+            backup_with(BackupOptions {
+                no_hidden: true,
+                ..Default::default()
+            })?,
+            names(&["sub", "sub/bar", "visible.tmp"])
+        );
+        // patterns still exclude visible files
+        assert_eq!(
+            backup_with(BackupOptions {
+                hidden: true,
+                exclude: vec![".*".to_string(), "*.tmp".to_string()],
+                ..Default::default()
+            })?,
+            names(&[".config", ".config/foo", "sub", "sub/.env", "sub/bar"])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_file() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        fs::create_dir_all(src.join("target").join("debug"))?;
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(
+            src.join(".lopplerignore"),
+            b"# build output\ntarget/\n*.log\n!keep.log\n",
+        )?;
+        fs::write(src.join("target").join("debug").join("bin"), CONTENT)?;
+        fs::write(src.join("sub").join("x.log"), CONTENT)?;
+        fs::write(src.join("keep.log"), CONTENT)?;
+        fs::write(src.join("sub").join("tmp"), CONTENT)?;
+        fs::write(src.join("foo"), CONTENT)?;
+
+        let opts = BackupOptions {
+            exclude: vec!["/sub/tmp".to_string()],
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert!(backup.join(".lopplerignore").exists());
+        assert!(!backup.join("target").exists());
+        assert!(!backup.join("sub").join("x.log").exists());
+        assert!(!backup.join("sub").join("tmp").exists());
+        assert!(backup.join("keep.log").exists());
+        assert!(backup.join("foo").exists());
+        assert_eq!(
+            estimate_size(&src, &opts),
+            (CONTENT.len() * 2) as u64 + fs::metadata(src.join(".lopplerignore"))?.len()
+        );
+
+        // a missing --exclude-from is an error, unlike a missing .lopplerignore
+        let opts = BackupOptions {
+            exclude_from: vec![t.path().join("missing")],
+            ..Default::default()
+        };
+        assert!(backup_dir(&src, &opts, &mut BackupStats::default()).is_err());
 
         Ok(())
     }
@@ -0,0 +1,537 @@
+//! Checksum manifests, written next to a backup to verify it later
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::add_extension;
+use crate::color;
+use crate::compare::{backup_entries, Kind};
+use crate::hash::{hash_reader, to_hex, HashAlgorithm};
+use crate::unpack::{map_name, PrefixMap};
+
+/// First line of every manifest, the number is bumped on incompatible changes
+const MANIFEST_HEADER: &str = "loppler-manifest 1";
+
+/// Checksum of one regular file in a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ManifestEntry {
+    /// Name of the entry, relative to the directory it is restored to
+    pub(crate) name: PathBuf,
+    pub(crate) size: u64,
+    /// Hex encoded hash of the content
+    pub(crate) hash: String,
+}
+
+/// Where the path that was backed up came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Origin {
+    /// Its name in the backup, that the names of the entries below it start with
+    pub(crate) name: PathBuf,
+    /// Its absolute path
+    pub(crate) path: PathBuf,
+}
+
+/// Checksums of all regular files in a backup, stored as `<backup>.manifest`
+///
+/// The format is line based: the header, `hash <algorithm>`, a `tag <tag>` line per tag, the
+/// [`Origin`] as `root <name>` and `origin <path>`, `since <path>` for differential backups,
+/// then one `<hash> <size> <name>` line per file. Backslashes and newlines in names are
+/// escaped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) algorithm: HashAlgorithm,
+    /// The `--tag`s of the backup
+    pub(crate) tags: Vec<String>,
+    /// [`None`] for manifests of older versions
+    pub(crate) origin: Option<Origin>,
+    /// The backup that this one only has the changes since, with `--since`
+    pub(crate) since: Option<PathBuf>,
+    pub(crate) entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Where the manifest of the backup at `backup` is stored
+    pub(crate) fn path_for(backup: &Path) -> io::Result<PathBuf> {
+        add_extension(backup, ".manifest")
+    }
+
+    /// Hashes all regular files in the backup at `backup`
+    pub(crate) fn create(
+        backup: &Path,
+        algorithm: HashAlgorithm,
+        window_log_max: Option<u32>,
+    ) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        backup_entries(backup, window_log_max, |name, kind, size, content| {
+            if kind == Kind::File {
+                let hash = to_hex(&hash_reader(algorithm, content)?);
+                entries.push(ManifestEntry { name, size, hash });
+            }
+            Ok(())
+        })?;
+        Ok(Manifest {
+            algorithm,
+            tags: Vec::new(),
+            origin: None,
+            since: None,
+            entries,
+        })
+    }
+
+    /// Reads the manifest of the backup at `backup`, [`None`] if it has none
+    pub(crate) fn for_backup(backup: &Path) -> io::Result<Option<Self>> {
+        match fs::File::open(Self::path_for(backup)?) {
+            Ok(file) => Self::read(io::BufReader::new(file)).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut lines = reader.split(b'\n');
+        let mut next_line = || -> io::Result<Option<Vec<u8>>> { lines.next().transpose() };
+
+        if next_line()?.as_deref() != Some(MANIFEST_HEADER.as_bytes()) {
+            return Err(invalid("not a loppler manifest".to_string()));
+        }
+        let algorithm = next_line()?
+            .as_deref()
+            .and_then(|l| l.strip_prefix(b"hash "))
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .and_then(HashAlgorithm::from_name)
+            .ok_or_else(|| invalid("manifest has no known hash algorithm".to_string()))?;
+
+        let mut tags = Vec::new();
+        let (mut root, mut origin, mut since) = (None, None, None);
+        let mut entries = Vec::new();
+        let path = |escaped: &[u8]| PathBuf::from(OsStr::from_bytes(&unescape(escaped)));
+        while let Some(line) = next_line()? {
+            // hashes are hex, so no entry starts like these
+            if let Some(tag) = line.strip_prefix(b"tag ") {
+                tags.push(String::from_utf8_lossy(tag).into_owned());
+                continue;
+            }
+            if let Some(name) = line.strip_prefix(b"root ") {
+                root = Some(path(name));
+                continue;
+            }
+            if let Some(origin_path) = line.strip_prefix(b"origin ") {
+                origin = Some(path(origin_path));
+                continue;
+            }
+            if let Some(base) = line.strip_prefix(b"since ") {
+                since = Some(path(base));
+                continue;
+            }
+            let mut parts = line.splitn(3, |b| *b == b' ');
+            let (Some(hash), Some(size), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid(format!(
+                    "malformed manifest line: {}",
+                    String::from_utf8_lossy(&line)
+                )));
+            };
+            let size = std::str::from_utf8(size)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| invalid(format!("malformed size in manifest: {size:?}")))?;
+            entries.push(ManifestEntry {
+                name: path(name),
+                size,
+                hash: String::from_utf8_lossy(hash).into_owned(),
+            });
+        }
+        let origin = match (root, origin) {
+            (Some(name), Some(path)) => Some(Origin { name, path }),
+            _ => None,
+        };
+        Ok(Manifest {
+            algorithm,
+            tags,
+            origin,
+            since,
+            entries,
+        })
+    }
+
+    pub(crate) fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "{MANIFEST_HEADER}")?;
+        writeln!(writer, "hash {}", self.algorithm.name())?;
+        for tag in &self.tags {
+            writeln!(writer, "tag {tag}")?;
+        }
+        if let Some(origin) = &self.origin {
+            writer.write_all(b"root ")?;
+            writer.write_all(&escape(origin.name.as_os_str().as_bytes()))?;
+            writer.write_all(b"\norigin ")?;
+            writer.write_all(&escape(origin.path.as_os_str().as_bytes()))?;
+            writeln!(writer)?;
+        }
+        if let Some(since) = &self.since {
+            writer.write_all(b"since ")?;
+            writer.write_all(&escape(since.as_os_str().as_bytes()))?;
+            writeln!(writer)?;
+        }
+        for entry in &self.entries {
+            write!(writer, "{} {} ", entry.hash, entry.size)?;
+            writer.write_all(&escape(entry.name.as_os_str().as_bytes()))?;
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Checks the files restored below `base` against the manifest and returns the names of
+    /// those that are missing or differ. Names are rewritten with `maps` first, like restoring
+    /// an archive does.
+    pub(crate) fn verify(&self, base: &Path, maps: &[PrefixMap]) -> io::Result<Vec<PathBuf>> {
+        let mut mismatches = Vec::new();
+        for entry in &self.entries {
+            let name = map_name(&entry.name, maps);
+            let path = base.join(&name);
+            let matches = match fs::metadata(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e),
+                Ok(meta) => {
+                    meta.is_file()
+                        && meta.len() == entry.size
+                        && to_hex(&hash_reader(self.algorithm, fs::File::open(&path)?)?)
+                            == entry.hash
+                }
+            };
+            if !matches {
+                mismatches.push(name);
+            }
+        }
+        Ok(mismatches)
+    }
+}
+
+/// The backups in `dir` whose manifests have all of `tags`, with all of their tags, sorted by
+/// name. Manifests that can not be read are skipped with a warning.
+pub(crate) fn find(dir: &Path, tags: &[String]) -> io::Result<Vec<(PathBuf, Vec<String>)>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let Some(backup) = name.strip_suffix(".manifest") else {
+            continue;
+        };
+        let backup = dir.join(backup);
+        // a manifest left over from a deleted backup
+        if backup.symlink_metadata().is_err() {
+            continue;
+        }
+        match fs::File::open(&path).and_then(|file| read_tags(io::BufReader::new(file))) {
+            Ok(have) if tags.iter().all(|tag| have.contains(tag)) => found.push((backup, have)),
+            Ok(_) => (),
+            Err(e) => eprintln!(
+                "{}",
+                color::error(format_args!("skipping {}: {e}", path.display()))
+            ),
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Reads only the tags of a manifest, which come before its entries
+fn read_tags<R: BufRead>(reader: R) -> io::Result<Vec<String>> {
+    let mut lines = reader.split(b'\n');
+    if lines.next().transpose()?.as_deref() != Some(MANIFEST_HEADER.as_bytes()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a loppler manifest",
+        ));
+    }
+    let mut tags = Vec::new();
+    // skips the hash line
+    for line in lines.skip(1) {
+        match line?.strip_prefix(b"tag ") {
+            Some(tag) => tags.push(String::from_utf8_lossy(tag).into_owned()),
+            None => break,
+        }
+    }
+    Ok(tags)
+}
+
+/// Reads the whole backup at `backup` without restoring it and checks its files against its
+/// manifest, if it has one. Returns the number of entries and the algorithm of the manifest.
+/// The first error names the entry it happened in.
+pub(crate) fn verify_backup(
+    backup: &Path,
+    window_log_max: Option<u32>,
+) -> io::Result<(usize, Option<HashAlgorithm>)> {
+    let manifest = Manifest::for_backup(backup)?;
+    let mut expected: HashMap<&Path, &ManifestEntry> = manifest
+        .iter()
+        .flat_map(|m| &m.entries)
+        .map(|entry| (entry.name.as_path(), entry))
+        .collect();
+
+    let mut entries = 0;
+    let mut last: Option<PathBuf> = None;
+    let mut in_entry = false;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let result = backup_entries(backup, window_log_max, |name, kind, size, content| {
+        let check = || -> io::Result<()> {
+            match (kind, expected.remove(name.as_path()), &manifest) {
+                (Kind::File, Some(entry), Some(manifest)) => {
+                    let hash = to_hex(&hash_reader(manifest.algorithm, content)?);
+                    if size != entry.size || hash != entry.hash {
+                        return Err(invalid("does not match the manifest".to_string()));
+                    }
+                }
+                _ => {
+                    io::copy(content, &mut io::sink())?;
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = check() {
+            in_entry = true;
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{e}, in {}", name.display()),
+            ));
+        }
+        entries += 1;
+        last = Some(name);
+        Ok(())
+    });
+    if let Err(e) = result {
+        // errors reading the entries themselves happen between two of them
+        return Err(match &last {
+            Some(last) if !in_entry => {
+                io::Error::new(e.kind(), format!("{e}, after {}", last.display()))
+            }
+            _ => e,
+        });
+    }
+
+    if let Some(entry) = expected.values().min_by_key(|entry| &entry.name) {
+        return Err(invalid(format!(
+            "missing from the backup: {}",
+            entry.name.display()
+        )));
+    }
+    Ok((entries, manifest.map(|m| m.algorithm)))
+}
+
+fn escape(name: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len());
+    for &b in name {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+fn unescape(name: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&b) = bytes.next() {
+        match (b, bytes.clone().next()) {
+            (b'\\', Some(b'n')) => {
+                out.push(b'\n');
+                bytes.next();
+            }
+            (b'\\', Some(b'\\')) => {
+                out.push(b'\\');
+                bytes.next();
+            }
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    #[cfg(feature = "compression")]
+    use super::{find, verify_backup};
+    use super::{Manifest, ManifestEntry, Origin};
+    use crate::hash::HashAlgorithm;
+    use crate::{backup_dir, BackupOptions, BackupStats, Compression};
+
+    #[test]
+    fn test_manifest_roundtrip() -> io::Result<()> {
+        let manifest = Manifest {
+            algorithm: HashAlgorithm::Xxh3,
+            tags: vec!["weekly".to_string(), "prod".to_string()],
+            origin: Some(Origin {
+                name: PathBuf::from("home/me/dir"),
+                path: PathBuf::from("/home/me/dir"),
+            }),
+            since: Some(PathBuf::from("/backups/dir.tar.zst")),
+            entries: vec![
+                ManifestEntry {
+                    name: PathBuf::from("dir/a file"),
+                    size: 3,
+                    hash: "0123456789abcdef".to_string(),
+                },
+                ManifestEntry {
+                    name: PathBuf::from("dir/new\nline\\n"),
+                    size: 0,
+                    hash: "2d06800538d394c2".to_string(),
+                },
+            ],
+        };
+        let mut buf = Vec::new();
+        manifest.write(&mut buf)?;
+        assert_eq!(Manifest::read(&buf[..])?, manifest);
+        assert!(Manifest::read(&b"something else\n"[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_manifest_verify() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a"), b"aaa")?;
+        fs::write(src.join("sub").join("b"), b"bbb")?;
+
+        for (compress, algorithm) in [
+            (None, HashAlgorithm::Blake3),
+            (Some(Compression::None), HashAlgorithm::Sha256),
+            #[cfg(feature = "compression")]
+            (Some(Compression::Zstd), HashAlgorithm::Xxh3),
+        ] {
+            let opts = BackupOptions {
+                compress,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let manifest = Manifest::create(&backup, algorithm, None)?;
+            assert_eq!(manifest.entries.len(), 2);
+            assert!(manifest.verify(tdir, &[])?.is_empty());
+
+            fs::write(src.join("sub").join("b"), b"bbc")?;
+            assert_eq!(manifest.verify(tdir, &[])?, vec![src.join("sub").join("b")]);
+            fs::write(src.join("sub").join("b"), b"bbb")?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_verify_backup() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("a"), b"aaa")?;
+        fs::write(src.join("sub").join("b"), b"bbb")?;
+
+        let opts = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+        assert_eq!(verify_backup(&backup, None)?, (4, None));
+
+        let mut manifest = Manifest::create(&backup, HashAlgorithm::Blake3, None)?;
+        manifest.write(fs::File::create(Manifest::path_for(&backup)?)?)?;
+        assert_eq!(
+            verify_backup(&backup, None)?,
+            (4, Some(HashAlgorithm::Blake3))
+        );
+
+        manifest.entries[0].hash = "00".to_string();
+        manifest.write(fs::File::create(Manifest::path_for(&backup)?)?)?;
+        let err = verify_backup(&backup, None).unwrap_err();
+        assert!(err.to_string().contains("does not match the manifest"));
+
+        manifest.entries[0] = ManifestEntry {
+            name: src.join("gone"),
+            size: 0,
+            hash: String::new(),
+        };
+        manifest.write(fs::File::create(Manifest::path_for(&backup)?)?)?;
+        let err = verify_backup(&backup, None).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("missing from the backup: src/gone"));
+
+        fs::remove_file(Manifest::path_for(&backup)?)?;
+        let content = fs::read(&backup)?;
+        fs::write(&backup, &content[..content.len() / 2])?;
+        assert!(verify_backup(&backup, None).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    #[serial]
+    fn test_find() -> io::Result<()> {
+        let t = tempdir()?;
+        let tdir = t.path();
+        std::env::set_current_dir(tdir)?;
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        for (name, with) in [("a", tags(&["weekly", "prod"])), ("b", tags(&["weekly"]))] {
+            fs::create_dir(name)?;
+            fs::write(PathBuf::from(name).join("file"), name)?;
+            let opts = BackupOptions {
+                compress: Some(Compression::Zstd),
+                tags: with,
+                ..Default::default()
+            };
+            backup_dir(&PathBuf::from(name), &opts, &mut BackupStats::default())?;
+        }
+        fs::create_dir("untagged")?;
+        backup_dir(
+            &PathBuf::from("untagged"),
+            &BackupOptions {
+                hash: Some(HashAlgorithm::Xxh3),
+                ..Default::default()
+            },
+            &mut BackupStats::default(),
+        )?;
+        fs::write("garbage.manifest", b"not a manifest")?;
+        fs::write("garbage", b"")?;
+
+        let found = |with: &[&str]| -> io::Result<Vec<PathBuf>> {
+            let found = find(tdir, &tags(with))?;
+            Ok(found.into_iter().map(|(backup, _)| backup).collect())
+        };
+        assert_eq!(
+            found(&["weekly"])?,
+            [tdir.join("a.tar.zst"), tdir.join("b.tar.zst")]
+        );
+        assert_eq!(found(&["prod", "weekly"])?, [tdir.join("a.tar.zst")]);
+        assert!(found(&["daily"])?.is_empty());
+        assert_eq!(found(&[])?.len(), 3);
+        assert_eq!(
+            find(tdir, &tags(&["prod"]))?[0].1,
+            tags(&["weekly", "prod"])
+        );
+
+        // the tags survive reading the whole manifest
+        let manifest = Manifest::for_backup(&tdir.join("b.tar.zst"))?.unwrap();
+        assert_eq!(manifest.tags, tags(&["weekly"]));
+        assert_eq!(manifest.entries.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,1067 @@
+//! `mount`, which serves an archive read-only through FUSE to browse it with other tools. It
+//! speaks the protocol of `/dev/fuse` itself, so there is no library to link against.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{format, read_archive, unpack, Compression};
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+/// The newest minor version of the protocol that is known here
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// Lets the kernel keep the content of opened files cached, archives do not change
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// Inode of the mount point itself
+const ROOT: u64 = 1;
+/// How long the kernel may cache names and attributes, which never change either
+const TTL_SECS: u64 = 3600;
+/// Largest write the kernel is told about, nothing is ever written to a read-only mount
+const MAX_WRITE: u32 = 4096;
+/// Room for the largest request, a lookup of a long name or a write of `MAX_WRITE`
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Set by SIGINT and SIGTERM, to unmount and stop
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Structs of the kernel ABI, which are sent and received as their bytes
+///
+/// # Safety
+///
+/// Implementors are `repr(C)` without implicit padding and valid for any bytes.
+unsafe trait Abi: Copy {
+    fn as_bytes(&self) -> &[u8] {
+        // SAFETY: any initialized struct without padding can be read as bytes
+        unsafe { std::slice::from_raw_parts((self as *const Self).cast(), size_of::<Self>()) }
+    }
+
+    fn from_bytes(data: &[u8]) -> Option<Self> {
+        // SAFETY: there are enough bytes and any bytes are a valid value
+        (data.len() >= size_of::<Self>())
+            .then(|| unsafe { std::ptr::read_unaligned(data.as_ptr().cast()) })
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct InHeader {
+    len: u32,
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct OutHeader {
+    len: u32,
+    error: i32,
+    unique: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct InitIn {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct InitOut {
+    major: u32,
+    minor: u32,
+    max_readahead: u32,
+    flags: u32,
+    max_background: u16,
+    congestion_threshold: u16,
+    max_write: u32,
+    time_gran: u32,
+    max_pages: u16,
+    map_alignment: u16,
+    flags2: u32,
+    unused: [u32; 7],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Attr {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    atimensec: u32,
+    mtimensec: u32,
+    ctimensec: u32,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    blksize: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct EntryOut {
+    nodeid: u64,
+    generation: u64,
+    entry_valid: u64,
+    attr_valid: u64,
+    entry_valid_nsec: u32,
+    attr_valid_nsec: u32,
+    attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct AttrOut {
+    attr_valid: u64,
+    attr_valid_nsec: u32,
+    dummy: u32,
+    attr: Attr,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct OpenOut {
+    fh: u64,
+    open_flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ReadIn {
+    fh: u64,
+    offset: u64,
+    size: u32,
+    read_flags: u32,
+    lock_owner: u64,
+    flags: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct StatfsOut {
+    blocks: u64,
+    bfree: u64,
+    bavail: u64,
+    files: u64,
+    ffree: u64,
+    bsize: u32,
+    namelen: u32,
+    frsize: u32,
+    padding: u32,
+    spare: [u32; 6],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Dirent {
+    ino: u64,
+    off: u64,
+    namelen: u32,
+    typ: u32,
+}
+
+// SAFETY: all of them are repr(C), their fields leave no gaps and integers take any bytes
+unsafe impl Abi for InHeader {}
+unsafe impl Abi for OutHeader {}
+unsafe impl Abi for InitIn {}
+unsafe impl Abi for InitOut {}
+unsafe impl Abi for Attr {}
+unsafe impl Abi for EntryOut {}
+unsafe impl Abi for AttrOut {}
+unsafe impl Abi for OpenOut {}
+unsafe impl Abi for ReadIn {}
+unsafe impl Abi for StatfsOut {}
+unsafe impl Abi for Dirent {}
+
+/// What an inode is
+#[derive(Debug)]
+enum Kind {
+    Dir(BTreeMap<OsString, u64>),
+    /// The `index` of its archive entry, and where its data starts in archives without
+    /// compression
+    File {
+        index: usize,
+        offset: Option<u64>,
+    },
+    Symlink(PathBuf),
+    /// Fifos and devices
+    Other,
+}
+
+#[derive(Debug)]
+struct Node {
+    kind: Kind,
+    /// Type and permission bits
+    mode: u32,
+    size: u64,
+    mtime: SystemTime,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    nlink: u32,
+}
+
+/// An opened file
+enum Handle {
+    /// Read straight from an archive without compression, `size` bytes from `offset` on
+    Plain { offset: u64, size: u64 },
+    /// Decompressed into a temporary file when it was opened
+    Extracted(fs::File),
+}
+
+/// The entries of an archive as a tree of inodes, the inode of a node is its index + 1
+struct Tree {
+    archive: PathBuf,
+    window_log_max: Option<u32>,
+    nodes: Vec<Node>,
+    /// The archive itself if it is not compressed, to read files from it directly
+    plain: Option<fs::File>,
+    handles: HashMap<u64, Handle>,
+    next_handle: u64,
+}
+
+impl Tree {
+    /// Reads all entries of the archive at `archive`, without their content
+    fn read(archive: &Path, window_log_max: Option<u32>) -> io::Result<Self> {
+        let mtime = fs::metadata(archive)?.modified()?;
+        let plain = match Compression::detect(archive) {
+            Some(Compression::None) => Some(fs::File::open(archive)?),
+            _ => None,
+        };
+        let mut tree = Tree {
+            archive: archive.to_path_buf(),
+            window_log_max,
+            nodes: vec![implicit_dir(mtime)],
+            plain,
+            handles: HashMap::new(),
+            next_handle: 1,
+        };
+        read_archive(archive, window_log_max, |entries| {
+            for (index, entry) in entries.entries()?.enumerate() {
+                tree.add(index, &mut entry?, mtime)?;
+            }
+            Ok(())
+        })?;
+        Ok(tree)
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(usize::try_from(ino).ok()?.checked_sub(1)?)
+    }
+
+    fn push(&mut self, node: Node) -> u64 {
+        self.nodes.push(node);
+        self.nodes.len() as u64
+    }
+
+    /// Adds the archive entry number `index`, with the directories above it that the archive
+    /// has no entries for
+    fn add<R: Read>(
+        &mut self,
+        index: usize,
+        entry: &mut tar::Entry<R>,
+        mtime: SystemTime,
+    ) -> io::Result<()> {
+        let name = unpack::normalize_path(&entry.path()?);
+        if name.components().any(|c| c == Component::ParentDir) {
+            return Ok(());
+        }
+        let header = entry.header();
+        let ty = header.entry_type();
+        let mut node = Node {
+            kind: Kind::Other,
+            mode: header.mode()? & 0o7777,
+            size: 0,
+            mtime: UNIX_EPOCH + std::time::Duration::from_secs(header.mtime()?),
+            uid: header.uid()? as u32,
+            gid: header.gid()? as u32,
+            rdev: 0,
+            nlink: 1,
+        };
+        node.kind = match ty {
+            tar::EntryType::Directory => Kind::Dir(BTreeMap::new()),
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                node.size = entry.size();
+                Kind::File {
+                    index,
+                    offset: Some(entry.raw_file_position()),
+                }
+            }
+            tar::EntryType::GNUSparse => {
+                node.size = entry.size();
+                Kind::File {
+                    index,
+                    offset: None,
+                }
+            }
+            tar::EntryType::Symlink => {
+                let target = entry.link_name()?.unwrap_or_default().into_owned();
+                node.size = target.as_os_str().len() as u64;
+                Kind::Symlink(target)
+            }
+            tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+                let major = header.device_major()?.unwrap_or(0);
+                let minor = header.device_minor()?.unwrap_or(0);
+                node.rdev = libc::makedev(major, minor) as u32;
+                Kind::Other
+            }
+            tar::EntryType::Link => {
+                let target = entry.link_name()?.unwrap_or_default();
+                let target = unpack::normalize_path(&target);
+                return match self.find(&target) {
+                    Some(ino) => {
+                        if let Some(node) = self.nodes.get_mut(ino as usize - 1) {
+                            node.nlink += 1;
+                        }
+                        self.link(&name, ino, mtime);
+                        Ok(())
+                    }
+                    None => Ok(()),
+                };
+            }
+            _ => return Ok(()),
+        };
+        node.mode |= match node.kind {
+            Kind::Dir(_) => libc::S_IFDIR,
+            Kind::File { .. } => libc::S_IFREG,
+            Kind::Symlink(_) => libc::S_IFLNK,
+            Kind::Other => match ty {
+                tar::EntryType::Char => libc::S_IFCHR,
+                tar::EntryType::Block => libc::S_IFBLK,
+                _ => libc::S_IFIFO,
+            },
+        };
+        if let Some(nanos) = format::pax_mtime(entry)? {
+            node.mtime = nanos;
+        }
+
+        if name.as_os_str().is_empty() {
+            // the entry of the root itself, `./`, keeps what is below it
+            if let Kind::Dir(_) = node.kind {
+                let root = &mut self.nodes[0];
+                node.kind = std::mem::replace(&mut root.kind, Kind::Other);
+                *root = node;
+            }
+            return Ok(());
+        }
+        // an existing directory keeps its children when its own entry comes after them
+        if let (Kind::Dir(_), Some(ino)) = (&node.kind, self.find(&name)) {
+            let existing = &mut self.nodes[ino as usize - 1];
+            if let Kind::Dir(children) = &mut existing.kind {
+                node.kind = Kind::Dir(std::mem::take(children));
+                *existing = node;
+                return Ok(());
+            }
+        }
+        let ino = self.push(node);
+        self.link(&name, ino, mtime);
+        Ok(())
+    }
+
+    /// Names `ino` as `name`, replacing what had that name before
+    fn link(&mut self, name: &Path, ino: u64, mtime: SystemTime) {
+        let mut parent = ROOT;
+        let parts: Vec<&OsStr> = name.iter().collect();
+        let Some((last, dirs)) = parts.split_last() else {
+            return;
+        };
+        for part in dirs {
+            parent = match self.child(parent, part) {
+                Some(child) if matches!(self.node(child).map(|n| &n.kind), Some(Kind::Dir(_))) => {
+                    child
+                }
+                _ => {
+                    let dir = self.push(implicit_dir(mtime));
+                    self.children(parent).insert(part.to_os_string(), dir);
+                    dir
+                }
+            };
+        }
+        self.children(parent).insert(last.to_os_string(), ino);
+    }
+
+    fn children(&mut self, dir: u64) -> &mut BTreeMap<OsString, u64> {
+        match &mut self.nodes[dir as usize - 1].kind {
+            Kind::Dir(children) => children,
+            _ => unreachable!("only directories are parents"),
+        }
+    }
+
+    fn child(&self, dir: u64, name: &OsStr) -> Option<u64> {
+        match &self.node(dir)?.kind {
+            Kind::Dir(children) => children.get(name).copied(),
+            _ => None,
+        }
+    }
+
+    /// The inode of the entry named `name`
+    fn find(&self, name: &Path) -> Option<u64> {
+        name.iter()
+            .try_fold(ROOT, |dir, part| self.child(dir, part))
+    }
+
+    fn attr(&self, ino: u64) -> Option<Attr> {
+        let node = self.node(ino)?;
+        let mtime = node.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Some(Attr {
+            ino,
+            size: node.size,
+            blocks: node.size.div_ceil(512),
+            atime: mtime.as_secs(),
+            mtime: mtime.as_secs(),
+            ctime: mtime.as_secs(),
+            atimensec: mtime.subsec_nanos(),
+            mtimensec: mtime.subsec_nanos(),
+            ctimensec: mtime.subsec_nanos(),
+            mode: node.mode,
+            // unknown for directories, like btrfs has it, so find does not count on it
+            nlink: node.nlink,
+            uid: node.uid,
+            gid: node.gid,
+            rdev: node.rdev,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+
+    /// Opens the file `ino` and returns its handle, which files in compressed archives need
+    /// one pass over the archive for
+    fn open(&mut self, ino: u64) -> io::Result<u64> {
+        let handle = match self.node(ino).map(|n| (&n.kind, n.size)) {
+            Some((&Kind::File { index, offset }, size)) => match (offset, &self.plain) {
+                (Some(offset), Some(_)) => Handle::Plain { offset, size },
+                _ => Handle::Extracted(self.extract(index)?),
+            },
+            Some((Kind::Dir(_), _)) => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+            _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(fh, handle);
+        Ok(fh)
+    }
+
+    /// Decompresses entry number `index` into an unnamed temporary file
+    fn extract(&self, index: usize) -> io::Result<fs::File> {
+        let mut file = temp_file()?;
+        read_archive(&self.archive, self.window_log_max, |archive| {
+            for (i, entry) in archive.entries()?.enumerate() {
+                let mut entry = entry?;
+                if i == index {
+                    io::copy(&mut entry, &mut file)?;
+                    return Ok(());
+                }
+            }
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the archive changed since it was mounted",
+            ))
+        })?;
+        Ok(file)
+    }
+
+    /// Reads up to `size` bytes from `offset` on of the file opened as `fh`
+    fn read_file(&self, fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let (file, start, len) = match self.handles.get(&fh) {
+            Some(Handle::Plain { offset, size }) => {
+                (self.plain.as_ref().expect("plain archive"), *offset, *size)
+            }
+            Some(Handle::Extracted(file)) => (file, 0, u64::MAX),
+            None => return Err(io::Error::from_raw_os_error(libc::EBADF)),
+        };
+        let want = u64::from(size).min(len.saturating_sub(offset));
+        let mut buf = vec![0; want as usize];
+        let mut done = 0;
+        while done < buf.len() {
+            match file.read_at(&mut buf[done..], start + offset + done as u64)? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        buf.truncate(done);
+        Ok(buf)
+    }
+
+    /// Directory entries of `ino` from number `offset` on, as many as fit into `size` bytes
+    fn read_dir(&self, ino: u64, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let Kind::Dir(children) = &self.node(ino)?.kind else {
+            return None;
+        };
+        let parent = self.parent(ino).unwrap_or(ROOT);
+        let entries = [(OsStr::new("."), ino), (OsStr::new(".."), parent)]
+            .into_iter()
+            .chain(children.iter().map(|(name, &ino)| (name.as_os_str(), ino)));
+        let mut out = Vec::new();
+        for (i, (name, child)) in entries.enumerate().skip(offset as usize) {
+            let dirent = Dirent {
+                ino: child,
+                off: i as u64 + 1,
+                namelen: name.len() as u32,
+                typ: self.node(child).map_or(0, |n| n.mode >> 12),
+            };
+            let len = (size_of::<Dirent>() + name.len()).next_multiple_of(8);
+            if out.len() + len > size as usize {
+                break;
+            }
+            out.extend_from_slice(dirent.as_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.resize(out.len().next_multiple_of(8), 0);
+        }
+        Some(out)
+    }
+
+    fn parent(&self, ino: u64) -> Option<u64> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .find_map(|(i, node)| match &node.kind {
+                Kind::Dir(children) if children.values().any(|&c| c == ino) => Some(i as u64 + 1),
+                _ => None,
+            })
+    }
+
+    fn entry_out(&self, ino: u64) -> Option<EntryOut> {
+        Some(EntryOut {
+            nodeid: ino,
+            generation: 0,
+            entry_valid: TTL_SECS,
+            attr_valid: TTL_SECS,
+            entry_valid_nsec: 0,
+            attr_valid_nsec: 0,
+            attr: self.attr(ino)?,
+        })
+    }
+
+    /// Answers one request, [`None`] for those that get no answer. Errors are errnos.
+    fn handle(&mut self, header: &InHeader, data: &[u8]) -> Option<Result<Vec<u8>, i32>> {
+        let ino = header.nodeid;
+        let enoent = Err(libc::ENOENT);
+        Some(match header.opcode {
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+            FUSE_LOOKUP => {
+                let name = data.split(|&b| b == 0).next().unwrap_or_default();
+                self.child(ino, OsStr::from_bytes(name))
+                    .and_then(|child| self.entry_out(child))
+                    .map_or(enoent, |out| Ok(out.as_bytes().to_vec()))
+            }
+            FUSE_GETATTR => self.attr(ino).map_or(enoent, |attr| {
+                let out = AttrOut {
+                    attr_valid: TTL_SECS,
+                    attr_valid_nsec: 0,
+                    dummy: 0,
+                    attr,
+                };
+                Ok(out.as_bytes().to_vec())
+            }),
+            FUSE_READLINK => match self.node(ino).map(|n| &n.kind) {
+                Some(Kind::Symlink(target)) => Ok(target.as_os_str().as_bytes().to_vec()),
+                _ => Err(libc::EINVAL),
+            },
+            FUSE_OPEN => match self.open(ino) {
+                Ok(fh) => {
+                    let out = OpenOut {
+                        fh,
+                        open_flags: FOPEN_KEEP_CACHE,
+                        padding: 0,
+                    };
+                    Ok(out.as_bytes().to_vec())
+                }
+                Err(e) => Err(e.raw_os_error().unwrap_or(libc::EIO)),
+            },
+            FUSE_READ => match ReadIn::from_bytes(data) {
+                Some(read) => self
+                    .read_file(read.fh, read.offset, read.size)
+                    .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO)),
+                None => Err(libc::EINVAL),
+            },
+            FUSE_RELEASE => {
+                if let Some(read) = ReadIn::from_bytes(data) {
+                    self.handles.remove(&read.fh);
+                }
+                Ok(Vec::new())
+            }
+            FUSE_OPENDIR => match self.node(ino).map(|n| &n.kind) {
+                Some(Kind::Dir(_)) => Ok(OpenOut {
+                    fh: 0,
+                    open_flags: FOPEN_KEEP_CACHE,
+                    padding: 0,
+                }
+                .as_bytes()
+                .to_vec()),
+                Some(_) => Err(libc::ENOTDIR),
+                None => enoent,
+            },
+            FUSE_READDIR => match ReadIn::from_bytes(data) {
+                Some(read) => self
+                    .read_dir(ino, read.offset, read.size)
+                    .ok_or(libc::ENOTDIR),
+                None => Err(libc::EINVAL),
+            },
+            FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_DESTROY => Ok(Vec::new()),
+            FUSE_STATFS => {
+                let size: u64 = self.nodes.iter().map(|n| n.size).sum();
+                let out = StatfsOut {
+                    blocks: size.div_ceil(4096),
+                    files: self.nodes.len() as u64,
+                    bsize: 4096,
+                    frsize: 4096,
+                    namelen: 255,
+                    ..Default::default()
+                };
+                Ok(out.as_bytes().to_vec())
+            }
+            _ => Err(libc::ENOSYS),
+        })
+    }
+}
+
+fn implicit_dir(mtime: SystemTime) -> Node {
+    Node {
+        kind: Kind::Dir(BTreeMap::new()),
+        mode: libc::S_IFDIR | 0o755,
+        size: 0,
+        mtime,
+        // SAFETY: getuid and getgid can not fail
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        rdev: 0,
+        nlink: 1,
+    }
+}
+
+/// A file without a name in the temporary directory, which is gone once it is closed
+fn temp_file() -> io::Result<fs::File> {
+    let dir = std::env::temp_dir();
+    match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .mode(0o600)
+        .open(&dir)
+    {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+            let path = dir.join(format!(".loppler-mount-{}", process::id()));
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?;
+            fs::remove_file(&path)?;
+            Ok(file)
+        }
+        result => result,
+    }
+}
+
+/// Mounts the archive at `archive` read-only at `mountpoint` and serves it until it is
+/// unmounted, or unmounts it on SIGINT and SIGTERM
+pub(crate) fn mount(
+    archive: &Path,
+    mountpoint: &Path,
+    window_log_max: Option<u32>,
+) -> io::Result<()> {
+    if Compression::detect(archive).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not an archive: {}", archive.display()),
+        ));
+    }
+    let mut tree = Tree::read(archive, window_log_max)?;
+    let (fuse, root) = open_fuse(archive, mountpoint)?;
+    println!(
+        "Mounted {} at {}, unmount it or press Ctrl-C to stop",
+        archive.display(),
+        mountpoint.display()
+    );
+    on_stop_signals();
+    let result = serve(&fuse, &mut tree);
+    if STOP.load(Ordering::Relaxed) {
+        unmount(mountpoint, root)?;
+    }
+    result
+}
+
+/// Answers requests until the file system is unmounted or a stop signal arrives
+fn serve(fuse: &fs::File, tree: &mut Tree) -> io::Result<()> {
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        if STOP.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let n = match (&*fuse).read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => match e.raw_os_error() {
+                // interrupted by a signal, or the request was withdrawn
+                Some(libc::EINTR | libc::ENOENT | libc::EAGAIN) => continue,
+                // unmounted
+                Some(libc::ENODEV) => return Ok(()),
+                _ => return Err(e),
+            },
+        };
+        let Some(header) = InHeader::from_bytes(&buf[..n]) else {
+            continue;
+        };
+        let data = &buf[size_of::<InHeader>()..n];
+        let reply = if header.opcode == FUSE_INIT {
+            Some(init(data))
+        } else {
+            tree.handle(&header, data)
+        };
+        let (error, payload) = match reply {
+            None => continue,
+            Some(Ok(payload)) => (0, payload),
+            Some(Err(errno)) => (-errno, Vec::new()),
+        };
+        let out = OutHeader {
+            len: (size_of::<OutHeader>() + payload.len()) as u32,
+            error,
+            unique: header.unique,
+        };
+        let mut message = out.as_bytes().to_vec();
+        message.extend_from_slice(&payload);
+        // fails if the request was interrupted meanwhile, which needs no answer then
+        let _ = (&*fuse).write(&message);
+    }
+}
+
+/// Agrees on the version of the protocol
+fn init(data: &[u8]) -> Result<Vec<u8>, i32> {
+    let init = InitIn::from_bytes(data).ok_or(libc::EINVAL)?;
+    if init.major < FUSE_KERNEL_VERSION {
+        return Err(libc::EPROTO);
+    }
+    let out = InitOut {
+        major: FUSE_KERNEL_VERSION,
+        minor: init.minor.min(FUSE_KERNEL_MINOR_VERSION),
+        max_readahead: init.max_readahead,
+        max_write: MAX_WRITE,
+        time_gran: 1,
+        ..Default::default()
+    };
+    Ok(out.as_bytes().to_vec())
+}
+
+/// Opens `/dev/fuse` and mounts it at `mountpoint`, with mount(2) as root and fusermount
+/// otherwise. Returns whether it was mounted as root, to unmount it the same way.
+fn open_fuse(archive: &Path, mountpoint: &Path) -> io::Result<(fs::File, bool)> {
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    // commas separate the options
+    let fsname = format!("loppel:{}", name.replace(',', "_"));
+    // SAFETY: geteuid can not fail
+    if unsafe { libc::geteuid() } != 0 {
+        return fusermount(&fsname, mountpoint).map(|fuse| (fuse, false));
+    }
+    let fuse = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let options = format!(
+        "fd={},rootmode=40000,user_id=0,group_id=0,default_permissions",
+        fuse.as_raw_fd()
+    );
+    let c = |s: &[u8]| CString::new(s).map_err(io::Error::other);
+    let (source, target) = (c(fsname.as_bytes())?, c(mountpoint.as_os_str().as_bytes())?);
+    let (fstype, data) = (c(b"fuse.loppel")?, c(options.as_bytes())?);
+    // SAFETY: all arguments are valid strings
+    let mounted = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+            data.as_ptr().cast(),
+        )
+    };
+    if mounted != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((fuse, true))
+}
+
+/// Mounts with the setuid fusermount helper, which sends the opened `/dev/fuse` back over a
+/// socket whose number it gets in `_FUSE_COMMFD`
+fn fusermount(fsname: &str, mountpoint: &Path) -> io::Result<fs::File> {
+    let mut sockets = [0; 2];
+    // SAFETY: socketpair writes two descriptors into the array
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, sockets.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just created and belong to nothing else
+    let (ours, theirs) = unsafe {
+        (
+            fs::File::from_raw_fd(sockets[0]),
+            fs::File::from_raw_fd(sockets[1]),
+        )
+    };
+    let options = format!("ro,nosuid,nodev,fsname={fsname},subtype=loppel");
+    let mut status = None;
+    for program in ["fusermount3", "fusermount"] {
+        match process::Command::new(program)
+            .args(["-o", &options, "--"])
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status()
+        {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => {
+                status = Some(result?);
+                break;
+            }
+        }
+    }
+    drop(theirs);
+    match status {
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "mounting needs fusermount3 or fusermount when not run as root",
+            ))
+        }
+        Some(status) if !status.success() => {
+            return Err(io::Error::other(format!("fusermount failed with {status}")))
+        }
+        Some(_) => (),
+    }
+    receive_fd(&ours)
+}
+
+/// Receives a descriptor sent with SCM_RIGHTS over `socket`
+fn receive_fd(socket: &fs::File) -> io::Result<fs::File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // aligned for the cmsghdr
+    let mut control = [0u64; 8];
+    // SAFETY: an all zero msghdr is valid, the pointers are set below
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = size_of::<[u64; 8]>() as _;
+    // SAFETY: msg points to buffers that live until the call returns
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the control buffer was filled by recvmsg
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    // SAFETY: checked for null before it is read
+    if cmsg.is_null() || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(io::Error::other("fusermount sent no file descriptor"));
+    }
+    // SAFETY: SCM_RIGHTS data is a descriptor that is now ours
+    Ok(unsafe {
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::c_int>());
+        fs::File::from_raw_fd(fd)
+    })
+}
+
+fn unmount(mountpoint: &Path, root: bool) -> io::Result<()> {
+    if !root {
+        let status = process::Command::new("fusermount3")
+            .args(["-u", "-z", "--"])
+            .arg(mountpoint)
+            .status()
+            .or_else(|_| {
+                process::Command::new("fusermount")
+                    .args(["-u", "-z", "--"])
+                    .arg(mountpoint)
+                    .status()
+            })?;
+        return match status.success() {
+            true => Ok(()),
+            false => Err(io::Error::other(format!("fusermount failed with {status}"))),
+        };
+    }
+    let target = CString::new(mountpoint.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    // SAFETY: target is a valid string
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "C" fn on_stop(_: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+/// Makes SIGINT and SIGTERM set [`STOP`] and interrupt reading the next request
+fn on_stop_signals() {
+    // SAFETY: the handler only stores to an atomic, and without SA_RESTART the blocking read
+    // returns with EINTR
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_stop as *const () as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::mem::size_of;
+    use std::path::{Path, PathBuf};
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::{
+        mount, unmount, AttrOut, EntryOut, InHeader, InitOut, StatfsOut, Tree, FUSE_LOOKUP, ROOT,
+    };
+    use crate::{backup_dir, BackupOptions, BackupStats, Compression};
+
+    #[test]
+    fn test_abi_sizes() {
+        assert_eq!(size_of::<InHeader>(), 40);
+        assert_eq!(size_of::<InitOut>(), 64);
+        assert_eq!(size_of::<EntryOut>(), 128);
+        assert_eq!(size_of::<AttrOut>(), 104);
+        assert_eq!(size_of::<StatfsOut>(), 80);
+    }
+
+    fn archives(tdir: &Path) -> io::Result<Vec<PathBuf>> {
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub").join("foo"), b"foo content")?;
+        fs::write(src.join("bar"), b"bar")?;
+        std::os::unix::fs::symlink("sub/foo", src.join("link"))?;
+        let mut archives = Vec::new();
+        for compress in [Compression::None, Compression::Zstd] {
+            let opts = BackupOptions {
+                compress: Some(compress),
+                ..Default::default()
+            };
+            archives.push(tdir.join(backup_dir(&src, &opts, &mut BackupStats::default())?));
+        }
+        Ok(archives)
+    }
+
+    #[test]
+    #[serial]
+    fn test_tree() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        for archive in archives(t.path())? {
+            let mut tree = Tree::read(&archive, None)?;
+            let foo = tree.find(Path::new("src/sub/foo")).unwrap();
+            assert_eq!(tree.attr(foo).unwrap().size, 11);
+            let fh = tree.open(foo)?;
+            assert_eq!(tree.read_file(fh, 0, 4096)?, b"foo content");
+            assert_eq!(tree.read_file(fh, 4, 3)?, b"con");
+            assert!(tree.read_file(fh, 20, 10)?.is_empty());
+
+            let link = tree.find(Path::new("src/link")).unwrap();
+            assert!(tree.open(link).is_err());
+            let src = tree.find(Path::new("src")).unwrap();
+            assert_eq!(tree.parent(src), Some(ROOT));
+
+            let header = InHeader {
+                len: 0,
+                opcode: FUSE_LOOKUP,
+                unique: 1,
+                nodeid: src,
+                uid: 0,
+                gid: 0,
+                pid: 0,
+                padding: 0,
+            };
+            assert!(matches!(tree.handle(&header, b"bar\0"), Some(Ok(_))));
+            assert_eq!(tree.handle(&header, b"baz\0"), Some(Err(libc::ENOENT)));
+
+            let listing = tree.read_dir(src, 0, 4096).unwrap();
+            // ., .., bar, link and sub
+            let names = listing.windows(4).filter(|w| *w == b"bar\0").count();
+            assert_eq!(names, 1);
+            assert!(tree.read_dir(src, 5, 4096).unwrap().is_empty());
+            assert!(tree.read_dir(foo, 0, 4096).is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_mount() -> io::Result<()> {
+        // SAFETY: geteuid can not fail
+        if unsafe { libc::geteuid() } != 0 || !Path::new("/dev/fuse").exists() {
+            return Ok(());
+        }
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let archive = archives(t.path())?.pop().unwrap();
+        let mnt = t.path().join("mnt");
+        fs::create_dir(&mnt)?;
+
+        let (archive_, mnt_) = (archive.clone(), mnt.clone());
+        let server = std::thread::spawn(move || mount(&archive_, &mnt_, None));
+        let foo = mnt.join("src/sub/foo");
+        for _ in 0..100 {
+            if foo.exists() || server.is_finished() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let read = fs::read(&foo);
+        let link = fs::read_link(mnt.join("src/link"));
+        let written = fs::write(mnt.join("src/bar"), b"no");
+        let mut names: Vec<_> = fs::read_dir(mnt.join("src"))?
+            .map(|e| e.map(|e| e.file_name()))
+            .collect::<io::Result<_>>()?;
+        names.sort();
+        unmount(&mnt, true)?;
+        server.join().unwrap()?;
+
+        assert_eq!(read?, b"foo content");
+        assert_eq!(link?, Path::new("sub/foo"));
+        assert!(written.is_err());
+        assert_eq!(names, ["bar", "link", "sub"]);
+        Ok(())
+    }
+}
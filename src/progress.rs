@@ -0,0 +1,183 @@
+//! Machine readable progress for frontends, as newline delimited JSON on a file descriptor
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How often file events are written to terminals by default
+const TTY_INTERVAL: Duration = Duration::from_millis(100);
+/// How often they are written to files and pipes, which might be logs
+const FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes progress events to a file descriptor given by the caller
+#[derive(Debug)]
+pub(crate) struct Progress {
+    out: fs::File,
+    /// Estimated number of bytes of the whole run
+    total: u64,
+    /// Least time between two file events, the ones in between are dropped
+    interval: Duration,
+    /// When the last file event was written
+    last: Option<Instant>,
+}
+
+impl Progress {
+    /// Takes ownership of `fd`, which has to be open for writing
+    pub(crate) fn from_fd(fd: RawFd, total: u64) -> io::Result<Self> {
+        // SAFETY: fcntl only inspects the descriptor
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("progress fd {fd} is not open"),
+            ));
+        }
+        // SAFETY: isatty only inspects the descriptor
+        let interval = if unsafe { libc::isatty(fd) } == 1 {
+            TTY_INTERVAL
+        } else {
+            FILE_INTERVAL
+        };
+        // SAFETY: the descriptor is open and nothing else in loppler uses it
+        let out = unsafe { fs::File::from_raw_fd(fd) };
+        Ok(Progress {
+            out,
+            total,
+            interval,
+            last: None,
+        })
+    }
+
+    /// Writes file events at most once per `interval` instead of the default, which depends
+    /// on whether the descriptor is a terminal. Zero writes all of them.
+    pub(crate) fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Writes a file event for `path`, unless the last one was written less than the interval
+    /// ago
+    pub(crate) fn file(&mut self, path: &Path, done: u64) -> io::Result<()> {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return Ok(());
+        }
+        self.last = Some(now);
+        self.event("file", Some(path), done)
+    }
+
+    /// Writes one event, `done` is the number of bytes read so far
+    pub(crate) fn event(&mut self, event: &str, path: Option<&Path>, done: u64) -> io::Result<()> {
+        let mut line = format!("{{\"event\":{}", json_string(event));
+        if let Some(path) = path {
+            write!(line, ",\"path\":{}", json_string(&path.to_string_lossy())).unwrap();
+        }
+        writeln!(
+            line,
+            ",\"bytes_done\":{done},\"bytes_total\":{}}}",
+            self.total
+        )
+        .unwrap();
+        self.out.write_all(line.as_bytes())
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use std::os::fd::IntoRawFd;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::{json_string, Progress};
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), r#""a \"b\"\\\n\u0001""#);
+    }
+
+    #[test]
+    fn test_progress_events() -> io::Result<()> {
+        let mut file = tempfile::tempfile()?;
+        let fd = file.try_clone()?.into_raw_fd();
+        let mut progress = Progress::from_fd(fd, 10)?;
+        progress.event("file", Some(Path::new("dir/a")), 4)?;
+        progress.event("done", None, 10)?;
+        drop(progress);
+
+        let mut written = String::new();
+        io::Seek::rewind(&mut file)?;
+        file.read_to_string(&mut written)?;
+        assert_eq!(
+            written,
+            concat!(
+                r#"{"event":"file","path":"dir/a","bytes_done":4,"bytes_total":10}"#,
+                "\n",
+                r#"{"event":"done","bytes_done":10,"bytes_total":10}"#,
+                "\n"
+            )
+        );
+        assert!(Progress::from_fd(9999, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_interval() -> io::Result<()> {
+        let mut file = tempfile::tempfile()?;
+        let fd = file.try_clone()?.into_raw_fd();
+        let mut progress = Progress::from_fd(fd, 10)?;
+        // files are no terminals, so the events are throttled to one per second
+        for i in 0..5 {
+            progress.file(Path::new("a"), i)?;
+        }
+        progress.set_interval(Duration::ZERO);
+        for i in 5..8 {
+            progress.file(Path::new("b"), i)?;
+        }
+        progress.event("done", None, 10)?;
+        drop(progress);
+
+        let mut written = String::new();
+        io::Seek::rewind(&mut file)?;
+        file.read_to_string(&mut written)?;
+        let done: Vec<&str> = written
+            .lines()
+            .map(|line| line.split("bytes_done\":").nth(1).unwrap())
+            .collect();
+        assert_eq!(
+            done,
+            [
+                "0,\"bytes_total\":10}",
+                "5,\"bytes_total\":10}",
+                "6,\"bytes_total\":10}",
+                "7,\"bytes_total\":10}",
+                "10,\"bytes_total\":10}"
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+//! Copy on write clones of files with `FICLONE`, for `--reflink` on btrfs, XFS and the like
+
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Makes `dst` share the data of `src`, fails if the filesystem can not do that
+fn clone(src: &fs::File, dst: &fs::File) -> io::Result<()> {
+    // SAFETY: both descriptors are open for as long as the call takes
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `e` says that files can not be cloned here, rather than that something went wrong
+fn is_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV | libc::EINVAL | libc::ENOSYS)
+    )
+}
+
+/// Clones the file at `src` to `dst` with its permissions, `false` if the filesystem can not
+/// do that and it has to be copied instead
+pub(crate) fn copy(src: &Path, dst: &Path) -> io::Result<bool> {
+    let input = fs::File::open(src)?;
+    let output = fs::File::create(dst)?;
+    match clone(&input, &output) {
+        Err(e) if is_unsupported(&e) => Ok(false),
+        Err(e) => Err(e),
+        Ok(()) => {
+            output.set_permissions(input.metadata()?.permissions())?;
+            Ok(true)
+        }
+    }
+}
+
+/// An unnamed clone of the file at `path` next to it, which keeps its content of this instant
+/// while the file itself is still written to. [`None`] if the filesystem can not clone it.
+pub(crate) fn snapshot(path: &Path) -> io::Result<Option<fs::File>> {
+    let input = fs::File::open(path)?;
+    let dir = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    // without a name, walking the directory never sees it and nothing is left to clean up
+    let output = match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .mode(0o600)
+        .open(dir)
+    {
+        Ok(output) => output,
+        Err(e) if is_unsupported(&e) || e.kind() == io::ErrorKind::PermissionDenied => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    match clone(&input, &output) {
+        Err(e) if is_unsupported(&e) => Ok(None),
+        Err(e) => Err(e),
+        Ok(()) => Ok(Some(output)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Read};
+
+    use tempfile::tempdir;
+
+    use super::{copy, snapshot};
+
+    #[test]
+    fn test_reflink() -> io::Result<()> {
+        let t = tempdir()?;
+        let src = t.path().join("src");
+        fs::write(&src, b"the content")?;
+
+        // most test machines can not clone, then nothing may be left behind
+        let dst = t.path().join("dst");
+        if copy(&src, &dst)? {
+            assert_eq!(fs::read(&dst)?, b"the content");
+        }
+        if let Some(mut clone) = snapshot(&src)? {
+            fs::write(&src, b"changed")?;
+            let mut content = Vec::new();
+            clone.read_to_end(&mut content)?;
+            assert_eq!(content, b"the content");
+        }
+        let names: Vec<_> = fs::read_dir(t.path())?.collect::<io::Result<_>>()?;
+        assert!(names.len() <= 2);
+        Ok(())
+    }
+}
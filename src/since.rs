@@ -0,0 +1,131 @@
+//! Differential backups with `--since`, which leave out the files that an earlier backup has
+//! as they are
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compare::{backup_entries, Kind};
+use crate::hash::{hash_reader, to_hex, HashAlgorithm};
+use crate::manifest::Manifest;
+use crate::path_identity;
+
+/// The files of the backup that `--since` names
+#[derive(Debug)]
+pub(crate) struct Reference {
+    /// Where the backup is, as [`path_identity`] has it
+    pub(crate) backup: PathBuf,
+    algorithm: HashAlgorithm,
+    /// Size and hash of every file, by its name below the backed up directory
+    files: HashMap<PathBuf, (u64, String)>,
+}
+
+impl Reference {
+    /// Reads the files of the backup at `backup` from its manifest, or hashes them if it has
+    /// no manifest that tells where they are below
+    pub(crate) fn read(backup: &Path, window_log_max: Option<u32>) -> io::Result<Self> {
+        let manifest = Manifest::for_backup(backup)?;
+        if let Some((manifest, origin)) = manifest
+            .as_ref()
+            .and_then(|m| Some((m, m.origin.as_ref()?)))
+        {
+            let files = manifest.entries.iter().filter_map(|entry| {
+                let name = entry.name.strip_prefix(&origin.name).ok()?;
+                Some((name.to_path_buf(), (entry.size, entry.hash.clone())))
+            });
+            return Ok(Reference {
+                backup: path_identity(backup),
+                algorithm: manifest.algorithm,
+                files: files.collect(),
+            });
+        }
+        let mut reference = Reference {
+            backup: path_identity(backup),
+            algorithm: HashAlgorithm::default(),
+            files: HashMap::new(),
+        };
+        // the backed up directory comes first
+        let mut root = None;
+        backup_entries(backup, window_log_max, |name, kind, size, content| {
+            let root = root.get_or_insert_with(|| match kind {
+                Kind::Dir => name.clone(),
+                _ => PathBuf::new(),
+            });
+            if kind == Kind::File {
+                if let Ok(rel) = name.strip_prefix(&*root) {
+                    let hash = to_hex(&hash_reader(reference.algorithm, content)?);
+                    reference.files.insert(rel.to_path_buf(), (size, hash));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(reference)
+    }
+
+    /// Whether the file at `path`, named `rel` below the backed up directory, is in the
+    /// reference as it is now
+    pub(crate) fn has(&self, path: &Path, rel: &Path) -> io::Result<bool> {
+        let Some((size, hash)) = self.files.get(rel) else {
+            return Ok(false);
+        };
+        if fs::metadata(path)?.len() != *size {
+            return Ok(false);
+        }
+        Ok(to_hex(&hash_reader(self.algorithm, fs::File::open(path)?)?) == *hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::Reference;
+    use crate::hash::HashAlgorithm;
+    use crate::{backup_dir, BackupOptions, BackupStats, Compression};
+
+    #[test]
+    #[serial]
+    fn test_reference() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("same"), b"same")?;
+        fs::write(src.join("sub").join("edited"), b"before")?;
+
+        for (compress, hash) in [
+            (None, None),
+            (Some(Compression::None), Some(HashAlgorithm::Blake3)),
+            #[cfg(feature = "compression")]
+            (Some(Compression::Zstd), None),
+            #[cfg(feature = "compression")]
+            (Some(Compression::Zstd), Some(HashAlgorithm::Blake3)),
+        ] {
+            let opts = BackupOptions {
+                compress,
+                hash,
+                prefix: compress.map(|_| PathBuf::from("below")),
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let reference = Reference::read(&backup, None)?;
+            fs::write(src.join("sub").join("edited"), b"after!")?;
+            let has = |rel: &str| reference.has(&src.join(rel), Path::new(rel));
+            assert!(has("same")?, "{opts:?}");
+            assert!(!has("sub/edited")?);
+            fs::write(src.join("new"), b"new")?;
+            assert!(!has("new")?);
+
+            fs::write(src.join("sub").join("edited"), b"before")?;
+            fs::remove_file(src.join("new"))?;
+            crate::recursive_remove(&backup)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+//! Files that are written as one, for `--also-to`: everything written goes into all of them,
+//! so the sources are read once however many destinations the backups have
+
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::path::Path;
+
+/// The same content for several files. Writes only return once they are in all of them, and
+/// the first error fails the whole.
+pub(crate) struct Tee {
+    files: Vec<fs::File>,
+}
+
+impl Tee {
+    /// Creates the files at `paths`, truncating them if they exist
+    pub(crate) fn create<P: AsRef<Path>>(paths: &[P]) -> io::Result<Self> {
+        let files = paths
+            .iter()
+            .map(fs::File::create)
+            .collect::<io::Result<_>>()?;
+        Ok(Tee { files })
+    }
+
+    /// The file itself if there is only one, to hand it to others
+    pub(crate) fn into_single(mut self) -> Result<fs::File, Self> {
+        match self.files.len() {
+            1 => Ok(self.files.pop().expect("one file")),
+            _ => Err(self),
+        }
+    }
+
+    /// Cuts off or extends all files to `len`
+    pub(crate) fn set_len(&self, len: u64) -> io::Result<()> {
+        self.files.iter().try_for_each(|file| file.set_len(len))
+    }
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for file in &mut self.files {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.iter_mut().try_for_each(|file| file.flush())
+    }
+}
+
+impl Seek for Tee {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        // the files have the same length, and so end up at the same position
+        let mut position = 0;
+        for file in &mut self.files {
+            position = file.seek(pos)?;
+        }
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Seek, Write};
+
+    use tempfile::tempdir;
+
+    use super::Tee;
+
+    #[test]
+    fn test_tee() -> io::Result<()> {
+        let t = tempdir()?;
+        let paths = [t.path().join("a"), t.path().join("b")];
+        let mut tee = Tee::create(&paths)?;
+        tee.write_all(b"hello world")?;
+        assert_eq!(tee.seek(io::SeekFrom::Start(6))?, 6);
+        tee.write_all(b"there")?;
+        assert_eq!(tee.stream_position()?, 11);
+        tee.set_len(8)?;
+        let tee = tee.into_single().err().unwrap();
+        drop(tee);
+        for path in &paths {
+            assert_eq!(fs::read(path)?, b"hello th");
+        }
+
+        let single = Tee::create(&paths[..1])?;
+        assert!(single.into_single().is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,743 @@
+//! Extracting archives entry by entry
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::color;
+use crate::compare::Kind;
+use crate::format::{check_version, pax_mtime};
+use crate::hash::{hash_reader, to_hex, HashAlgorithm, Hasher, CHUNK_SIZE};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::{
+    keep_existing, report_errors, set_dir_times, set_mtime, trace, trace_done, RestoreOptions,
+};
+
+/// Rewrites entry names starting with `from` to start with `to` instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PrefixMap {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+impl FromStr for PrefixMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected OLD=NEW, got {s:?}"))?;
+        Ok(PrefixMap {
+            from: normalize_path(Path::new(from)),
+            to: normalize_path(Path::new(to)),
+        })
+    }
+}
+
+impl PrefixMap {
+    /// Maps entries starting with `from` below `to`
+    pub(crate) fn new(from: &Path, to: &Path) -> Self {
+        PrefixMap {
+            from: normalize_path(from),
+            to: normalize_path(to),
+        }
+    }
+
+    fn apply(&self, name: &Path) -> Option<PathBuf> {
+        name.strip_prefix(&self.from)
+            .ok()
+            .map(|rest| self.to.join(rest))
+    }
+}
+
+/// Translates the uids and gids of archive entries for `restore --owner-map`. Ids that are
+/// not in the map are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct OwnerMap {
+    uids: HashMap<u64, u64>,
+    gids: HashMap<u64, u64>,
+}
+
+impl OwnerMap {
+    /// Reads a map file, for the command line
+    pub(crate) fn from_file(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        content.parse().map_err(|e| format!("{path}: {e}"))
+    }
+
+    pub(crate) fn uid(&self, uid: u64) -> u64 {
+        self.uids.get(&uid).copied().unwrap_or(uid)
+    }
+
+    pub(crate) fn gid(&self, gid: u64) -> u64 {
+        self.gids.get(&gid).copied().unwrap_or(gid)
+    }
+
+    /// Gives `target` the owner of `header`, translated. Symlinks themselves are changed.
+    fn apply(&self, target: &Path, header: &tar::Header) -> io::Result<()> {
+        let uid = u32::try_from(self.uid(header.uid()?)).map_err(io::Error::other)?;
+        let gid = u32::try_from(self.gid(header.gid()?)).map_err(io::Error::other)?;
+        std::os::unix::fs::lchown(target, Some(uid), Some(gid))
+    }
+}
+
+/// One mapping per line, `OLD:NEW` for both the uid and the gid, or `user OLD:NEW` and
+/// `group OLD:NEW` for only one of them. `#` starts a comment.
+impl FromStr for OwnerMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = OwnerMap::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || {
+                format!(
+                    "line {}: expected [user|group] OLD:NEW, got {line:?}",
+                    i + 1
+                )
+            };
+            let (kind, ids) = match line.split_once(char::is_whitespace) {
+                Some((kind, ids)) => (Some(kind), ids.trim()),
+                None => (None, line),
+            };
+            let (old, new) = ids.split_once(':').ok_or_else(invalid)?;
+            let (old, new) = match (old.parse(), new.parse()) {
+                (Ok(old), Ok(new)) => (old, new),
+                _ => return Err(invalid()),
+            };
+            match kind {
+                None => {
+                    map.uids.insert(old, new);
+                    map.gids.insert(old, new);
+                }
+                Some("user") => {
+                    map.uids.insert(old, new);
+                }
+                Some("group") => {
+                    map.gids.insert(old, new);
+                }
+                Some(_) => return Err(invalid()),
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Drops `.` and leading `/` parts, like unpacking does
+pub(crate) fn normalize_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| !matches!(c, Component::RootDir | Component::CurDir))
+        .collect()
+}
+
+/// Normalizes an entry name and rewrites it with the first matching prefix map
+pub(crate) fn map_name(name: &Path, maps: &[PrefixMap]) -> PathBuf {
+    let name = normalize_path(name);
+    maps.iter().find_map(|m| m.apply(&name)).unwrap_or(name)
+}
+
+/// Where an entry named `name` ends up below `output_dir`, [`None`] if it would escape it
+fn entry_target(output_dir: &Path, name: &Path) -> Option<PathBuf> {
+    let mut target = output_dir.to_path_buf();
+    for part in name.components() {
+        match part {
+            Component::Prefix(..) | Component::RootDir | Component::CurDir => continue,
+            Component::ParentDir => return None,
+            Component::Normal(part) => target.push(part),
+        }
+    }
+    Some(target)
+}
+
+/// Creates the FIFO or device file `path`, `mode` includes the file type bits like `st_mode`.
+/// Devices can usually only be created with privileges.
+pub(crate) fn mknod(path: &Path, mode: u32, dev: u64) -> io::Result<()> {
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(path)?;
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: c_path is a valid, nul terminated string that outlives the call
+    if unsafe { libc::mknod(c_path.as_ptr(), mode as libc::mode_t, dev as libc::dev_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn case_fold(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// The entry written earlier that `target` is the same file as, because it only differs in
+/// case on a case insensitive file system
+fn case_collision(case_folded: &HashMap<String, PathBuf>, target: &Path) -> Option<PathBuf> {
+    let earlier = case_folded
+        .get(&case_fold(target))
+        .filter(|e| *e != target)?;
+    let (a, b) = (
+        earlier.symlink_metadata().ok()?,
+        target.symlink_metadata().ok()?,
+    );
+    (a.dev() == b.dev() && a.ino() == b.ino()).then(|| earlier.clone())
+}
+
+/// Whether something at `target` was modified at the same time as `entry` or later, for
+/// `--overwrite-older`
+fn is_newer_than<R: Read>(target: &Path, entry: &mut tar::Entry<R>) -> io::Result<bool> {
+    let Ok(meta) = target.symlink_metadata() else {
+        return Ok(false);
+    };
+    let archived = match pax_mtime(entry)? {
+        Some(mtime) => mtime,
+        None => UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?),
+    };
+    Ok(meta.modified()? >= archived)
+}
+
+fn outside_error(name: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "entry would be unpacked outside of the output directory: {}",
+            name.display()
+        ),
+    )
+}
+
+/// The `st_mode` file type bits for FIFO and device entries
+fn special_file_type(ty: tar::EntryType) -> Option<u32> {
+    match ty {
+        tar::EntryType::Fifo => Some(libc::S_IFIFO),
+        tar::EntryType::Char => Some(libc::S_IFCHR),
+        tar::EntryType::Block => Some(libc::S_IFBLK),
+        _ => None,
+    }
+}
+
+/// Writes a regular file entry to `target` like [`tar::Entry::unpack`] and checks it against
+/// its manifest entry while doing so. It is written next to `target` first and only replaces
+/// what is there once it matches, a file that does not is removed again.
+fn unpack_verified<R: Read>(
+    entry: &mut tar::Entry<R>,
+    target: &Path,
+    algorithm: HashAlgorithm,
+    expected: &ManifestEntry,
+) -> io::Result<()> {
+    let mut temp = target.as_os_str().to_os_string();
+    temp.push(format!(".loppler-{}", std::process::id()));
+    let temp = PathBuf::from(temp);
+    let result =
+        write_verified(entry, &temp, algorithm, expected).and_then(|()| fs::rename(&temp, target));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Writes the entry of [`unpack_verified`] to `path`, failing if it does not match `expected`
+fn write_verified<R: Read>(
+    entry: &mut tar::Entry<R>,
+    path: &Path,
+    algorithm: HashAlgorithm,
+    expected: &ManifestEntry,
+) -> io::Result<()> {
+    let mode = entry.header().mode()? & 0o777;
+    let mtime = entry.header().mtime()?;
+    let mut file = fs::File::create(path)?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = match entry.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..n]);
+        file.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+
+    if size != expected.size || to_hex(&hasher.finalize()) != expected.hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("does not match the manifest: {}", expected.name.display()),
+        ));
+    }
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+}
+
+/// Reads all of `archive` without writing anything, for `restore --list-then-extract`. Fails
+/// like [`unpack_archive`] would on damaged archives, entries outside of the output directory
+/// and files that do not match `manifest`. Returns the names the entries get when restored,
+/// with their kinds.
+pub(crate) fn check_archive<R: Read>(
+    archive: &mut tar::Archive<R>,
+    opts: &RestoreOptions,
+    manifest: Option<&Manifest>,
+) -> io::Result<Vec<(PathBuf, Kind)>> {
+    let mut expected: HashMap<&Path, &ManifestEntry> = manifest
+        .iter()
+        .flat_map(|m| &m.entries)
+        .map(|entry| (entry.name.as_path(), entry))
+        .collect();
+    let mut names = Vec::new();
+    for (i, entry) in archive.entries()?.enumerate() {
+        let mut entry = entry?;
+        if i == 0 && opts.version_check {
+            if let Some(problem) = check_version(&mut entry)? {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, problem));
+            }
+        }
+        let original = normalize_path(&entry.path()?);
+        let name = map_name(&original, &opts.prefix_map);
+        entry_target(Path::new(""), &name).ok_or_else(|| outside_error(&name))?;
+        if entry.header().entry_type().is_hard_link() {
+            let link = entry.link_name()?.unwrap_or_default();
+            let link = map_name(&link, &opts.prefix_map);
+            entry_target(Path::new(""), &link).ok_or_else(|| outside_error(&link))?;
+        }
+
+        // reading the contents decompresses them, which finds damage in the middle
+        let expected = expected
+            .remove(original.as_path())
+            .filter(|_| entry.header().entry_type().is_file());
+        match (expected, manifest) {
+            (Some(expected), Some(manifest)) => {
+                let size = entry.size();
+                let hash = hash_reader(manifest.algorithm, &mut entry)?;
+                if size != expected.size || to_hex(&hash) != expected.hash {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("does not match the manifest: {}", expected.name.display()),
+                    ));
+                }
+            }
+            _ => {
+                io::copy(&mut entry, &mut io::sink())?;
+            }
+        }
+        if !name.as_os_str().is_empty() {
+            names.push((name, Kind::of(entry.header().entry_type())));
+        }
+    }
+    if let Some(entry) = expected.values().min_by_key(|entry| &entry.name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("missing from the backup: {}", entry.name.display()),
+        ));
+    }
+    Ok(names)
+}
+
+/// Extracts all entries of `archive` below `output_dir`, like [`tar::Archive::unpack`], but
+/// with the restore options applied to every entry. Returns the paths of all unpacked entries
+/// and the number of entries that failed, which is only not zero with `--ignore-errors`.
+///
+/// Regular files are checked against `manifest` while they are written, the first one that
+/// does not match aborts.
+///
+/// Entries that can not be written are reported together at the end, after unpacking all
+/// others. Entries that would end up outside of `output_dir` and damaged archives abort.
+/// `--ignore-errors` reports all of these at the end as well, a damaged archive is unpacked
+/// up to the damage.
+pub(crate) fn unpack_archive<R: Read>(
+    archive: &mut tar::Archive<R>,
+    output_dir: &Path,
+    opts: &RestoreOptions,
+    manifest: Option<&Manifest>,
+) -> io::Result<(Vec<PathBuf>, usize)> {
+    let output_dir = output_dir.canonicalize()?;
+    let mut expected: HashMap<&Path, &ManifestEntry> = manifest
+        .iter()
+        .flat_map(|m| &m.entries)
+        .map(|entry| (entry.name.as_path(), entry))
+        .collect();
+
+    // directories are applied last, so that their permissions can not get in the way of
+    // unpacking their contents
+    let mut directories = Vec::new();
+    let mut written = Vec::new();
+    let mut errors = Vec::new();
+    // written entries by their case folded path, to notice case insensitive file systems
+    let mut case_folded = HashMap::new();
+    // names for errors that are not in an entry
+    let mut last = PathBuf::new();
+    let after = |last: &Path| match last.as_os_str().is_empty() {
+        true => PathBuf::from("(start of the archive)"),
+        false => PathBuf::from(format!("(after {})", last.display())),
+    };
+    for (i, entry) in archive.entries()?.enumerate() {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            // nothing after the damage can be found
+            Err(e) => {
+                entry_failed(&mut errors, after(&last), e, opts)?;
+                break;
+            }
+        };
+        if i == 0 {
+            if let Some(problem) = check_version(&mut entry)? {
+                if opts.version_check {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, problem));
+                }
+                eprintln!("warning: {problem}, it might not be restored correctly");
+            }
+        }
+        // manifests have the names of the archive
+        let original = match entry.path() {
+            Ok(path) => normalize_path(&path),
+            Err(e) => {
+                entry_failed(&mut errors, after(&last), e, opts)?;
+                continue;
+            }
+        };
+        last.clone_from(&original);
+        let name = map_name(&original, &opts.prefix_map);
+        let Some(target) = entry_target(&output_dir, &name) else {
+            entry_failed(&mut errors, name.clone(), outside_error(&name), opts)?;
+            continue;
+        };
+        if target == output_dir {
+            continue;
+        }
+
+        let parent = target.parent().expect("entry target has no parent");
+        if let Err(e) = fs::create_dir_all(parent) {
+            errors.push((name, e));
+            continue;
+        }
+        // a symlink unpacked earlier could point anywhere
+        match parent.canonicalize() {
+            Ok(parent) if parent.starts_with(&output_dir) => (),
+            Ok(_) => {
+                entry_failed(&mut errors, name.clone(), outside_error(&name), opts)?;
+                continue;
+            }
+            Err(e) => {
+                entry_failed(&mut errors, name, e, opts)?;
+                continue;
+            }
+        }
+
+        let ty = entry.header().entry_type();
+        if ty.is_dir() {
+            directories.push((target, name, entry));
+            continue;
+        }
+        let newer = match opts.overwrite_older {
+            true => is_newer_than(&target, &mut entry),
+            false => Ok(false),
+        };
+        let newer = match newer {
+            Ok(newer) => newer,
+            Err(e) => {
+                entry_failed(&mut errors, name, e, opts)?;
+                continue;
+            }
+        };
+        if newer {
+            println!(
+                "{}",
+                color::skipped(format_args!("keeping newer: {}", target.display()))
+            );
+        }
+        let kept = match newer {
+            true => Ok(true),
+            false => keep_existing(&target, opts),
+        };
+        match kept {
+            Ok(false) => (),
+            Ok(true) => {
+                expected.remove(original.as_path());
+                written.push(target);
+                continue;
+            }
+            Err(e) => {
+                entry_failed(&mut errors, name, e, opts)?;
+                continue;
+            }
+        }
+        // hard links are the same file as another entry on purpose
+        let collision = (!ty.is_hard_link())
+            .then(|| case_collision(&case_folded, &target))
+            .flatten();
+        if let Some(earlier) = collision {
+            errors.push((
+                name,
+                io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "only differs in case from {}, which the file system does not tell \
+                         apart, not restored",
+                        earlier.display()
+                    ),
+                ),
+            ));
+            continue;
+        }
+        let started = trace(opts.verbose, "restoring", &target);
+        let result = if ty.is_hard_link() {
+            // the link target is an entry name as well, it has to be mapped the same way
+            let link = entry.link_name().and_then(|link| {
+                link.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("hard link without a target: {}", name.display()),
+                    )
+                })
+            });
+            let source = link.and_then(|link| {
+                let link = map_name(&link, &opts.prefix_map);
+                let source =
+                    entry_target(&output_dir, &link).ok_or_else(|| outside_error(&link))?;
+                // like for the parent of the target, a symlink on the way could lead outside
+                let parent = source.parent().expect("entry target has no parent");
+                match parent.canonicalize()?.starts_with(&output_dir) {
+                    true => Ok(source),
+                    false => Err(outside_error(&link)),
+                }
+            });
+            let source = match source {
+                Ok(source) => source,
+                Err(e) => {
+                    entry_failed(&mut errors, name, e, opts)?;
+                    continue;
+                }
+            };
+            let removed = match target.symlink_metadata() {
+                Ok(_) => fs::remove_file(&target),
+                Err(_) => Ok(()),
+            };
+            removed.and_then(|()| fs::hard_link(source, &target))
+        } else if let Some(file_type) = special_file_type(ty) {
+            // the tar crate would unpack these as regular files
+            let header = entry.header();
+            let mode = file_type | (header.mode()? & 0o7777);
+            let dev = libc::makedev(
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0),
+            );
+            mknod(&target, mode, dev)
+        } else if let (Some(expected), Some(manifest)) = (
+            expected.remove(original.as_path()).filter(|_| ty.is_file()),
+            manifest,
+        ) {
+            match unpack_verified(&mut entry, &target, manifest.algorithm, expected) {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    entry_failed(&mut errors, name, e, opts)?;
+                    continue;
+                }
+            }
+        } else {
+            entry.unpack(&target).map(|_| ())
+        };
+        trace_done(started);
+        let result = match &opts.owner_map {
+            Some(owners) => result.and_then(|()| owners.apply(&target, entry.header())),
+            None => result,
+        };
+        // unpacking only sets whole seconds, hard links have the time of their target
+        let result = match pax_mtime(&mut entry) {
+            Ok(Some(mtime)) if !ty.is_hard_link() => {
+                result.and_then(|()| set_mtime(&target, Some(mtime)))
+            }
+            Ok(_) => result,
+            Err(e) => {
+                entry_failed(&mut errors, name, e, opts)?;
+                continue;
+            }
+        };
+        match result {
+            Ok(()) => {
+                case_folded.insert(case_fold(&target), target.clone());
+                written.push(target);
+            }
+            Err(e) => errors.push((name, e)),
+        }
+    }
+
+    directories.sort_by(|a, b| b.0.cmp(&a.0));
+    let mut dir_times = Vec::new();
+    for (target, name, mut dir) in directories {
+        let mtime = pax_mtime(&mut dir).and_then(|mtime| match mtime {
+            Some(mtime) => Ok(mtime),
+            None => Ok(UNIX_EPOCH + Duration::from_secs(dir.header().mtime()?)),
+        });
+        let mtime = match mtime {
+            Ok(mtime) => mtime,
+            Err(e) => {
+                entry_failed(&mut errors, name, e, opts)?;
+                continue;
+            }
+        };
+        let result = dir.unpack(&target).map(drop);
+        let result = match &opts.owner_map {
+            Some(owners) => result.and_then(|()| owners.apply(&target, dir.header())),
+            None => result,
+        };
+        match result {
+            Ok(()) => {
+                if opts.preserve_mtime_on_dirs {
+                    dir_times.push((target.clone(), mtime));
+                }
+                written.push(target);
+            }
+            Err(e) => errors.push((name, e)),
+        }
+    }
+    set_dir_times(dir_times)?;
+
+    let mut missing: Vec<_> = expected.into_values().collect();
+    missing.sort_by_key(|entry| &entry.name);
+    for entry in missing {
+        let e = io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("missing from the backup: {}", entry.name.display()),
+        );
+        entry_failed(&mut errors, entry.name.clone(), e, opts)?;
+    }
+    if !errors.is_empty() {
+        report_errors(&errors);
+        if !opts.ignore_errors {
+            return Err(io::Error::other(format!(
+                "{} entries could not be restored",
+                errors.len()
+            )));
+        }
+    }
+    Ok((written, errors.len()))
+}
+
+/// Collects the error `e` of the entry `name` for `--ignore-errors`, or returns it to abort
+fn entry_failed(
+    errors: &mut Vec<(PathBuf, io::Error)>,
+    name: PathBuf,
+    e: io::Error,
+    opts: &RestoreOptions,
+) -> io::Result<()> {
+    if !opts.ignore_errors {
+        return Err(e);
+    }
+    errors.push((name, e));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use tempfile::tempdir;
+
+    use super::{
+        case_collision, case_fold, entry_target, map_name, unpack_archive, OwnerMap, PrefixMap,
+    };
+    use crate::RestoreOptions;
+
+    #[test]
+    fn test_prefix_map() {
+        let maps: Vec<PrefixMap> = ["/home/alice=/home/bob", "etc=backup/etc"]
+            .iter()
+            .map(|m| m.parse().unwrap())
+            .collect();
+        let map = |name: &str| map_name(Path::new(name), &maps);
+
+        assert_eq!(map("home/alice/.bashrc"), PathBuf::from("home/bob/.bashrc"));
+        assert_eq!(map("./etc/hosts"), PathBuf::from("backup/etc/hosts"));
+        // prefixes are matched by whole components
+        assert_eq!(map("home/alicia/x"), PathBuf::from("home/alicia/x"));
+        assert!("no-equals".parse::<PrefixMap>().is_err());
+    }
+
+    #[test]
+    fn test_owner_map() {
+        let map: OwnerMap = "# old system\n1000:1001\nuser 1002:0  # root\n\ngroup 50:60\n"
+            .parse()
+            .unwrap();
+        assert_eq!((map.uid(1000), map.gid(1000)), (1001, 1001));
+        assert_eq!((map.uid(1002), map.gid(1002)), (0, 1002));
+        assert_eq!((map.uid(50), map.gid(50)), (50, 60));
+        assert_eq!(map.uid(7), 7);
+        for bad in ["1000", "1000:x", "other 1:2", "1:2:3"] {
+            assert!(bad.parse::<OwnerMap>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_entry_target() {
+        let out = Path::new("/out");
+        assert_eq!(entry_target(out, Path::new("a/b")), Some(out.join("a/b")));
+        assert_eq!(entry_target(out, Path::new("/a")), Some(out.join("a")));
+        assert_eq!(entry_target(out, Path::new("a/../../b")), None);
+    }
+
+    #[test]
+    fn test_hard_link_through_symlink() -> io::Result<()> {
+        let t = tempdir()?;
+        let secret = t.path().join("secret");
+        fs::create_dir(&secret)?;
+        fs::write(secret.join("s"), b"secret")?;
+        let out = t.path().join("out");
+        fs::create_dir(&out)?;
+
+        // a symlink out of the output directory, and a hard link through it
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "evil", &secret)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, "x", "evil/s")?;
+        let data = builder.into_inner()?;
+
+        let e = unpack_archive(
+            &mut tar::Archive::new(data.as_slice()),
+            &out,
+            &RestoreOptions::default(),
+            None,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("outside"), "{e}");
+        assert!(out.join("evil").is_symlink());
+        assert!(!out.join("x").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_collision() -> io::Result<()> {
+        let t = tempdir()?;
+        let upper = t.path().join("Foo");
+        let lower = t.path().join("foo");
+        fs::write(&upper, b"upper")?;
+        let mut case_folded = HashMap::new();
+        case_folded.insert(case_fold(&upper), upper.clone());
+
+        // on a case sensitive file system, these are different files
+        assert_eq!(case_collision(&case_folded, &lower), None);
+        fs::write(&lower, b"lower")?;
+        assert_eq!(case_collision(&case_folded, &lower), None);
+        assert_eq!(case_collision(&case_folded, &upper), None);
+
+        // a case insensitive one behaves like both names link to the same file
+        fs::remove_file(&lower)?;
+        fs::hard_link(&upper, &lower)?;
+        assert_eq!(case_collision(&case_folded, &lower), Some(upper));
+        Ok(())
+    }
+}
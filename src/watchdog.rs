@@ -0,0 +1,264 @@
+//! `--keep-going-timeout`: file operations that can hang forever, like reads from a dead
+//! network mount, run in threads of their own, which are given up on when they take too long.
+//! A thread stuck in the kernel can not be stopped, it is left behind.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+/// Size of the chunks a [`Reader`] reads, files up to it are read before anything is written
+const CHUNK_SIZE: usize = 128 * 1024;
+
+/// What to do with a file that does not respond in time, `--on-timeout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum OnTimeout {
+    /// Skip the file and report it at the end
+    #[default]
+    Skip,
+    /// Stop the backups like `--time-limit` does
+    Abort,
+}
+
+/// The cause of the errors about files that did not respond in time
+#[derive(Debug)]
+struct Stalled(String);
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Stalled {}
+
+fn stalled(path: &Path, timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        Stalled(format!("{}: no response for {timeout:?}", path.display())),
+    )
+}
+
+/// Whether `e` is about a file that did not respond within the timeout
+pub(crate) fn is_stalled(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<Stalled>())
+}
+
+/// Runs `op` on `path` in a thread, failing if it takes longer than `timeout`. Without a
+/// timeout it runs right here.
+pub(crate) fn run<T, F>(path: &Path, timeout: Option<Duration>, op: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Path) -> io::Result<T> + Send + 'static,
+{
+    let Some(timeout) = timeout else {
+        return op(path);
+    };
+    let (tx, rx) = mpsc::channel();
+    let owned = path.to_path_buf();
+    thread::Builder::new()
+        .name("watchdog".to_string())
+        .spawn(move || {
+            let _ = tx.send(op(&owned));
+        })?;
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(stalled(path, timeout)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(io::Error::other(format!(
+            "{}: the thread working on it panicked",
+            path.display()
+        ))),
+    }
+}
+
+/// Reads a file in a thread of its own, failing once a read takes longer than the timeout.
+/// The first chunk is read by [`Reader::new`], so that a file which does not respond from the
+/// start fails before anything of it is written.
+pub(crate) struct Reader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+    path: PathBuf,
+    timeout: Duration,
+}
+
+impl Reader {
+    /// Starts reading `file`, which was opened from `path`
+    pub(crate) fn new(path: &Path, mut file: fs::File, timeout: Duration) -> io::Result<Self> {
+        // a chunk ahead at most
+        let (tx, rx) = mpsc::sync_channel(1);
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || loop {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let result = match file.read(&mut chunk) {
+                    Ok(n) => {
+                        chunk.truncate(n);
+                        Ok(chunk)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let end = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+                if tx.send(result).is_err() || end {
+                    return;
+                }
+            })?;
+        let mut reader = Reader {
+            chunks: rx,
+            chunk: Vec::new(),
+            pos: 0,
+            done: false,
+            path: path.to_path_buf(),
+            timeout,
+        };
+        reader.next_chunk()?;
+        Ok(reader)
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        match self.chunks.recv_timeout(self.timeout) {
+            Ok(Ok(chunk)) => {
+                self.done = chunk.is_empty();
+                self.chunk = chunk;
+                self.pos = 0;
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                self.done = true;
+                Err(e)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(stalled(&self.path, self.timeout)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                self.done = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::{is_stalled, run, OnTimeout, Reader, CHUNK_SIZE};
+    use crate::{
+        backup_dir, restore, skip_unreadable, unpack, BackupOptions, BackupStats, Compression,
+        RestoreOptions,
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn test_watchdog() -> io::Result<()> {
+        let t = tempdir()?;
+        assert_eq!(run(t.path(), Some(TIMEOUT), |_| Ok(1))?, 1);
+        let e = run(t.path(), Some(TIMEOUT), |_| {
+            std::thread::sleep(TIMEOUT * 10);
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(is_stalled(&e), "{e}");
+        assert!(!is_stalled(&io::Error::from(io::ErrorKind::TimedOut)));
+
+        // nothing comes out of an empty fifo that is open for writing as well
+        let fifo = t.path().join("fifo");
+        unpack::mknod(&fifo, libc::S_IFIFO | 0o600, 0)?;
+        let writer = fs::OpenOptions::new().read(true).write(true).open(&fifo)?;
+        let e = Reader::new(&fifo, writer.try_clone()?, TIMEOUT)
+            .err()
+            .unwrap();
+        assert!(is_stalled(&e), "{e}");
+        // the thread left behind would read from the same one
+        let fifo = t.path().join("fifo2");
+        unpack::mknod(&fifo, libc::S_IFIFO | 0o600, 0)?;
+        let mut writer = fs::OpenOptions::new().read(true).write(true).open(&fifo)?;
+        writer.write_all(b"start")?;
+        let mut reader = Reader::new(&fifo, writer.try_clone()?, TIMEOUT)?;
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"start");
+        assert!(is_stalled(&reader.read(&mut buf).unwrap_err()));
+
+        let file = t.path().join("file");
+        let content = vec![7; CHUNK_SIZE * 2 + 1];
+        fs::write(&file, &content)?;
+        let mut read = Vec::new();
+        Reader::new(&file, fs::File::open(&file)?, TIMEOUT)?.read_to_end(&mut read)?;
+        assert_eq!(read, content);
+
+        let mut opts = BackupOptions::default();
+        let mut stats = BackupStats::default();
+        skip_unreadable(&fifo, e, &opts, &mut stats)?;
+        assert_eq!(stats.errors.len(), 1);
+        opts.on_timeout = OnTimeout::Abort;
+        let (path, e) = stats.errors.pop().unwrap();
+        assert!(skip_unreadable(&path, e, &opts, &mut stats).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn test_backup_with_timeout() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("small"), b"small")?;
+        let large: Vec<u8> = (0..CHUNK_SIZE * 3).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(src.join("sub").join("large"), &large)?;
+
+        for compress in [
+            None,
+            Some(Compression::None),
+            #[cfg(feature = "compression")]
+            Some(Compression::Zstd),
+        ] {
+            let opts = BackupOptions {
+                compress,
+                keep_going_timeout: Some(Duration::from_secs(10)),
+                store_incompressible: true,
+                ..Default::default()
+            };
+            let backup = backup_dir(&src, &opts, &mut BackupStats::default())?;
+            let out = t.path().join("out");
+            fs::create_dir(&out)?;
+            restore(&backup, &out, &RestoreOptions::default())?;
+            assert_eq!(fs::read(out.join("src/small"))?, b"small");
+            assert_eq!(fs::read(out.join("src/sub/large"))?, large);
+            fs::remove_dir_all(&out)?;
+            crate::recursive_remove(&backup)?;
+        }
+        assert!(crate::parse_timeout("0").is_err());
+        assert_eq!(crate::parse_timeout("30s"), Ok(Duration::from_secs(30)));
+        Ok(())
+    }
+}
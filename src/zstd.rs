@@ -0,0 +1,125 @@
+//! Stands in for the zstd crate in builds without the `compression` feature. It has the parts
+//! of its API that loppel uses, and creating an encoder or decoder fails with an error that
+//! says why.
+
+use std::convert::Infallible;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+
+/// The level of the zstd crate, `--level` still parses without it
+pub(crate) const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Why zstd archives can not be created or read
+pub(crate) fn unavailable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "this loppel was built without the compression feature, so it can not create or read \
+         zstd archives. Use --compress=none for plain .tar archives, or --compress-program zstd \
+         and --decompress-program 'zstd -d'.",
+    )
+}
+
+/// Can not exist, [`Encoder::new`] always fails
+pub(crate) struct Encoder<'a, W>(Infallible, PhantomData<&'a W>);
+
+impl<W: Write> Encoder<'static, W> {
+    pub(crate) fn new(_: W, _: i32) -> io::Result<Self> {
+        Err(unavailable())
+    }
+
+    pub(crate) fn include_checksum(&mut self, _: bool) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub(crate) fn multithread(&mut self, _: u32) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub(crate) fn long_distance_matching(&mut self, _: bool) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub(crate) fn window_log(&mut self, _: u32) -> io::Result<()> {
+        match self.0 {}
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self.0 {}
+    }
+}
+
+impl<W> Write for Encoder<'_, W> {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        match self.0 {}
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0 {}
+    }
+}
+
+/// Can not exist, [`Decoder::new`] always fails
+pub(crate) struct Decoder<'a, R>(Infallible, PhantomData<&'a R>);
+
+impl<R: Read> Decoder<'static, io::BufReader<R>> {
+    pub(crate) fn new(_: R) -> io::Result<Self> {
+        Err(unavailable())
+    }
+
+    pub(crate) fn window_log_max(&mut self, _: u32) -> io::Result<()> {
+        match self.0 {}
+    }
+}
+
+impl<R> Read for Decoder<'_, R> {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        match self.0 {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use crate::{backup_dir, restore, BackupOptions, BackupStats, Compression, RestoreOptions};
+
+    #[test]
+    #[serial]
+    fn test_without_zstd() -> io::Result<()> {
+        let t = tempdir()?;
+        std::env::set_current_dir(t.path())?;
+        let src = PathBuf::from("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("foo"), b"foo")?;
+
+        let zstd = BackupOptions {
+            compress: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        let e = backup_dir(&src, &zstd, &mut BackupStats::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+        fs::write("fake.tar.zst", b"")?;
+        let e = restore(
+            "fake.tar.zst".as_ref(),
+            t.path(),
+            &RestoreOptions::default(),
+        );
+        assert_eq!(e.unwrap_err().kind(), io::ErrorKind::Unsupported);
+
+        // plain archives work as usual
+        let plain = BackupOptions {
+            compress: Some(Compression::None),
+            ..Default::default()
+        };
+        let backup = backup_dir(&src, &plain, &mut BackupStats::default())?;
+        fs::remove_dir_all(&src)?;
+        restore(&backup, t.path(), &RestoreOptions::default())?;
+        assert_eq!(fs::read(src.join("foo"))?, b"foo");
+        Ok(())
+    }
+}